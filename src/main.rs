@@ -1,36 +1,111 @@
 pub mod nodes;
 pub mod parsers;
 pub mod prelude;
+pub mod providers;
 pub mod utils;
 
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, Command};
 use prelude::*;
 use std::fs::File;
 use std::io::{self, Read};
+
+// Host functions executing transactions, which every `require` of a block
+// must precede
+const TRANSACTIONAL_FUNCTIONS: [&str; 3] = ["Buy", "Sell", "TransferAmount"];
+
+// The other host functions of the command line
+const HOST_FUNCTIONS: [&str; 1] = ["Notify"];
+
+// Registers the host functions of the command line, which runs scripts as a
+// dry run: transactions and notifications are printed rather than carried out
+fn with_host_functions(evaluator: ExpressionEvaluator) -> ExpressionEvaluator {
+    TRANSACTIONAL_FUNCTIONS
+        .iter()
+        .chain(HOST_FUNCTIONS.iter())
+        .fold(evaluator, |evaluator, &name| {
+            evaluator.with_host_function(name, move |args| {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                println!("{}({})", name, args.join(", "));
+                Ok(Value::Bool(true))
+            })
+        })
+}
+
 // This is a placeholder function for your lexer, parser, and evaluator.
 // Replace it with your actual implementation.
-fn run_lefi_script(script: &str) -> Result<Vec<Value>> {
+fn run_lefi_script(
+    script: &str,
+    calendars: Vec<BusinessCalendar>,
+) -> Result<(RunResult, Vec<Value>)> {
     // Tokenize the script (implement this with your actual lexer)
-    let tokens = Lexer::new(script.to_string())
-        .tokenize()
-        .map_err(|e| ScriptingError::from(e))?;
+    let tokens = Lexer::new(script.to_string()).tokenize()?;
 
     // Parse the tokens into an AST (implement with your parser)
-    let nodes = Parser::new(tokens)
-        .parse()
-        .map_err(|e| ScriptingError::from(e))?;
+    let parser = Parser::new(tokens)
+        .with_host_functions(&HOST_FUNCTIONS)
+        .with_transactional_functions(&TRANSACTIONAL_FUNCTIONS);
+    let nodes = parser.parse()?;
+    for warning in parser.warnings() {
+        eprintln!("{}", warning);
+    }
+
+    // Fold the constant expressions and the branches that never run
+    let nodes = Optimizer::new().visit(&nodes);
 
     // Index expressions and initialize evaluator (adjust according to your actual logic)
     let indexer = ExpressionIndexer::new();
     indexer.visit(&nodes);
+    indexer.check_shadowing()?;
+
+    // Reject reads of variables that may not be assigned yet
+    InitializationChecker::new().visit(&nodes)?;
+
+    let evaluator = calendars.into_iter().fold(
+        with_host_functions(ExpressionEvaluator::new().with_variables(indexer.get_size())),
+        ExpressionEvaluator::with_calendar,
+    );
+    let result = evaluator.run(nodes)?;
+
+    // Return how the run ended and the evaluated variable values
+    Ok((result, evaluator.variables()))
+}
 
-    let evaluator = ExpressionEvaluator::new().with_variables(indexer.get_size());
-    evaluator
-        .const_visit(nodes)
-        .map_err(|e| ScriptingError::from(e))?;
+// Lints a script without running it, with the severities given as
+// `CODE=LEVEL`, e.g. `L001=error`
+fn lint_lefi_script(script: &str, severities: Vec<&String>) -> Result<Vec<Diagnostic>> {
+    let tokens = Lexer::new(script.to_string()).tokenize()?;
+    let parser = Parser::new(tokens)
+        .with_host_functions(&HOST_FUNCTIONS)
+        .with_transactional_functions(&TRANSACTIONAL_FUNCTIONS);
+    let nodes = parser.parse()?;
+    for warning in parser.warnings() {
+        eprintln!("{}", warning);
+    }
+
+    let mut linter = Linter::new();
+    for severity in severities {
+        let (code, level) = severity.split_once('=').ok_or_else(|| {
+            ScriptingError::EvaluationError(format!(
+                "Expected CODE=LEVEL, e.g. L001=error, found {}",
+                severity
+            ))
+        })?;
+        linter = linter.with_severity(code.parse()?, level.parse()?);
+    }
+    linter.lint(&nodes)
+}
 
-    // Return the evaluated variable values
-    Ok(evaluator.variables().clone())
+// Reads a .lefi file, exiting when the path has another extension
+fn read_script(input_path: &str) -> io::Result<String> {
+    if !input_path.ends_with(".lefi") {
+        eprintln!("Error: The input file must have a .lefi extension.");
+        std::process::exit(1);
+    }
+
+    let mut file = File::open(input_path)?;
+    let mut script = String::new();
+    file.read_to_string(&mut script)?;
+    Ok(script)
 }
 
 fn main() -> io::Result<()> {
@@ -45,28 +120,98 @@ fn main() -> io::Result<()> {
                 .required(true)
                 .index(1),
         )
+        .arg(
+            Arg::new("calendar")
+                .long("calendar")
+                .help("Holiday file of a business day calendar, named after the file")
+                .action(ArgAction::Append),
+        )
+        .args_conflicts_with_subcommands(true)
+        .subcommand(
+            Command::new("lint")
+                .about("Reports likely mistakes in a .lefi file without running it")
+                .arg(
+                    Arg::new("input")
+                        .help("Input .lefi file")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("severity")
+                        .long("severity")
+                        .value_name("CODE=LEVEL")
+                        .help("Severity of a lint: allow, warning or error, e.g. L001=error")
+                        .action(ArgAction::Append),
+                ),
+        )
         .get_matches();
 
+    // `lefi lint script.lefi` prints the diagnostics, and fails on errors
+    if let Some(("lint", matches)) = matches.subcommand() {
+        let input_path: &String = matches.get_one("input").expect("Input file is required");
+        let script = read_script(input_path)?;
+        let severities = matches
+            .get_many::<String>("severity")
+            .unwrap_or_default()
+            .collect();
+        match lint_lefi_script(&script, severities) {
+            Ok(diagnostics) => {
+                for diagnostic in &diagnostics {
+                    println!("{}", diagnostic);
+                }
+                if diagnostics
+                    .iter()
+                    .any(|diagnostic| diagnostic.severity == Severity::Error)
+                {
+                    std::process::exit(1);
+                }
+            }
+            Err(e) => {
+                eprintln!("Lint Error: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
     // Retrieve the input file path using `get_one`
     let input_path: &String = matches.get_one("input").expect("Input file is required");
 
-    // Check file extension
-    if !input_path.ends_with(".lefi") {
-        eprintln!("Error: The input file must have a .lefi extension.");
-        std::process::exit(1);
-    }
-
     // Read the contents of the .lefi file
-    let mut file = File::open(input_path)?;
-    let mut script = String::new();
-    file.read_to_string(&mut script)?;
+    let script = read_script(input_path)?;
+
+    // Load the business day calendars, e.g. `--calendar holidays/NYSE.txt`
+    let mut calendars = Vec::new();
+    for path in matches.get_many::<String>("calendar").unwrap_or_default() {
+        match BusinessCalendar::load(path) {
+            Ok(calendar) => calendars.push(calendar),
+            Err(e) => {
+                eprintln!("Error: cannot load calendar {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    }
 
     // Tokenize, parse, and evaluate the script (Replace this section with your actual lexer, parser, and evaluator)
-    match run_lefi_script(&script) {
-        Ok(variables) => {
+    match run_lefi_script(&script, calendars) {
+        Ok((result, variables)) => {
             for (index, value) in variables.iter().enumerate() {
                 println!("Variable {}: {:?}", index, value);
             }
+            let line = result.line.unwrap_or_default();
+            match result.status {
+                RunStatus::Completed => {}
+                RunStatus::Returned(value) => println!("Returned {:?} at line {}", value, line),
+                RunStatus::Exited => println!("Exited at line {}", line),
+                RunStatus::Aborted(reason) => {
+                    eprintln!("Aborted at line {}: {}", line, reason);
+                    std::process::exit(2);
+                }
+                RunStatus::Rejected(reason) => {
+                    eprintln!("Requirement not met at line {}: {}", line, reason);
+                    std::process::exit(2);
+                }
+            }
         }
         Err(e) => {
             eprintln!("Execution Error: {:?}", e);
@@ -1,411 +1,2384 @@
-use std::sync::Mutex;
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    fmt, slice,
+    sync::Mutex,
+};
 
 use super::{
-    node::Node,
+    node::{Indicator, MathFunction, Node, Statistic},
     traits::{ConstVisitable, NodeConstVisitor},
 };
 
-use crate::utils::errors::{Result, ScriptingError};
+use crate::providers::{
+    calendar::{Adjustment, BusinessCalendar, MarketSession},
+    clock::{Clock, SystemClock},
+    fx::FxRateProvider,
+    history::PriceHistoryProvider,
+    observations::{Observation, ObservationStore},
+};
+use crate::utils::{
+    decimal::{Decimal, RoundingMode, DEFAULT_SCALE},
+    errors::{Result, ScriptingError},
+    format::{format_currency, format_decimal, format_decimal_currency, format_number},
+    indicators::{bollinger, ema, high, low, rsi, sma},
+    math::{floor_mod, ncdf, npdf},
+    statistics::{correlation, max_drawdown, mean, median, percentile, stdev, variance},
+    time::{Date, DateTime, Duration},
+};
 
-#[derive(Debug, Clone, PartialEq)]
+/// Maximum number of nested user-defined function calls, recursion included
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 64;
+
+/// A value computed by a script. Number literals are exact decimals, see
+/// [`Decimal`]; floating point numbers come from `ln`, `exp`, `pow` and the
+/// other math builtins of real numbers such as `sqrt` or `ncdf`, or from the host.
+#[derive(Debug, Clone)]
 pub enum Value {
     Bool(bool),
     Number(f64),
+    Decimal(Decimal),
+    // An amount of money and its currency code, e.g. `100 USD`
+    Money(Decimal, String),
+    Date(Date),
+    DateTime(DateTime),
+    Duration(Duration),
+    String(String),
+    List(Vec<Value>),
+    // Maps keep their keys sorted, which is the order of iteration
+    Map(BTreeMap<String, Value>),
+    Error(ErrorValue),
     Null,
 }
 
+impl Value {
+    /// The value of a number, whether decimal or floating point
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(value) => Some(*value),
+            Value::Decimal(value) => Some(value.to_f64()),
+            _ => None,
+        }
+    }
+}
+
+// A decimal equals the floating point number written the same
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Decimal(a), Value::Decimal(b)) => a == b,
+            (Value::Number(a), Value::Decimal(b)) | (Value::Decimal(b), Value::Number(a)) => {
+                Decimal::from_f64(*a) == Some(*b)
+            }
+            (Value::Money(a, a_currency), Value::Money(b, b_currency)) => {
+                a == b && a_currency == b_currency
+            }
+            (Value::Date(a), Value::Date(b)) => a == b,
+            (Value::DateTime(a), Value::DateTime(b)) => a == b,
+            (Value::Duration(a), Value::Duration(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::List(a), Value::List(b)) => a == b,
+            (Value::Map(a), Value::Map(b)) => a == b,
+            (Value::Error(a), Value::Error(b)) => a == b,
+            (Value::Null, Value::Null) => true,
+            _ => false,
+        }
+    }
+}
+
+// How values show in interpolated strings: strings without quotes, unless
+// they are inside a list or a map
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Number(value) => write!(f, "{}", value),
+            Value::Decimal(value) => write!(f, "{}", value),
+            Value::Money(amount, currency) => write!(f, "{} {}", amount, currency),
+            Value::Date(date) => write!(f, "{}", date),
+            Value::DateTime(time) => write!(f, "{}", time),
+            Value::Duration(duration) => write!(f, "{}", duration),
+            Value::String(value) => write!(f, "{}", value),
+            Value::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write_item(f, item)?;
+                }
+                write!(f, "]")
+            }
+            Value::Map(entries) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: ", key)?;
+                    write_item(f, value)?;
+                }
+                write!(f, "}}")
+            }
+            Value::Error(error) => write!(f, "{}: {}", error.kind, error.message),
+            Value::Null => write!(f, "null"),
+        }
+    }
+}
+
+fn write_item(f: &mut fmt::Formatter<'_>, item: &Value) -> fmt::Result {
+    match item {
+        Value::String(value) => write!(f, "{:?}", value),
+        item => write!(f, "{}", item),
+    }
+}
+
+/// An error caught by `try ... catch`, with fields `kind` and `message`.
+/// Host functions choose their own kinds, errors raised by the language itself
+/// are of kind `runtime`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorValue {
+    pub kind: String,
+    pub message: String,
+}
+
+impl From<&ScriptingError> for ErrorValue {
+    fn from(error: &ScriptingError) -> Self {
+        match error {
+            ScriptingError::HostError(kind, message) => ErrorValue {
+                kind: kind.clone(),
+                message: message.clone(),
+            },
+            ScriptingError::EvaluationError(message) => ErrorValue {
+                kind: "runtime".to_string(),
+                message: message.clone(),
+            },
+            error => ErrorValue {
+                kind: "runtime".to_string(),
+                message: error.to_string(),
+            },
+        }
+    }
+}
+
+/// A function provided by the host application, such as `Sell`. It receives
+/// the evaluated arguments and reports failures with
+/// [`ScriptingError::HostError`].
+pub type HostFunction = Box<dyn Fn(&[Value]) -> Result<Value> + Send + Sync>;
+
+/// How a run of a script ended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunStatus {
+    /// The last statement of the script ran.
+    Completed,
+    /// A top-level `return`, with its value if it was given one.
+    Returned(Value),
+    /// An `exit` statement.
+    Exited,
+    /// An `abort(reason)` statement, or an `assert` whose condition did not
+    /// hold, with its message.
+    Aborted(String),
+    /// A `require` whose condition did not hold, with its message.
+    Rejected(String),
+}
+
+/// Outcome of [`ExpressionEvaluator::run`]: the status, and the line of the
+/// statement that ended the run unless it completed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunResult {
+    pub status: RunStatus,
+    pub line: Option<usize>,
+}
+
+// Pending unwinding of the evaluation, set by statements that leave the
+// enclosing blocks early
+#[derive(Debug)]
+enum Signal {
+    Return(Value, usize),
+    Exit(usize),
+    Abort(String, usize),
+    Reject(String, usize),
+    Break,
+    Continue,
+}
+
+// How an arithmetic operator treats amounts of money
+#[derive(Clone, Copy)]
+enum MoneyRule {
+    // `+` and `-`: two amounts of the same currency
+    SameCurrency,
+    // `*`: an amount and a number, in either order
+    Scale,
+    // `/`: an amount by a number, or by an amount of the same currency which
+    // gives a number
+    Ratio,
+}
+
 //type MarketData = Vec<f64>;
 #[allow(unused)]
 pub struct ExpressionEvaluator {
-    variables: Mutex<Vec<Value>>,
-    digit_stack: Mutex<Vec<f64>>,
-    boolean_stack: Mutex<Vec<bool>>,
-    is_lhs_variable: Mutex<bool>,
-    lhs_variable: Mutex<Option<Box<Node>>>,
+    // Slots are empty until assigned, which is told apart from a `null` value
+    variables: Mutex<Vec<Option<Value>>>,
+    stack: Mutex<Vec<Value>>,
+    functions: Mutex<HashMap<String, Box<Node>>>,
+    host_functions: HashMap<String, HostFunction>,
+    // Local slots of the user-defined function calls in progress
+    frames: Mutex<Vec<Vec<Option<Value>>>>,
+    signal: Mutex<Option<Signal>>,
+    max_call_depth: usize,
+    // Decimals kept by products and quotients, and how they are rounded
+    decimal_scale: u32,
+    rounding: RoundingMode,
+    fx_rates: Option<Box<dyn FxRateProvider>>,
+    clock: Box<dyn Clock>,
+    // Business day calendars and market sessions, by name, and the calendar
+    // used when none is named
+    calendars: HashMap<String, BusinessCalendar>,
+    markets: HashMap<String, MarketSession>,
+    weekends: BusinessCalendar,
+    price_history: Option<Box<dyn PriceHistoryProvider>>,
+    // Latest prices left out of the history, to evaluate an indicator as it
+    // was before them
+    history_offset: Mutex<usize>,
+    // Results of the host function calls of the run, by call, and where they
+    // are kept once the run completes
+    observations: Mutex<BTreeMap<String, Observation>>,
+    observation_store: Option<Box<dyn ObservationStore>>,
     current_event: Option<usize>,
 }
 
+impl Default for ExpressionEvaluator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ExpressionEvaluator {
     pub fn new() -> Self {
         ExpressionEvaluator {
             variables: Mutex::new(Vec::new()),
-            digit_stack: Mutex::new(Vec::new()),
-            boolean_stack: Mutex::new(Vec::new()),
-            is_lhs_variable: Mutex::new(false),
-            lhs_variable: Mutex::new(None),
+            stack: Mutex::new(Vec::new()),
+            functions: Mutex::new(HashMap::new()),
+            host_functions: HashMap::new(),
+            frames: Mutex::new(Vec::new()),
+            signal: Mutex::new(None),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            decimal_scale: DEFAULT_SCALE,
+            rounding: RoundingMode::default(),
+            fx_rates: None,
+            clock: Box::new(SystemClock),
+            calendars: HashMap::new(),
+            markets: HashMap::new(),
+            weekends: BusinessCalendar::new("weekends"),
+            price_history: None,
+            history_offset: Mutex::new(0),
+            observations: Mutex::new(BTreeMap::new()),
+            observation_store: None,
             current_event: None,
         }
     }
 
     pub fn with_variables(self, n: usize) -> Self {
-        self.variables.lock().unwrap().resize(n, Value::Null);
+        self.variables.lock().unwrap().resize(n, None);
         self
     }
 
+    /// Registers a host function. The parser must be told about it too, see
+    /// `Parser::with_host_functions`.
+    pub fn with_host_function(
+        mut self,
+        name: &str,
+        function: impl Fn(&[Value]) -> Result<Value> + Send + Sync + 'static,
+    ) -> Self {
+        self.host_functions
+            .insert(name.to_string(), Box::new(function));
+        self
+    }
+
+    pub fn with_max_call_depth(mut self, depth: usize) -> Self {
+        self.max_call_depth = depth;
+        self
+    }
+
+    /// Sets the number of decimals kept by decimal products and quotients,
+    /// at most [`Decimal::MAX_SCALE`], and how they are rounded. Literals with
+    /// more decimals are rounded the same way.
+    pub fn with_decimals(mut self, scale: u32, rounding: RoundingMode) -> Self {
+        self.decimal_scale = scale.min(Decimal::MAX_SCALE);
+        self.rounding = rounding;
+        self
+    }
+
+    /// Sets the exchange rates used by `convert(amount, currency)`. Without
+    /// them, converting to another currency fails.
+    pub fn with_fx_rates(mut self, rates: impl FxRateProvider + 'static) -> Self {
+        self.fx_rates = Some(Box::new(rates));
+        self
+    }
+
+    /// Sets the clock read by `today()` and `now()`, the system clock in UTC
+    /// by default
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Registers a business day calendar, which scripts name as the last
+    /// argument of `is_business_day`, `add_business_days` and `adjust`.
+    /// Without one, only weekends are closed.
+    pub fn with_calendar(mut self, calendar: BusinessCalendar) -> Self {
+        self.calendars.insert(calendar.name().to_string(), calendar);
+        self
+    }
+
+    /// Registers the trading session checked by `is_market_open(name)`
+    pub fn with_market(mut self, name: &str, session: MarketSession) -> Self {
+        self.markets.insert(name.to_string(), session);
+        self
+    }
+
+    /// Sets the past prices read by the indicator builtins such as
+    /// `sma(symbol, window)`. Without them, indicators fail.
+    pub fn with_price_history(mut self, history: impl PriceHistoryProvider + 'static) -> Self {
+        self.price_history = Some(Box::new(history));
+        self
+    }
+
+    /// Keeps the results of host function calls in `store` when a run
+    /// completes, and reads the past results that `Spot("AAPL", at=-1)` looks
+    /// back at. Without it, looking back fails.
+    pub fn with_observations(mut self, store: impl ObservationStore + 'static) -> Self {
+        self.observation_store = Some(Box::new(store));
+        self
+    }
+
+    /// Results of the host function calls of the last run, by call such as
+    /// `Spot("AAPL")`. A function called several times with the same
+    /// arguments keeps its last result.
+    pub fn observations(&self) -> BTreeMap<String, Observation> {
+        self.observations.lock().unwrap().clone()
+    }
+
+    /// Values of the global variables, `Value::Null` for those never assigned
     pub fn variables(&self) -> Vec<Value> {
-        self.variables.lock().unwrap().clone()
+        self.variables
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|slot| slot.clone().unwrap_or(Value::Null))
+            .collect()
+    }
+
+    pub fn stack(&self) -> Vec<Value> {
+        self.stack.lock().unwrap().clone()
     }
 
+    /// The numbers left on the stack
     pub fn digit_stack(&self) -> Vec<f64> {
-        self.digit_stack.lock().unwrap().clone()
+        self.stack
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Value::as_number)
+            .collect()
     }
 
+    /// The booleans left on the stack
     pub fn boolean_stack(&self) -> Vec<bool> {
-        self.boolean_stack.lock().unwrap().clone()
+        self.stack
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|value| match value {
+                Value::Bool(v) => Some(*v),
+                _ => None,
+            })
+            .collect()
     }
-}
 
-impl NodeConstVisitor for ExpressionEvaluator {
-    type Output = Result<()>;
-    fn const_visit(&self, node: Box<Node>) -> Self::Output {
-        let eval: Result<()> = match node.as_ref() {
-            Node::Base(children) => {
-                children
-                    .iter()
-                    .try_for_each(|child| self.const_visit(child.clone()))?;
+    fn push(&self, value: Value) {
+        self.stack.lock().unwrap().push(value);
+    }
+
+    fn pop(&self) -> Result<Value> {
+        self.stack
+            .lock()
+            .unwrap()
+            .pop()
+            .ok_or_else(|| ScriptingError::EvaluationError("Missing operand".to_string()))
+    }
+
+    // Pops a number, failing instead of panicking when an operand is missing
+    // or is not a number (e.g. a boolean was used where a number was expected).
+    fn pop_digit(&self) -> Result<f64> {
+        match self.pop()? {
+            Value::Number(v) => Ok(v),
+            Value::Decimal(v) => Ok(v.to_f64()),
+            value => Err(ScriptingError::EvaluationError(format!(
+                "Expected a numeric operand, found {:?}",
+                value
+            ))),
+        }
+    }
+
+    // Pops a number, or `None` for a null operand
+    fn pop_number(&self) -> Result<Option<f64>> {
+        match self.pop()? {
+            Value::Number(v) => Ok(Some(v)),
+            Value::Decimal(v) => Ok(Some(v.to_f64())),
+            Value::Null => Ok(None),
+            value => Err(ScriptingError::EvaluationError(format!(
+                "Expected a numeric operand, found {:?}",
+                value
+            ))),
+        }
+    }
+
+    // Arithmetic on a null operand gives null
+    fn unary_operation(&self, operation: impl Fn(f64) -> f64) -> Result<()> {
+        let value = self.pop_number()?;
+        self.push(value.map_or(Value::Null, |value| Value::Number(operation(value))));
+        Ok(())
+    }
+
+    fn binary_operation(&self, operation: impl Fn(f64, f64) -> f64) -> Result<()> {
+        let right = self.pop_number()?;
+        let left = self.pop_number()?;
+        self.push(match (left, right) {
+            (Some(left), Some(right)) => Value::Number(operation(left, right)),
+            _ => Value::Null,
+        });
+        Ok(())
+    }
+
+    // Pops a decimal, a floating point number, an amount of money or null
+    fn pop_numeric(&self) -> Result<Value> {
+        match self.pop()? {
+            value @ (Value::Number(_) | Value::Decimal(_) | Value::Money(..) | Value::Null) => {
+                Ok(value)
+            }
+            value => Err(ScriptingError::EvaluationError(format!(
+                "Expected a numeric operand, found {:?}",
+                value
+            ))),
+        }
+    }
+
+    // Pops any value arithmetic and comparisons work on: a numeric operand,
+    // a date, a date and time or a duration
+    fn pop_operand(&self) -> Result<Value> {
+        match self.pop()? {
+            value @ (Value::Date(_) | Value::DateTime(_) | Value::Duration(_)) => Ok(value),
+            value @ (Value::Number(_) | Value::Decimal(_) | Value::Money(..) | Value::Null) => {
+                Ok(value)
+            }
+            value => Err(ScriptingError::EvaluationError(format!(
+                "Expected a numeric operand, found {:?}",
+                value
+            ))),
+        }
+    }
+
+    // Pops a date, the day of a date and time, or `None` for null
+    fn pop_date(&self) -> Result<Option<Date>> {
+        match self.pop()? {
+            Value::Date(date) => Ok(Some(date)),
+            Value::DateTime(time) => Ok(Some(time.date())),
+            Value::Null => Ok(None),
+            value => Err(ScriptingError::EvaluationError(format!(
+                "Expected a date, found {:?}",
+                value
+            ))),
+        }
+    }
+
+    // Pops the name of a calendar if the builtin was given one, as its last
+    // argument of `count`
+    fn pop_calendar(&self, children: &[Box<Node>], count: usize) -> Result<&BusinessCalendar> {
+        if children.len() < count {
+            return Ok(&self.weekends);
+        }
+        let name = self.pop_string()?;
+        self.calendars
+            .get(&name)
+            .ok_or_else(|| ScriptingError::EvaluationError(format!("Unknown calendar {}", name)))
+    }
+
+    // Arithmetic on two decimals is exact, up to the rounding of products and
+    // quotients; a floating point operand makes the result floating point.
+    // Amounts of money are decimals too, combined as `rule` allows.
+    fn arithmetic(
+        &self,
+        verb: &str,
+        rule: MoneyRule,
+        decimal: impl Fn(&Decimal, &Decimal) -> Result<Decimal>,
+        float: impl Fn(f64, f64) -> f64,
+    ) -> Result<()> {
+        let right = self.pop_operand()?;
+        let left = self.pop_operand()?;
+        let value = match (&left, &right) {
+            (Value::Null, _) | (_, Value::Null) => Value::Null,
+            _ if is_time(&left) || is_time(&right) => time_arithmetic(verb, &left, &right)?,
+            (Value::Money(a, currency), Value::Money(b, other)) => {
+                same_currency(verb, currency, other)?;
+                match rule {
+                    MoneyRule::SameCurrency => Value::Money(decimal(a, b)?, currency.clone()),
+                    MoneyRule::Ratio => Value::Decimal(decimal(a, b)?),
+                    MoneyRule::Scale => return Err(cannot_combine(verb, &left, &right)),
+                }
+            }
+            (Value::Money(amount, currency), number)
+                if matches!(rule, MoneyRule::Scale | MoneyRule::Ratio) =>
+            {
+                Value::Money(decimal(amount, &to_decimal(number)?)?, currency.clone())
+            }
+            (number, Value::Money(amount, currency)) if matches!(rule, MoneyRule::Scale) => {
+                Value::Money(decimal(&to_decimal(number)?, amount)?, currency.clone())
+            }
+            (Value::Money(..), _) | (_, Value::Money(..)) => {
+                return Err(cannot_combine(verb, &left, &right))
+            }
+            (Value::Decimal(left), Value::Decimal(right)) => Value::Decimal(decimal(left, right)?),
+            _ => match (left.as_number(), right.as_number()) {
+                (Some(left), Some(right)) => Value::Number(float(left, right)),
+                _ => Value::Null,
+            },
+        };
+        self.push(value);
+        Ok(())
+    }
+
+    // Absolute values, signs, floors and ceilings are exact on decimals and
+    // keep the currency of an amount of money, the other functions work on
+    // floating point numbers
+    fn math(&self, function: MathFunction) -> Result<()> {
+        let float: fn(f64) -> f64 = match function {
+            MathFunction::Abs => f64::abs,
+            MathFunction::Sign => |value| match value == 0.0 {
+                true => 0.0,
+                false => value.signum(),
+            },
+            MathFunction::Floor => f64::floor,
+            MathFunction::Ceil => f64::ceil,
+            MathFunction::Sqrt => f64::sqrt,
+            MathFunction::Log10 => f64::log10,
+            MathFunction::Sin => f64::sin,
+            MathFunction::Cos => f64::cos,
+            MathFunction::Tan => f64::tan,
+            MathFunction::Ncdf => ncdf,
+            MathFunction::Npdf => npdf,
+        };
+        let exact = |value: &Decimal| match function {
+            MathFunction::Abs => Some(value.abs()),
+            MathFunction::Sign => Some(Decimal::from(value.cmp(&Decimal::default()) as i64)),
+            MathFunction::Floor => Some(value.round(0, RoundingMode::Floor)),
+            MathFunction::Ceil => Some(value.round(0, RoundingMode::Ceiling)),
+            _ => None,
+        };
+        let value = match self.pop_numeric()? {
+            Value::Decimal(value) => match exact(&value) {
+                Some(result) => Value::Decimal(result),
+                None => Value::Number(float(value.to_f64())),
+            },
+            Value::Money(amount, currency) => match (function, exact(&amount)) {
+                (MathFunction::Sign, Some(sign)) => Value::Decimal(sign),
+                (_, Some(amount)) => Value::Money(amount, currency),
+                (_, None) => {
+                    return Err(ScriptingError::EvaluationError(format!(
+                        "Expected a numeric operand, found {:?}",
+                        Value::Money(amount, currency)
+                    )))
+                }
+            },
+            Value::Number(value) => Value::Number(float(value)),
+            _ => Value::Null,
+        };
+        self.push(value);
+        Ok(())
+    }
+
+    // The prices of `symbol`, without the latest ones while `crosses_above`
+    // looks back
+    fn price_history(&self, symbol: &str) -> Result<Vec<f64>> {
+        let history = self.price_history.as_ref().ok_or_else(|| {
+            ScriptingError::EvaluationError(format!("No price history for {}", symbol))
+        })?;
+        let mut prices = history.prices(symbol)?;
+        let offset = *self.history_offset.lock().unwrap();
+        prices.truncate(prices.len().saturating_sub(offset));
+        Ok(prices)
+    }
+
+    // Indicators are null until the history covers their window
+    fn indicator(&self, indicator: Indicator, args: usize) -> Result<()> {
+        let width = match args {
+            3 => self.pop_digit()?,
+            _ => 2.0,
+        };
+        let window = self.pop_integer()?;
+        if window < 1 {
+            return Err(ScriptingError::EvaluationError(format!(
+                "Expected a positive window, found {}",
+                window
+            )));
+        }
+        let window = window as usize;
+        let prices = self.price_history(&self.pop_string()?)?;
+        let value = match indicator {
+            Indicator::Sma => sma(&prices, window).map(Value::Number),
+            Indicator::Ema => ema(&prices, window).map(Value::Number),
+            Indicator::Rsi => rsi(&prices, window).map(Value::Number),
+            Indicator::High => high(&prices, window).map(Value::Number),
+            Indicator::Low => low(&prices, window).map(Value::Number),
+            Indicator::Bollinger => {
+                bollinger(&prices, window, width).map(|(lower, middle, upper)| {
+                    Value::Map(BTreeMap::from([
+                        ("lower".to_string(), Value::Number(lower)),
+                        ("middle".to_string(), Value::Number(middle)),
+                        ("upper".to_string(), Value::Number(upper)),
+                    ]))
+                })
+            }
+        };
+        self.push(value.unwrap_or(Value::Null));
+        Ok(())
+    }
+
+    // `a` crosses above `b` when it was at or below `b` one price earlier and
+    // is above it now. The arguments are evaluated twice, first without the
+    // latest price, which the parser only allows for indicators and constants.
+    fn crosses_above(&self, children: &[Box<Node>]) -> Result<()> {
+        *self.history_offset.lock().unwrap() += 1;
+        let visited = self.visit_operands(children);
+        *self.history_offset.lock().unwrap() -= 1;
+        if !visited? {
+            return Ok(());
+        }
+        let (previous_b, previous_a) = (self.pop_operand()?, self.pop_operand()?);
+
+        if !self.visit_operands(children)? {
+            return Ok(());
+        }
+        let (b, a) = (self.pop_operand()?, self.pop_operand()?);
+        let was_below = compare_values(&previous_a, &previous_b)?.is_some_and(Ordering::is_le);
+        let is_above = compare_values(&a, &b)?.is_some_and(Ordering::is_gt);
+        self.push(Value::Bool(was_below && is_above));
+        Ok(())
+    }
+
+    // Sums keep the kind of the values, exact for decimals and amounts of
+    // money; the other statistics work on floating point numbers. A null in a
+    // list makes its statistic null.
+    fn statistic(&self, statistic: Statistic) -> Result<()> {
+        let value = match statistic {
+            Statistic::Sum => {
+                let totals = self.running_totals(self.pop_list()?)?;
+                totals
+                    .last()
+                    .cloned()
+                    .unwrap_or(Value::Decimal(Decimal::default()))
+            }
+            Statistic::Cumsum => Value::List(self.running_totals(self.pop_list()?)?),
+            Statistic::Percentile => {
+                let percent = self.pop_digit()?;
+                if !(0.0..=100.0).contains(&percent) {
+                    return Err(ScriptingError::EvaluationError(format!(
+                        "Expected a percentile from 0 to 100, found {}",
+                        percent
+                    )));
+                }
+                let values = numbers(self.pop_list()?)?;
+                values
+                    .and_then(|values| percentile(&values, percent))
+                    .map_or(Value::Null, Value::Number)
+            }
+            Statistic::Correlation => {
+                let ys = self.pop_list()?;
+                let xs = self.pop_list()?;
+                if xs.len() != ys.len() {
+                    return Err(ScriptingError::EvaluationError(format!(
+                        "Cannot correlate lists of {} and {} values",
+                        xs.len(),
+                        ys.len()
+                    )));
+                }
+                match (numbers(xs)?, numbers(ys)?) {
+                    (Some(xs), Some(ys)) => {
+                        correlation(&xs, &ys).map_or(Value::Null, Value::Number)
+                    }
+                    _ => Value::Null,
+                }
+            }
+            statistic => {
+                let function = match statistic {
+                    Statistic::Mean => mean,
+                    Statistic::Median => median,
+                    Statistic::Variance => variance,
+                    Statistic::Stdev => stdev,
+                    _ => max_drawdown,
+                };
+                let values = numbers(self.pop_list()?)?;
+                values
+                    .and_then(|values| function(&values))
+                    .map_or(Value::Null, Value::Number)
+            }
+        };
+        self.push(value);
+        Ok(())
+    }
+
+    // The sums of the first values of a list, added like `+` does
+    fn running_totals(&self, items: Vec<Value>) -> Result<Vec<Value>> {
+        let mut totals: Vec<Value> = Vec::with_capacity(items.len());
+        for item in items {
+            let total = match totals.last() {
+                Some(total) => {
+                    self.push(total.clone());
+                    self.push(item);
+                    self.arithmetic(
+                        "add",
+                        MoneyRule::SameCurrency,
+                        |left, right| left.checked_add(right).ok_or_else(overflow),
+                        |left, right| left + right,
+                    )?;
+                    self.pop()?
+                }
+                None => {
+                    self.push(item);
+                    self.pop_operand()?
+                }
+            };
+            totals.push(total);
+        }
+        Ok(totals)
+    }
+
+    // A past result of a host function, or null if there is none: `at` counts
+    // the runs back when negative, or is the date or time it was observed by
+    fn look_back(&self, name: &str, args: usize) -> Result<()> {
+        let at = self.pop()?;
+        let mut values = (0..args).map(|_| self.pop()).collect::<Result<Vec<_>>>()?;
+        values.reverse();
+        let key = observation_key(name, &values);
+        let store = self.observation_store.as_ref().ok_or_else(|| {
+            ScriptingError::EvaluationError(format!("No observations of {}", key))
+        })?;
+        let past = store.observations(&key)?;
+        let found = match &at {
+            Value::Date(date) => past.iter().rev().find(|past| past.time.date() <= *date),
+            Value::DateTime(time) => past.iter().rev().find(|past| past.time <= *time),
+            at => match at.as_number() {
+                Some(runs) if runs < 0.0 && runs.fract() == 0.0 => past
+                    .len()
+                    .checked_sub(-runs as usize)
+                    .and_then(|index| past.get(index)),
+                _ => {
+                    return Err(ScriptingError::EvaluationError(format!(
+                        "Expected a negative number of runs, a date or a time, found {:?}",
+                        at
+                    )))
+                }
+            },
+        };
+        self.push(found.map_or(Value::Null, |past| past.value.clone()));
+        Ok(())
+    }
+
+    // Clamping with a null bound gives null
+    fn clamp(&self) -> Result<()> {
+        let high = self.pop_operand()?;
+        let low = self.pop_operand()?;
+        let value = self.pop_operand()?;
+        if compare_values(&low, &high)? == Some(Ordering::Greater) {
+            return Err(ScriptingError::EvaluationError(format!(
+                "Cannot clamp between {} and {}",
+                low, high
+            )));
+        }
+        let value = match (
+            compare_values(&value, &low)?,
+            compare_values(&value, &high)?,
+        ) {
+            (Some(Ordering::Less), _) => low,
+            (_, Some(Ordering::Greater)) => high,
+            (Some(_), Some(_)) => value,
+            _ => Value::Null,
+        };
+        self.push(value);
+        Ok(())
+    }
+
+    // Ordering comparisons with a null operand are false
+    fn comparison(&self, operation: impl Fn(Ordering) -> bool) -> Result<()> {
+        let right = self.pop_operand()?;
+        let left = self.pop_operand()?;
+        self.push(Value::Bool(
+            compare_values(&left, &right)?.is_some_and(operation),
+        ));
+        Ok(())
+    }
+
+    fn pop_string(&self) -> Result<String> {
+        match self.pop()? {
+            Value::String(v) => Ok(v),
+            value => Err(ScriptingError::EvaluationError(format!(
+                "Expected a string operand, found {:?}",
+                value
+            ))),
+        }
+    }
+
+    // Pops a whole number, such as a number of digits
+    fn pop_integer(&self) -> Result<i32> {
+        let value = self.pop_digit()?;
+        if value.fract() != 0.0 || value.abs() > i32::MAX as f64 {
+            return Err(ScriptingError::EvaluationError(format!(
+                "Expected an integer, found {}",
+                value
+            )));
+        }
+        Ok(value as i32)
+    }
+
+    fn pop_boolean(&self) -> Result<bool> {
+        match self.pop()? {
+            Value::Bool(v) => Ok(v),
+            value => Err(ScriptingError::EvaluationError(format!(
+                "Expected a boolean operand, found {:?}",
+                value
+            ))),
+        }
+    }
+
+    fn pop_list(&self) -> Result<Vec<Value>> {
+        match self.pop()? {
+            Value::List(items) => Ok(items),
+            value => Err(ScriptingError::EvaluationError(format!(
+                "Expected a list operand, found {:?}",
+                value
+            ))),
+        }
+    }
+
+    // Variables live in the frame of the current function call, if any, and in
+    // the global store otherwise
+    fn read_variable(&self, id: usize, name: &str) -> Result<Option<Value>> {
+        let frames = self.frames.lock().unwrap();
+        let value = match frames.last() {
+            Some(frame) => frame.get(id).cloned(),
+            None => self.variables.lock().unwrap().get(id).cloned(),
+        };
+        value.ok_or_else(|| {
+            ScriptingError::EvaluationError(format!("Variable {} out of range", name))
+        })
+    }
+
+    fn write_variable(&self, id: usize, name: &str, value: Value) -> Result<()> {
+        self.write_slot(id, name, Some(value))
+    }
+
+    fn write_slot(&self, id: usize, name: &str, value: Option<Value>) -> Result<()> {
+        let mut frames = self.frames.lock().unwrap();
+        let mut variables = self.variables.lock().unwrap();
+        let slot = match frames.last_mut() {
+            Some(frame) => frame.get_mut(id),
+            None => variables.get_mut(id),
+        };
+        match slot {
+            Some(slot) => {
+                *slot = value;
                 Ok(())
             }
-            Node::Variable(_, name, index) => {
-                if *self.is_lhs_variable.lock().unwrap() {
-                    *self.lhs_variable.lock().unwrap() = Some(node.clone());
-                    Ok(())
-                } else {
-                    match index.get() {
-                        None => {
+            None => Err(ScriptingError::EvaluationError(format!(
+                "Variable {} out of range",
+                name
+            ))),
+        }
+    }
+
+    // Visits the children in order, stopping at the first failure or as soon
+    // as a statement leaves the enclosing blocks early
+    fn visit_children(&self, children: &[Box<Node>]) -> Result<()> {
+        for child in children {
+            child.const_accept(self)?;
+            if self.signal.lock().unwrap().is_some() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Visits the operands of an expression. Returns false when `exit` or
+    // `abort` ran in a function called by one of them, in which case the
+    // expression must not be computed.
+    fn visit_operands(&self, children: &[Box<Node>]) -> Result<bool> {
+        self.visit_children(children)?;
+        Ok(!self.stopping())
+    }
+
+    // Whether the whole script is being stopped by `exit`, `abort` or a
+    // failed pre-condition
+    fn stopping(&self) -> bool {
+        matches!(
+            *self.signal.lock().unwrap(),
+            Some(Signal::Exit(_) | Signal::Abort(..) | Signal::Reject(..))
+        )
+    }
+
+    // Evaluates a call to a builtin function. The builtins are kept out of
+    // `const_visit`, whose stack frame every nested expression and function
+    // call pays for.
+    fn call_builtin(&self, node: &Node) -> Result<()> {
+        match node {
+            Node::Today(_) => {
+                self.push(Value::Date(self.clock.now().date()));
+                Ok(())
+            }
+            Node::Now(_) => {
+                self.push(Value::DateTime(self.clock.now()));
+                Ok(())
+            }
+            Node::DayOfMonth(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
+
+                let value = self.pop_date()?.map_or(Value::Null, |date| {
+                    Value::Decimal(Decimal::from(date.day_of_month() as i64))
+                });
+                self.push(value);
+                Ok(())
+            }
+            Node::IsWeekday(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
+
+                let value = self
+                    .pop_date()?
+                    .map_or(Value::Null, |date| Value::Bool(date.is_weekday()));
+                self.push(value);
+                Ok(())
+            }
+            Node::IsBusinessDay(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
+
+                let calendar = self.pop_calendar(children, 2)?;
+                let value = self.pop_date()?.map_or(Value::Null, |date| {
+                    Value::Bool(calendar.is_business_day(date))
+                });
+                self.push(value);
+                Ok(())
+            }
+            Node::AddBusinessDays(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
+
+                let calendar = self.pop_calendar(children, 3)?;
+                let days = self.pop_integer()?;
+                let value = match self.pop_date()? {
+                    Some(date) => Value::Date(
+                        calendar
+                            .add_business_days(date, days as i64)
+                            .ok_or_else(out_of_range)?,
+                    ),
+                    None => Value::Null,
+                };
+                self.push(value);
+                Ok(())
+            }
+            Node::Adjust(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
+
+                let calendar = self.pop_calendar(children, 3)?;
+                let adjustment = self.pop_string()?.parse::<Adjustment>()?;
+                let value = match self.pop_date()? {
+                    Some(date) => {
+                        Value::Date(calendar.adjust(date, adjustment).ok_or_else(out_of_range)?)
+                    }
+                    None => Value::Null,
+                };
+                self.push(value);
+                Ok(())
+            }
+            Node::EndOfMonth(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
+
+                let value = self
+                    .pop_date()?
+                    .map_or(Value::Null, |date| Value::Date(date.end_of_month()));
+                self.push(value);
+                Ok(())
+            }
+            // Whether the market is open now, or at the given time
+            Node::IsMarketOpen(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
+
+                let time = match children.len() {
+                    2 => match self.pop()? {
+                        Value::DateTime(time) => Some(time),
+                        Value::Null => None,
+                        value => {
                             return Err(ScriptingError::EvaluationError(format!(
-                                "Variable {} not indexed",
-                                name
+                                "Expected a date and time, found {:?}",
+                                value
                             )))
                         }
-                        Some(id) => {
-                            // let value = self.variables.lock().unwrap()[*id];
-                            // self.digit_stack.lock().unwrap().push(value);
-
-                            //let value = self.variables.lock().unwrap().get(*id).unwrap();
-                            let vars = self.variables.lock().unwrap();
-                            let value = vars.get(*id).unwrap();
-                            match value {
-                                Value::Number(v) => self.digit_stack.lock().unwrap().push(*v),
-                                Value::Bool(v) => self.boolean_stack.lock().unwrap().push(*v),
-                                Value::Null => {
-                                    return Err(ScriptingError::EvaluationError(format!(
-                                        "Variable {} not initialized",
-                                        name
-                                    )))
-                                }
-                            }
-                            Ok(())
-                        }
+                    },
+                    _ => Some(self.clock.now()),
+                };
+                let name = self.pop_string()?;
+                let session = self.markets.get(&name).ok_or_else(|| {
+                    ScriptingError::EvaluationError(format!("Unknown market {}", name))
+                })?;
+                self.push(time.map_or(Value::Null, |time| Value::Bool(session.is_open(time))));
+                Ok(())
+            }
+            Node::Format(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
+
+                let spec = self.pop_string()?;
+                let value = match self.pop_numeric()? {
+                    Value::Decimal(value) | Value::Money(value, _) => {
+                        Value::String(format_decimal(&value, &spec)?)
                     }
+                    Value::Number(value) => Value::String(format_number(value, &spec)?),
+                    _ => Value::Null,
+                };
+                self.push(value);
+                Ok(())
+            }
+            Node::FormatCurrency(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
                 }
+
+                let decimals = match children.len() {
+                    3 => {
+                        let decimals = self.pop_integer()?;
+                        Some(usize::try_from(decimals).map_err(|_| {
+                            ScriptingError::EvaluationError(format!(
+                                "Expected a positive number of decimals, found {}",
+                                decimals
+                            ))
+                        })?)
+                    }
+                    _ => None,
+                };
+                let currency = self.pop_string()?;
+                let value = match self.pop_numeric()? {
+                    Value::Decimal(value) => {
+                        Value::String(format_decimal_currency(&value, &currency, decimals))
+                    }
+                    Value::Money(value, other) => {
+                        same_currency("format", &other, &currency)?;
+                        Value::String(format_decimal_currency(&value, &currency, decimals))
+                    }
+                    Value::Number(value) => {
+                        Value::String(format_currency(value, &currency, decimals))
+                    }
+                    _ => Value::Null,
+                };
+                self.push(value);
+                Ok(())
             }
+            Node::RoundTo(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
 
-            Node::Constant(value) => {
-                self.digit_stack.lock().unwrap().push(*value);
+                // Half away from zero unless told otherwise
+                let mode = match children.len() {
+                    3 => self.pop_string()?.parse()?,
+                    _ => RoundingMode::HalfUp,
+                };
+                // `round(x)` rounds to a whole number
+                let digits = match children.len() {
+                    1 => 0,
+                    _ => self.pop_integer()?,
+                };
+                let value = match self.pop_numeric()? {
+                    Value::Decimal(value) => Value::Decimal(value.round(digits, mode)),
+                    Value::Money(value, currency) => {
+                        Value::Money(value.round(digits, mode), currency)
+                    }
+                    // Floating point numbers are rounded as they are written
+                    Value::Number(value) => match Decimal::from_f64(value) {
+                        Some(decimal) => Value::Number(decimal.round(digits, mode).to_f64()),
+                        None => Value::Number(value),
+                    },
+                    _ => Value::Null,
+                };
+                self.push(value);
                 Ok(())
             }
-            Node::Add(children) => {
-                children
-                    .iter()
-                    .try_for_each(|child| self.const_visit(child.clone()))?;
+            // Converting to the same currency needs no rate
+            Node::Convert(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
 
-                let right = self.digit_stack.lock().unwrap().pop().unwrap();
-                let left = self.digit_stack.lock().unwrap().pop().unwrap();
-                self.digit_stack.lock().unwrap().push(left + right);
+                let currency = self.pop_string()?;
+                let value = match self.pop()? {
+                    Value::Money(amount, from) if from == currency => Value::Money(amount, from),
+                    Value::Money(amount, from) => {
+                        let rates = self.fx_rates.as_ref().ok_or_else(|| {
+                            ScriptingError::EvaluationError(format!(
+                                "No exchange rates to convert {} to {}",
+                                from, currency
+                            ))
+                        })?;
+                        let rate = rates.rate(&from, &currency)?;
+                        let amount = amount
+                            .checked_mul(&rate, self.decimal_scale, self.rounding)
+                            .ok_or_else(overflow)?;
+                        Value::Money(amount, currency)
+                    }
+                    Value::Null => Value::Null,
+                    value => {
+                        return Err(ScriptingError::EvaluationError(format!(
+                            "Expected an amount of money, found {:?}",
+                            value
+                        )))
+                    }
+                };
+                self.push(value);
                 Ok(())
             }
-            Node::Subtract(children) => {
-                children
-                    .iter()
-                    .try_for_each(|child| self.const_visit(child.clone()))?;
+            Node::Min(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
+
+                let mut min = self.pop_operand()?;
+                for _ in 1..children.len() {
+                    let value = self.pop_operand()?;
+                    min = match compare_values(&value, &min)? {
+                        _ if value == Value::Null => value,
+                        Some(ordering) if Ordering::is_lt(ordering) => value,
+                        _ => min,
+                    };
+                }
+                self.push(min);
 
-                let right = self.digit_stack.lock().unwrap().pop().unwrap();
-                let left = self.digit_stack.lock().unwrap().pop().unwrap();
-                self.digit_stack.lock().unwrap().push(left - right);
                 Ok(())
             }
-            Node::Multiply(children) => {
-                children
-                    .iter()
-                    .try_for_each(|child| self.const_visit(child.clone()))?;
+            Node::Max(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
+
+                let mut max = self.pop_operand()?;
+                for _ in 1..children.len() {
+                    let value = self.pop_operand()?;
+                    max = match compare_values(&value, &max)? {
+                        _ if value == Value::Null => value,
+                        Some(ordering) if Ordering::is_gt(ordering) => value,
+                        _ => max,
+                    };
+                }
+                self.push(max);
 
-                let right = self.digit_stack.lock().unwrap().pop().unwrap();
-                let left = self.digit_stack.lock().unwrap().pop().unwrap();
-                self.digit_stack.lock().unwrap().push(left * right);
                 Ok(())
             }
-            Node::Divide(children) => {
+            Node::Pow(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
+
+                self.binary_operation(f64::powf)
+            }
+            Node::Ln(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
+
+                self.unary_operation(f64::ln)
+            }
+            Node::Exp(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
+
+                self.unary_operation(f64::exp)
+            }
+            Node::Clamp(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
+
+                self.clamp()
+            }
+            Node::Math(children, function) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
+
+                self.math(*function)
+            }
+            Node::Select(children) => {
+                let [condition, when_true, when_false] = children.as_slice() else {
+                    return Err(ScriptingError::EvaluationError(
+                        "Invalid select".to_string(),
+                    ));
+                };
+                if !self.visit_operands(slice::from_ref(condition))? {
+                    return Ok(());
+                }
+                match self.pop_boolean()? {
+                    true => self.visit_children(slice::from_ref(when_true)),
+                    false => self.visit_children(slice::from_ref(when_false)),
+                }
+            }
+            Node::Len(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
+
+                let length = match self.pop()? {
+                    Value::List(items) => items.len(),
+                    Value::Map(entries) => entries.len(),
+                    Value::String(string) => string.chars().count(),
+                    value => {
+                        return Err(ScriptingError::EvaluationError(format!(
+                            "Expected a list, a map or a string, found {:?}",
+                            value
+                        )))
+                    }
+                };
+                self.push(Value::Decimal(Decimal::from(length as i64)));
+                Ok(())
+            }
+            Node::Append(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
+
+                let item = self.pop()?;
+                let mut items = self.pop_list()?;
+                items.push(item);
+                self.push(Value::List(items));
+                Ok(())
+            }
+            Node::Indicator(children, indicator) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
+
+                self.indicator(*indicator, children.len())
+            }
+            Node::CrossesAbove(children) => self.crosses_above(children),
+            Node::Statistic(children, statistic) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
+
+                self.statistic(*statistic)
+            }
+            Node::Lookback(children, name) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
+
+                self.look_back(name, children.len() - 1)
+            }
+            node => Err(ScriptingError::EvaluationError(format!(
+                "Not a builtin function: {:?}",
+                node
+            ))),
+        }
+    }
+
+    /// Runs a script and reports how it ended. Unlike `const_visit`, a
+    /// top-level `return`, `exit` or `abort` is consumed.
+    pub fn run(&self, node: Box<Node>) -> Result<RunResult> {
+        self.check_literals(&node)?;
+        self.observations.lock().unwrap().clear();
+        self.const_visit(node)?;
+        let (status, line) = match self.signal.lock().unwrap().take() {
+            Some(Signal::Return(value, line)) => (RunStatus::Returned(value), Some(line)),
+            Some(Signal::Exit(line)) => (RunStatus::Exited, Some(line)),
+            Some(Signal::Abort(reason, line)) => (RunStatus::Aborted(reason), Some(line)),
+            Some(Signal::Reject(reason, line)) => (RunStatus::Rejected(reason), Some(line)),
+            Some(Signal::Break | Signal::Continue) | None => (RunStatus::Completed, None),
+        };
+        if line.is_some() {
+            // Drop the operands of the expressions left unfinished
+            self.stack.lock().unwrap().clear();
+        }
+        if let Some(store) = &self.observation_store {
+            for (key, observation) in self.observations.lock().unwrap().iter() {
+                store.record(key, observation.clone())?;
+            }
+        }
+        Ok(RunResult { status, line })
+    }
+
+    // A number written in the script, which must not have more decimals than
+    // the scale rather than be rounded
+    fn literal(&self, value: &Decimal) -> Result<Decimal> {
+        match value.scale() > self.decimal_scale {
+            true => Err(ScriptingError::EvaluationError(format!(
+                "Number {} has more than the {} decimals of the script",
+                value, self.decimal_scale
+            ))),
+            false => Ok(*value),
+        }
+    }
+
+    // Rejects the numbers that cannot be written at the scale before any of
+    // the script runs
+    fn check_literals(&self, node: &Node) -> Result<()> {
+        match node {
+            Node::Decimal(value) | Node::Money(value, _) => self.literal(value).map(|_| ()),
+            node if node.is_leaf() => Ok(()),
+            node => node
+                .children()
+                .iter()
+                .try_for_each(|child| self.check_literals(child)),
+        }
+    }
+
+    fn register_function(&self, node: &Node) {
+        if let Node::Function(_, name, _, _) = node {
+            self.functions
+                .lock()
+                .unwrap()
+                .insert(name.clone(), Box::new(node.clone()));
+        }
+    }
+
+    // Runs the body of a user-defined function in a new frame and returns the
+    // value given to `return`, or null if the body ends without one. Other
+    // names are looked up in the host functions.
+    fn call_function(&self, name: &str, args: Vec<Value>) -> Result<Value> {
+        let function = self.functions.lock().unwrap().get(name).cloned();
+        let Some(function) = function else {
+            return match self.host_functions.get(name) {
+                Some(host_function) => {
+                    let value = host_function(&args)?;
+                    self.observations.lock().unwrap().insert(
+                        observation_key(name, &args),
+                        Observation {
+                            time: self.clock.now(),
+                            value: value.clone(),
+                        },
+                    );
+                    Ok(value)
+                }
+                None => Err(ScriptingError::EvaluationError(format!(
+                    "Undefined function {}",
+                    name
+                ))),
+            };
+        };
+        let (body, params, layout) = match function.as_ref() {
+            Node::Function(body, _, params, layout) => (body, params, layout),
+            _ => unreachable!("only function definitions are registered"),
+        };
+        if params.len() != args.len() {
+            return Err(ScriptingError::EvaluationError(format!(
+                "Function {} expects {} arguments, found {}",
+                name,
+                params.len(),
+                args.len()
+            )));
+        }
+        let layout = layout.get().ok_or_else(|| {
+            ScriptingError::EvaluationError(format!("Function {} not indexed", name))
+        })?;
+
+        let mut frame = vec![None; layout.size];
+        for (slot, arg) in frame.iter_mut().zip(args) {
+            *slot = Some(arg);
+        }
+        {
+            let globals = self.variables.lock().unwrap();
+            for (global, local) in &layout.captures {
+                frame[*local] = globals.get(*global).cloned().flatten();
+            }
+        }
+
+        {
+            let mut frames = self.frames.lock().unwrap();
+            if frames.len() >= self.max_call_depth {
+                return Err(ScriptingError::EvaluationError(format!(
+                    "Maximum call depth of {} exceeded in function {}",
+                    self.max_call_depth, name
+                )));
+            }
+            frames.push(frame);
+        }
+        let result = self.visit_children(body);
+        self.frames.lock().unwrap().pop();
+        result?;
+
+        // `exit`, `abort` and failed pre-conditions keep unwinding past the
+        // call
+        let mut signal = self.signal.lock().unwrap();
+        match signal.take() {
+            Some(Signal::Return(value, _)) => Ok(value),
+            Some(other @ (Signal::Exit(_) | Signal::Abort(..) | Signal::Reject(..))) => {
+                *signal = Some(other);
+                Ok(Value::Null)
+            }
+            Some(Signal::Break | Signal::Continue) | None => Ok(Value::Null),
+        }
+    }
+}
+
+// Name and slot of the variable written by an assignment or a declaration
+fn target_slot(node: &Node) -> Result<(&String, usize)> {
+    match node {
+        Node::Variable(_, name, index, _) => match index.get() {
+            Some(id) => Ok((name, *id)),
+            None => Err(ScriptingError::EvaluationError(format!(
+                "Variable {} not indexed",
+                name
+            ))),
+        },
+        _ => Err(ScriptingError::EvaluationError(
+            "Invalid variable assignment".to_string(),
+        )),
+    }
+}
+
+// Position of `index` in a list of `length` elements, negative indexes
+// counting from the end. Slice bounds can also be `length` itself.
+fn list_position(index: f64, length: usize, is_bound: bool) -> Result<usize> {
+    if index.fract() != 0.0 {
+        return Err(ScriptingError::EvaluationError(format!(
+            "List index must be an integer, found {}",
+            index
+        )));
+    }
+    let position = match index < 0.0 {
+        true => length as f64 + index,
+        false => index,
+    };
+    let last = match is_bound {
+        true => length as f64,
+        false => length as f64 - 1.0,
+    };
+    if position < 0.0 || position > last {
+        return Err(ScriptingError::EvaluationError(format!(
+            "Index {} out of bounds for a list of length {}",
+            index, length
+        )));
+    }
+    Ok(position as usize)
+}
+
+fn overflow() -> ScriptingError {
+    ScriptingError::EvaluationError("Decimal overflow".to_string())
+}
+
+// Converts a number to a decimal, to be combined with an amount of money
+fn to_decimal(value: &Value) -> Result<Decimal> {
+    match value {
+        Value::Decimal(value) => Ok(*value),
+        Value::Number(value) => Decimal::from_f64(*value).ok_or_else(overflow),
+        value => Err(ScriptingError::EvaluationError(format!(
+            "Expected a numeric operand, found {:?}",
+            value
+        ))),
+    }
+}
+
+// The numbers of a list, or `None` if one of them is null
+fn numbers(items: Vec<Value>) -> Result<Option<Vec<f64>>> {
+    items
+        .iter()
+        .map(|item| match item {
+            Value::Null => Ok(None),
+            item => item.as_number().map(Some).ok_or_else(|| {
+                ScriptingError::EvaluationError(format!(
+                    "Expected a numeric operand, found {:?}",
+                    item
+                ))
+            }),
+        })
+        .collect()
+}
+
+// The call that gave an observation, e.g. `Spot("AAPL")`
+fn observation_key(name: &str, args: &[Value]) -> String {
+    let args = Value::List(args.to_vec()).to_string();
+    format!("{}({})", name, &args[1..args.len() - 1])
+}
+
+fn same_currency(verb: &str, left: &str, right: &str) -> Result<()> {
+    match left == right {
+        true => Ok(()),
+        false => Err(ScriptingError::EvaluationError(format!(
+            "Currency mismatch: cannot {} {} and {}",
+            verb, left, right
+        ))),
+    }
+}
+
+fn cannot_combine(verb: &str, left: &Value, right: &Value) -> ScriptingError {
+    let describe = |value: &Value| match value {
+        Value::Money(_, currency) => format!("an amount of {}", currency),
+        Value::Date(_) => "a date".to_string(),
+        Value::DateTime(_) => "a date and time".to_string(),
+        Value::Duration(_) => "a duration".to_string(),
+        _ => "a number".to_string(),
+    };
+    ScriptingError::EvaluationError(format!(
+        "Cannot {} {} and {}",
+        verb,
+        describe(left),
+        describe(right)
+    ))
+}
+
+fn is_time(value: &Value) -> bool {
+    matches!(
+        value,
+        Value::Date(_) | Value::DateTime(_) | Value::Duration(_)
+    )
+}
+
+// Dates and times move by durations and differ by a duration; durations add
+// up and scale by whole numbers. A date moved by hours becomes a date and
+// time, at midnight plus the hours.
+fn time_arithmetic(verb: &str, left: &Value, right: &Value) -> Result<Value> {
+    let move_date = |date: &Date, duration: &Duration| match duration.is_whole_days() {
+        true => date.checked_add(duration).map(Value::Date),
+        false => date
+            .at_midnight()
+            .checked_add(duration)
+            .map(Value::DateTime),
+    };
+    let value = match (verb, left, right) {
+        ("add", Value::Date(date), Value::Duration(duration))
+        | ("add", Value::Duration(duration), Value::Date(date)) => move_date(date, duration),
+        ("subtract", Value::Date(date), Value::Duration(duration)) => move_date(date, &-*duration),
+        ("add", Value::DateTime(time), Value::Duration(duration))
+        | ("add", Value::Duration(duration), Value::DateTime(time)) => {
+            time.checked_add(duration).map(Value::DateTime)
+        }
+        ("subtract", Value::DateTime(time), Value::Duration(duration)) => {
+            time.checked_add(&-*duration).map(Value::DateTime)
+        }
+        ("subtract", Value::Date(a), Value::Date(b)) => Some(Value::Duration(Duration::new(
+            0,
+            a.days_since_epoch() - b.days_since_epoch(),
+            0,
+        ))),
+        ("subtract", Value::DateTime(a), Value::DateTime(b)) => Some(Value::Duration(
+            Duration::new(0, 0, a.seconds_since_epoch() - b.seconds_since_epoch()),
+        )),
+        ("add", Value::Duration(a), Value::Duration(b)) => a.checked_add(b).map(Value::Duration),
+        ("subtract", Value::Duration(a), Value::Duration(b)) => {
+            a.checked_add(&-*b).map(Value::Duration)
+        }
+        ("multiply", Value::Duration(duration), number)
+        | ("multiply", number, Value::Duration(duration))
+            if number.as_number().is_some() =>
+        {
+            let factor = to_decimal(number)?;
+            if factor.scale() > 0 || factor.to_f64().abs() > i64::MAX as f64 {
+                return Err(ScriptingError::EvaluationError(format!(
+                    "A duration can only be multiplied by a whole number, found {}",
+                    factor
+                )));
+            }
+            duration
+                .checked_mul(factor.to_f64() as i64)
+                .map(Value::Duration)
+        }
+        _ => return Err(cannot_combine(verb, left, right)),
+    };
+    value.ok_or_else(out_of_range)
+}
+
+fn out_of_range() -> ScriptingError {
+    ScriptingError::EvaluationError("Date out of range".to_string())
+}
+
+// Orders two numbers, exactly when both are decimals, two amounts of the same
+// currency, or two dates, a date being midnight when compared with a date and
+// time. `None` when either is null.
+fn compare_values(left: &Value, right: &Value) -> Result<Option<Ordering>> {
+    match (left, right) {
+        (Value::Money(left, currency), Value::Money(right, other)) => {
+            same_currency("compare", currency, other)?;
+            Ok(Some(left.cmp(right)))
+        }
+        (Value::Null, _) | (_, Value::Null) => Ok(None),
+        (Value::Money(..), _) | (_, Value::Money(..)) => {
+            Err(cannot_combine("compare", left, right))
+        }
+        (Value::Date(left), Value::Date(right)) => Ok(Some(left.cmp(right))),
+        (Value::DateTime(left), Value::DateTime(right)) => Ok(Some(left.cmp(right))),
+        (Value::Date(left), Value::DateTime(right)) => Ok(Some(left.at_midnight().cmp(right))),
+        (Value::DateTime(left), Value::Date(right)) => Ok(Some(left.cmp(&right.at_midnight()))),
+        (Value::Duration(a), Value::Duration(b)) => match a.partial_cmp(b) {
+            Some(ordering) => Ok(Some(ordering)),
+            None => Err(ScriptingError::EvaluationError(format!(
+                "Cannot compare {} with {}, months have no fixed length",
+                a, b
+            ))),
+        },
+        _ if is_time(left) || is_time(right) => Err(cannot_combine("compare", left, right)),
+        (Value::Decimal(left), Value::Decimal(right)) => Ok(Some(left.cmp(right))),
+        _ => Ok(left
+            .as_number()
+            .zip(right.as_number())
+            .and_then(|(left, right)| left.partial_cmp(&right))),
+    }
+}
+
+// Decimals are compared exactly, floating point numbers within
+// `f64::EPSILON`; values of different types cannot be compared
+fn values_equal(left: &Value, right: &Value) -> Result<bool> {
+    match (left, right) {
+        (Value::Decimal(a), Value::Decimal(b)) => Ok(a == b),
+        (Value::Money(a, a_currency), Value::Money(b, b_currency)) => {
+            same_currency("compare", a_currency, b_currency)?;
+            Ok(a == b)
+        }
+        (Value::Date(a), Value::Date(b)) => Ok(a == b),
+        (Value::DateTime(a), Value::DateTime(b)) => Ok(a == b),
+        (Value::Duration(a), Value::Duration(b)) => Ok(a == b),
+        (Value::Number(_) | Value::Decimal(_), Value::Number(_) | Value::Decimal(_)) => Ok(left
+            .as_number()
+            .zip(right.as_number())
+            .is_some_and(|(a, b)| (a - b).abs() < f64::EPSILON)),
+        (Value::Bool(a), Value::Bool(b)) => Ok(a == b),
+        (Value::String(a), Value::String(b)) => Ok(a == b),
+        // Any value can be compared with null
+        (Value::Null, other) | (other, Value::Null) => Ok(*other == Value::Null),
+        (Value::List(a), Value::List(b)) => {
+            if a.len() != b.len() {
+                return Ok(false);
+            }
+            for (a, b) in a.iter().zip(b) {
+                if !values_equal(a, b)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+        (Value::Map(a), Value::Map(b)) => {
+            if a.len() != b.len() {
+                return Ok(false);
+            }
+            for ((a_key, a), (b_key, b)) in a.iter().zip(b) {
+                if a_key != b_key || !values_equal(a, b)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+        _ => Err(ScriptingError::EvaluationError(format!(
+            "Cannot compare {:?} with {:?}",
+            left, right
+        ))),
+    }
+}
+
+impl NodeConstVisitor for ExpressionEvaluator {
+    type Output = Result<()>;
+    fn const_visit(&self, node: Box<Node>) -> Self::Output {
+        match node.as_ref() {
+            Node::Base(children) => {
+                // Functions can be called before their definition
                 children
                     .iter()
-                    .try_for_each(|child| self.const_visit(child.clone()))?;
+                    .for_each(|child| self.register_function(child));
+                self.visit_children(children)
+            }
+            Node::Variable(_, name, index, _) => match index.get() {
+                None => Err(ScriptingError::EvaluationError(format!(
+                    "Variable {} not indexed",
+                    name
+                ))),
+                Some(id) => match self.read_variable(*id, name)? {
+                    None => Err(ScriptingError::EvaluationError(format!(
+                        "Variable {} not initialized",
+                        name
+                    ))),
+                    Some(value) => {
+                        self.push(value);
+                        Ok(())
+                    }
+                },
+            },
 
-                let right = self.digit_stack.lock().unwrap().pop().unwrap();
-                let left = self.digit_stack.lock().unwrap().pop().unwrap();
-                self.digit_stack.lock().unwrap().push(left / right);
+            Node::Money(amount, currency) => {
+                self.push(Value::Money(self.literal(amount)?, currency.clone()));
                 Ok(())
             }
-            Node::Assign(children) => {
-                *self.is_lhs_variable.lock().unwrap() = true;
-                children.get(0).unwrap().const_accept(self);
+            Node::Date(date) => {
+                self.push(Value::Date(*date));
+                Ok(())
+            }
+            Node::DateTime(time) => {
+                self.push(Value::DateTime(*time));
+                Ok(())
+            }
+            Node::Duration(duration) => {
+                self.push(Value::Duration(*duration));
+                Ok(())
+            }
+            Node::Today(_)
+            | Node::Now(_)
+            | Node::DayOfMonth(_)
+            | Node::IsWeekday(_)
+            | Node::IsBusinessDay(_)
+            | Node::AddBusinessDays(_)
+            | Node::Adjust(_)
+            | Node::EndOfMonth(_)
+            | Node::IsMarketOpen(_)
+            | Node::Format(_)
+            | Node::FormatCurrency(_)
+            | Node::RoundTo(_)
+            | Node::Convert(_)
+            | Node::Min(_)
+            | Node::Max(_)
+            | Node::Pow(_)
+            | Node::Ln(_)
+            | Node::Exp(_)
+            | Node::Clamp(_)
+            | Node::Math(..)
+            | Node::Select(_)
+            | Node::Len(_)
+            | Node::Append(_)
+            | Node::Indicator(..)
+            | Node::CrossesAbove(_)
+            | Node::Statistic(..)
+            | Node::Lookback(..) => self.call_builtin(&node),
+            Node::String(value) => {
+                self.push(Value::String(value.clone()));
+                Ok(())
+            }
+            Node::Null => {
+                self.push(Value::Null);
+                Ok(())
+            }
+            Node::Decimal(value) => {
+                self.push(Value::Decimal(self.literal(value)?));
+                Ok(())
+            }
+            Node::Number(value) => {
+                self.push(Value::Number(*value));
+                Ok(())
+            }
+            Node::Interpolation(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
 
-                *self.is_lhs_variable.lock().unwrap() = false;
-                children.get(1).unwrap().const_accept(self);
+                let mut parts = children
+                    .iter()
+                    .map(|_| self.pop())
+                    .collect::<Result<Vec<_>>>()?;
+                parts.reverse();
+                let string = parts.iter().map(|part| part.to_string()).collect();
+                self.push(Value::String(string));
+                Ok(())
+            }
+            // Formatting null gives null, so that a default can be given with `??`
+            Node::Add(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
 
-                let v = self.lhs_variable.lock().unwrap().clone().unwrap();
-                let variable = v.as_ref();
-                match variable {
-                    Node::Variable(_, name, index) => match index.get() {
-                        None => {
-                            return Err(ScriptingError::EvaluationError(format!(
-                                "Variable {} not indexed",
-                                name
-                            )))
-                        }
-                        Some(id) => {
-                            // let value = self.digit_stack.lock().unwrap().pop().unwrap();
-                            // self.variables.lock().unwrap()[*id] = value
-
-                            let mut variables = self.variables.lock().unwrap();
-                            if !self.boolean_stack.lock().unwrap().is_empty() {
-                                // Pop from boolean stack and store the boolean value
-                                let value = self.boolean_stack.lock().unwrap().pop().unwrap();
-                                variables[*id] = Value::Bool(value);
-
-                                Ok(())
-                            } else {
-                                // Pop from digit stack and store the numeric value
-                                let value = self.digit_stack.lock().unwrap().pop().unwrap();
-                                variables[*id] = Value::Number(value);
-
-                                Ok(())
-                            }
-                        }
+                self.arithmetic(
+                    "add",
+                    MoneyRule::SameCurrency,
+                    |left, right| left.checked_add(right).ok_or_else(overflow),
+                    |left, right| left + right,
+                )
+            }
+            Node::Subtract(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
+
+                self.arithmetic(
+                    "subtract",
+                    MoneyRule::SameCurrency,
+                    |left, right| left.checked_sub(right).ok_or_else(overflow),
+                    |left, right| left - right,
+                )
+            }
+            Node::Multiply(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
+
+                self.arithmetic(
+                    "multiply",
+                    MoneyRule::Scale,
+                    |left, right| {
+                        left.checked_mul(right, self.decimal_scale, self.rounding)
+                            .ok_or_else(overflow)
+                    },
+                    |left, right| left * right,
+                )
+            }
+            Node::Divide(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
+
+                self.arithmetic(
+                    "divide",
+                    MoneyRule::Ratio,
+                    |left, right| match right.is_zero() {
+                        true => Err(ScriptingError::EvaluationError(
+                            "Division by zero".to_string(),
+                        )),
+                        false => left
+                            .checked_div(right, self.decimal_scale, self.rounding)
+                            .ok_or_else(overflow),
+                    },
+                    |left, right| left / right,
+                )
+            }
+            Node::Modulo(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
+
+                self.arithmetic(
+                    "take the remainder of",
+                    MoneyRule::SameCurrency,
+                    |left, right| match right.is_zero() {
+                        true => Err(ScriptingError::EvaluationError(
+                            "Division by zero".to_string(),
+                        )),
+                        false => left.checked_rem(right).ok_or_else(overflow),
                     },
+                    floor_mod,
+                )
+            }
+            Node::Assign(children) => {
+                let (lhs, rhs) = match children.as_slice() {
+                    [lhs, rhs] => (lhs, rhs),
                     _ => {
                         return Err(ScriptingError::EvaluationError(
                             "Invalid variable assignment".to_string(),
                         ))
                     }
+                };
+                let (name, id) = target_slot(lhs)?;
+
+                if !self.visit_operands(slice::from_ref(rhs))? {
+                    return Ok(());
                 }
+                let value = self.pop()?;
+                self.write_variable(id, name, value)
+            }
+            Node::Local(children) => {
+                let (lhs, rhs) = match children.as_slice() {
+                    [lhs] => (lhs, None),
+                    [lhs, rhs] => (lhs, Some(rhs)),
+                    _ => {
+                        return Err(ScriptingError::EvaluationError(
+                            "Invalid local declaration".to_string(),
+                        ))
+                    }
+                };
+                let (name, id) = target_slot(lhs)?;
+
+                // A declaration without value resets the slot
+                let value = match rhs {
+                    Some(rhs) => {
+                        if !self.visit_operands(slice::from_ref(rhs))? {
+                            return Ok(());
+                        }
+                        Some(self.pop()?)
+                    }
+                    None => None,
+                };
+                self.write_slot(id, name, value)
             }
             Node::NotEqual(children) => {
-                children
-                    .iter()
-                    .try_for_each(|child| self.const_visit(child.clone()))?;
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
 
-                let right = self.digit_stack.lock().unwrap().pop().unwrap();
-                let left = self.digit_stack.lock().unwrap().pop().unwrap();
-                self.boolean_stack
-                    .lock()
-                    .unwrap()
-                    .push((right - left).abs() >= f64::EPSILON);
+                let right = self.pop()?;
+                let left = self.pop()?;
+                self.push(Value::Bool(!values_equal(&left, &right)?));
 
                 Ok(())
             }
             Node::And(children) => {
-                children
-                    .iter()
-                    .try_for_each(|child| self.const_visit(child.clone()))?;
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
 
-                let right = self.boolean_stack.lock().unwrap().pop().unwrap();
-                let left = self.boolean_stack.lock().unwrap().pop().unwrap();
-                self.boolean_stack.lock().unwrap().push(left && right);
+                let right = self.pop_boolean()?;
+                let left = self.pop_boolean()?;
+                self.push(Value::Bool(left && right));
 
                 Ok(())
             }
             Node::Or(children) => {
-                children
-                    .iter()
-                    .try_for_each(|child| self.const_visit(child.clone()))?;
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
 
-                let right = self.boolean_stack.lock().unwrap().pop().unwrap();
-                let left = self.boolean_stack.lock().unwrap().pop().unwrap();
-                self.boolean_stack.lock().unwrap().push(left || right);
+                let right = self.pop_boolean()?;
+                let left = self.pop_boolean()?;
+                self.push(Value::Bool(left || right));
 
                 Ok(())
             }
             Node::Not(children) => {
-                children
-                    .iter()
-                    .try_for_each(|child| self.const_visit(child.clone()))?;
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
 
-                let value = self.boolean_stack.lock().unwrap().pop().unwrap();
-                self.boolean_stack.lock().unwrap().push(!value);
+                let value = self.pop_boolean()?;
+                self.push(Value::Bool(!value));
 
                 Ok(())
             }
             Node::Superior(children) => {
-                children
-                    .iter()
-                    .try_for_each(|child| self.const_visit(child.clone()))?;
-
-                let right = self.digit_stack.lock().unwrap().pop().unwrap();
-                let left = self.digit_stack.lock().unwrap().pop().unwrap();
-                self.boolean_stack.lock().unwrap().push(left > right);
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
 
-                Ok(())
+                self.comparison(Ordering::is_gt)
             }
             Node::Inferior(children) => {
-                children
-                    .iter()
-                    .try_for_each(|child| self.const_visit(child.clone()))?;
-
-                let right = self.digit_stack.lock().unwrap().pop().unwrap();
-                let left = self.digit_stack.lock().unwrap().pop().unwrap();
-                self.boolean_stack.lock().unwrap().push(left < right);
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
 
-                Ok(())
+                self.comparison(Ordering::is_lt)
             }
             Node::SuperiorOrEqual(children) => {
-                children
-                    .iter()
-                    .try_for_each(|child| self.const_visit(child.clone()))?;
-
-                let right = self.digit_stack.lock().unwrap().pop().unwrap();
-                let left = self.digit_stack.lock().unwrap().pop().unwrap();
-                self.boolean_stack.lock().unwrap().push(left >= right);
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
 
-                Ok(())
+                self.comparison(Ordering::is_ge)
             }
             Node::InferiorOrEqual(children) => {
-                children
-                    .iter()
-                    .try_for_each(|child| self.const_visit(child.clone()))?;
-
-                let right = self.digit_stack.lock().unwrap().pop().unwrap();
-                let left = self.digit_stack.lock().unwrap().pop().unwrap();
-                self.boolean_stack.lock().unwrap().push(left <= right);
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
 
-                Ok(())
+                self.comparison(Ordering::is_le)
             }
             Node::True => {
-                self.boolean_stack.lock().unwrap().push(true);
+                self.push(Value::Bool(true));
 
                 Ok(())
             }
 
             Node::False => {
-                self.boolean_stack.lock().unwrap().push(false);
+                self.push(Value::Bool(false));
 
                 Ok(())
             }
             Node::Equal(children) => {
-                children
-                    .iter()
-                    .try_for_each(|child| self.const_visit(child.clone()))?;
-
-                let right = self.digit_stack.lock().unwrap().pop().unwrap();
-                let left = self.digit_stack.lock().unwrap().pop().unwrap();
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
 
-                self.boolean_stack
-                    .lock()
-                    .unwrap()
-                    .push((right - left).abs() < f64::EPSILON);
+                let right = self.pop()?;
+                let left = self.pop()?;
+                self.push(Value::Bool(values_equal(&left, &right)?));
 
                 Ok(())
             }
-            Node::UnaryPlus(children) => {
-                children
-                    .iter()
-                    .try_for_each(|child| self.const_visit(child.clone()))?;
+            Node::Coalesce(children) => {
+                let (value, default) = match children.as_slice() {
+                    [value, default] => (value, default),
+                    _ => {
+                        return Err(ScriptingError::EvaluationError(
+                            "Invalid default operator".to_string(),
+                        ))
+                    }
+                };
+                if !self.visit_operands(slice::from_ref(value))? {
+                    return Ok(());
+                }
+                match self.pop()? {
+                    Value::Null => self.visit_children(slice::from_ref(default)),
+                    value => {
+                        self.push(value);
+                        Ok(())
+                    }
+                }
+            }
+            Node::UnaryPlus(children) => self.visit_children(children),
+            Node::UnaryMinus(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
 
+                match self.pop_operand()? {
+                    Value::Decimal(value) => self.push(Value::Decimal(-value)),
+                    Value::Duration(value) => self.push(Value::Duration(-value)),
+                    Value::Money(value, currency) => self.push(Value::Money(-value, currency)),
+                    value => {
+                        self.push(value);
+                        self.unary_operation(|value| -value)?;
+                    }
+                }
                 Ok(())
             }
-            Node::UnaryMinus(children) => {
-                children
-                    .iter()
-                    .try_for_each(|child| self.const_visit(child.clone()))?;
+            Node::If(children, first_else, _) => {
+                // Evaluate the condition
+                let condition = children.first().ok_or_else(|| {
+                    ScriptingError::EvaluationError("If statement without condition".to_string())
+                })?;
+                if !self.visit_operands(slice::from_ref(condition))? {
+                    return Ok(());
+                }
+                let is_true = self.pop_boolean()?;
+
+                // The then block runs up to the first else statement, the else
+                // block from there to the end.
+                let last_then = first_else
+                    .unwrap_or(children.len())
+                    .clamp(1, children.len());
+                let block = if is_true {
+                    &children[1..last_then]
+                } else {
+                    &children[last_then..]
+                };
 
-                let top = self.digit_stack.lock().unwrap().pop().unwrap();
-                self.digit_stack.lock().unwrap().push(-top);
+                // Any failure inside the taken branch stops the evaluation
+                self.visit_children(block)
+            }
+            Node::Match(children, first_else) => {
+                let value = children.first().ok_or_else(|| {
+                    ScriptingError::EvaluationError("Match statement without value".to_string())
+                })?;
+                if !self.visit_operands(slice::from_ref(value))? {
+                    return Ok(());
+                }
+                let value = self.pop()?;
+
+                // The first case with an equal value runs, the values of the
+                // following cases are not evaluated
+                let last_case = first_else
+                    .unwrap_or(children.len())
+                    .clamp(1, children.len());
+                for case in &children[1..last_case] {
+                    let Node::Case(case, values) = case.as_ref() else {
+                        return Err(ScriptingError::EvaluationError(
+                            "Invalid match case".to_string(),
+                        ));
+                    };
+                    let values = (*values).min(case.len());
+                    for candidate in &case[..values] {
+                        if !self.visit_operands(slice::from_ref(candidate))? {
+                            return Ok(());
+                        }
+                        // Values of different types never match
+                        if let Ok(true) = values_equal(&value, &self.pop()?) {
+                            return self.visit_children(&case[values..]);
+                        }
+                    }
+                }
+                self.visit_children(&children[last_case..])
+            }
+            Node::Case(..) => Err(ScriptingError::EvaluationError(
+                "Case outside of a match statement".to_string(),
+            )),
+            Node::List(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
 
+                let mut items = children
+                    .iter()
+                    .map(|_| self.pop())
+                    .collect::<Result<Vec<_>>>()?;
+                items.reverse();
+                self.push(Value::List(items));
                 Ok(())
             }
-            Node::Min(children) => {
-                children
-                    .iter()
-                    .try_for_each(|child| self.const_visit(child.clone()))?;
+            Node::Index(children) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
 
-                let right = self.digit_stack.lock().unwrap().pop().unwrap();
-                let left = self.digit_stack.lock().unwrap().pop().unwrap();
-                self.digit_stack.lock().unwrap().push(left.min(right));
+                let value = match (self.pop()?, self.pop()?) {
+                    (index @ (Value::Number(_) | Value::Decimal(_)), Value::List(mut items)) => {
+                        let index = index.as_number().unwrap_or(f64::NAN);
+                        let position = list_position(index, items.len(), false)?;
+                        items.swap_remove(position)
+                    }
+                    (Value::String(key), Value::Map(mut entries)) => {
+                        entries.remove(&key).ok_or_else(|| {
+                            ScriptingError::EvaluationError(format!("Missing key {}", key))
+                        })?
+                    }
+                    (index, value @ (Value::List(_) | Value::Map(_))) => {
+                        return Err(ScriptingError::EvaluationError(format!(
+                            "Cannot index {:?} with {:?}",
+                            value, index
+                        )))
+                    }
+                    (_, value) => {
+                        return Err(ScriptingError::EvaluationError(format!(
+                            "Expected a list or a map, found {:?}",
+                            value
+                        )))
+                    }
+                };
+                self.push(value);
+                Ok(())
+            }
+            Node::Slice(children, has_start, has_end) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
 
+                let end = match has_end {
+                    true => Some(self.pop_digit()?),
+                    false => None,
+                };
+                let start = match has_start {
+                    true => Some(self.pop_digit()?),
+                    false => None,
+                };
+                let items = self.pop_list()?;
+                let start = match start {
+                    Some(start) => list_position(start, items.len(), true)?,
+                    None => 0,
+                };
+                let end = match end {
+                    Some(end) => list_position(end, items.len(), true)?,
+                    None => items.len(),
+                };
+                self.push(Value::List(items[start..end.max(start)].to_vec()));
                 Ok(())
             }
-            Node::Max(children) => {
-                children
+            Node::For(children, variables) => {
+                let variables = *variables;
+                if variables == 0 || children.len() <= variables {
+                    return Err(ScriptingError::EvaluationError(
+                        "Invalid for loop".to_string(),
+                    ));
+                }
+                let targets = children[..variables]
                     .iter()
-                    .try_for_each(|child| self.const_visit(child.clone()))?;
+                    .map(|variable| target_slot(variable))
+                    .collect::<Result<Vec<_>>>()?;
+                let body = &children[variables + 1..];
 
-                let right = self.digit_stack.lock().unwrap().pop().unwrap();
-                let left = self.digit_stack.lock().unwrap().pop().unwrap();
-                self.digit_stack.lock().unwrap().push(left.max(right));
+                if !self.visit_operands(&children[variables..variables + 1])? {
+                    return Ok(());
+                }
+                // Values given to the loop variables at each iteration
+                let iterations: Vec<Vec<Value>> = match (self.pop()?, variables) {
+                    (Value::List(items), 1) => items.into_iter().map(|item| vec![item]).collect(),
+                    (Value::List(items), _) => items
+                        .into_iter()
+                        .enumerate()
+                        .map(|(index, item)| {
+                            vec![Value::Decimal(Decimal::from(index as i64)), item]
+                        })
+                        .collect(),
+                    (Value::Map(entries), 1) => entries
+                        .into_keys()
+                        .map(|key| vec![Value::String(key)])
+                        .collect(),
+                    (Value::Map(entries), _) => entries
+                        .into_iter()
+                        .map(|(key, value)| vec![Value::String(key), value])
+                        .collect(),
+                    (value, _) => {
+                        return Err(ScriptingError::EvaluationError(format!(
+                            "Expected a list or a map to loop over, found {:?}",
+                            value
+                        )))
+                    }
+                };
 
+                for values in iterations {
+                    for ((name, id), value) in targets.iter().zip(values) {
+                        self.write_variable(*id, name, value)?;
+                    }
+                    self.visit_children(body)?;
+
+                    // `break` and `continue` stop at the loop, anything else
+                    // keeps unwinding
+                    let mut signal = self.signal.lock().unwrap();
+                    match signal.take() {
+                        Some(Signal::Break) => break,
+                        Some(Signal::Continue) | None => {}
+                        other => {
+                            *signal = other;
+                            break;
+                        }
+                    }
+                }
                 Ok(())
             }
-            Node::Pow(children) => {
-                children
-                    .iter()
-                    .try_for_each(|child| self.const_visit(child.clone()))?;
+            Node::Map(children, keys) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
 
-                let right = self.digit_stack.lock().unwrap().pop().unwrap();
-                let left = self.digit_stack.lock().unwrap().pop().unwrap();
-                self.digit_stack.lock().unwrap().push(left.powf(right));
+                let mut values = children
+                    .iter()
+                    .map(|_| self.pop())
+                    .collect::<Result<Vec<_>>>()?;
+                values.reverse();
+                self.push(Value::Map(keys.iter().cloned().zip(values).collect()));
+                Ok(())
+            }
+            Node::Try(children, catch_index, binding) => {
+                let catch_index = (*catch_index).min(children.len());
+                let depth = self.stack.lock().unwrap().len();
+                let frames = self.frames.lock().unwrap().len();
+
+                let error = match self.visit_children(&children[..catch_index]) {
+                    Ok(()) => return Ok(()),
+                    Err(error) => error,
+                };
+
+                // Unwind what the failed statement left behind
+                self.stack.lock().unwrap().truncate(depth);
+                self.frames.lock().unwrap().truncate(frames);
+                if let Some(binding) = binding {
+                    let (name, id) = target_slot(binding)?;
+                    self.write_variable(id, name, Value::Error(ErrorValue::from(&error)))?;
+                }
+                self.visit_children(&children[catch_index..])
+            }
+            Node::Field(children, field) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
 
+                let value = match (self.pop()?, field.as_str()) {
+                    (Value::Error(error), "kind") => Some(Value::String(error.kind)),
+                    (Value::Error(error), "message") => Some(Value::String(error.message)),
+                    (Value::Map(mut entries), _) => entries.remove(field),
+                    _ => None,
+                };
+                let value = value.ok_or_else(|| {
+                    ScriptingError::EvaluationError(format!("No field {}", field))
+                })?;
+                self.push(value);
                 Ok(())
             }
-            Node::Ln(children) => {
-                children
-                    .iter()
-                    .try_for_each(|child| self.const_visit(child.clone()))?;
+            Node::Function(..) => {
+                self.register_function(&node);
+                Ok(())
+            }
+            Node::Call(children, name) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
 
-                let top = self.digit_stack.lock().unwrap().pop().unwrap();
-                self.digit_stack.lock().unwrap().push(top.ln());
+                let mut args = children
+                    .iter()
+                    .map(|_| self.pop())
+                    .collect::<Result<Vec<_>>>()?;
+                args.reverse();
+                let value = self.call_function(name, args)?;
+                if !self.stopping() {
+                    self.push(value);
+                }
 
                 Ok(())
             }
-            Node::Exp(children) => {
-                children
-                    .iter()
-                    .try_for_each(|child| self.const_visit(child.clone()))?;
+            Node::Return(children, line) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
 
-                let top = self.digit_stack.lock().unwrap().pop().unwrap();
-                self.digit_stack.lock().unwrap().push(top.exp());
+                let value = match children.is_empty() {
+                    true => Value::Null,
+                    false => self.pop()?,
+                };
+                *self.signal.lock().unwrap() = Some(Signal::Return(value, *line));
 
                 Ok(())
             }
-            Node::If(children, first_else) => {
-                // Evaluate the condition
-                children.get(0).unwrap().const_accept(self);
-                // Pop the condition result
-                let is_true = self.boolean_stack.lock().unwrap().pop().unwrap();
-
-                // Find the first else node
-                if is_true {
-                    // then, the following expressions are either conditions or
-                    // the logic block
-                    let last_condition = if first_else.is_none() {
-                        children.len()
-                    } else {
-                        first_else.unwrap()
-                    };
+            Node::Exit(line) => {
+                *self.signal.lock().unwrap() = Some(Signal::Exit(*line));
+                Ok(())
+            }
+            Node::Abort(children, line) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
+                }
 
-                    // Evaluate the conditions
-                    for i in 1..last_condition {
-                        children.get(i).unwrap().const_accept(self);
+                let reason = match self.pop()? {
+                    Value::String(reason) => reason,
+                    value => {
+                        return Err(ScriptingError::EvaluationError(format!(
+                            "Expected a string reason for abort, found {:?}",
+                            value
+                        )))
                     }
+                };
+                *self.signal.lock().unwrap() = Some(Signal::Abort(reason, *line));
+
+                Ok(())
+            }
+            Node::Require(children, line) | Node::Assert(children, line) => {
+                if !self.visit_operands(children)? {
+                    return Ok(());
                 }
-                // Evaluate the else block
-                else if first_else.is_some() {
-                    // the following conditions are the else block
-                    for i in first_else.unwrap()..children.len() {
-                        children.get(i).unwrap().const_accept(self);
+
+                let notify = match children.len() {
+                    3 => self.pop_boolean()?,
+                    _ => false,
+                };
+                let message = match self.pop()? {
+                    Value::String(message) => message,
+                    value => {
+                        return Err(ScriptingError::EvaluationError(format!(
+                            "Expected a string message for the pre-condition, found {:?}",
+                            value
+                        )))
                     }
+                };
+                if self.pop_boolean()? {
+                    return Ok(());
                 }
+
+                if notify {
+                    self.call_function("Notify", vec![Value::String(message.clone())])?;
+                }
+                let signal = match node.as_ref() {
+                    Node::Require(..) => Signal::Reject(message, *line),
+                    _ => Signal::Abort(message, *line),
+                };
+                *self.signal.lock().unwrap() = Some(signal);
                 Ok(())
             }
-        };
-        eval
+            Node::Break => {
+                *self.signal.lock().unwrap() = Some(Signal::Break);
+                Ok(())
+            }
+            Node::Continue => {
+                *self.signal.lock().unwrap() = Some(Signal::Continue);
+                Ok(())
+            }
+        }
     }
 }
 
@@ -499,14 +2472,7 @@ mod tests {
 
         base.add_child(assign);
 
-        let evaluator = ExpressionEvaluator {
-            variables: Mutex::new(vec![Value::Null]),
-            digit_stack: Mutex::new(Vec::new()),
-            boolean_stack: Mutex::new(Vec::new()),
-            is_lhs_variable: Mutex::new(false),
-            lhs_variable: Mutex::new(None),
-            current_event: None,
-        };
+        let evaluator = ExpressionEvaluator::new().with_variables(1);
 
         evaluator.const_visit(base).unwrap();
 
@@ -517,33 +2483,26 @@ mod tests {
     fn test_assign_boolean() {
         let base = Box::new(Node::Base(vec![
             Box::new(Node::Assign(vec![
-                Box::new(Node::Variable(Vec::new(), "x".to_string(), 0.into())),
+                Box::new(Node::Variable(Vec::new(), "x".to_string(), 0.into(), 0)),
                 Box::new(Node::True),
             ])),
             Box::new(Node::Assign(vec![
-                Box::new(Node::Variable(Vec::new(), "y".to_string(), 1.into())),
+                Box::new(Node::Variable(Vec::new(), "y".to_string(), 1.into(), 0)),
                 Box::new(Node::False),
             ])),
             Box::new(Node::Assign(vec![
-                Box::new(Node::Variable(Vec::new(), "z".to_string(), 2.into())),
+                Box::new(Node::Variable(Vec::new(), "z".to_string(), 2.into(), 0)),
                 Box::new(Node::And(vec![
-                    Box::new(Node::Variable(Vec::new(), "x".to_string(), 0.into())),
-                    Box::new(Node::Variable(Vec::new(), "y".to_string(), 1.into())),
+                    Box::new(Node::Variable(Vec::new(), "x".to_string(), 0.into(), 0)),
+                    Box::new(Node::Variable(Vec::new(), "y".to_string(), 1.into(), 0)),
                 ])),
             ])),
         ]));
 
-        let evaluator = ExpressionEvaluator {
-            variables: Mutex::new(vec![Value::Null, Value::Null, Value::Null]),
-            digit_stack: Mutex::new(Vec::new()),
-            boolean_stack: Mutex::new(Vec::new()),
-            is_lhs_variable: Mutex::new(false),
-            lhs_variable: Mutex::new(None),
-            current_event: None,
-        };
+        let evaluator = ExpressionEvaluator::new().with_variables(3);
         evaluator.const_visit(base).unwrap();
 
-        assert_eq!(evaluator.variables().get(0).unwrap(), &Value::Bool(true));
+        assert_eq!(evaluator.variables().first().unwrap(), &Value::Bool(true));
         assert_eq!(evaluator.variables().get(1).unwrap(), &Value::Bool(false));
         assert_eq!(evaluator.variables().get(2).unwrap(), &Value::Bool(false));
     }
@@ -561,14 +2520,7 @@ mod tests {
 
         base.add_child(add);
 
-        let evaluator = ExpressionEvaluator {
-            variables: Mutex::new(vec![Value::Null]),
-            digit_stack: Mutex::new(Vec::new()),
-            boolean_stack: Mutex::new(Vec::new()),
-            is_lhs_variable: Mutex::new(false),
-            lhs_variable: Mutex::new(None),
-            current_event: None,
-        };
+        let evaluator = ExpressionEvaluator::new().with_variables(1);
 
         assert!(evaluator.const_visit(base).is_err());
     }
@@ -603,14 +2555,7 @@ mod tests {
         base.add_child(assign_y);
         base.add_child(assign_z);
 
-        let evaluator = ExpressionEvaluator {
-            variables: Mutex::new(vec![Value::Null, Value::Null, Value::Null]),
-            digit_stack: Mutex::new(Vec::new()),
-            boolean_stack: Mutex::new(Vec::new()),
-            is_lhs_variable: Mutex::new(false),
-            lhs_variable: Mutex::new(None),
-            current_event: None,
-        };
+        let evaluator = ExpressionEvaluator::new().with_variables(3);
 
         evaluator.const_visit(base).unwrap();
 
@@ -630,18 +2575,11 @@ mod tests {
 
         base.add_child(equal);
 
-        let evaluator = ExpressionEvaluator {
-            variables: Mutex::new(Vec::new()),
-            digit_stack: Mutex::new(Vec::new()),
-            boolean_stack: Mutex::new(Vec::new()),
-            is_lhs_variable: Mutex::new(false),
-            lhs_variable: Mutex::new(None),
-            current_event: None,
-        };
+        let evaluator = ExpressionEvaluator::new();
 
         evaluator.const_visit(base).unwrap();
 
-        assert_eq!(evaluator.boolean_stack().pop().unwrap(), true);
+        assert!(evaluator.boolean_stack().pop().unwrap());
     }
 
     #[test]
@@ -657,18 +2595,11 @@ mod tests {
 
         base.add_child(and);
 
-        let evaluator = ExpressionEvaluator {
-            variables: Mutex::new(Vec::new()),
-            digit_stack: Mutex::new(Vec::new()),
-            boolean_stack: Mutex::new(Vec::new()),
-            is_lhs_variable: Mutex::new(false),
-            lhs_variable: Mutex::new(None),
-            current_event: None,
-        };
+        let evaluator = ExpressionEvaluator::new();
 
         evaluator.const_visit(base).unwrap();
 
-        assert_eq!(evaluator.boolean_stack().pop().unwrap(), true);
+        assert!(evaluator.boolean_stack().pop().unwrap());
     }
 
     #[test]
@@ -684,18 +2615,11 @@ mod tests {
 
         base.add_child(and);
 
-        let evaluator = ExpressionEvaluator {
-            variables: Mutex::new(Vec::new()),
-            digit_stack: Mutex::new(Vec::new()),
-            boolean_stack: Mutex::new(Vec::new()),
-            is_lhs_variable: Mutex::new(false),
-            lhs_variable: Mutex::new(None),
-            current_event: None,
-        };
+        let evaluator = ExpressionEvaluator::new();
 
         evaluator.const_visit(base).unwrap();
 
-        assert_eq!(evaluator.boolean_stack().pop().unwrap(), true);
+        assert!(evaluator.boolean_stack().pop().unwrap());
     }
 
     #[test]
@@ -711,18 +2635,11 @@ mod tests {
 
         base.add_child(and);
 
-        let evaluator = ExpressionEvaluator {
-            variables: Mutex::new(Vec::new()),
-            digit_stack: Mutex::new(Vec::new()),
-            boolean_stack: Mutex::new(Vec::new()),
-            is_lhs_variable: Mutex::new(false),
-            lhs_variable: Mutex::new(None),
-            current_event: None,
-        };
+        let evaluator = ExpressionEvaluator::new();
 
         evaluator.const_visit(base).unwrap();
 
-        assert_eq!(evaluator.boolean_stack().pop().unwrap(), true);
+        assert!(evaluator.boolean_stack().pop().unwrap());
     }
 
     #[test]
@@ -738,18 +2655,11 @@ mod tests {
 
         base.add_child(and);
 
-        let evaluator = ExpressionEvaluator {
-            variables: Mutex::new(Vec::new()),
-            digit_stack: Mutex::new(Vec::new()),
-            boolean_stack: Mutex::new(Vec::new()),
-            is_lhs_variable: Mutex::new(false),
-            lhs_variable: Mutex::new(None),
-            current_event: None,
-        };
+        let evaluator = ExpressionEvaluator::new();
 
         evaluator.const_visit(base).unwrap();
 
-        assert_eq!(evaluator.boolean_stack().pop().unwrap(), true);
+        assert!(evaluator.boolean_stack().pop().unwrap());
     }
 
     #[test]
@@ -775,18 +2685,11 @@ mod tests {
         base.add_child(equal_2.clone());
         base.add_child(and);
 
-        let evaluator = ExpressionEvaluator {
-            variables: Mutex::new(Vec::new()),
-            digit_stack: Mutex::new(Vec::new()),
-            boolean_stack: Mutex::new(Vec::new()),
-            is_lhs_variable: Mutex::new(false),
-            lhs_variable: Mutex::new(None),
-            current_event: None,
-        };
+        let evaluator = ExpressionEvaluator::new();
 
         evaluator.const_visit(base).unwrap();
 
-        assert_eq!(evaluator.boolean_stack().pop().unwrap(), true);
+        assert!(evaluator.boolean_stack().pop().unwrap());
     }
 
     #[test]
@@ -812,18 +2715,11 @@ mod tests {
         base.add_child(equal_2.clone());
         base.add_child(or);
 
-        let evaluator = ExpressionEvaluator {
-            variables: Mutex::new(Vec::new()),
-            digit_stack: Mutex::new(Vec::new()),
-            boolean_stack: Mutex::new(Vec::new()),
-            is_lhs_variable: Mutex::new(false),
-            lhs_variable: Mutex::new(None),
-            current_event: None,
-        };
+        let evaluator = ExpressionEvaluator::new();
 
         evaluator.const_visit(base).unwrap();
 
-        assert_eq!(evaluator.boolean_stack().pop().unwrap(), true);
+        assert!(evaluator.boolean_stack().pop().unwrap());
     }
 
     #[test]
@@ -843,16 +2739,9 @@ mod tests {
         base.add_child(equal.clone());
         base.add_child(not.clone());
 
-        let evaluator = ExpressionEvaluator {
-            variables: Mutex::new(Vec::new()),
-            digit_stack: Mutex::new(Vec::new()),
-            boolean_stack: Mutex::new(Vec::new()),
-            is_lhs_variable: Mutex::new(false),
-            lhs_variable: Mutex::new(None),
-            current_event: None,
-        };
+        let evaluator = ExpressionEvaluator::new();
         evaluator.const_visit(base).unwrap();
-        assert_eq!(evaluator.boolean_stack().pop().unwrap(), false);
+        assert!(!evaluator.boolean_stack().pop().unwrap());
     }
 
     #[test]
@@ -887,14 +2776,7 @@ mod tests {
         base.add_child(assing_x);
         base.add_child(if_node);
 
-        let evaluator = ExpressionEvaluator {
-            variables: Mutex::new(vec![Value::Null]),
-            digit_stack: Mutex::new(Vec::new()),
-            boolean_stack: Mutex::new(Vec::new()),
-            is_lhs_variable: Mutex::new(false),
-            lhs_variable: Mutex::new(None),
-            current_event: None,
-        };
+        let evaluator = ExpressionEvaluator::new().with_variables(1);
 
         evaluator.const_visit(base).unwrap();
         assert_eq!(evaluator.variables().pop().unwrap(), Value::Number(2.0));
@@ -904,56 +2786,267 @@ mod tests {
     fn test_if_new_variable() {
         let base = Box::new(Node::Base(vec![
             Box::new(Node::Assign(vec![
-                Box::new(Node::Variable(Vec::new(), "x".to_string(), 0.into())),
-                Box::new(Node::Constant(2.0)),
+                Box::new(Node::Variable(Vec::new(), "x".to_string(), 0.into(), 0)),
+                Box::new(Node::Number(2.0)),
             ])),
             Box::new(Node::If(
                 vec![
                     Box::new(Node::Equal(vec![
-                        Box::new(Node::Variable(Vec::new(), "x".to_string(), 0.into())),
-                        Box::new(Node::Constant(1.0)),
+                        Box::new(Node::Variable(Vec::new(), "x".to_string(), 0.into(), 0)),
+                        Box::new(Node::Number(1.0)),
                     ])),
                     Box::new(Node::Assign(vec![
-                        Box::new(Node::Variable(Vec::new(), "z".to_string(), 1.into())),
-                        Box::new(Node::Constant(3.0)),
+                        Box::new(Node::Variable(Vec::new(), "z".to_string(), 1.into(), 0)),
+                        Box::new(Node::Number(3.0)),
                     ])),
                     Box::new(Node::Assign(vec![
-                        Box::new(Node::Variable(Vec::new(), "w".to_string(), 2.into())),
-                        Box::new(Node::Constant(4.0)),
+                        Box::new(Node::Variable(Vec::new(), "w".to_string(), 2.into(), 0)),
+                        Box::new(Node::Number(4.0)),
                     ])),
                 ],
                 None,
+                0,
             )),
         ]));
 
-        let evaluator = ExpressionEvaluator {
-            variables: Mutex::new(vec![Value::Null, Value::Null, Value::Null]),
-            digit_stack: Mutex::new(Vec::new()),
-            boolean_stack: Mutex::new(Vec::new()),
-            is_lhs_variable: Mutex::new(false),
-            lhs_variable: Mutex::new(None),
-            current_event: None,
-        };
+        let evaluator = ExpressionEvaluator::new().with_variables(3);
         evaluator.const_visit(base).unwrap();
 
-        assert_eq!(evaluator.variables().get(0).unwrap(), &Value::Number(2.0));
+        assert_eq!(evaluator.variables().first().unwrap(), &Value::Number(2.0));
         assert_eq!(evaluator.variables().get(1).unwrap(), &Value::Null);
         assert_eq!(evaluator.variables().get(2).unwrap(), &Value::Null);
     }
+
+    #[test]
+    fn test_if_propagates_body_error() {
+        let base = Box::new(Node::Base(vec![
+            Box::new(Node::If(
+                vec![
+                    Box::new(Node::True),
+                    Box::new(Node::Assign(vec![
+                        Box::new(Node::Variable(Vec::new(), "x".to_string(), 0.into(), 0)),
+                        Box::new(Node::Variable(Vec::new(), "y".to_string(), 1.into(), 0)),
+                    ])),
+                ],
+                None,
+                0,
+            )),
+            Box::new(Node::Assign(vec![
+                Box::new(Node::Variable(Vec::new(), "z".to_string(), 2.into(), 0)),
+                Box::new(Node::Number(1.0)),
+            ])),
+        ]));
+
+        let evaluator = ExpressionEvaluator::new().with_variables(3);
+        assert!(evaluator.const_visit(base).is_err());
+        // Execution stops at the failing statement
+        assert_eq!(evaluator.variables().get(2).unwrap(), &Value::Null);
+    }
+
+    #[test]
+    fn test_if_propagates_else_error() {
+        let base = Box::new(Node::If(
+            vec![
+                Box::new(Node::False),
+                Box::new(Node::Assign(vec![
+                    Box::new(Node::Variable(Vec::new(), "x".to_string(), 0.into(), 0)),
+                    Box::new(Node::Number(1.0)),
+                ])),
+                Box::new(Node::Assign(vec![
+                    Box::new(Node::Variable(Vec::new(), "x".to_string(), 0.into(), 0)),
+                    Box::new(Node::Variable(Vec::new(), "y".to_string(), 1.into(), 0)),
+                ])),
+            ],
+            Some(2),
+            0,
+        ));
+
+        let evaluator = ExpressionEvaluator::new().with_variables(2);
+        assert!(evaluator.const_visit(base).is_err());
+        assert_eq!(evaluator.variables().first().unwrap(), &Value::Null);
+    }
+
+    #[test]
+    fn test_if_non_boolean_condition() {
+        let base = Box::new(Node::If(
+            vec![Box::new(Node::Number(1.0)), Box::new(Node::True)],
+            None,
+            0,
+        ));
+
+        let evaluator = ExpressionEvaluator::new();
+        assert!(evaluator.const_visit(base).is_err());
+    }
 }
 
 #[cfg(test)]
 mod script_tests {
     use crate::{
         nodes::{
-            expressionevaluator::Value,
+            expressionevaluator::{RunResult, RunStatus, Value},
             expressionindexer::ExpressionIndexer,
             traits::{NodeConstVisitor, NodeVisitor},
         },
         parsers::{lexer::Lexer, parser::Parser},
+        providers::{
+            calendar::{BusinessCalendar, MarketSession},
+            clock::FixedClock,
+            fx::InMemoryFxRates,
+            history::InMemoryPriceHistory,
+            observations::{InMemoryObservations, ObservationStore},
+        },
+        utils::{
+            decimal::RoundingMode,
+            errors::{Result, ScriptingError},
+            time::Duration,
+        },
     };
 
-    use super::ExpressionEvaluator;
+    use std::sync::{Arc, Mutex};
+
+    use super::{ExpressionEvaluator, HostFunction};
+
+    // Parses, indexes and runs a script with the providers and host functions
+    // a test sets up, e.g. `Script::new().at("2024-05-31T10:00").run(script)`
+    #[derive(Default)]
+    struct Script {
+        clock: Option<FixedClock>,
+        rates: Option<InMemoryFxRates>,
+        calendar: Option<(BusinessCalendar, MarketSession)>,
+        history: Option<InMemoryPriceHistory>,
+        observations: Option<Arc<InMemoryObservations>>,
+        hosts: Vec<(&'static str, HostFunction)>,
+        decimals: Option<(u32, RoundingMode)>,
+        max_call_depth: Option<usize>,
+    }
+
+    impl Script {
+        fn new() -> Self {
+            Self::default()
+        }
+
+        fn at(mut self, now: &str) -> Self {
+            self.clock = Some(FixedClock(now.parse().unwrap()));
+            self
+        }
+
+        // USD to EUR and CLP rates
+        fn with_rates(mut self) -> Self {
+            self.rates = Some(
+                InMemoryFxRates::new()
+                    .with_rate("USD", "EUR", "0.92".parse().unwrap())
+                    .with_rate("USD", "CLP", "950".parse().unwrap()),
+            );
+            self
+        }
+
+        // The NYSE calendar, closed on Memorial Day 2024, and its market hours
+        fn with_calendar(mut self) -> Self {
+            let calendar = BusinessCalendar::parse("NYSE", "2024-05-27 Memorial Day").unwrap();
+            let session = MarketSession::new(calendar.clone(), (9, 30), (16, 0)).unwrap();
+            self.calendar = Some((calendar, session));
+            self
+        }
+
+        // 30 closing prices of XYZ, and the last 5 of UP
+        fn with_history(mut self) -> Self {
+            let closes = vec![
+                22.27, 22.19, 22.08, 22.17, 22.18, 22.13, 22.23, 22.43, 22.24, 22.29, 22.15, 22.39,
+                22.38, 22.61, 23.36, 24.05, 23.75, 23.83, 23.95, 23.63, 23.82, 23.87, 23.65, 23.19,
+                23.10, 23.33, 22.68, 23.10, 22.40, 22.17,
+            ];
+            self.history = Some(
+                InMemoryPriceHistory::new()
+                    .with_prices("XYZ", closes)
+                    .with_prices("UP", vec![10.0, 10.0, 10.0, 9.0, 12.0]),
+            );
+            self
+        }
+
+        fn with_observations(mut self, store: &Arc<InMemoryObservations>) -> Self {
+            self.observations = Some(store.clone());
+            self
+        }
+
+        fn with_host(
+            mut self,
+            name: &'static str,
+            function: impl Fn(&[Value]) -> Result<Value> + Send + Sync + 'static,
+        ) -> Self {
+            self.hosts.push((name, Box::new(function)));
+            self
+        }
+
+        fn with_decimals(mut self, scale: u32, rounding: RoundingMode) -> Self {
+            self.decimals = Some((scale, rounding));
+            self
+        }
+
+        fn with_max_call_depth(mut self, depth: usize) -> Self {
+            self.max_call_depth = Some(depth);
+            self
+        }
+
+        // The indexer, the evaluator and how the run ended, a parsing error
+        // included
+        fn run(self, script: &str) -> (ExpressionIndexer, ExpressionEvaluator, Result<RunResult>) {
+            let names: Vec<&str> = self.hosts.iter().map(|(name, _)| *name).collect();
+            let nodes = Lexer::new(script.to_string())
+                .tokenize()
+                .and_then(|tokens| Parser::new(tokens).with_host_functions(&names).parse());
+            let indexer = ExpressionIndexer::new();
+            if let Ok(nodes) = &nodes {
+                indexer.visit(nodes);
+            }
+
+            let mut evaluator = ExpressionEvaluator::new().with_variables(indexer.get_size());
+            if let Some(clock) = self.clock {
+                evaluator = evaluator.with_clock(clock);
+            }
+            if let Some(rates) = self.rates {
+                evaluator = evaluator.with_fx_rates(rates);
+            }
+            if let Some((calendar, session)) = self.calendar {
+                evaluator = evaluator
+                    .with_calendar(calendar)
+                    .with_market("NYSE", session);
+            }
+            if let Some(history) = self.history {
+                evaluator = evaluator.with_price_history(history);
+            }
+            if let Some(store) = self.observations {
+                evaluator = evaluator.with_observations(store);
+            }
+            if let Some((scale, rounding)) = self.decimals {
+                evaluator = evaluator.with_decimals(scale, rounding);
+            }
+            if let Some(depth) = self.max_call_depth {
+                evaluator = evaluator.with_max_call_depth(depth);
+            }
+            for (name, function) in self.hosts {
+                evaluator = evaluator.with_host_function(name, function);
+            }
+
+            let result = nodes.and_then(|nodes| evaluator.run(nodes));
+            (indexer, evaluator, result)
+        }
+
+        // Same as `run`, for a script expected to run without error
+        fn run_ok(self, script: &str) -> (ExpressionIndexer, ExpressionEvaluator, RunResult) {
+            let (indexer, evaluator, result) = self.run(script);
+            (indexer, evaluator, result.unwrap())
+        }
+
+        fn evaluate(self, script: &str) -> Result<(ExpressionIndexer, ExpressionEvaluator)> {
+            let (indexer, evaluator, result) = self.run(script);
+            result.map(|_| (indexer, evaluator))
+        }
+
+        // The message of the error a script is expected to fail with
+        fn error(self, script: &str) -> String {
+            self.run(script).2.unwrap_err().to_string()
+        }
+    }
 
     #[test]
     fn test_simple_adding_script() {
@@ -973,7 +3066,7 @@ mod script_tests {
         let evaluator = ExpressionEvaluator::new().with_variables(indexer.get_size());
         evaluator.const_visit(nodes).unwrap();
 
-        assert_eq!(*evaluator.variables().get(0).unwrap(), Value::Number(1.0));
+        assert_eq!(*evaluator.variables().first().unwrap(), Value::Number(1.0));
     }
 
     #[test]
@@ -997,7 +3090,7 @@ mod script_tests {
         let evaluator = ExpressionEvaluator::new().with_variables(indexer.get_size());
         evaluator.const_visit(nodes).unwrap();
 
-        assert_eq!(*evaluator.variables().get(0).unwrap(), Value::Number(2.0));
+        assert_eq!(*evaluator.variables().first().unwrap(), Value::Number(2.0));
         assert_eq!(*evaluator.variables().get(1).unwrap(), Value::Number(2.0));
         assert_eq!(*evaluator.variables().get(2).unwrap(), Value::Number(4.0));
     }
@@ -1025,7 +3118,7 @@ mod script_tests {
         let evaluator = ExpressionEvaluator::new().with_variables(indexer.get_size());
         evaluator.const_visit(nodes).unwrap();
 
-        assert_eq!(*evaluator.variables().get(0).unwrap(), Value::Number(2.0));
+        assert_eq!(*evaluator.variables().first().unwrap(), Value::Number(2.0));
         assert_eq!(*evaluator.variables().get(1).unwrap(), Value::Number(2.0));
         assert_eq!(*evaluator.variables().get(2).unwrap(), Value::Number(4.0));
     }
@@ -1057,7 +3150,7 @@ mod script_tests {
         let evaluator = ExpressionEvaluator::new().with_variables(indexer.get_size());
         evaluator.const_visit(nodes).unwrap();
 
-        assert_eq!(*evaluator.variables().get(0).unwrap(), Value::Number(2.0));
+        assert_eq!(*evaluator.variables().first().unwrap(), Value::Number(2.0));
         assert_eq!(*evaluator.variables().get(1).unwrap(), Value::Number(2.0));
         assert_eq!(*evaluator.variables().get(2).unwrap(), Value::Number(5.0));
     }
@@ -1084,7 +3177,7 @@ mod script_tests {
         let evaluator = ExpressionEvaluator::new().with_variables(indexer.get_size());
         evaluator.const_visit(nodes).unwrap();
 
-        assert_eq!(*evaluator.variables().get(0).unwrap(), Value::Number(2.0));
+        assert_eq!(*evaluator.variables().first().unwrap(), Value::Number(2.0));
         assert_eq!(*evaluator.variables().get(1).unwrap(), Value::Number(2.0));
         assert_eq!(*evaluator.variables().get(2).unwrap(), Value::Number(4.0));
         assert_eq!(*evaluator.variables().get(3).unwrap(), Value::Null);
@@ -1109,7 +3202,7 @@ mod script_tests {
         let evaluator = ExpressionEvaluator::new().with_variables(indexer.get_size());
         evaluator.const_visit(nodes).unwrap();
 
-        assert_eq!(*evaluator.variables().get(0).unwrap(), Value::Number(2.0));
+        assert_eq!(*evaluator.variables().first().unwrap(), Value::Number(2.0));
         assert_eq!(*evaluator.variables().get(1).unwrap(), Value::Number(2.0));
         assert_eq!(*evaluator.variables().get(2).unwrap(), Value::Number(3.0));
         assert_eq!(*evaluator.variables().get(3).unwrap(), Value::Number(4.0));
@@ -1142,8 +3235,1435 @@ mod script_tests {
         let evaluator = ExpressionEvaluator::new().with_variables(indexer.get_size());
         evaluator.const_visit(nodes).unwrap();
 
-        assert_eq!(*evaluator.variables().get(0).unwrap(), Value::Number(2.0));
+        assert_eq!(*evaluator.variables().first().unwrap(), Value::Number(2.0));
         assert_eq!(*evaluator.variables().get(1).unwrap(), Value::Number(2.0));
         assert_eq!(*evaluator.variables().get(2).unwrap(), Value::Number(5.0));
     }
+
+    #[test]
+    fn test_else_skips_then_block() {
+        let script = "
+            x = 2;
+            if x == 1 then
+                y = 1;
+            else
+                z = 1;
+            end
+        ";
+        let (_, evaluator, _) = Script::new().run_ok(script);
+
+        assert_eq!(*evaluator.variables().get(1).unwrap(), Value::Null);
+        assert_eq!(*evaluator.variables().get(2).unwrap(), Value::Number(1.0));
+    }
+
+    #[test]
+    fn test_error_in_if_body_script() {
+        let script = "
+            x = 2;
+            if x == 2 then
+                z = y + 1;
+            end
+            w = 1;
+        ";
+        let (_, evaluator, result) = Script::new().run(script);
+        assert!(result.is_err());
+        assert_eq!(*evaluator.variables().get(3).unwrap(), Value::Null);
+    }
+
+    #[test]
+    fn test_single_line_script() {
+        // Same shape as the `expression` field of the JSON rules
+        let script = "spot = 120; if spot > 100 then units = 10; else units = 0; end";
+        let (_, evaluator, _) = Script::new().run_ok(script);
+
+        assert_eq!(*evaluator.variables().get(1).unwrap(), Value::Number(10.0));
+    }
+
+    // The `expression` field of a JSON rule, with its quotes unescaped
+    fn json_expression(rule: &str) -> String {
+        let start = rule.find("\"expression\": \"").unwrap() + "\"expression\": \"".len();
+        let mut expression = String::new();
+        let mut chars = rule[start..].chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => break,
+                '\\' => expression.extend(chars.next()),
+                c => expression.push(c),
+            }
+        }
+        expression
+    }
+
+    #[test]
+    fn test_json_example_script() {
+        let script = json_expression(include_str!("../../examples/simple.json"));
+        assert_eq!(
+            script,
+            "spot = Spot(\"AAPL\"); if spot > 100 then Buy(spot); end"
+        );
+
+        let bought = Arc::new(Mutex::new(Vec::new()));
+        let orders = bought.clone();
+        let (indexer, evaluator, result) = Script::new()
+            .with_host("Spot", |_| Ok(Value::Number(120.0)))
+            .with_host("Buy", move |args| {
+                orders.lock().unwrap().extend(args.iter().cloned());
+                Ok(Value::Bool(true))
+            })
+            .run_ok(&script);
+        assert_eq!(result.status, RunStatus::Completed);
+
+        let spot = indexer.get_index("spot").unwrap();
+        assert_eq!(evaluator.variables()[spot], Value::Number(120.0));
+        assert_eq!(*bought.lock().unwrap(), vec![Value::Number(120.0)]);
+    }
+
+    #[test]
+    fn test_brace_block_script() {
+        let (_, _, result) =
+            Script::new().run("spot = 80 if spot > 100 { units = 10 } else { units = 0 }");
+        assert!(result.is_err());
+
+        let (_, evaluator, _) =
+            Script::new().run_ok("spot = 80; if spot > 100 { units = 10 } else { units = 0 }");
+
+        assert_eq!(*evaluator.variables().get(1).unwrap(), Value::Number(0.0));
+    }
+
+    #[test]
+    fn test_compound_assignment_script() {
+        let script = "
+            total = 100
+            fee = 2.5
+            total += fee
+            total -= 0.5
+            total *= 2
+            total /= 4
+        ";
+        let (indexer, evaluator, _) = Script::new().run_ok(script);
+        assert_eq!(indexer.get_size(), 2);
+
+        assert_eq!(*evaluator.variables().first().unwrap(), Value::Number(51.0));
+    }
+
+    #[test]
+    fn test_compound_assignment_uninitialized_target() {
+        let error = Script::new().error("total += 1");
+        assert!(error.contains("total not initialized"));
+    }
+
+    #[test]
+    fn test_function_call_script() {
+        let (indexer, evaluator) = Script::new()
+            .evaluate(
+                "
+            limit = 95
+            function stop_loss(price, cost) {
+                loss = (cost - price) / cost
+                return loss > 0.05 or price < limit
+            }
+            sell = stop_loss(90, 100)
+            keep = stop_loss(99, 100)
+            ",
+            )
+            .unwrap();
+
+        let sell = indexer.get_index("sell").unwrap();
+        let keep = indexer.get_index("keep").unwrap();
+        assert_eq!(evaluator.variables()[sell], Value::Bool(true));
+        assert_eq!(evaluator.variables()[keep], Value::Bool(false));
+        // `loss` only lives in the frame of the call
+        assert_eq!(indexer.get_index("loss"), None);
+    }
+
+    #[test]
+    fn test_function_locals_do_not_touch_globals() {
+        let (indexer, evaluator) = Script::new()
+            .evaluate(
+                "
+            x = 1
+            function f(y) {
+                x = y * 2
+                return x
+            }
+            z = f(5)
+            ",
+            )
+            .unwrap();
+
+        assert_eq!(
+            evaluator.variables()[indexer.get_index("x").unwrap()],
+            Value::Number(1.0)
+        );
+        assert_eq!(
+            evaluator.variables()[indexer.get_index("z").unwrap()],
+            Value::Number(10.0)
+        );
+    }
+
+    #[test]
+    fn test_recursive_function() {
+        let (indexer, evaluator) = Script::new()
+            .evaluate(
+                "
+            function factorial(n) {
+                if n <= 1 { return 1 }
+                return n * factorial(n - 1)
+            }
+            x = factorial(10)
+            ",
+            )
+            .unwrap();
+
+        assert_eq!(
+            evaluator.variables()[indexer.get_index("x").unwrap()],
+            Value::Number(3_628_800.0)
+        );
+    }
+
+    #[test]
+    fn test_return_ends_the_call() {
+        let (indexer, evaluator) = Script::new()
+            .evaluate(
+                "
+            function signum(x) {
+                if x < 0 then
+                    return -1
+                end
+                if x == 0 then return 0 end
+                return 1
+            }
+            a = signum(-5)
+            b = signum(0)
+            c = signum(3)
+            ",
+            )
+            .unwrap();
+
+        let value = |name| evaluator.variables()[indexer.get_index(name).unwrap()].clone();
+        assert_eq!(value("a"), Value::Number(-1.0));
+        assert_eq!(value("b"), Value::Number(0.0));
+        assert_eq!(value("c"), Value::Number(1.0));
+    }
+
+    #[test]
+    fn test_recursion_depth_limit() {
+        let script = "
+            function forever(n) { return forever(n + 1) }
+            x = forever(0)
+        ";
+        let error = Script::new().with_max_call_depth(16).error(script);
+        assert!(error.contains("Maximum call depth of 16"));
+    }
+
+    #[test]
+    fn test_error_inside_function() {
+        let script = "
+            function f(a) { return a + missing }
+            x = f(1)
+        ";
+        let error = Script::new().error(script);
+        assert!(error.contains("missing not initialized"));
+    }
+
+    #[test]
+    fn test_local_variables_script() {
+        let (indexer, evaluator) = Script::new()
+            .evaluate(
+                "
+            x = 1
+            if x > 0 then
+                local x = x + 10
+                y = x
+            end
+            z = x
+            ",
+            )
+            .unwrap();
+
+        let value = |name| evaluator.variables()[indexer.get_index(name).unwrap()].clone();
+        assert_eq!(value("x"), Value::Number(1.0));
+        assert_eq!(value("y"), Value::Number(11.0));
+        assert_eq!(value("z"), Value::Number(1.0));
+    }
+
+    #[test]
+    fn test_run_completed() {
+        let (_, _, result) = Script::new().run_ok("x = 1\ny = x + 1");
+        assert_eq!(
+            result,
+            RunResult {
+                status: RunStatus::Completed,
+                line: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_top_level_return() {
+        let (indexer, evaluator, result) = Script::new().run_ok(
+            "spot = 90
+            if spot < 100 then
+                return spot
+            end
+            bought = true",
+        );
+
+        assert_eq!(
+            result,
+            RunResult {
+                status: RunStatus::Returned(Value::Number(90.0)),
+                line: Some(3),
+            }
+        );
+        let bought = indexer.get_index("bought").unwrap();
+        assert_eq!(evaluator.variables()[bought], Value::Null);
+
+        let (_, _, result) = Script::new().run_ok("return\nx = 1");
+        assert_eq!(result.status, RunStatus::Returned(Value::Null));
+    }
+
+    #[test]
+    fn test_exit_from_function() {
+        let (indexer, evaluator, result) = Script::new().run_ok(
+            "function check(price) {
+                if price > 100 { exit }
+                return price
+            }
+            a = 1 + check(50)
+            b = 1 + check(150)
+            c = 3",
+        );
+
+        assert_eq!(
+            result,
+            RunResult {
+                status: RunStatus::Exited,
+                line: Some(2),
+            }
+        );
+        let value = |name| evaluator.variables()[indexer.get_index(name).unwrap()].clone();
+        assert_eq!(value("a"), Value::Number(51.0));
+        // The assignment that called `exit` does not complete
+        assert_eq!(value("b"), Value::Null);
+        assert_eq!(value("c"), Value::Null);
+        assert!(evaluator.stack().is_empty());
+    }
+
+    #[test]
+    fn test_abort() {
+        let (indexer, evaluator, result) = Script::new().run_ok(
+            "balance = 50
+            if balance < 100 {
+                abort(\"Insufficient balance\")
+            }
+            sent = balance",
+        );
+
+        assert_eq!(
+            result,
+            RunResult {
+                status: RunStatus::Aborted("Insufficient balance".to_string()),
+                line: Some(3),
+            }
+        );
+        let sent = indexer.get_index("sent").unwrap();
+        assert_eq!(evaluator.variables()[sent], Value::Null);
+    }
+
+    #[test]
+    fn test_abort_requires_string_reason() {
+        let error = Script::new().error("abort(1 + 2)");
+        assert!(error.contains("Expected a string reason"));
+    }
+
+    // A `Sell` host function that rejects orders above 100 units, and a
+    // `Notify` one accepting any message
+    fn broker() -> Script {
+        Script::new()
+            .with_host("Sell", |args| match args {
+                [units] if units.as_number() > Some(100.0) => Err(ScriptingError::HostError(
+                    "insufficient_shares".to_string(),
+                    format!("Cannot sell {} units", units),
+                )),
+                [units] if units.as_number().is_some() => Ok(Value::Bool(true)),
+                _ => Err(ScriptingError::HostError(
+                    "arguments".to_string(),
+                    "Sell expects a number of units".to_string(),
+                )),
+            })
+            .with_host("Notify", |_| Ok(Value::Bool(true)))
+    }
+
+    #[test]
+    fn test_host_function_call() {
+        let (indexer, evaluator, result) = broker().run("sold = Sell(10)");
+        assert_eq!(result.unwrap().status, RunStatus::Completed);
+        let sold = indexer.get_index("sold").unwrap();
+        assert_eq!(evaluator.variables()[sold], Value::Bool(true));
+
+        let (_, _, result) = broker().run("sold = Sell(150)");
+        assert!(matches!(
+            result,
+            Err(ScriptingError::HostError(kind, _)) if kind == "insufficient_shares"
+        ));
+    }
+
+    #[test]
+    fn test_catch_host_error() {
+        let (indexer, evaluator, result) = broker().run(
+            "units = 150
+            try
+                Sell(units)
+                sold = units
+            catch err
+                kind = err.kind
+                message = err.message
+                units = units / 2
+                Sell(units)
+                sold = units
+            end",
+        );
+
+        assert_eq!(result.unwrap().status, RunStatus::Completed);
+        let value = |name| evaluator.variables()[indexer.get_index(name).unwrap()].clone();
+        assert_eq!(value("sold"), Value::Number(75.0));
+        assert_eq!(
+            value("kind"),
+            Value::String("insufficient_shares".to_string())
+        );
+        assert_eq!(
+            value("message"),
+            Value::String("Cannot sell 150 units".to_string())
+        );
+        // The error variable is local to the catch block
+        assert_eq!(indexer.get_index("err"), None);
+    }
+
+    #[test]
+    fn test_catch_runtime_error() {
+        let (indexer, evaluator, result) = broker().run(
+            "function ratio(a, b) { return a / (b > 0) }
+            try {
+                x = ratio(1, 2)
+            } catch err {
+                kind = err.kind
+            }
+            y = 1",
+        );
+
+        assert_eq!(result.unwrap().status, RunStatus::Completed);
+        let value = |name| evaluator.variables()[indexer.get_index(name).unwrap()].clone();
+        assert_eq!(value("kind"), Value::String("runtime".to_string()));
+        assert_eq!(value("x"), Value::Null);
+        assert_eq!(value("y"), Value::Number(1.0));
+        assert!(evaluator.stack().is_empty());
+    }
+
+    #[test]
+    fn test_try_without_error() {
+        let (indexer, evaluator, result) =
+            broker().run("try { sold = Sell(1) } catch { sold = false }");
+        assert_eq!(result.unwrap().status, RunStatus::Completed);
+        let sold = indexer.get_index("sold").unwrap();
+        assert_eq!(evaluator.variables()[sold], Value::Bool(true));
+    }
+
+    #[test]
+    fn test_error_in_catch_block() {
+        let (_, _, result) = broker().run("try { Sell(500) } catch err { Sell(err) }");
+        assert!(matches!(
+            result,
+            Err(ScriptingError::HostError(kind, _)) if kind == "arguments"
+        ));
+    }
+
+    #[test]
+    fn test_abort_is_not_caught() {
+        let (_, _, result) = broker().run("try { abort(\"stop\") } catch { x = 1 }");
+        assert_eq!(
+            result.unwrap().status,
+            RunStatus::Aborted("stop".to_string())
+        );
+    }
+
+    #[test]
+    fn test_require() {
+        let script = "balance = 50
+            require(balance >= 100, \"Insufficient balance\")
+            sent = Sell(balance)";
+
+        let (indexer, evaluator, result) = broker().run(script);
+        assert_eq!(
+            result.unwrap(),
+            RunResult {
+                status: RunStatus::Rejected("Insufficient balance".to_string()),
+                line: Some(2),
+            }
+        );
+        let sent = indexer.get_index("sent").unwrap();
+        assert_eq!(evaluator.variables()[sent], Value::Null);
+
+        let (_, _, result) = broker().run("require(1 < 2, \"never\"); x = 1");
+        assert_eq!(result.unwrap().status, RunStatus::Completed);
+    }
+
+    #[test]
+    fn test_assert() {
+        let (_, _, result) = broker().run("x = 1\nassert(x == 2, \"x must be 2\")");
+        assert_eq!(
+            result.unwrap(),
+            RunResult {
+                status: RunStatus::Aborted("x must be 2".to_string()),
+                line: Some(2),
+            }
+        );
+    }
+
+    #[test]
+    fn test_require_notifies() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let notifications = sent.clone();
+
+        let (_, _, result) = Script::new()
+            .with_host("Notify", move |args| {
+                notifications.lock().unwrap().push(args.to_vec());
+                Ok(Value::Bool(true))
+            })
+            .run_ok("require(false, \"No cash\", true)");
+        assert_eq!(result.status, RunStatus::Rejected("No cash".to_string()));
+        assert_eq!(
+            *sent.lock().unwrap(),
+            vec![vec![Value::String("No cash".to_string())]]
+        );
+    }
+
+    #[test]
+    fn test_require_inside_function() {
+        let (_, _, result) = broker().run(
+            "function check(units) {
+                require(units <= 100, \"Order too large\")
+                return units
+            }
+            x = check(10) + check(500)
+            y = 1",
+        );
+        assert_eq!(
+            result.unwrap(),
+            RunResult {
+                status: RunStatus::Rejected("Order too large".to_string()),
+                line: Some(2),
+            }
+        );
+    }
+
+    #[test]
+    fn test_list_operations() {
+        let (indexer, evaluator, result) = Script::new().run_ok(
+            "symbols = [\"AAPL\", \"MSFT\", \"GOOG\"]
+            first = symbols[0]
+            last = symbols[-1]
+            middle = symbols[1:-1]
+            head = symbols[:2]
+            tail = symbols[1:]
+            empty = symbols[3:]
+            symbols = append(symbols, \"TSLA\")
+            count = len(symbols)
+            chars = len(first)
+            same = [1, [2]] == [1, [2]]",
+        );
+
+        assert_eq!(result.status, RunStatus::Completed);
+        let value = |name| evaluator.variables()[indexer.get_index(name).unwrap()].clone();
+        let strings = |items: &[&str]| {
+            Value::List(
+                items
+                    .iter()
+                    .map(|item| Value::String(item.to_string()))
+                    .collect(),
+            )
+        };
+        assert_eq!(value("first"), Value::String("AAPL".to_string()));
+        assert_eq!(value("last"), Value::String("GOOG".to_string()));
+        assert_eq!(value("middle"), strings(&["MSFT"]));
+        assert_eq!(value("head"), strings(&["AAPL", "MSFT"]));
+        assert_eq!(value("tail"), strings(&["MSFT", "GOOG"]));
+        assert_eq!(value("empty"), strings(&[]));
+        assert_eq!(value("symbols"), strings(&["AAPL", "MSFT", "GOOG", "TSLA"]));
+        assert_eq!(value("count"), Value::Number(4.0));
+        assert_eq!(value("chars"), Value::Number(4.0));
+        assert_eq!(value("same"), Value::Bool(true));
+    }
+
+    #[test]
+    fn test_list_bounds() {
+        let error = |script: &str| Script::new().error(script);
+
+        assert!(error("xs = [1, 2]; y = xs[2]").contains("Index 2 out of bounds"));
+        assert!(error("xs = [1, 2]; y = xs[-3]").contains("Index -3 out of bounds"));
+        assert!(error("xs = [1, 2]; y = xs[0.5]").contains("must be an integer"));
+        assert!(error("xs = [1, 2]; y = xs[0:3]").contains("out of bounds"));
+        assert!(error("xs = 1; y = xs[0]").contains("Expected a list"));
+        assert!(error("for x in 3 do y = x end").contains("Expected a list"));
+    }
+
+    #[test]
+    fn test_for_loop_script() {
+        let (indexer, evaluator, result) = Script::new().run_ok(
+            "total = 0
+            visited = 0
+            for price in [10, 20, -1, 30, 1000, 40] do
+                if price > 100 then break end
+                visited += 1
+                if price < 0 { continue }
+                total += price
+            end",
+        );
+
+        assert_eq!(result.status, RunStatus::Completed);
+        let value = |name| evaluator.variables()[indexer.get_index(name).unwrap()].clone();
+        assert_eq!(value("total"), Value::Number(60.0));
+        assert_eq!(value("visited"), Value::Number(4.0));
+        // The loop variable is local to the body
+        assert_eq!(indexer.get_index("price"), None);
+    }
+
+    #[test]
+    fn test_return_from_loop() {
+        let (indexer, evaluator, result) = Script::new().run_ok(
+            "function find(xs, target) {
+                i = 0
+                for x in xs {
+                    if x == target { return i }
+                    i += 1
+                }
+                return -1
+            }
+            a = find([5, 6, 7], 6)
+            b = find([5, 6, 7], 8)
+            for x in [1, 2] { for y in [3, 4] { exit } }
+            c = 1",
+        );
+
+        assert_eq!(result.status, RunStatus::Exited);
+        let value = |name| evaluator.variables()[indexer.get_index(name).unwrap()].clone();
+        assert_eq!(value("a"), Value::Number(1.0));
+        assert_eq!(value("b"), Value::Number(-1.0));
+        assert_eq!(value("c"), Value::Null);
+    }
+
+    #[test]
+    fn test_map_operations() {
+        let (indexer, evaluator, result) = Script::new().run_ok(
+            "pos = {units: 10, \"avg cost\": 9.5, symbol: \"AAPL\"}
+            units = pos.units
+            cost = pos[\"avg cost\"]
+            size = len(pos)
+            keys = []
+            for key in pos do keys = append(keys, key) end
+            total = 0
+            for key, value in {a: 1, b: 2} { total += value }
+            indexes = 0
+            for i, s in [\"x\", \"y\"] { indexes += i }
+            same = {a: [1]} == {a: [1]}
+            different = {a: 1} == {b: 1}",
+        );
+
+        assert_eq!(result.status, RunStatus::Completed);
+        let value = |name| evaluator.variables()[indexer.get_index(name).unwrap()].clone();
+        assert_eq!(value("units"), Value::Number(10.0));
+        assert_eq!(value("cost"), Value::Number(9.5));
+        assert_eq!(value("size"), Value::Number(3.0));
+        // Keys come in sorted order
+        assert_eq!(
+            value("keys"),
+            Value::List(vec![
+                Value::String("avg cost".to_string()),
+                Value::String("symbol".to_string()),
+                Value::String("units".to_string()),
+            ])
+        );
+        assert_eq!(value("total"), Value::Number(3.0));
+        assert_eq!(value("indexes"), Value::Number(1.0));
+        assert_eq!(value("same"), Value::Bool(true));
+        assert_eq!(value("different"), Value::Bool(false));
+    }
+
+    #[test]
+    fn test_missing_map_keys() {
+        let error = |script: &str| Script::new().error(script);
+
+        assert!(error("m = {a: 1}; x = m.b").contains("No field b"));
+        assert!(error("m = {a: 1}; x = m[\"b\"]").contains("Missing key b"));
+        assert!(error("m = {a: 1}; x = m[0]").contains("Cannot index"));
+        assert!(error("x = (1).a").contains("No field a"));
+    }
+
+    #[test]
+    fn test_host_function_returning_map() {
+        let (indexer, evaluator, _) = Script::new()
+            .with_host("Position", |_| {
+                Ok(Value::Map(
+                    [
+                        ("units".to_string(), Value::Number(25.0)),
+                        ("avg_cost".to_string(), Value::Number(180.0)),
+                        ("pnl".to_string(), Value::Number(-12.5)),
+                    ]
+                    .into_iter()
+                    .collect(),
+                ))
+            })
+            .run_ok(
+                "pos = Position(\"AAPL\")
+                if pos.pnl < 0 { units = pos.units } else { units = 0 }",
+            );
+
+        let units = indexer.get_index("units").unwrap();
+        assert_eq!(evaluator.variables()[units], Value::Number(25.0));
+    }
+
+    #[test]
+    fn test_null_values() {
+        let (indexer, evaluator, result) = Script::new().run_ok(
+            "price = null
+            missing = price == null
+            present = 1 != null
+            total = price * 10 + 1
+            smaller = min(price, 1)
+            negative = -price
+            above = price > 100
+            below = price <= 100
+            fallback = price ?? 42
+            kept = 7 ?? [1][5]
+            chained = null ?? price ?? 3
+            same = null == null",
+        );
+
+        assert_eq!(result.status, RunStatus::Completed);
+        let value = |name| evaluator.variables()[indexer.get_index(name).unwrap()].clone();
+        assert_eq!(value("price"), Value::Null);
+        assert_eq!(value("missing"), Value::Bool(true));
+        assert_eq!(value("present"), Value::Bool(true));
+        assert_eq!(value("total"), Value::Null);
+        assert_eq!(value("smaller"), Value::Null);
+        assert_eq!(value("negative"), Value::Null);
+        assert_eq!(value("above"), Value::Bool(false));
+        assert_eq!(value("below"), Value::Bool(false));
+        assert_eq!(value("fallback"), Value::Number(42.0));
+        // The default is not evaluated, `[1][5]` would fail
+        assert_eq!(value("kept"), Value::Number(7.0));
+        assert_eq!(value("chained"), Value::Number(3.0));
+        assert_eq!(value("same"), Value::Bool(true));
+    }
+
+    #[test]
+    fn test_null_errors() {
+        let error = |script: &str| Script::new().error(script);
+
+        // Null is not a condition, nor a list or a map
+        assert!(error("if null then x = 1 end").contains("Expected a boolean"));
+        assert!(error("x = not null").contains("Expected a boolean"));
+        assert!(error("x = null; y = x.units").contains("No field units"));
+        assert!(error("x = len(null)").contains("found Null"));
+        // An unassigned variable is still not null
+        assert!(error("local x; y = x ?? 1").contains("x not initialized"));
+    }
+
+    #[test]
+    fn test_host_function_returning_null() {
+        let (indexer, evaluator, _) = Script::new()
+            .with_host("Spot", |args| match args {
+                [Value::String(symbol)] if symbol == "AAPL" => Ok(Value::Number(180.0)),
+                _ => Ok(Value::Null),
+            })
+            .run_ok(
+                "price = Spot(\"AAPL\") ?? 0
+                unavailable = Spot(\"XYZ\") == null
+                if Spot(\"XYZ\") > 100 then sold = true else sold = false end",
+            );
+
+        let value = |name| evaluator.variables()[indexer.get_index(name).unwrap()].clone();
+        assert_eq!(value("price"), Value::Number(180.0));
+        assert_eq!(value("unavailable"), Value::Bool(true));
+        assert_eq!(value("sold"), Value::Bool(false));
+    }
+
+    #[test]
+    fn test_string_formatting() {
+        let (indexer, evaluator, result) = Script::new().run_ok(
+            "units = 100
+            symbol = \"AAPL\"
+            price = 152.3449
+            pnl = 0.0321
+            message = \"Sold {units} {symbol} at {format_currency(price, \"USD\")} (PnL {format(pnl, \"+.1%\")})\"
+            total = format(units * price, \",.2\")
+            rounded = round_to(price, 1)
+            listed = \"{[1, \"a\"]} {{b: null}} {true}\"
+            missing = format(null, \".2\") ?? \"n/a\"",
+        );
+
+        assert_eq!(result.status, RunStatus::Completed);
+        let value = |name| evaluator.variables()[indexer.get_index(name).unwrap()].clone();
+        assert_eq!(
+            value("message"),
+            Value::String("Sold 100 AAPL at $152.34 (PnL +3.2%)".to_string())
+        );
+        assert_eq!(value("total"), Value::String("15,234.49".to_string()));
+        assert_eq!(value("rounded"), Value::Number(152.3));
+        assert_eq!(
+            value("listed"),
+            Value::String("[1, \"a\"] {b: null} true".to_string())
+        );
+        assert_eq!(value("missing"), Value::String("n/a".to_string()));
+    }
+
+    #[test]
+    fn test_readme_interpolation() {
+        // The example of the Strings section, and the message it documents
+        let readme = include_str!("../../README.md");
+        let section = &readme[readme.find("### Strings").unwrap()..];
+        let example = section.split("```").nth(1).unwrap();
+        let mut lines = example.lines().skip(1);
+        let script = lines.next().unwrap();
+        let expected = lines.next().unwrap().trim_start_matches("-- ");
+
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let sent = messages.clone();
+        Script::new()
+            .with_host("Notify", move |args| {
+                sent.lock().unwrap().extend(args.iter().cloned());
+                Ok(Value::Bool(true))
+            })
+            .run_ok(&format!(
+                "units = 100; symbol = \"AAPL\"; price = 152.3449; pnl = 0.0321\n{}",
+                script
+            ));
+
+        assert_eq!(
+            *messages.lock().unwrap(),
+            vec![Value::String(expected.to_string())]
+        );
+    }
+
+    #[test]
+    fn test_formatting_errors() {
+        let error = |script: &str| Script::new().error(script);
+
+        assert!(error("x = format(1, \"abc\")").contains("Invalid format `abc`"));
+        assert!(error("x = format(1, 2)").contains("Expected a string"));
+        assert!(error("x = format(\"1\", \".2\")").contains("Expected a numeric"));
+        assert!(error("x = round_to(1, 0.5)").contains("Expected an integer"));
+        assert!(error("x = format_currency(1, \"USD\", -1)").contains("positive number"));
+    }
+
+    #[test]
+    fn test_decimal_arithmetic() {
+        let (indexer, evaluator, result) = Script::new().run_ok(
+            "exact = 0.1 + 0.2 == 0.3
+            total = 0.1 + 0.2
+            third = 1 / 3
+            price = 19.99 * 3
+            bankers = round_to(2.345, 2, \"half_even\")
+            half_up = round_to(2.345, 2)
+            floor = round_to(-2.341, 2, \"floor\")
+            float = exp(0) + 0.1
+            mixed = 0.5 < exp(0)",
+        );
+
+        assert_eq!(result.status, RunStatus::Completed);
+        let value = |name| evaluator.variables()[indexer.get_index(name).unwrap()].clone();
+        let decimal = |text: &str| Value::Decimal(text.parse().unwrap());
+        assert_eq!(value("exact"), Value::Bool(true));
+        assert_eq!(value("total"), decimal("0.3"));
+        assert_eq!(value("third"), decimal("0.3333333333"));
+        assert_eq!(value("price"), decimal("59.97"));
+        assert_eq!(value("bankers"), decimal("2.34"));
+        assert_eq!(value("half_up"), decimal("2.35"));
+        assert_eq!(value("floor"), decimal("-2.35"));
+        // Transcendental functions stay in floating point
+        assert!(matches!(value("float"), Value::Number(x) if (x - 1.1).abs() < 1e-12));
+        assert_eq!(value("mixed"), Value::Bool(true));
+    }
+
+    #[test]
+    fn test_decimal_scale() {
+        let run = |script: &str| {
+            Script::new()
+                .with_decimals(2, RoundingMode::HalfEven)
+                .run(script)
+        };
+
+        let (indexer, evaluator, result) = run("a = 2 / 3\nb = 1 / 8\nc = 1.50");
+        result.unwrap();
+        let value = |name| evaluator.variables()[indexer.get_index(name).unwrap()].clone();
+        let decimal = |text: &str| Value::Decimal(text.parse().unwrap());
+        assert_eq!(value("a"), decimal("0.67"));
+        assert_eq!(value("b"), decimal("0.12"));
+        assert_eq!(value("c"), decimal("1.5"));
+
+        // Literals are not rounded, and are rejected before anything runs
+        let (indexer, evaluator, result) = run("a = 1\nc = 1.005");
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("Number 1.005 has more than the 2 decimals"));
+        assert_eq!(
+            evaluator.variables()[indexer.get_index("a").unwrap()],
+            Value::Null
+        );
+        let (_, _, result) = run("c = 0.001 USD");
+        assert!(result.unwrap_err().to_string().contains("Number 0.001"));
+    }
+
+    #[test]
+    fn test_exact_literals() {
+        let (indexer, evaluator, result) = Script::new().run_ok(
+            "big = 12345678901234567.1
+            odd = 9007199254740993 + 0.0000000001
+            tiny = 0.0000000001 * 3
+            amount = 12345678901234567.89 USD + 0.01 USD
+            rate = 12.5%",
+        );
+
+        assert_eq!(result.status, RunStatus::Completed);
+        let value = |name| evaluator.variables()[indexer.get_index(name).unwrap()].clone();
+        let decimal = |text: &str| text.parse().unwrap();
+        assert_eq!(value("big"), Value::Decimal(decimal("12345678901234567.1")));
+        assert_eq!(
+            value("odd"),
+            Value::Decimal(decimal("9007199254740993.0000000001"))
+        );
+        assert_eq!(value("tiny"), Value::Decimal(decimal("0.0000000003")));
+        assert_eq!(
+            value("amount"),
+            Value::Money(decimal("12345678901234567.9"), "USD".to_string())
+        );
+        assert_eq!(value("rate"), Value::Decimal(decimal("0.125")));
+
+        // More decimals than the scale once written as a fraction
+        let error = |script: &str| Script::new().error(script);
+        assert!(error("x = 0.00000000001")
+            .contains("Number 0.00000000001 has more than the 10 decimals"));
+        assert!(error("x = 0.123456789012345678%").contains("more than the 10 decimals"));
+        assert!(error("x = 1e-29").contains("has more than 28 decimals"));
+        assert!(error("x = 1e40 USD").contains("is too large"));
+    }
+
+    #[test]
+    fn test_decimal_errors() {
+        let error = |script: &str| Script::new().error(script);
+
+        assert!(error("x = 1.5 / 0").contains("Division by zero"));
+        assert!(error("x = round_to(1.5, 0, \"nearest\")").contains("Unknown rounding mode"));
+    }
+
+    #[test]
+    fn test_math_builtins() {
+        let (indexer, evaluator, result) = Script::new().run_ok(
+            "magnitude = abs(-2.5)
+            direction = sign(-0.3)
+            floored = floor(-2.5)
+            ceiled = ceil(2.1)
+            cents = round(2.345, 2)
+            whole = round(2.5)
+            remainder = 7 % 3
+            wrapped = mod(-7, 3)
+            rate = 50% * 2
+            capped = clamp(150, 0, 100)
+            root = sqrt(16)
+            digits = log10(1000)
+            angle = cos(0)
+            picked = select(2 > 1, \"yes\", 1 / 0)
+            refund = abs(-12.5 USD)
+            missing = clamp(null, 0, 1)",
+        );
+
+        assert_eq!(result.status, RunStatus::Completed);
+        let value = |name| evaluator.variables()[indexer.get_index(name).unwrap()].clone();
+        let decimal = |text: &str| Value::Decimal(text.parse().unwrap());
+        assert_eq!(value("magnitude"), decimal("2.5"));
+        assert_eq!(value("direction"), decimal("-1"));
+        assert_eq!(value("floored"), decimal("-3"));
+        assert_eq!(value("ceiled"), decimal("3"));
+        assert_eq!(value("cents"), decimal("2.35"));
+        assert_eq!(value("whole"), decimal("3"));
+        assert_eq!(value("remainder"), decimal("1"));
+        assert_eq!(value("wrapped"), decimal("2"));
+        assert_eq!(value("rate"), decimal("1"));
+        assert_eq!(value("capped"), decimal("100"));
+        assert_eq!(value("root"), Value::Number(4.0));
+        assert!(matches!(value("digits"), Value::Number(x) if (x - 3.0).abs() < 1e-12));
+        assert_eq!(value("angle"), Value::Number(1.0));
+        // Only the selected value is evaluated
+        assert_eq!(value("picked"), Value::String("yes".to_string()));
+        assert_eq!(
+            value("refund"),
+            Value::Money("12.5".parse().unwrap(), "USD".to_string())
+        );
+        assert_eq!(value("missing"), Value::Null);
+    }
+
+    #[test]
+    fn test_option_pricing() {
+        let (indexer, evaluator, _) = Script::new().run_ok(
+            "function call_price(spot, strike, rate, volatility, years) {
+                d1 = (ln(spot / strike) + (rate + volatility ** 2 / 2) * years) / (volatility * sqrt(years))
+                d2 = d1 - volatility * sqrt(years)
+                return spot * ncdf(d1) - strike * exp(-rate * years) * ncdf(d2)
+            }
+            price = call_price(100, 100, 5%, 20%, 1)
+            density = npdf(0)",
+        );
+
+        let value = |name| evaluator.variables()[indexer.get_index(name).unwrap()].clone();
+        assert!(
+            matches!(value("price"), Value::Number(x) if (x - 10.450_583_572_185_565).abs() < 1e-9)
+        );
+        assert!(
+            matches!(value("density"), Value::Number(x) if (x - 0.398_942_280_401_432_7).abs() < 1e-15)
+        );
+    }
+
+    #[test]
+    fn test_math_errors() {
+        let error = |script: &str| Script::new().with_rates().error(script);
+
+        assert!(error("x = 1 % 0").contains("Division by zero"));
+        assert!(error("x = clamp(1, 2, 1)").contains("Cannot clamp between 2 and 1"));
+        assert!(error("x = sqrt(4 USD)").contains("Expected a numeric operand"));
+        assert!(error("x = select(1, 2, 3)").contains("Expected a boolean operand"));
+        assert!(error("x = 10 USD % 3")
+            .contains("Cannot take the remainder of an amount of USD and a number"));
+        assert!(error("x = 10 USD % 3 EUR").contains("Currency mismatch"));
+    }
+
+    #[test]
+    fn test_statistics() {
+        let (indexer, evaluator, result) = Script::new().run_ok(
+            "prices = [100, 120, 90, 110, 60, 130]
+            total = sum([0.1, 0.2])
+            fees = sum([1.50 USD, 2.25 USD])
+            nothing = sum([])
+            average = mean(prices)
+            middle = median(prices)
+            spread = variance([2, 4, 4, 4, 5, 5, 7, 9])
+            deviation = stdev([2, 4, 4, 4, 5, 5, 7, 9])
+            tail = percentile([15, 20, 35, 40, 50], 40)
+            together = correlation([1, 2, 3, 4, 5], [2, 4, 5, 4, 5])
+            flat = correlation([1, 2], [3, 3])
+            running = cumsum([1, 2, 3])
+            worst = max_drawdown(prices)
+            unknown = mean([1, null])
+            empty = median([])
+            returns = []
+            for i in [1, 2, 3, 4, 5] do
+                returns = append(returns, prices[i] / prices[i - 1] - 1)
+            end
+            volatility = stdev(returns) * sqrt(252)
+            volatile = volatility > 40%",
+        );
+
+        assert_eq!(result.status, RunStatus::Completed);
+        let value = |name| evaluator.variables()[indexer.get_index(name).unwrap()].clone();
+        let number = |name| value(name).as_number().unwrap();
+        assert_eq!(value("total"), Value::Decimal("0.3".parse().unwrap()));
+        assert_eq!(
+            value("fees"),
+            Value::Money("3.75".parse().unwrap(), "USD".to_string())
+        );
+        assert_eq!(value("nothing"), Value::Decimal(0.into()));
+        assert_eq!(value("average"), Value::Number(101.0 + 2.0 / 3.0));
+        assert_eq!(value("middle"), Value::Number(105.0));
+        assert!((number("spread") - 32.0 / 7.0).abs() < 1e-12);
+        assert!((number("deviation") - (32.0f64 / 7.0).sqrt()).abs() < 1e-12);
+        assert!((number("tail") - 29.0).abs() < 1e-12);
+        assert!((number("together") - 0.774_596_669_241_483_4).abs() < 1e-12);
+        assert_eq!(value("flat"), Value::Null);
+        assert_eq!(
+            value("running"),
+            Value::List(vec![
+                Value::Decimal(1.into()),
+                Value::Decimal(3.into()),
+                Value::Decimal(6.into())
+            ])
+        );
+        assert_eq!(value("worst"), Value::Number(0.5));
+        assert_eq!(value("unknown"), Value::Null);
+        assert_eq!(value("empty"), Value::Null);
+        assert!(number("volatility") > 5.0);
+        assert_eq!(value("volatile"), Value::Bool(true));
+    }
+
+    #[test]
+    fn test_statistic_errors() {
+        let error = |script: &str| Script::new().with_rates().error(script);
+
+        assert!(error("x = mean([1, \"2\"])").contains("Expected a numeric operand"));
+        assert!(error("x = sum([1, \"2\"])").contains("Expected a numeric operand"));
+        assert!(error("x = sum([1 USD, 2 EUR])").contains("Currency mismatch"));
+        assert!(error("x = mean([1 USD])").contains("Expected a numeric operand"));
+        assert!(error("x = stdev(5)").contains("Expected a list operand"));
+        assert!(error("x = percentile([1, 2], 101)")
+            .contains("Expected a percentile from 0 to 100, found 101"));
+        assert!(error("x = correlation([1, 2], [1, 2, 3])")
+            .contains("Cannot correlate lists of 2 and 3 values"));
+    }
+
+    #[test]
+    fn test_money_arithmetic() {
+        let (indexer, evaluator) = Script::new()
+            .with_rates()
+            .evaluate(
+                "balance = 100 USD + 25.50 USD
+            fee = balance * 1%
+            half = balance / 2
+            share = 25.5 USD / balance
+            refund = -fee
+            large = balance > 120 USD and max(1 USD, 2 USD) == 2 USD
+            rounded = round_to(fee, 2)
+            label = \"{balance} ({format_currency(balance, \"USD\")})\"",
+            )
+            .unwrap();
+
+        let value = |name| evaluator.variables()[indexer.get_index(name).unwrap()].clone();
+        let money = |amount: &str, currency: &str| {
+            Value::Money(amount.parse().unwrap(), currency.to_string())
+        };
+        assert_eq!(value("balance"), money("125.5", "USD"));
+        assert_eq!(value("fee"), money("1.255", "USD"));
+        assert_eq!(value("half"), money("62.75", "USD"));
+        assert_eq!(
+            value("share"),
+            Value::Decimal("0.2031872510".parse().unwrap())
+        );
+        assert_eq!(value("refund"), money("-1.255", "USD"));
+        assert_eq!(value("large"), Value::Bool(true));
+        assert_eq!(value("rounded"), money("1.26", "USD"));
+        assert_eq!(
+            value("label"),
+            Value::String("125.5 USD ($125.50)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_currency_mismatch() {
+        let error = |script: &str| Script::new().with_rates().error(script);
+
+        assert!(
+            error("x = 100 USD + 1000 CLP").contains("Currency mismatch: cannot add USD and CLP")
+        );
+        assert!(error("x = 100 USD - 5").contains("Cannot subtract an amount of USD and a number"));
+        assert!(error("x = 2 USD * 3 USD")
+            .contains("Cannot multiply an amount of USD and an amount of USD"));
+        assert!(
+            error("x = 1 EUR < 1 USD").contains("Currency mismatch: cannot compare EUR and USD")
+        );
+        assert!(error("x = 1 USD == 1").contains("Cannot compare"));
+        assert!(error("x = format_currency(1 USD, \"EUR\")").contains("Currency mismatch"));
+        assert!(error("x = ln(1 USD)").contains("Expected a numeric operand"));
+    }
+
+    #[test]
+    fn test_convert() {
+        let (indexer, evaluator) = Script::new()
+            .with_rates()
+            .evaluate(
+                "eur = convert(100 USD, \"EUR\")
+            usd = convert(92 EUR, \"USD\")
+            clp = 5 CLP
+            same = convert(clp, \"CLP\")
+            total = 10 USD + convert(9500 CLP, \"USD\")
+            try
+                missing = convert(1 EUR, \"CLP\")
+            catch err
+                kind = err.kind
+            end",
+            )
+            .unwrap();
+
+        let value = |name| evaluator.variables()[indexer.get_index(name).unwrap()].clone();
+        let money = |amount: &str, currency: &str| {
+            Value::Money(amount.parse().unwrap(), currency.to_string())
+        };
+        assert_eq!(value("eur"), money("92", "EUR"));
+        assert_eq!(value("usd"), money("100", "USD"));
+        assert_eq!(value("same"), money("5", "CLP"));
+        assert_eq!(value("total"), money("20", "USD"));
+        assert_eq!(value("kind"), Value::String("unknown_rate".to_string()));
+
+        let error = Script::new()
+            .with_rates()
+            .error("x = convert(100, \"EUR\")");
+        assert!(error.contains("Expected an amount of money"));
+
+        // Without exchange rates, only conversions to the same currency work
+        let error = Script::new().error("x = convert(1 USD, \"EUR\")");
+        assert!(error.contains("No exchange rates"));
+    }
+
+    #[test]
+    fn test_dates() {
+        let (indexer, evaluator) = Script::new()
+            .at("2024-05-15T16:30")
+            .evaluate(
+                "start = 2024-05-04
+            next_month = today() + 1m
+            end_of_february = 2024-01-31 + 1m
+            elapsed = today() - start
+            started = today() >= start and start < 2024-05-04T00:01
+            day = day_of_month(today())
+            weekday = is_weekday(today())
+            saturday = is_weekday(start)
+            meeting = now() + 2h
+            deadline = start + 12h
+            period = 2 * 1w - 1d
+            later = max(start, 2024-06-01, start + 30d)
+            label = \"{start} + {period}\"",
+            )
+            .unwrap();
+
+        let value = |name| evaluator.variables()[indexer.get_index(name).unwrap()].clone();
+        let date = |text: &str| Value::Date(text.parse().unwrap());
+        let time = |text: &str| Value::DateTime(text.parse().unwrap());
+        assert_eq!(value("next_month"), date("2024-06-15"));
+        assert_eq!(value("end_of_february"), date("2024-02-29"));
+        assert_eq!(value("elapsed"), Value::Duration(Duration::new(0, 11, 0)));
+        assert_eq!(value("started"), Value::Bool(true));
+        assert_eq!(value("day"), Value::Number(15.0));
+        assert_eq!(value("weekday"), Value::Bool(true));
+        assert_eq!(value("saturday"), Value::Bool(false));
+        assert_eq!(value("meeting"), time("2024-05-15T18:30"));
+        assert_eq!(value("deadline"), time("2024-05-04T12:00"));
+        assert_eq!(value("period"), Value::Duration(Duration::new(0, 13, 0)));
+        assert_eq!(value("later"), date("2024-06-03"));
+        assert_eq!(
+            value("label"),
+            Value::String("2024-05-04 + 13d".to_string())
+        );
+    }
+
+    #[test]
+    fn test_date_errors() {
+        let error = |script: &str| Script::new().at("2024-05-15T16:30").error(script);
+
+        assert!(error("x = 2024-05-04 + 2024-05-05").contains("Cannot add a date and a date"));
+        assert!(error("x = 2024-05-04 + 1").contains("Cannot add a date and a number"));
+        assert!(error("x = 1d * 1.5").contains("whole number"));
+        assert!(error("x = 1m > 30d").contains("months have no fixed length"));
+        assert!(error("x = 2024-05-04 > 5").contains("Cannot compare a date and a number"));
+        assert!(error("x = day_of_month(5)").contains("Expected a date"));
+        assert!(error("x = 9999-12-31 + 1d").contains("Date out of range"));
+    }
+
+    #[test]
+    fn test_business_days() {
+        let (indexer, evaluator) = Script::new()
+            .at("2024-05-31T10:00")
+            .with_calendar()
+            .evaluate(
+                "last_day = adjust(end_of_month(today()), \"preceding\", \"NYSE\")
+            transfer = today() == last_day
+            holiday = is_business_day(2024-05-27, \"NYSE\")
+            weekday = is_business_day(2024-05-27)
+            settlement = add_business_days(2024-05-24, 2, \"NYSE\")
+            spot = add_business_days(now(), -1)
+            payment = adjust(2024-06-30, \"modified_following\", \"NYSE\")
+            open = is_market_open(\"NYSE\")
+            closed = is_market_open(\"NYSE\", 2024-05-27T10:00)",
+            )
+            .unwrap();
+
+        let value = |name| evaluator.variables()[indexer.get_index(name).unwrap()].clone();
+        let date = |text: &str| Value::Date(text.parse().unwrap());
+        assert_eq!(value("last_day"), date("2024-05-31"));
+        assert_eq!(value("transfer"), Value::Bool(true));
+        assert_eq!(value("holiday"), Value::Bool(false));
+        assert_eq!(value("weekday"), Value::Bool(true));
+        assert_eq!(value("settlement"), date("2024-05-29"));
+        assert_eq!(value("spot"), date("2024-05-30"));
+        assert_eq!(value("payment"), date("2024-06-28"));
+        assert_eq!(value("open"), Value::Bool(true));
+        assert_eq!(value("closed"), Value::Bool(false));
+    }
+
+    #[test]
+    fn test_calendar_errors() {
+        let error = |script: &str| {
+            Script::new()
+                .at("2024-05-31T10:00")
+                .with_calendar()
+                .error(script)
+        };
+
+        assert!(error("x = is_business_day(today(), \"LSE\")").contains("Unknown calendar LSE"));
+        assert!(error("x = is_market_open(\"LSE\")").contains("Unknown market LSE"));
+        assert!(
+            error("x = adjust(today(), \"nearest\")").contains("Unknown business day convention")
+        );
+        assert!(error("x = is_market_open(\"NYSE\", today())").contains("Expected a date and time"));
+    }
+
+    #[test]
+    fn test_indicators() {
+        let (indexer, evaluator) = Script::new()
+            .with_history()
+            .evaluate(
+                "average = sma(\"XYZ\", 10)
+            trend = ema(\"XYZ\", 10)
+            strength = rsi(\"UP\", 2)
+            bands = bollinger(\"XYZ\", 20)
+            wide = bollinger(\"XYZ\", 20, 3).upper - bands.upper > 0
+            top = high(\"XYZ\", 20)
+            bottom = low(\"XYZ\", 5)
+            short = sma(\"XYZ\", 31)
+            breakout = crosses_above(sma(\"UP\", 1), sma(\"UP\", 3))
+            above_11 = crosses_above(sma(\"UP\", 1), 11)
+            above_8 = crosses_above(sma(\"UP\", 1), 8)",
+            )
+            .unwrap();
+
+        let value = |name| evaluator.variables()[indexer.get_index(name).unwrap()].clone();
+        let number = |name| value(name).as_number().unwrap();
+        assert!((number("average") - 23.131).abs() < 1e-9);
+        assert!((number("trend") - 22.915_004_434_033_058).abs() < 1e-9);
+        // Smoothed over 2 prices, the average gain is 1.5 and the average loss 0.25
+        assert!((number("strength") - 600.0 / 7.0).abs() < 1e-9);
+        let Value::Map(bands) = value("bands") else {
+            panic!("Expected a map, found {:?}", value("bands"));
+        };
+        assert!((bands["lower"].as_number().unwrap() - 21.905_533_992_551_575).abs() < 1e-9);
+        assert!((bands["middle"].as_number().unwrap() - 23.1705).abs() < 1e-9);
+        assert!((bands["upper"].as_number().unwrap() - 24.435_466_007_448_42).abs() < 1e-9);
+        assert_eq!(value("wide"), Value::Bool(true));
+        assert_eq!(value("top"), Value::Number(24.05));
+        assert_eq!(value("bottom"), Value::Number(22.17));
+        assert_eq!(value("short"), Value::Null);
+        assert_eq!(value("breakout"), Value::Bool(true));
+        assert_eq!(value("above_11"), Value::Bool(true));
+        assert_eq!(value("above_8"), Value::Bool(false));
+    }
+
+    #[test]
+    fn test_indicator_errors() {
+        let error = |script: &str| Script::new().with_history().error(script);
+
+        assert!(error("x = sma(\"MSFT\", 5)").contains("No price history for MSFT"));
+        assert!(error("x = rsi(\"XYZ\", 0)").contains("Expected a positive window, found 0"));
+        assert!(error("x = high(\"XYZ\", 2.5)").contains("Expected an integer"));
+
+        let (indexer, evaluator) = Script::new()
+            .with_history()
+            .evaluate(
+                "try
+                x = sma(\"MSFT\", 5)
+            catch error
+                kind = error.kind
+            end",
+            )
+            .unwrap();
+        let kind = evaluator.variables()[indexer.get_index("kind").unwrap()].clone();
+        assert_eq!(kind, Value::String("unknown_symbol".to_string()));
+
+        let error = Script::new().error("x = sma(\"XYZ\", 5)");
+        assert!(error.contains("No price history for XYZ"));
+    }
+
+    #[test]
+    fn test_lookback() {
+        let script = "price = Spot(\"AAPL\")
+            previous = Spot(\"AAPL\", at=-1)
+            fell = previous != null and price < previous * 95%
+            first = Spot(\"AAPL\", at=-3)
+            yesterday = Spot(\"AAPL\", at=today() - 1d)
+            morning = Spot(\"AAPL\", at=2024-05-07T12:00)
+            other = Spot(\"MSFT\", at=-1)";
+        let store = Arc::new(InMemoryObservations::new());
+        let value = |(indexer, evaluator): &(ExpressionIndexer, ExpressionEvaluator), name| {
+            evaluator.variables()[indexer.get_index(name).unwrap()].clone()
+        };
+
+        let run = |now, price: f64| {
+            Script::new()
+                .at(now)
+                .with_host("Spot", move |_| Ok(Value::Number(price)))
+                .with_observations(&store)
+                .evaluate(script)
+                .unwrap()
+        };
+
+        let monday = run("2024-05-06T09:30:00", 100.0);
+        assert_eq!(value(&monday, "previous"), Value::Null);
+        assert_eq!(value(&monday, "fell"), Value::Bool(false));
+        let observations = monday.1.observations();
+        assert_eq!(observations.len(), 1);
+        assert_eq!(observations["Spot(\"AAPL\")"].value, Value::Number(100.0));
+
+        run("2024-05-07T09:30:00", 98.0);
+        run("2024-05-07T16:00:00", 97.0);
+        let wednesday = run("2024-05-08T09:30:00", 92.0);
+        assert_eq!(value(&wednesday, "previous"), Value::Number(97.0));
+        assert_eq!(value(&wednesday, "fell"), Value::Bool(true));
+        assert_eq!(value(&wednesday, "first"), Value::Number(100.0));
+        assert_eq!(value(&wednesday, "yesterday"), Value::Number(97.0));
+        assert_eq!(value(&wednesday, "morning"), Value::Number(98.0));
+        assert_eq!(value(&wednesday, "other"), Value::Null);
+        assert_eq!(store.observations("Spot(\"AAPL\")").unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_lookback_errors() {
+        let store = Arc::new(InMemoryObservations::new());
+        let error = |script: &str| {
+            Script::new()
+                .at("2024-05-06T09:30:00")
+                .with_host("Spot", |_| Ok(Value::Number(100.0)))
+                .with_observations(&store)
+                .error(script)
+        };
+
+        assert!(error("x = Spot(\"AAPL\", at=0)")
+            .contains("Expected a negative number of runs, a date or a time, found"));
+        assert!(error("x = Spot(\"AAPL\", at=-1.5)").contains("Expected a negative number"));
+        assert!(error("x = Spot(at=-1, \"AAPL\")").contains("Expected `at` to be the last"));
+        assert!(error("function f(x) return x end\ny = f(1, at=-1)")
+            .contains("Cannot look back at function f, only at host functions"));
+
+        // A run that fails records nothing
+        assert!(error("x = Spot(\"AAPL\")\ny = 1 / 0").contains("Division by zero"));
+        assert!(store.observations("Spot(\"AAPL\")").unwrap().is_empty());
+
+        // Without an observation store
+        let error = Script::new()
+            .with_host("Spot", |_| Ok(Value::Number(100.0)))
+            .error("x = Spot(\"AAPL\", at=-1)");
+        assert!(error.contains("No observations of Spot(\"AAPL\")"));
+    }
+
+    #[test]
+    fn test_match() {
+        let (indexer, evaluator, result) = Script::new().run_ok(
+            "function fee(symbol)
+                match symbol with
+                case \"AAPL\", \"MSFT\" then return 1
+                case \"GOOG\" then return 2
+                else return 3
+                end
+            end
+            a = fee(\"MSFT\")
+            g = fee(\"GOOG\")
+            other = fee(\"TSLA\")
+            match 2 with
+            case \"2\" then kind = \"string\"
+            case 1 + 1 then kind = \"number\"
+            case [1][5] then kind = \"not evaluated\"
+            end
+            match null with case 1 then none = false end",
+        );
+
+        assert_eq!(result.status, RunStatus::Completed);
+        let value = |name| evaluator.variables()[indexer.get_index(name).unwrap()].clone();
+        assert_eq!(value("a"), Value::Number(1.0));
+        assert_eq!(value("g"), Value::Number(2.0));
+        assert_eq!(value("other"), Value::Number(3.0));
+        assert_eq!(value("kind"), Value::String("number".to_string()));
+        assert_eq!(value("none"), Value::Null);
+    }
 }
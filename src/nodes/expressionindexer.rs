@@ -1,17 +1,61 @@
-use std::{collections::HashMap, sync::Mutex};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
 
-use super::{node::Node, traits::NodeVisitor};
+use super::{
+    node::{FrameLayout, Node},
+    traits::NodeVisitor,
+};
+use crate::utils::errors::{Result, ScriptingError};
 
+// Variables declared with `local` in a block. The scope opened by a function
+// body also holds its parameters.
+#[derive(Default)]
+struct Scope {
+    locals: HashMap<String, usize>,
+    function: bool,
+}
+
+// Frame of the function being indexed. Variables assigned in the body
+// without a `local` declaration are local to the call as well; any other
+// variable is a global copied into the frame when the function is called.
+struct FunctionScope {
+    name: String,
+    size: usize,
+    implicit: HashMap<String, usize>,
+    assigned: HashSet<String>,
+    captures: Vec<(usize, usize)>,
+}
+
+/// Assigns a slot to every variable of the tree.
+///
+/// Globals are named and listed in `variables`. Variables declared with
+/// `local` live until the end of their block: at the top level they take an
+/// unnamed global slot, inside a function a slot of its frame. A declaration
+/// shadows any variable of the same name and always takes a fresh slot.
 pub struct ExpressionIndexer {
     pub variables: Mutex<HashMap<String, usize>>,
+    size: Mutex<usize>,
+    scopes: Mutex<Vec<Scope>>,
+    function: Mutex<Option<FunctionScope>>,
+    // Variables a function assigns without declaring them: the function
+    // name, the variable, the line of its first use and whether it hides a
+    // top-level `local`
+    implicit_locals: Mutex<Vec<(String, String, usize, bool)>>,
 }
 
 impl NodeVisitor for ExpressionIndexer {
     type Output = ();
-    fn visit(&self, node: &Box<Node>) {
-        match node.as_ref() {
-            Node::Base(children)
-            | Node::Add(children)
+    fn visit(&self, node: &Node) {
+        match node {
+            Node::Base(children) => {
+                self.push_scope();
+                children.iter().for_each(|child| self.visit(child));
+                self.pop_scope();
+            }
+
+            Node::Add(children)
             | Node::Subtract(children)
             | Node::Multiply(children)
             | Node::Divide(children)
@@ -21,6 +65,9 @@ impl NodeVisitor for ExpressionIndexer {
             | Node::Exp(children)
             | Node::Pow(children)
             | Node::Ln(children)
+            | Node::Modulo(children)
+            | Node::Clamp(children)
+            | Node::Math(children, _)
             | Node::UnaryPlus(children)
             | Node::UnaryMinus(children)
             | Node::Equal(children)
@@ -32,28 +79,182 @@ impl NodeVisitor for ExpressionIndexer {
             | Node::Inferior(children)
             | Node::SuperiorOrEqual(children)
             | Node::InferiorOrEqual(children)
-            | Node::If(children, _) => {
+            | Node::Coalesce(children)
+            | Node::Select(children)
+            | Node::Interpolation(children)
+            | Node::Format(children)
+            | Node::FormatCurrency(children)
+            | Node::RoundTo(children)
+            | Node::Convert(children)
+            | Node::Today(children)
+            | Node::Now(children)
+            | Node::DayOfMonth(children)
+            | Node::IsWeekday(children)
+            | Node::IsBusinessDay(children)
+            | Node::AddBusinessDays(children)
+            | Node::Adjust(children)
+            | Node::EndOfMonth(children)
+            | Node::IsMarketOpen(children)
+            | Node::Indicator(children, _)
+            | Node::CrossesAbove(children)
+            | Node::Statistic(children, _)
+            | Node::Field(children, _)
+            | Node::List(children)
+            | Node::Index(children)
+            | Node::Slice(children, _, _)
+            | Node::Len(children)
+            | Node::Append(children)
+            | Node::Map(children, _)
+            | Node::Call(children, _)
+            | Node::Lookback(children, _)
+            | Node::Return(children, _)
+            | Node::Abort(children, _)
+            | Node::Require(children, _)
+            | Node::Assert(children, _) => {
                 children.iter().for_each(|child| self.visit(child));
             }
 
-            Node::Variable(children, name, opt_idx) => {
+            Node::If(children, first_else, _) => {
+                let Some(condition) = children.first() else {
+                    return;
+                };
+                self.visit(condition);
+
+                // The then and else blocks each open a scope
+                let last_then = first_else
+                    .unwrap_or(children.len())
+                    .clamp(1, children.len());
+                for block in [&children[1..last_then], &children[last_then..]] {
+                    self.push_scope();
+                    block.iter().for_each(|child| self.visit(child));
+                    self.pop_scope();
+                }
+            }
+
+            Node::Match(children, first_else) => {
+                if children.is_empty() {
+                    return;
+                }
+                // The cases open their own scopes, like the else block
+                let last_case = first_else
+                    .unwrap_or(children.len())
+                    .clamp(1, children.len());
+                children[..last_case]
+                    .iter()
+                    .for_each(|child| self.visit(child));
+                self.push_scope();
+                children[last_case..]
+                    .iter()
+                    .for_each(|child| self.visit(child));
+                self.pop_scope();
+            }
+
+            Node::Case(children, values) => {
+                let values = (*values).min(children.len());
+                children[..values]
+                    .iter()
+                    .for_each(|child| self.visit(child));
+                self.push_scope();
+                children[values..]
+                    .iter()
+                    .for_each(|child| self.visit(child));
+                self.pop_scope();
+            }
+
+            Node::For(children, variables) => {
+                let variables = (*variables).min(children.len());
+                let Some(list) = children.get(variables) else {
+                    return;
+                };
+                // The list is computed before the loop variables exist
+                self.visit(list);
+
+                self.push_scope();
+                children[..variables]
+                    .iter()
+                    .for_each(|variable| self.declare(variable));
+                children[variables + 1..]
+                    .iter()
+                    .for_each(|child| self.visit(child));
+                self.pop_scope();
+            }
+
+            Node::Try(children, catch_index, binding) => {
+                let catch_index = (*catch_index).min(children.len());
+                self.push_scope();
+                children[..catch_index]
+                    .iter()
+                    .for_each(|child| self.visit(child));
+                self.pop_scope();
+
+                // The error variable is a local of the catch block
+                self.push_scope();
+                if let Some(binding) = binding {
+                    self.declare(binding);
+                }
+                children[catch_index..]
+                    .iter()
+                    .for_each(|child| self.visit(child));
+                self.pop_scope();
+            }
+
+            Node::Function(children, name, params, layout) => {
+                if layout.get().is_some() {
+                    return;
+                }
+                let mut assigned = HashSet::new();
+                children
+                    .iter()
+                    .for_each(|child| collect_assigned(child, &mut assigned));
+                *self.function.lock().unwrap() = Some(FunctionScope {
+                    name: name.clone(),
+                    size: params.len(),
+                    implicit: HashMap::new(),
+                    assigned,
+                    captures: Vec::new(),
+                });
+                self.scopes.lock().unwrap().push(Scope {
+                    locals: params
+                        .iter()
+                        .enumerate()
+                        .map(|(slot, param)| (param.clone(), slot))
+                        .collect(),
+                    function: true,
+                });
+
+                children.iter().for_each(|child| self.visit(child));
+
+                self.pop_scope();
+                let function = self.function.lock().unwrap().take().unwrap();
+                let _ = layout.set(FrameLayout {
+                    size: function.size,
+                    captures: function.captures,
+                });
+            }
+
+            Node::Local(children) => {
+                // The value is computed before the declaration takes effect,
+                // so `local x = x + 1` reads the outer `x`
+                children.iter().skip(1).for_each(|child| self.visit(child));
+                if let Some(variable) = children.first() {
+                    self.declare(variable);
+                }
+            }
+
+            Node::Variable(children, name, opt_idx, line) => {
                 children.iter().for_each(|child| self.visit(child));
                 match opt_idx.get() {
                     Some(id) => {
-                        self.variables.lock().unwrap().insert(name.clone(), *id);
+                        // Already indexed, only globals need to be recorded
+                        if self.is_global(name) {
+                            self.variables.lock().unwrap().insert(name.clone(), *id);
+                            let mut size = self.size.lock().unwrap();
+                            *size = (*size).max(id + 1);
+                        }
                     }
                     None => {
-                        // check if the variable is already in the hashmap
-                        if self.variables.lock().unwrap().contains_key(name) {
-                            let size = self.variables.lock().unwrap().get(name).unwrap().clone();
-                            // Update the id of the variable
-                            opt_idx.set(size).unwrap();
-                        } else {
-                            let size = self.variables.lock().unwrap().len();
-                            self.variables.lock().unwrap().insert(name.clone(), size);
-                            // Update the id of the variable
-                            opt_idx.set(size).unwrap();
-                        }
+                        let slot = self.resolve(name, *line);
+                        opt_idx.set(slot).unwrap();
                     }
                 };
             }
@@ -63,10 +264,153 @@ impl NodeVisitor for ExpressionIndexer {
     }
 }
 
+impl Default for ExpressionIndexer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Names of the variables assigned anywhere in `node`
+fn collect_assigned(node: &Node, assigned: &mut HashSet<String>) {
+    match node {
+        Node::Assign(children) => {
+            if let Some(Node::Variable(_, name, _, _)) = children.first().map(|lhs| lhs.as_ref()) {
+                assigned.insert(name.clone());
+            }
+            children
+                .iter()
+                .for_each(|child| collect_assigned(child, assigned));
+        }
+        _ if node.is_leaf() => {}
+        _ => node
+            .children()
+            .iter()
+            .for_each(|child| collect_assigned(child, assigned)),
+    }
+}
+
 impl ExpressionIndexer {
     pub fn new() -> ExpressionIndexer {
         ExpressionIndexer {
             variables: Mutex::new(HashMap::new()),
+            size: Mutex::new(0),
+            scopes: Mutex::new(Vec::new()),
+            function: Mutex::new(None),
+            implicit_locals: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn push_scope(&self) {
+        self.scopes.lock().unwrap().push(Scope::default());
+    }
+
+    fn pop_scope(&self) {
+        self.scopes.lock().unwrap().pop();
+    }
+
+    // Declares a local variable in the innermost scope, in a fresh slot
+    fn declare(&self, variable: &Node) {
+        if let Node::Variable(_, name, opt_idx, _) = variable {
+            let slot = *opt_idx.get_or_init(|| self.fresh_slot());
+            if let Some(scope) = self.scopes.lock().unwrap().last_mut() {
+                scope.locals.insert(name.clone(), slot);
+            }
+        }
+    }
+
+    // A new slot in the frame of the function being indexed, or a new global
+    // slot at the top level
+    fn fresh_slot(&self) -> usize {
+        if let Some(function) = self.function.lock().unwrap().as_mut() {
+            function.size += 1;
+            return function.size - 1;
+        }
+        let mut size = self.size.lock().unwrap();
+        *size += 1;
+        *size - 1
+    }
+
+    // Index of a global variable, allocating it on first use
+    fn global_index(&self, name: &str) -> usize {
+        let mut variables = self.variables.lock().unwrap();
+        if let Some(index) = variables.get(name) {
+            return *index;
+        }
+        let mut size = self.size.lock().unwrap();
+        variables.insert(name.to_string(), *size);
+        *size += 1;
+        *size - 1
+    }
+
+    fn is_global(&self, name: &str) -> bool {
+        self.function.lock().unwrap().is_none()
+            && !self
+                .scopes
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|scope| scope.locals.contains_key(name))
+    }
+
+    // Slot of a variable in the current scope: the innermost declaration,
+    // then, inside a function, the implicit locals and the captured variables
+    fn resolve(&self, name: &str, line: usize) -> usize {
+        let mut outer = None;
+        let mut crossed_function = false;
+        for scope in self.scopes.lock().unwrap().iter().rev() {
+            if let Some(slot) = scope.locals.get(name) {
+                if !crossed_function {
+                    return *slot;
+                }
+                // A top-level local declared before the function
+                outer = Some(*slot);
+                break;
+            }
+            crossed_function |= scope.function;
+        }
+
+        let mut function = self.function.lock().unwrap();
+        let Some(function) = function.as_mut() else {
+            return outer.unwrap_or_else(|| self.global_index(name));
+        };
+        if let Some(slot) = function.implicit.get(name) {
+            return *slot;
+        }
+        let slot = function.size;
+        function.size += 1;
+        function.implicit.insert(name.to_string(), slot);
+        if function.assigned.contains(name) {
+            self.implicit_locals.lock().unwrap().push((
+                function.name.clone(),
+                name.to_string(),
+                line,
+                outer.is_some(),
+            ));
+        } else {
+            let global = outer.unwrap_or_else(|| self.global_index(name));
+            function.captures.push((global, slot));
+        }
+        slot
+    }
+
+    /// Rejects a function assigning a variable of the script without
+    /// declaring it `local`. The assignment would only change a variable of
+    /// the call, so the shadowing has to be explicit. Runs after the whole
+    /// script is indexed, as globals may be assigned after the function.
+    pub fn check_shadowing(&self) -> Result<()> {
+        let variables = self.variables.lock().unwrap();
+        match self
+            .implicit_locals
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, name, _, shadows_local)| *shadows_local || variables.contains_key(name))
+        {
+            Some((function, name, line, _)) => Err(ScriptingError::InvalidSyntax(format!(
+                "Error at line {}: function {} assigns {}, a variable of the script that functions cannot change; declare it with `local` to shadow it",
+                line, function, name
+            ))),
+            None => Ok(()),
         }
     }
 
@@ -91,15 +435,19 @@ impl ExpressionIndexer {
         self.variables.lock().unwrap().clone()
     }
 
+    /// Number of global slots, named variables and top-level locals alike.
     pub fn get_size(&self) -> usize {
-        self.variables.lock().unwrap().len()
+        *self.size.lock().unwrap()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::nodes::node::Node;
+    use crate::{
+        nodes::node::Node,
+        parsers::{lexer::Lexer, parser::Parser},
+    };
 
     #[test]
     fn test_expression_indexer() {
@@ -122,4 +470,185 @@ mod tests {
         assert_eq!(variables.get("x"), Some(&0));
         assert_eq!(variables.get("y"), Some(&1));
     }
+
+    #[test]
+    fn test_function_frame_layout() {
+        let tokens = Lexer::new(
+            "
+            limit = 10
+            function check(price) {
+                margin = price - limit
+                return margin > 0
+            }
+            ok = check(12)
+            "
+            .to_string(),
+        )
+        .tokenize()
+        .unwrap();
+        let nodes = Parser::new(tokens).parse().unwrap();
+
+        let indexer = ExpressionIndexer::new();
+        indexer.visit(&nodes);
+
+        // Locals do not take global slots
+        assert_eq!(indexer.get_size(), 2);
+        assert_eq!(indexer.get_index("limit"), Some(0));
+        assert_eq!(indexer.get_index("ok"), Some(1));
+        assert_eq!(indexer.get_index("margin"), None);
+
+        match nodes.children()[1].as_ref() {
+            Node::Function(_, _, _, layout) => assert_eq!(
+                layout.get(),
+                Some(&FrameLayout {
+                    size: 3,
+                    captures: vec![(0, 2)],
+                })
+            ),
+            node => panic!("Expected a function, found {:?}", node),
+        }
+    }
+
+    fn index(script: &str) -> (ExpressionIndexer, Box<Node>) {
+        let tokens = Lexer::new(script.to_string()).tokenize().unwrap();
+        let nodes = Parser::new(tokens).parse().unwrap();
+        let indexer = ExpressionIndexer::new();
+        indexer.visit(&nodes);
+        (indexer, nodes)
+    }
+
+    // Slots of every variable node named `name`, in visiting order
+    fn slots(node: &Node, name: &str, found: &mut Vec<usize>) {
+        if let Node::Variable(_, var, index, _) = node {
+            if var == name {
+                found.push(*index.get().unwrap());
+            }
+        }
+        if !node.is_leaf() {
+            node.children()
+                .iter()
+                .for_each(|child| slots(child, name, found));
+        }
+    }
+
+    #[test]
+    fn test_local_shadowing() {
+        let (indexer, nodes) = index(
+            "
+            x = 1
+            if x > 0 then
+                local x = x + 1
+                y = x
+            end
+            z = x
+            ",
+        );
+
+        let mut found = Vec::new();
+        slots(&nodes, "x", &mut found);
+        // x = 1, x > 0, local x, outer x read by the declaration, y = x, z = x
+        let outer = indexer.get_index("x").unwrap();
+        let inner = found[2];
+        assert_ne!(outer, inner);
+        assert_eq!(found, vec![outer, outer, inner, outer, inner, outer]);
+
+        // The local takes an unnamed slot
+        assert_eq!(indexer.get_size(), 4);
+        assert_eq!(indexer.get_variables().len(), 3);
+        assert_eq!(indexer.get_name(inner), None);
+    }
+
+    #[test]
+    fn test_function_assigning_global() {
+        let (indexer, _) = index(
+            "
+            function add(x) {
+                total = total + x
+            }
+            total = 0
+            add(2)
+            ",
+        );
+        let error = indexer.check_shadowing().unwrap_err().to_string();
+        assert!(error.contains("line 3"), "{}", error);
+        assert!(error.contains("function add assigns total"), "{}", error);
+
+        // Top-level locals cannot be changed either
+        let (indexer, _) = index(
+            "
+            local count = 0
+            function f() { count = 1 }
+            ",
+        );
+        assert!(indexer.check_shadowing().is_err());
+    }
+
+    #[test]
+    fn test_function_declaring_shadow() {
+        let (indexer, _) = index(
+            "
+            total = 0
+            function add(x) {
+                local total = x
+                margin = total * 2
+                return margin
+            }
+            y = add(2)
+            ",
+        );
+        assert!(indexer.check_shadowing().is_ok());
+    }
+
+    #[test]
+    fn test_local_ends_with_block() {
+        let (indexer, nodes) = index(
+            "
+            if true then
+                local a = 1
+            else
+                local a = 2
+            end
+            b = a
+            ",
+        );
+
+        let mut found = Vec::new();
+        slots(&nodes, "a", &mut found);
+        assert_eq!(found.len(), 3);
+        // Each block declares its own `a`, `b = a` reads the global one
+        assert_ne!(found[0], found[1]);
+        assert_eq!(found[2], indexer.get_index("a").unwrap());
+        assert!(!found[..2].contains(&found[2]));
+    }
+
+    #[test]
+    fn test_local_in_function() {
+        let (indexer, nodes) = index(
+            "
+            local rate = 2
+            function f(x) {
+                local y = x * rate
+                if y > 1 { local y = 0; return y }
+                return y
+            }
+            z = f(1)
+            ",
+        );
+
+        let mut found = Vec::new();
+        slots(&nodes, "y", &mut found);
+        // parameter x in slot 0, the captured `rate`, then the two `y`
+        assert_eq!(found, vec![2, 2, 3, 3, 2]);
+        assert_eq!(indexer.get_index("rate"), None);
+        match nodes.children()[1].as_ref() {
+            Node::Function(_, _, _, layout) => assert_eq!(
+                layout.get(),
+                Some(&FrameLayout {
+                    size: 4,
+                    captures: vec![(0, 1)],
+                })
+            ),
+            node => panic!("Expected a function, found {:?}", node),
+        }
+    }
 }
@@ -0,0 +1,422 @@
+use std::{collections::HashSet, sync::Mutex};
+
+use super::{node::Node, traits::NodeVisitor};
+use crate::utils::errors::{Result, ScriptingError};
+
+/// Checks, before running a script, that every variable is assigned on all
+/// the paths leading to where it is read, e.g.
+///
+/// ```text
+/// if spot > 100 then
+///     units = 10
+/// end
+/// Buy(units) -- `units` is not assigned when spot <= 100
+/// ```
+///
+/// Runs on an indexed tree, as slots tell apart a `local` from the variable it
+/// shadows. Each function body is checked on its own, with its parameters
+/// assigned. The globals it reads are only known when it is called, so those
+/// are left to the evaluator.
+pub struct InitializationChecker {
+    // Slots definitely assigned at this point of the current block
+    assigned: Mutex<HashSet<usize>>,
+    // Whether the current block always ends before this point
+    ended: Mutex<bool>,
+    // Variables read before they are assigned and the lines of the reads,
+    // collected instead of failing on the first one when set
+    unassigned_reads: Mutex<Option<Vec<(String, usize)>>>,
+}
+
+impl NodeVisitor for InitializationChecker {
+    type Output = Result<()>;
+    fn visit(&self, node: &Node) -> Result<()> {
+        match node {
+            Node::Exit(_) | Node::Break | Node::Continue => {
+                *self.ended.lock().unwrap() = true;
+                Ok(())
+            }
+            _ if node.is_leaf() => Ok(()),
+
+            Node::Base(children) => self.visit_block(children),
+
+            Node::Variable(children, name, index, line) => {
+                self.visit_block(children)?;
+                match index.get() {
+                    Some(id) if !self.assigned.lock().unwrap().contains(id) => {
+                        match self.unassigned_reads.lock().unwrap().as_mut() {
+                            Some(reads) => {
+                                reads.push((name.clone(), *line));
+                                Ok(())
+                            }
+                            None => Err(ScriptingError::UninitializedVariable(format!(
+                                "Variable {} may be read before it is assigned",
+                                name
+                            ))),
+                        }
+                    }
+                    _ => Ok(()),
+                }
+            }
+
+            Node::Assign(children) | Node::Local(children) => {
+                // Only the value is read, the target is written
+                self.visit_block(&children[children.len().min(1)..])?;
+                if let Some(Node::Variable(_, _, index, _)) = children.first().map(|c| c.as_ref()) {
+                    if let Some(id) = index.get() {
+                        let mut assigned = self.assigned.lock().unwrap();
+                        // `local x` declares `x` without a value
+                        if children.len() > 1 {
+                            assigned.insert(*id);
+                        } else {
+                            assigned.remove(id);
+                        }
+                    }
+                }
+                Ok(())
+            }
+
+            Node::If(children, first_else, _) => {
+                let Some(condition) = children.first() else {
+                    return Ok(());
+                };
+                self.visit(condition)?;
+
+                let last_then = first_else
+                    .unwrap_or(children.len())
+                    .clamp(1, children.len());
+                let before = self.assigned.lock().unwrap().clone();
+                let then = self.visit_branch(&children[1..last_then], before.clone())?;
+                let otherwise = self.visit_branch(&children[last_then..], before.clone())?;
+
+                self.join(before, then, otherwise);
+                Ok(())
+            }
+
+            Node::Match(children, first_else) => {
+                let Some(value) = children.first() else {
+                    return Ok(());
+                };
+                self.visit(value)?;
+
+                let last_case = first_else
+                    .unwrap_or(children.len())
+                    .clamp(1, children.len());
+
+                // Case values are expressions, which assign nothing, so each
+                // block starts from what is assigned before the match
+                let before = self.assigned.lock().unwrap().clone();
+                let mut joined = None;
+                for case in &children[1..last_case] {
+                    let Node::Case(case, values) = case.as_ref() else {
+                        continue;
+                    };
+                    let values = (*values).min(case.len());
+                    self.visit_block(&case[..values])?;
+                    let assigned = self.visit_branch(&case[values..], before.clone())?;
+                    joined = intersect(joined, assigned);
+                }
+                // Without an else block, no case may match
+                let otherwise = self.visit_branch(&children[last_case..], before.clone())?;
+
+                self.join(before, joined, otherwise);
+                Ok(())
+            }
+
+            Node::For(children, variables) => {
+                let variables = (*variables).min(children.len());
+                let Some(list) = children.get(variables) else {
+                    return Ok(());
+                };
+                self.visit(list)?;
+
+                // The body may not run at all, so what it assigns does not
+                // count after the loop
+                let before = self.assigned.lock().unwrap().clone();
+                let mut iteration = before.clone();
+                for variable in &children[..variables] {
+                    if let Node::Variable(_, _, index, _) = variable.as_ref() {
+                        iteration.extend(index.get());
+                    }
+                }
+                self.visit_branch(&children[variables + 1..], iteration)?;
+                *self.assigned.lock().unwrap() = before;
+                Ok(())
+            }
+
+            Node::Try(children, catch_index, binding) => {
+                let catch_index = (*catch_index).min(children.len());
+                let before = self.assigned.lock().unwrap().clone();
+                let tried = self.visit_branch(&children[..catch_index], before.clone())?;
+
+                // The try block may fail before any of its assignments, the
+                // catch block starts with the error variable only
+                let mut caught = before.clone();
+                if let Some(Node::Variable(_, _, index, _)) = binding.as_deref() {
+                    caught.extend(index.get());
+                }
+                let caught = self.visit_branch(&children[catch_index..], caught)?;
+
+                self.join(before, tried, caught);
+                Ok(())
+            }
+
+            Node::Function(children, _, params, layout) => {
+                // The body runs in its own frame: arguments and captured
+                // globals are set when the call starts
+                let mut frame = (0..params.len()).collect::<HashSet<_>>();
+                if let Some(layout) = layout.get() {
+                    frame.extend(layout.captures.iter().map(|(_, local)| *local));
+                }
+                let outer = std::mem::replace(&mut *self.assigned.lock().unwrap(), frame);
+                let outer_ended = std::mem::take(&mut *self.ended.lock().unwrap());
+
+                let result = self.visit_block(children);
+
+                *self.assigned.lock().unwrap() = outer;
+                *self.ended.lock().unwrap() = outer_ended;
+                result
+            }
+
+            Node::Return(children, _) | Node::Abort(children, _) => {
+                self.visit_block(children)?;
+                *self.ended.lock().unwrap() = true;
+                Ok(())
+            }
+
+            _ => self.visit_block(node.children()),
+        }
+    }
+}
+
+// Slots assigned at the end of both branches, `None` standing for a branch
+// that never gets there
+fn intersect(
+    left: Option<HashSet<usize>>,
+    right: Option<HashSet<usize>>,
+) -> Option<HashSet<usize>> {
+    match (left, right) {
+        (Some(left), Some(right)) => Some(&left & &right),
+        (left, right) => left.or(right),
+    }
+}
+
+impl Default for InitializationChecker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InitializationChecker {
+    pub fn new() -> InitializationChecker {
+        InitializationChecker {
+            assigned: Mutex::new(HashSet::new()),
+            ended: Mutex::new(false),
+            unassigned_reads: Mutex::new(None),
+        }
+    }
+
+    /// Names of the variables that may be read before they are assigned, with
+    /// the line of each read in the order of the reads, where `visit` fails
+    /// on the first one
+    pub fn unassigned_reads(&self, node: &Node) -> Result<Vec<(String, usize)>> {
+        *self.unassigned_reads.lock().unwrap() = Some(Vec::new());
+        let result = self.visit(node);
+        let reads = self.unassigned_reads.lock().unwrap().take();
+        result.map(|_| reads.unwrap_or_default())
+    }
+
+    // Statements after the end of a block are never run, so they are skipped
+    fn visit_block(&self, children: &[Box<Node>]) -> Result<()> {
+        for child in children {
+            if *self.ended.lock().unwrap() {
+                break;
+            }
+            self.visit(child)?;
+        }
+        Ok(())
+    }
+
+    // Where two branches meet, a variable is assigned when both branches
+    // assign it, ignoring the branches that never get there
+    fn join(
+        &self,
+        before: HashSet<usize>,
+        left: Option<HashSet<usize>>,
+        right: Option<HashSet<usize>>,
+    ) {
+        let assigned = match (left, right) {
+            (Some(left), Some(right)) => &left & &right,
+            (Some(assigned), None) | (None, Some(assigned)) => assigned,
+            (None, None) => {
+                *self.ended.lock().unwrap() = true;
+                before
+            }
+        };
+        *self.assigned.lock().unwrap() = assigned;
+    }
+
+    // Slots assigned at the end of a branch, or `None` if it never gets there
+    fn visit_branch(
+        &self,
+        children: &[Box<Node>],
+        assigned: HashSet<usize>,
+    ) -> Result<Option<HashSet<usize>>> {
+        *self.assigned.lock().unwrap() = assigned;
+        self.visit_block(children)?;
+
+        let ended = std::mem::take(&mut *self.ended.lock().unwrap());
+        Ok((!ended).then(|| self.assigned.lock().unwrap().clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        nodes::expressionindexer::ExpressionIndexer,
+        parsers::{lexer::Lexer, parser::Parser},
+    };
+
+    fn check(script: &str) -> Result<()> {
+        let tokens = Lexer::new(script.to_string()).tokenize().unwrap();
+        let nodes = Parser::new(tokens).parse().unwrap();
+        ExpressionIndexer::new().visit(&nodes);
+        InitializationChecker::new().visit(&nodes)
+    }
+
+    #[test]
+    fn test_assigned_before_read() {
+        assert!(check("x = 1; y = x + 1").is_ok());
+        assert!(check("y = x + 1").is_err());
+        assert!(check("x = x + 1").is_err());
+        assert!(check("x = 1; x += 1").is_ok());
+    }
+
+    #[test]
+    fn test_assigned_on_one_branch() {
+        let err = check(
+            "
+            spot = 120
+            if spot > 100 then
+                units = 10
+            end
+            total = units * spot
+            ",
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("units"));
+
+        assert!(check(
+            "
+            spot = 120
+            if spot > 100 then units = 10 else units = 0 end
+            total = units * spot
+            "
+        )
+        .is_ok());
+        assert!(check(
+            "
+            spot = 120
+            if spot > 100 { units = 10 } else if spot > 50 { units = 5 }
+            total = units * spot
+            "
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_unassigned_reads() {
+        let tokens = Lexer::new("y = x + 1\nif y > 0 then z = 1 end\nw = z + x".to_string())
+            .tokenize()
+            .unwrap();
+        let nodes = Parser::new(tokens).parse().unwrap();
+        ExpressionIndexer::new().visit(&nodes);
+        let reads = InitializationChecker::new()
+            .unassigned_reads(&nodes)
+            .unwrap();
+        let reads: Vec<_> = reads
+            .iter()
+            .map(|(name, line)| (name.as_str(), *line))
+            .collect();
+        assert_eq!(reads, vec![("x", 1), ("z", 3), ("x", 3)]);
+    }
+
+    #[test]
+    fn test_local_declarations() {
+        assert!(check("local x; y = x").is_err());
+        assert!(check("local x; x = 1; y = x").is_ok());
+        // The shadowing local is not assigned, the outer `x` is
+        assert!(check("x = 1; if x > 0 then local x; y = x end").is_err());
+        assert!(check("x = 1; if x > 0 then local x; y = 1 end; z = x").is_ok());
+    }
+
+    #[test]
+    fn test_functions() {
+        assert!(check(
+            "
+            function f(a) {
+                if a > 0 { return 1 }
+                b = a
+                return b
+            }
+            "
+        )
+        .is_ok());
+        assert!(check(
+            "
+            function f(a) {
+                if a > 0 { b = 1 }
+                return b
+            }
+            "
+        )
+        .is_err());
+        // A branch that returns does not reach the statements after the `if`
+        assert!(check(
+            "
+            function f(a) {
+                if a > 0 then b = 1 else return 0 end
+                return b
+            }
+            "
+        )
+        .is_ok());
+        // Globals read in a function are checked when it runs
+        assert!(check("function f() { return g }").is_ok());
+    }
+
+    #[test]
+    fn test_try_catch() {
+        // Both blocks assign `x`
+        assert!(check("try x = 1 catch x = 0 end; y = x").is_ok());
+        // The error variable is assigned in the catch block only
+        assert!(check("try x = 1 catch e\n y = e end").is_ok());
+        assert!(check("try x = 1 catch e\n y = 0 end; z = e").is_err());
+        // A failing try block may not have assigned `x`
+        assert!(check("try x = 1 catch e\n y = 0 end; z = x").is_err());
+        assert!(check("try x = 1 catch e\n exit end; z = x").is_ok());
+    }
+
+    #[test]
+    fn test_for_loop() {
+        assert!(check("total = 0; for x in [1, 2] do total += x end; y = total").is_ok());
+        // The loop variable is assigned in the body only
+        assert!(check("for x in [1, 2] do y = x end; z = x").is_err());
+        // The body may not run
+        assert!(check("for x in [] do y = x end; z = y").is_err());
+        assert!(check("for x in xs do y = x end").is_err());
+        assert!(check("m = {a: 1}; for k, v in m do y = v end").is_ok());
+        assert!(check("m = {a: 1}; for k, v in m do y = v end; z = k").is_err());
+    }
+
+    #[test]
+    fn test_match() {
+        let script = |cases: &str| format!("s = 1; match s with {} end; y = x", cases);
+        assert!(check(&script("case 1 then x = 1 case 2 then x = 2 else x = 3")).is_ok());
+        assert!(check(&script("case 1 then x = 1 case 2 then exit else x = 3")).is_ok());
+        // No case may match
+        assert!(check(&script("case 1 then x = 1 case 2 then x = 2")).is_err());
+        assert!(check(&script("case 1 then x = 1 case 2 then y = 2 else x = 3")).is_err());
+        assert!(check("match y with case 1 then x = 1 end").is_err());
+    }
+}
@@ -0,0 +1,805 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    str::FromStr,
+    sync::Mutex,
+};
+
+use super::{
+    expressionindexer::ExpressionIndexer,
+    initializationchecker::InitializationChecker,
+    node::{ExpressionTree, Node},
+    optimizer::Optimizer,
+    traits::NodeVisitor,
+};
+use crate::utils::errors::{Result, ScriptingError};
+
+/// The checks of the linter, each known by a code such as `L001`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lint {
+    /// `L001`: a value assigned to a variable but never read, as the variable
+    /// is never read or the value is overwritten first
+    UnusedAssignment,
+    /// `L002`: a variable that may be read before it is assigned
+    UnassignedRead,
+    /// `L003`: a branch that never runs, as its condition is a constant
+    UnreachableBranch,
+    /// `L005`: a condition that is always true or always false, e.g. `x == x`
+    ConstantCondition,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 4] = [
+        Lint::UnusedAssignment,
+        Lint::UnassignedRead,
+        Lint::UnreachableBranch,
+        Lint::ConstantCondition,
+    ];
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Lint::UnusedAssignment => "L001",
+            Lint::UnassignedRead => "L002",
+            Lint::UnreachableBranch => "L003",
+            Lint::ConstantCondition => "L005",
+        }
+    }
+
+    /// The severity of the lint unless configured otherwise. Reads before
+    /// assignments are errors, as the runner rejects those scripts.
+    pub fn default_severity(self) -> Severity {
+        match self {
+            Lint::UnassignedRead => Severity::Error,
+            _ => Severity::Warning,
+        }
+    }
+}
+
+impl FromStr for Lint {
+    type Err = ScriptingError;
+
+    /// Parses a lint code, e.g. `L001`
+    fn from_str(code: &str) -> Result<Self> {
+        Lint::ALL
+            .into_iter()
+            .find(|lint| lint.code() == code)
+            .ok_or_else(|| ScriptingError::EvaluationError(format!("Unknown lint {}", code)))
+    }
+}
+
+/// How a lint is reported: `allow` turns it off
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Allow,
+    Warning,
+    Error,
+}
+
+impl FromStr for Severity {
+    type Err = ScriptingError;
+
+    /// Parses `allow`, `warning` or `error`
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "allow" => Ok(Severity::Allow),
+            "warning" => Ok(Severity::Warning),
+            "error" => Ok(Severity::Error),
+            _ => Err(ScriptingError::EvaluationError(format!(
+                "Unknown severity {}",
+                name
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Allow => write!(f, "allow"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A problem found by the linter and the line of the script where it is, e.g.
+/// `warning[L001] at line 3: Variable x is assigned but never read`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub lint: Lint,
+    pub severity: Severity,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}[{}] at line {}: {}",
+            self.severity,
+            self.lint.code(),
+            self.line,
+            self.message
+        )
+    }
+}
+
+// The stores that may be read at some point of a block, by variable name, or
+// `None` where the block has already ended, e.g. after a `return`
+type Live = Option<HashMap<String, HashSet<usize>>>;
+
+// A value assigned to a variable, and whether it may be read
+struct Store {
+    name: String,
+    line: usize,
+    read: bool,
+    overwritten: bool,
+}
+
+// The values assigned by the script or by the body of a function, and the
+// names it reads
+struct Usage {
+    function: Option<String>,
+    stores: Vec<Store>,
+    live: Live,
+    read: HashSet<String>,
+    // Globals read by the functions of the script
+    globals: HashSet<String>,
+    // What is live at the `break` and `continue` statements of the loops
+    // being visited
+    loops: Vec<(Live, Live)>,
+}
+
+impl Usage {
+    fn new(function: Option<String>) -> Self {
+        Usage {
+            function,
+            stores: Vec::new(),
+            live: Some(HashMap::new()),
+            read: HashSet::new(),
+            globals: HashSet::new(),
+            loops: Vec::new(),
+        }
+    }
+
+    fn read(&mut self, name: &str) {
+        self.read.insert(name.to_string());
+        let live = self.live.as_ref().and_then(|live| live.get(name));
+        for &store in live.into_iter().flatten() {
+            self.stores[store].read = true;
+        }
+    }
+
+    // The new value of the variable replaces those that may be read
+    fn store(&mut self, name: &str, line: usize) {
+        let store = self.stores.len();
+        if let Some(live) = self.live.as_mut() {
+            let replaced = live.insert(name.to_string(), HashSet::from([store]));
+            for replaced in replaced.into_iter().flatten() {
+                self.stores[replaced].overwritten = true;
+            }
+        }
+        self.stores.push(Store {
+            name: name.to_string(),
+            line,
+            read: false,
+            overwritten: false,
+        });
+    }
+}
+
+// What may be live after either of two paths
+fn join(left: Live, right: Live) -> Live {
+    match (left, right) {
+        (Some(mut left), Some(right)) => {
+            for (name, stores) in right {
+                left.entry(name).or_default().extend(stores);
+            }
+            Some(left)
+        }
+        (left, right) => left.or(right),
+    }
+}
+
+/// Reports likely mistakes in a parsed script without running it: see
+/// [`Lint`] for the checks. Each lint has a severity, which
+/// [`Linter::with_severity`] changes.
+///
+/// Variables are told apart by name. Each value assigned to a variable is
+/// followed along the branches and loops of the script until it is read or
+/// replaced. Functions only assign variables of their own, so a value a
+/// function assigns and never reads is reported as well.
+pub struct Linter {
+    severities: HashMap<Lint, Severity>,
+    diagnostics: Mutex<Vec<Diagnostic>>,
+    // The script, then the bodies of the functions being visited
+    scopes: Mutex<Vec<Usage>>,
+}
+
+impl Default for Linter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NodeVisitor for Linter {
+    type Output = ();
+    fn visit(&self, node: &Node) {
+        match node {
+            Node::Variable(children, name, _, _) => {
+                self.scope(|usage| usage.read(name));
+                self.visit_all(children);
+            }
+
+            Node::Assign(children) | Node::Local(children) => {
+                // The value is read before the target is written
+                self.visit_all(children.get(1..).unwrap_or_default());
+                match children.first().map(|target| target.as_ref()) {
+                    // `local x` declares `x` without a value
+                    Some(Node::Variable(_, name, _, line)) if children.len() > 1 => {
+                        self.scope(|usage| usage.store(name, *line));
+                    }
+                    Some(Node::Variable(..)) => (),
+                    Some(target) => self.visit(target),
+                    None => (),
+                }
+            }
+
+            Node::If(children, first_else, line) => {
+                self.check_condition(children, *first_else, *line);
+                let Some(condition) = children.first() else {
+                    return;
+                };
+                self.visit(condition);
+                let last_then = first_else
+                    .unwrap_or(children.len())
+                    .clamp(1, children.len());
+                let before = self.scope(|usage| usage.live.clone());
+                let then = self.visit_branch(&children[1..last_then], before.clone());
+                let otherwise = self.visit_branch(&children[last_then..], before);
+                self.scope(|usage| usage.live = join(then, otherwise));
+            }
+
+            Node::Match(children, first_else) => {
+                let Some(value) = children.first() else {
+                    return;
+                };
+                self.visit(value);
+                let last_case = first_else
+                    .unwrap_or(children.len())
+                    .clamp(1, children.len());
+                let before = self.scope(|usage| usage.live.clone());
+                // Without an else block, no case may match
+                let mut joined = self.visit_branch(&children[last_case..], before.clone());
+                for case in &children[1..last_case] {
+                    let Node::Case(case, values) = case.as_ref() else {
+                        continue;
+                    };
+                    let values = (*values).min(case.len());
+                    self.scope(|usage| usage.live = before.clone());
+                    self.visit_all(&case[..values]);
+                    let live = self.scope(|usage| usage.live.clone());
+                    joined = join(joined, self.visit_branch(&case[values..], live));
+                }
+                self.scope(|usage| usage.live = joined);
+            }
+
+            Node::For(children, variables) => {
+                let variables = (*variables).min(children.len());
+                let Some(list) = children.get(variables) else {
+                    return;
+                };
+                self.visit(list);
+
+                let body = &children[variables + 1..];
+                let before = self.scope(|usage| {
+                    usage.loops.push((None, None));
+                    usage.live.clone()
+                });
+                let after = self.visit_branch(body, before.clone());
+                self.scope(|usage| {
+                    let (broken, continued) = usage.loops.pop().unwrap_or_default();
+                    // The values live at the end of an iteration may be read
+                    // by the next one
+                    let next = join(after, continued);
+                    let mut read = HashSet::new();
+                    read_names(body, &mut read);
+                    for name in &read {
+                        let live = next.as_ref().and_then(|live| live.get(name));
+                        for &store in live.into_iter().flatten() {
+                            usage.stores[store].read = true;
+                        }
+                    }
+                    // The body may not run at all
+                    usage.live = join(join(before, next), broken);
+                });
+            }
+
+            Node::Break | Node::Continue => self.scope(|usage| {
+                let live = usage.live.take();
+                if let Some((broken, continued)) = usage.loops.last_mut() {
+                    match node {
+                        Node::Break => *broken = join(broken.take(), live),
+                        _ => *continued = join(continued.take(), live),
+                    }
+                }
+            }),
+
+            Node::Return(children, _) | Node::Abort(children, _) => {
+                self.visit_all(children);
+                self.scope(|usage| usage.live = None);
+            }
+            Node::Exit(_) => self.scope(|usage| usage.live = None),
+
+            Node::Try(children, catch_index, _) => {
+                let catch_index = (*catch_index).min(children.len());
+                let (before, first) = self.scope(|usage| (usage.live.clone(), usage.stores.len()));
+                let tried = self.visit_branch(&children[..catch_index], before.clone());
+
+                // The try block may fail after any of its assignments
+                let caught = self.scope(|usage| {
+                    let mut caught = join(before, tried.clone()).unwrap_or_default();
+                    for (index, store) in usage.stores.iter().enumerate().skip(first) {
+                        caught.entry(store.name.clone()).or_default().insert(index);
+                    }
+                    Some(caught)
+                });
+                let caught = self.visit_branch(&children[catch_index..], caught);
+                self.scope(|usage| usage.live = join(tried, caught));
+            }
+
+            Node::Function(children, name, params, _) => {
+                self.scopes
+                    .lock()
+                    .unwrap()
+                    .push(Usage::new(Some(name.clone())));
+                self.visit_all(children);
+
+                let Some(usage) = self.scopes.lock().unwrap().pop() else {
+                    return;
+                };
+                self.report_unused(&usage);
+                // The other variables the body reads are globals, which
+                // calls read at any point of the script
+                self.scope(|outer| {
+                    outer.globals.extend(usage.read.into_iter().filter(|name| {
+                        !usage.stores.iter().any(|store| &store.name == name)
+                            && !params.contains(name)
+                    }))
+                });
+            }
+
+            _ if node.is_leaf() => (),
+            _ => self.visit_all(node.children()),
+        }
+    }
+}
+
+// The names of the variables the statements read, assignments aside
+fn read_names(nodes: &[ExpressionTree], names: &mut HashSet<String>) {
+    for node in nodes {
+        match node.as_ref() {
+            Node::Variable(children, name, _, _) => {
+                names.insert(name.clone());
+                read_names(children, names);
+            }
+            Node::Assign(children) | Node::Local(children) => {
+                let target = match children.first().map(|target| target.as_ref()) {
+                    Some(Node::Variable(..)) => 1,
+                    _ => 0,
+                };
+                read_names(children.get(target..).unwrap_or_default(), names);
+            }
+            node if node.is_leaf() => (),
+            node => read_names(node.children(), names),
+        }
+    }
+}
+
+impl Linter {
+    pub fn new() -> Self {
+        Linter {
+            severities: HashMap::new(),
+            diagnostics: Mutex::new(Vec::new()),
+            scopes: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Reports `lint` with `severity`, or not at all with [`Severity::Allow`]
+    pub fn with_severity(mut self, lint: Lint, severity: Severity) -> Self {
+        self.severities.insert(lint, severity);
+        self
+    }
+
+    /// The diagnostics of a parsed script in the order of their lines, leaving
+    /// out the lints allowed
+    pub fn lint(&self, node: &Node) -> Result<Vec<Diagnostic>> {
+        *self.scopes.lock().unwrap() = vec![Usage::new(None)];
+        self.visit(node);
+        if let Some(usage) = self.scopes.lock().unwrap().pop() {
+            self.report_unused(&usage);
+        }
+
+        // Reads are checked on slots, so on an indexed copy of the script
+        let indexed = Box::new(node.clone());
+        let indexer = ExpressionIndexer::new();
+        indexer.visit(&indexed);
+        indexer.check_shadowing()?;
+        let mut reported = HashSet::new();
+        for (name, line) in InitializationChecker::new().unassigned_reads(&indexed)? {
+            if reported.insert(name.clone()) {
+                self.report(
+                    Lint::UnassignedRead,
+                    line,
+                    format!("Variable {} may be read before it is assigned", name),
+                );
+            }
+        }
+
+        let mut diagnostics = std::mem::take(&mut *self.diagnostics.lock().unwrap());
+        diagnostics.sort_by_key(|diagnostic| diagnostic.line);
+        Ok(diagnostics)
+    }
+
+    fn visit_all(&self, children: &[ExpressionTree]) {
+        children.iter().for_each(|child| self.visit(child));
+    }
+
+    // Visits a branch from what is live before it, returning what is live
+    // after it
+    fn visit_branch(&self, children: &[ExpressionTree], before: Live) -> Live {
+        self.scope(|usage| usage.live = before);
+        self.visit_all(children);
+        self.scope(|usage| usage.live.take())
+    }
+
+    // Runs `f` on the innermost scope, the script when none is open
+    fn scope<T>(&self, f: impl FnOnce(&mut Usage) -> T) -> T {
+        let mut scopes = self.scopes.lock().unwrap();
+        if scopes.is_empty() {
+            scopes.push(Usage::new(None));
+        }
+        let last = scopes.len() - 1;
+        f(&mut scopes[last])
+    }
+
+    fn report(&self, lint: Lint, line: usize, message: String) {
+        let severity = self
+            .severities
+            .get(&lint)
+            .copied()
+            .unwrap_or(lint.default_severity());
+        if severity != Severity::Allow {
+            self.diagnostics.lock().unwrap().push(Diagnostic {
+                lint,
+                severity,
+                line,
+                message,
+            });
+        }
+    }
+
+    // Reports the variables never read, at their first assignment, then the
+    // values never read of the other variables
+    fn report_unused(&self, usage: &Usage) {
+        let mut reported = HashSet::new();
+        for store in &usage.stores {
+            let name = &store.name;
+            if store.read || usage.globals.contains(name) {
+                continue;
+            }
+            let message = match usage.read.contains(name) {
+                false if !reported.insert(name) => continue,
+                false => format!("Variable {} is assigned but never read", name),
+                true if store.overwritten => format!(
+                    "The value assigned to {} is overwritten before it is read",
+                    name
+                ),
+                true => format!("The value assigned to {} is never read", name),
+            };
+            let message = match &usage.function {
+                Some(function) => format!("{} in function {}", message, function),
+                None => message,
+            };
+            self.report(Lint::UnusedAssignment, store.line, message);
+        }
+    }
+
+    // A condition that is always true or false leaves a branch that never
+    // runs, unless that branch is empty
+    fn check_condition(&self, children: &[ExpressionTree], first_else: Option<usize>, line: usize) {
+        let Some(condition) = children.first() else {
+            return;
+        };
+        let Some(value) = constant_condition(condition) else {
+            return;
+        };
+        let last_then = first_else
+            .unwrap_or(children.len())
+            .clamp(1, children.len());
+        let (dead, branch) = match value {
+            true => (&children[last_then..], "else"),
+            false => (&children[1..last_then], "then"),
+        };
+        let message = match dead.is_empty() {
+            true => (
+                Lint::ConstantCondition,
+                format!("Condition `{}` is always {}", describe(condition), value),
+            ),
+            false => (
+                Lint::UnreachableBranch,
+                format!(
+                    "The {} branch of `if {}` never runs, the condition is always {}",
+                    branch,
+                    describe(condition),
+                    value
+                ),
+            ),
+        };
+        self.report(message.0, line, message.1);
+    }
+}
+
+// The value of a condition when it folds to a constant, once the operands
+// compared with themselves are replaced by the result
+fn constant_condition(condition: &Node) -> Option<bool> {
+    match *Optimizer::new().visit(&without_self_comparisons(condition)) {
+        Node::True => Some(true),
+        Node::False => Some(false),
+        _ => None,
+    }
+}
+
+// Replaces the comparisons of an operand with itself by their value, e.g.
+// `x == x` by `true`
+fn without_self_comparisons(node: &Node) -> Node {
+    let comparison = match node {
+        Node::Equal(children)
+        | Node::SuperiorOrEqual(children)
+        | Node::InferiorOrEqual(children) => Some((children, true)),
+        Node::NotEqual(children) | Node::Superior(children) | Node::Inferior(children) => {
+            Some((children, false))
+        }
+        _ => None,
+    };
+    match comparison {
+        Some((children, value))
+            if children.len() == 2
+                && children[0].without_lines() == children[1].without_lines()
+                && is_pure(&children[0]) =>
+        {
+            match value {
+                true => Node::True,
+                false => Node::False,
+            }
+        }
+        _ if node.is_leaf() => node.clone(),
+        _ => {
+            let mut node = node.clone();
+            let children = node.children_mut();
+            *children = children
+                .iter()
+                .map(|child| Box::new(without_self_comparisons(child)))
+                .collect();
+            node
+        }
+    }
+}
+
+// Whether an expression has the same value each time it is evaluated, which
+// calls to host functions and the current time may not
+fn is_pure(node: &Node) -> bool {
+    match node {
+        Node::Call(..) | Node::Now(_) => false,
+        _ if node.is_leaf() => true,
+        _ => node.children().iter().all(|child| is_pure(child)),
+    }
+}
+
+// The expression about as written, for messages
+fn describe(node: &Node) -> String {
+    let binary = |symbol: &str, children: &[ExpressionTree]| match children {
+        [left, right] => format!("{} {} {}", operand(left), symbol, operand(right)),
+        _ => "...".to_string(),
+    };
+    let unary = |prefix: &str, children: &[ExpressionTree]| match children {
+        [value] => format!("{}{}", prefix, operand(value)),
+        _ => "...".to_string(),
+    };
+    match node {
+        Node::Variable(_, name, _, _) => name.clone(),
+        Node::Field(children, name) => format!("{}.{}", unary("", children), name),
+        Node::Number(value) => value.to_string(),
+        Node::Decimal(value) => value.to_string(),
+        Node::Money(amount, currency) => format!("{} {}", amount, currency),
+        Node::String(value) => format!("{:?}", value),
+        Node::True => "true".to_string(),
+        Node::False => "false".to_string(),
+        Node::Null => "null".to_string(),
+        Node::Not(children) => unary("not ", children),
+        Node::UnaryMinus(children) => unary("-", children),
+        Node::Add(children) => binary("+", children),
+        Node::Subtract(children) => binary("-", children),
+        Node::Multiply(children) => binary("*", children),
+        Node::Divide(children) => binary("/", children),
+        Node::Modulo(children) => binary("%", children),
+        Node::Equal(children) => binary("==", children),
+        Node::NotEqual(children) => binary("!=", children),
+        Node::Superior(children) => binary(">", children),
+        Node::Inferior(children) => binary("<", children),
+        Node::SuperiorOrEqual(children) => binary(">=", children),
+        Node::InferiorOrEqual(children) => binary("<=", children),
+        Node::And(children) => binary("and", children),
+        Node::Or(children) => binary("or", children),
+        Node::Coalesce(children) => binary("??", children),
+        Node::Call(_, name) => format!("{}(...)", name),
+        _ => "...".to_string(),
+    }
+}
+
+// Operations are put in parentheses inside another one
+fn operand(node: &Node) -> String {
+    match node {
+        Node::Add(_)
+        | Node::Subtract(_)
+        | Node::Multiply(_)
+        | Node::Divide(_)
+        | Node::Modulo(_)
+        | Node::Equal(_)
+        | Node::NotEqual(_)
+        | Node::Superior(_)
+        | Node::Inferior(_)
+        | Node::SuperiorOrEqual(_)
+        | Node::InferiorOrEqual(_)
+        | Node::And(_)
+        | Node::Or(_)
+        | Node::Coalesce(_) => format!("({})", describe(node)),
+        _ => describe(node),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::{lexer::Lexer, parser::Parser};
+
+    fn lint_with(linter: Linter, script: &str) -> Vec<String> {
+        let tokens = Lexer::new(script.to_string()).tokenize().unwrap();
+        let nodes = Parser::new(tokens)
+            .with_host_functions(&["Spot", "Notify"])
+            .parse()
+            .unwrap();
+        linter
+            .lint(&nodes)
+            .unwrap()
+            .iter()
+            .map(|diagnostic| diagnostic.to_string())
+            .collect()
+    }
+
+    fn lint(script: &str) -> Vec<String> {
+        lint_with(Linter::new(), script)
+    }
+
+    #[test]
+    fn test_unused_assignments() {
+        assert_eq!(
+            lint("x = 1\ny = 2\nz = y + 1\nNotify(z)"),
+            vec!["warning[L001] at line 1: Variable x is assigned but never read"]
+        );
+        assert!(lint("total = 0\ntotal += 1\nNotify(\"{total}\")").is_empty());
+        // Functions read globals, and assign variables of their own
+        assert!(lint("rate = 2\nfunction f(x) return x * rate end\nNotify(f(1))").is_empty());
+        assert_eq!(
+            lint("function f(x)\n unused = x\n return x\nend\nNotify(f(1))"),
+            vec![
+                "warning[L001] at line 2: Variable unused is assigned but never read in function f"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_overwritten_values() {
+        assert_eq!(
+            lint("x = 1\nx = 2\nNotify(x)"),
+            vec![
+                "warning[L001] at line 1: The value assigned to x is overwritten before it is read"
+            ]
+        );
+        assert_eq!(
+            lint("x = 1\nNotify(x)\nx = 2"),
+            vec!["warning[L001] at line 3: The value assigned to x is never read"]
+        );
+        assert_eq!(
+            lint("x = 1\nif Spot() > 1 then x = 2 else x = 3 end\nNotify(x)"),
+            vec![
+                "warning[L001] at line 1: The value assigned to x is overwritten before it is read"
+            ]
+        );
+        // The first value is read when the branch does not run
+        assert!(lint("x = 1\nif Spot() > 1 then x = 2 end\nNotify(x)").is_empty());
+        // A value is read by the next iteration, or after leaving the loop
+        assert!(
+            lint("total = 0\nfor p in [1, 2] do\n  Notify(total)\n  total = p\nend").is_empty()
+        );
+        assert!(lint(
+            "found = 0\nfor p in [1, 2] do\n  if p > 1 then\n    found = p\n    break\n  end\nend\nNotify(found)"
+        )
+        .is_empty());
+        // The try block may fail before the second assignment
+        assert!(
+            lint("x = 1\ntry\n  x = 2\n  x = Spot()\ncatch\n  Notify(x)\nend\nNotify(x)")
+                .is_empty()
+        );
+        assert_eq!(
+            lint("function f(x)\n  y = x\n  y = 2 * x\n  return y\nend\nNotify(f(1))"),
+            vec![
+                "warning[L001] at line 2: The value assigned to y is overwritten before it is read in function f"
+            ]
+        );
+        // Functions read the globals whenever they are called
+        assert!(
+            lint("rate = 1\nrate = 2\nfunction f(x) return x * rate end\nNotify(f(1))").is_empty()
+        );
+    }
+
+    #[test]
+    fn test_unassigned_reads() {
+        assert_eq!(
+            lint("if spot > 1 then units = 1 end\nNotify(units)\nNotify(units)"),
+            vec![
+                "error[L002] at line 1: Variable spot may be read before it is assigned",
+                "error[L002] at line 2: Variable units may be read before it is assigned"
+            ]
+        );
+        assert!(lint("units = 1\nNotify(units)").is_empty());
+    }
+
+    #[test]
+    fn test_constant_conditions() {
+        assert_eq!(
+            lint("x = 1\nif x == x then Notify(x) end"),
+            vec!["warning[L005] at line 2: Condition `x == x` is always true"]
+        );
+        assert_eq!(
+            lint("x = 1\nif x != x or 1 > 2 then Notify(x) end"),
+            vec![
+                "warning[L003] at line 2: The then branch of `if (x != x) or (1 > 2)` never runs, the condition is always false"
+            ]
+        );
+        assert_eq!(
+            lint("x = 1\nif x + 0 * 2 >= x + 0 * 2 { Notify(x) } else { Notify(0) }"),
+            vec![
+                "warning[L003] at line 2: The else branch of `if (x + (0 * 2)) >= (x + (0 * 2))` never runs, the condition is always true"
+            ]
+        );
+        // The operands are compared wherever they are written
+        assert_eq!(
+            lint("x = 1\nif (x ==\n    x) then Notify(x) end"),
+            vec!["warning[L005] at line 2: Condition `x == x` is always true"]
+        );
+        // Host functions may return a different value each time
+        assert!(lint("if Spot() == Spot() then Notify(1) end").is_empty());
+        assert!(lint("x = 1\nif x > 0 then Notify(x) end").is_empty());
+    }
+
+    #[test]
+    fn test_severities() {
+        let linter = Linter::new()
+            .with_severity(Lint::UnusedAssignment, Severity::Error)
+            .with_severity(Lint::ConstantCondition, Severity::Allow);
+        assert_eq!(
+            lint_with(linter, "x = 1\ny = 2\nif y == y then z = 3 end"),
+            vec![
+                "error[L001] at line 1: Variable x is assigned but never read",
+                "error[L001] at line 3: Variable z is assigned but never read"
+            ]
+        );
+
+        assert_eq!("L003".parse::<Lint>().unwrap(), Lint::UnreachableBranch);
+        assert!("L999".parse::<Lint>().is_err());
+        assert_eq!("allow".parse::<Severity>().unwrap(), Severity::Allow);
+        assert!("fatal".parse::<Severity>().is_err());
+    }
+}
@@ -1,4 +1,7 @@
 pub mod expressionevaluator;
 pub mod expressionindexer;
+pub mod initializationchecker;
+pub mod linter;
 pub mod node;
+pub mod optimizer;
 pub mod traits;
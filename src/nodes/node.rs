@@ -1,16 +1,132 @@
 use std::sync::OnceLock;
 
+use crate::utils::{
+    decimal::Decimal,
+    time::{Date, DateTime, Duration},
+};
+
 use super::traits::{ConstVisitable, NodeConstVisitor, NodeVisitor, Visitable};
 
 pub type ExpressionTree = Box<Node>;
 
+/// Layout of the frame created for each call of a user-defined function:
+/// `size` local slots, the first ones holding the arguments, and the
+/// `(global, local)` slot pairs of the globals the body reads, copied into the
+/// frame when the call starts.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FrameLayout {
+    pub size: usize,
+    pub captures: Vec<(usize, usize)>,
+}
+
+/// The math builtins taking a single number, evaluated by the same node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MathFunction {
+    Abs,
+    Sign,
+    Floor,
+    Ceil,
+    Sqrt,
+    Log10,
+    Sin,
+    Cos,
+    Tan,
+    Ncdf,
+    Npdf,
+}
+
+/// The technical indicators computed over the price history of a symbol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Indicator {
+    Sma,
+    Ema,
+    Rsi,
+    Bollinger,
+    High,
+    Low,
+}
+
+/// The statistics computed over a list of numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Statistic {
+    Sum,
+    Mean,
+    Median,
+    Variance,
+    Stdev,
+    Percentile,
+    Correlation,
+    Cumsum,
+    MaxDrawdown,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Node {
     Base(Vec<ExpressionTree>),
 
-    // variables
-    Variable(Vec<ExpressionTree>, String, OnceLock<usize>),
-    Constant(f64),
+    // variables: the name, the slot set by the indexer and the line
+    Variable(Vec<ExpressionTree>, String, OnceLock<usize>, usize),
+    // `local` declaration: the variable and its optional initial value
+    Local(Vec<ExpressionTree>),
+    // `object.name`, the object being the only child
+    Field(Vec<ExpressionTree>, String),
+
+    // lists: literal, `xs[i]`, and `xs[start:end]` whose children are the
+    // list then the bounds that are given, as flagged
+    List(Vec<ExpressionTree>),
+    Index(Vec<ExpressionTree>),
+    Slice(Vec<ExpressionTree>, bool, bool),
+    Len(Vec<ExpressionTree>),
+    Append(Vec<ExpressionTree>),
+
+    // map literal: the values, in the order of the keys
+    Map(Vec<ExpressionTree>, Vec<String>),
+    // amount of money: the amount and the currency code, `100 USD`
+    Money(Decimal, String),
+    // time literals: `2024-05-04`, `2024-05-04T09:30` and `30d`
+    Date(Date),
+    DateTime(DateTime),
+    Duration(Duration),
+    String(String),
+    Null,
+    // numbers: the parser emits every number literal as an exact decimal;
+    // floating point numbers only come from the values the optimizer folds,
+    // such as `ln(2)`, and evaluate as they are
+    Decimal(Decimal),
+    Number(f64),
+    // interpolated string: its text parts and expressions, in order
+    Interpolation(Vec<ExpressionTree>),
+
+    // formatting: `format(x, spec)`, `format_currency(x, currency[, decimals])`
+    // and `round_to(x, digits[, mode])`, also `round(x[, digits])`
+    Format(Vec<ExpressionTree>),
+    FormatCurrency(Vec<ExpressionTree>),
+    RoundTo(Vec<ExpressionTree>),
+
+    // money: `convert(amount, currency)`
+    Convert(Vec<ExpressionTree>),
+
+    // time: `today()`, `now()`, `day_of_month(date)` and `is_weekday(date)`
+    Today(Vec<ExpressionTree>),
+    Now(Vec<ExpressionTree>),
+    DayOfMonth(Vec<ExpressionTree>),
+    IsWeekday(Vec<ExpressionTree>),
+
+    // calendars: `is_business_day(date[, calendar])`,
+    // `add_business_days(date, days[, calendar])`,
+    // `adjust(date, convention[, calendar])`, `end_of_month(date)` and
+    // `is_market_open(market[, time])`
+    IsBusinessDay(Vec<ExpressionTree>),
+    AddBusinessDays(Vec<ExpressionTree>),
+    Adjust(Vec<ExpressionTree>),
+    EndOfMonth(Vec<ExpressionTree>),
+    IsMarketOpen(Vec<ExpressionTree>),
+
+    // price history: indicators such as `sma(symbol, window)` or
+    // `bollinger(symbol, window[, width])`, and `crosses_above(a, b)`
+    Indicator(Vec<ExpressionTree>, Indicator),
+    CrossesAbove(Vec<ExpressionTree>),
+    Statistic(Vec<ExpressionTree>, Statistic),
 
     // math
     Add(Vec<ExpressionTree>),
@@ -23,6 +139,11 @@ pub enum Node {
     Exp(Vec<ExpressionTree>),
     Pow(Vec<ExpressionTree>),
     Ln(Vec<ExpressionTree>),
+    // `x % y` and `mod(x, y)`, `clamp(x, low, high)`, and the builtins of a
+    // single number such as `abs(x)` or `ncdf(x)`
+    Modulo(Vec<ExpressionTree>),
+    Clamp(Vec<ExpressionTree>),
+    Math(Vec<ExpressionTree>, MathFunction),
 
     // unary
     UnaryPlus(Vec<ExpressionTree>),
@@ -40,9 +161,50 @@ pub enum Node {
     Inferior(Vec<ExpressionTree>),
     SuperiorOrEqual(Vec<ExpressionTree>),
     InferiorOrEqual(Vec<ExpressionTree>),
+    // `value ?? default`, the default is only evaluated when the value is null
+    Coalesce(Vec<ExpressionTree>),
+    // `select(condition, a, b)`, only the chosen value is evaluated
+    Select(Vec<ExpressionTree>),
 
     // control flow
-    If(Vec<ExpressionTree>, Option<usize>),
+    // `if`: the condition, the then statements, then the else statements from
+    // the given index if there is an else block, and the line of the `if`
+    If(Vec<ExpressionTree>, Option<usize>, usize),
+    // `for x in xs` or `for k, v in m`: the given number of loop variables,
+    // the list or map, then the body
+    For(Vec<ExpressionTree>, usize),
+    // `match value with case ... end`: the value, the cases, then the else
+    // statements from the given index if there is an else block
+    Match(Vec<ExpressionTree>, Option<usize>),
+    // a case of a match: its values, then the body from the given index
+    Case(Vec<ExpressionTree>, usize),
+    // try block followed by the catch block starting at the given index, and
+    // the variable receiving the error, if the catch block names one
+    Try(Vec<ExpressionTree>, usize, Option<ExpressionTree>),
+
+    // functions: body, name, parameters and the frame layout set by the indexer
+    Function(
+        Vec<ExpressionTree>,
+        String,
+        Vec<String>,
+        OnceLock<FrameLayout>,
+    ),
+    Call(Vec<ExpressionTree>, String),
+    // a past result of a host function: the arguments of the call, then when
+    Lookback(Vec<ExpressionTree>, String),
+
+    // early termination, with the line of the statement: `return` and its
+    // optional value, `exit` and `abort` and its reason
+    Return(Vec<ExpressionTree>, usize),
+    Exit(usize),
+    Abort(Vec<ExpressionTree>, usize),
+    Break,
+    Continue,
+
+    // pre-conditions, with the line of the statement: the condition, the
+    // message and the optional notification flag
+    Require(Vec<ExpressionTree>, usize),
+    Assert(Vec<ExpressionTree>, usize),
 }
 
 impl Node {
@@ -67,11 +229,47 @@ impl Node {
     }
 
     pub fn new_variable(name: String) -> Node {
-        Node::Variable(Vec::new(), name, OnceLock::new())
+        Node::Variable(Vec::new(), name, OnceLock::new(), 0)
     }
 
     pub fn new_variable_with_id(name: String, id: usize) -> Node {
-        Node::Variable(Vec::new(), name, id.into())
+        Node::Variable(Vec::new(), name, id.into(), 0)
+    }
+
+    pub fn new_local() -> Node {
+        Node::Local(Vec::new())
+    }
+
+    pub fn new_field(name: String) -> Node {
+        Node::Field(Vec::new(), name)
+    }
+
+    pub fn new_list() -> Node {
+        Node::List(Vec::new())
+    }
+
+    pub fn new_index() -> Node {
+        Node::Index(Vec::new())
+    }
+
+    pub fn new_slice(has_start: bool, has_end: bool) -> Node {
+        Node::Slice(Vec::new(), has_start, has_end)
+    }
+
+    pub fn new_len() -> Node {
+        Node::Len(Vec::new())
+    }
+
+    pub fn new_append() -> Node {
+        Node::Append(Vec::new())
+    }
+
+    pub fn new_for(variables: usize) -> Node {
+        Node::For(Vec::new(), variables)
+    }
+
+    pub fn new_map(keys: Vec<String>) -> Node {
+        Node::Map(Vec::new(), keys)
     }
 
     pub fn new_min() -> Node {
@@ -95,7 +293,7 @@ impl Node {
     }
 
     pub fn new_constant(value: f64) -> Node {
-        Node::Constant(value)
+        Node::Number(value)
     }
 
     pub fn new_assign() -> Node {
@@ -131,7 +329,7 @@ impl Node {
     }
 
     pub fn new_if() -> Node {
-        Node::If(Vec::new(), None)
+        Node::If(Vec::new(), None, 0)
     }
 
     pub fn new_unary_plus() -> Node {
@@ -150,6 +348,42 @@ impl Node {
         Node::NotEqual(Vec::new())
     }
 
+    pub fn new_function(name: String, params: Vec<String>) -> Node {
+        Node::Function(Vec::new(), name, params, OnceLock::new())
+    }
+
+    pub fn new_call(name: String) -> Node {
+        Node::Call(Vec::new(), name)
+    }
+
+    pub fn new_return(line: usize) -> Node {
+        Node::Return(Vec::new(), line)
+    }
+
+    pub fn new_abort(line: usize) -> Node {
+        Node::Abort(Vec::new(), line)
+    }
+
+    pub fn new_require(line: usize) -> Node {
+        Node::Require(Vec::new(), line)
+    }
+
+    pub fn new_assert(line: usize) -> Node {
+        Node::Assert(Vec::new(), line)
+    }
+
+    pub fn new_string(value: String) -> Node {
+        Node::String(value)
+    }
+
+    pub fn new_coalesce() -> Node {
+        Node::Coalesce(Vec::new())
+    }
+
+    pub fn new_null() -> Node {
+        Node::Null
+    }
+
     pub fn new_true() -> Node {
         Node::True
     }
@@ -165,7 +399,19 @@ impl Node {
             Node::Subtract(children) => children.push(child),
             Node::Multiply(children) => children.push(child),
             Node::Divide(children) => children.push(child),
-            Node::Variable(children, _, _) => children.push(child),
+            Node::Variable(children, _, _, _) => children.push(child),
+            Node::Local(children) => children.push(child),
+            Node::Field(children, _) => children.push(child),
+            Node::List(children) => children.push(child),
+            Node::Index(children) => children.push(child),
+            Node::Slice(children, _, _) => children.push(child),
+            Node::Len(children) => children.push(child),
+            Node::Append(children) => children.push(child),
+            Node::For(children, _) => children.push(child),
+            Node::Map(children, _) => children.push(child),
+            Node::Try(children, _, _) => children.push(child),
+            Node::Match(children, _) => children.push(child),
+            Node::Case(children, _) => children.push(child),
             Node::Assign(children) => children.push(child),
             Node::And(children) => children.push(child),
             Node::Or(children) => children.push(child),
@@ -175,21 +421,101 @@ impl Node {
             Node::SuperiorOrEqual(children) => children.push(child),
             Node::InferiorOrEqual(children) => children.push(child),
             Node::Equal(children) => children.push(child),
-            Node::If(children, _) => children.push(child),
+            Node::If(children, _, _) => children.push(child),
             Node::UnaryPlus(children) => children.push(child),
             Node::UnaryMinus(children) => children.push(child),
             Node::Min(children) => children.push(child),
             Node::Max(children) => children.push(child),
             Node::Exp(children) => children.push(child),
             Node::Ln(children) => children.push(child),
+            Node::Modulo(children) => children.push(child),
+            Node::Clamp(children) => children.push(child),
+            Node::Math(children, _) => children.push(child),
             Node::Pow(children) => children.push(child),
             Node::NotEqual(children) => children.push(child),
+            Node::Coalesce(children) => children.push(child),
+            Node::Select(children) => children.push(child),
+            Node::Interpolation(children) => children.push(child),
+            Node::Format(children) => children.push(child),
+            Node::FormatCurrency(children) => children.push(child),
+            Node::RoundTo(children) => children.push(child),
+            Node::Convert(children) => children.push(child),
+            Node::Today(children) => children.push(child),
+            Node::Now(children) => children.push(child),
+            Node::DayOfMonth(children) => children.push(child),
+            Node::IsWeekday(children) => children.push(child),
+            Node::IsBusinessDay(children) => children.push(child),
+            Node::AddBusinessDays(children) => children.push(child),
+            Node::Adjust(children) => children.push(child),
+            Node::EndOfMonth(children) => children.push(child),
+            Node::IsMarketOpen(children) => children.push(child),
+            Node::Indicator(children, _) => children.push(child),
+            Node::CrossesAbove(children) => children.push(child),
+            Node::Statistic(children, _) => children.push(child),
+            Node::Function(children, _, _, _) => children.push(child),
+            Node::Call(children, _) => children.push(child),
+            Node::Lookback(children, _) => children.push(child),
+            Node::Return(children, _) => children.push(child),
+            Node::Abort(children, _) => children.push(child),
+            Node::Require(children, _) => children.push(child),
+            Node::Assert(children, _) => children.push(child),
             Node::True => panic!("Cannot add child to true node"),
             Node::False => panic!("Cannot add child to false node"),
-            Node::Constant(_) => panic!("Cannot add child to constant node"),
+            Node::Money(..) => panic!("Cannot add child to money node"),
+            Node::Date(_) => panic!("Cannot add child to date node"),
+            Node::DateTime(_) => panic!("Cannot add child to date time node"),
+            Node::Duration(_) => panic!("Cannot add child to duration node"),
+            Node::String(_) => panic!("Cannot add child to string node"),
+            Node::Null => panic!("Cannot add child to null node"),
+            Node::Decimal(_) => panic!("Cannot add child to decimal node"),
+            Node::Number(_) => panic!("Cannot add child to number node"),
+            Node::Exit(_) => panic!("Cannot add child to exit node"),
+            Node::Break => panic!("Cannot add child to break node"),
+            Node::Continue => panic!("Cannot add child to continue node"),
+        }
+    }
+
+    /// A copy of the node whose variables have no line, so that an
+    /// expression compares equal to itself written on another line
+    pub fn without_lines(&self) -> Node {
+        let mut node = self.clone();
+        node.clear_lines();
+        node
+    }
+
+    fn clear_lines(&mut self) {
+        match self {
+            Node::Variable(_, _, _, line) => *line = 0,
+            Node::Try(_, _, Some(binding)) => binding.clear_lines(),
+            _ => (),
+        }
+        if !self.is_leaf() {
+            self.children_mut()
+                .iter_mut()
+                .for_each(|child| child.clear_lines());
         }
     }
 
+    /// Whether the node cannot have children, `children` panics on those
+    pub fn is_leaf(&self) -> bool {
+        matches!(
+            self,
+            Node::True
+                | Node::False
+                | Node::Money(..)
+                | Node::Date(_)
+                | Node::DateTime(_)
+                | Node::Duration(_)
+                | Node::String(_)
+                | Node::Null
+                | Node::Decimal(_)
+                | Node::Number(_)
+                | Node::Exit(_)
+                | Node::Break
+                | Node::Continue
+        )
+    }
+
     pub fn children(&self) -> &Vec<ExpressionTree> {
         match self {
             Node::Base(children) => children,
@@ -197,7 +523,102 @@ impl Node {
             Node::Subtract(children) => children,
             Node::Multiply(children) => children,
             Node::Divide(children) => children,
-            Node::Variable(children, _, _) => children,
+            Node::Variable(children, _, _, _) => children,
+            Node::Local(children) => children,
+            Node::Field(children, _) => children,
+            Node::List(children) => children,
+            Node::Index(children) => children,
+            Node::Slice(children, _, _) => children,
+            Node::Len(children) => children,
+            Node::Append(children) => children,
+            Node::For(children, _) => children,
+            Node::Map(children, _) => children,
+            Node::Try(children, _, _) => children,
+            Node::Match(children, _) => children,
+            Node::Case(children, _) => children,
+            Node::Assign(children) => children,
+            Node::And(children) => children,
+            Node::Or(children) => children,
+            Node::Not(children) => children,
+            Node::Superior(children) => children,
+            Node::Inferior(children) => children,
+            Node::SuperiorOrEqual(children) => children,
+            Node::InferiorOrEqual(children) => children,
+            Node::Equal(children) => children,
+            Node::If(children, _, _) => children,
+            Node::UnaryPlus(children) => children,
+            Node::UnaryMinus(children) => children,
+            Node::Min(children) => children,
+            Node::Max(children) => children,
+            Node::Exp(children) => children,
+            Node::Ln(children) => children,
+            Node::Modulo(children) => children,
+            Node::Clamp(children) => children,
+            Node::Math(children, _) => children,
+            Node::Pow(children) => children,
+            Node::NotEqual(children) => children,
+            Node::Coalesce(children) => children,
+            Node::Select(children) => children,
+            Node::Interpolation(children) => children,
+            Node::Format(children) => children,
+            Node::FormatCurrency(children) => children,
+            Node::RoundTo(children) => children,
+            Node::Convert(children) => children,
+            Node::Today(children) => children,
+            Node::Now(children) => children,
+            Node::DayOfMonth(children) => children,
+            Node::IsWeekday(children) => children,
+            Node::IsBusinessDay(children) => children,
+            Node::AddBusinessDays(children) => children,
+            Node::Adjust(children) => children,
+            Node::EndOfMonth(children) => children,
+            Node::IsMarketOpen(children) => children,
+            Node::Indicator(children, _) => children,
+            Node::CrossesAbove(children) => children,
+            Node::Statistic(children, _) => children,
+            Node::Function(children, _, _, _) => children,
+            Node::Call(children, _) => children,
+            Node::Lookback(children, _) => children,
+            Node::Return(children, _) => children,
+            Node::Abort(children, _) => children,
+            Node::Require(children, _) => children,
+            Node::Assert(children, _) => children,
+            Node::True => panic!("Cannot get children from true node"),
+            Node::False => panic!("Cannot get children from false node"),
+            Node::Money(..) => panic!("Cannot get children from money node"),
+            Node::Date(_) => panic!("Cannot get children from date node"),
+            Node::DateTime(_) => panic!("Cannot get children from date time node"),
+            Node::Duration(_) => panic!("Cannot get children from duration node"),
+            Node::String(_) => panic!("Cannot get children from string node"),
+            Node::Null => panic!("Cannot get children from null node"),
+            Node::Decimal(_) => panic!("Cannot get children from decimal node"),
+            Node::Number(_) => panic!("Cannot get children from number node"),
+            Node::Exit(_) => panic!("Cannot get children from exit node"),
+            Node::Break => panic!("Cannot get children from break node"),
+            Node::Continue => panic!("Cannot get children from continue node"),
+        }
+    }
+
+    pub fn children_mut(&mut self) -> &mut Vec<ExpressionTree> {
+        match self {
+            Node::Base(children) => children,
+            Node::Add(children) => children,
+            Node::Subtract(children) => children,
+            Node::Multiply(children) => children,
+            Node::Divide(children) => children,
+            Node::Variable(children, _, _, _) => children,
+            Node::Local(children) => children,
+            Node::Field(children, _) => children,
+            Node::List(children) => children,
+            Node::Index(children) => children,
+            Node::Slice(children, _, _) => children,
+            Node::Len(children) => children,
+            Node::Append(children) => children,
+            Node::For(children, _) => children,
+            Node::Map(children, _) => children,
+            Node::Try(children, _, _) => children,
+            Node::Match(children, _) => children,
+            Node::Case(children, _) => children,
             Node::Assign(children) => children,
             Node::And(children) => children,
             Node::Or(children) => children,
@@ -207,32 +628,69 @@ impl Node {
             Node::SuperiorOrEqual(children) => children,
             Node::InferiorOrEqual(children) => children,
             Node::Equal(children) => children,
-            Node::If(children, _) => children,
+            Node::If(children, _, _) => children,
             Node::UnaryPlus(children) => children,
             Node::UnaryMinus(children) => children,
             Node::Min(children) => children,
             Node::Max(children) => children,
             Node::Exp(children) => children,
             Node::Ln(children) => children,
+            Node::Modulo(children) => children,
+            Node::Clamp(children) => children,
+            Node::Math(children, _) => children,
             Node::Pow(children) => children,
             Node::NotEqual(children) => children,
+            Node::Coalesce(children) => children,
+            Node::Select(children) => children,
+            Node::Interpolation(children) => children,
+            Node::Format(children) => children,
+            Node::FormatCurrency(children) => children,
+            Node::RoundTo(children) => children,
+            Node::Convert(children) => children,
+            Node::Today(children) => children,
+            Node::Now(children) => children,
+            Node::DayOfMonth(children) => children,
+            Node::IsWeekday(children) => children,
+            Node::IsBusinessDay(children) => children,
+            Node::AddBusinessDays(children) => children,
+            Node::Adjust(children) => children,
+            Node::EndOfMonth(children) => children,
+            Node::IsMarketOpen(children) => children,
+            Node::Indicator(children, _) => children,
+            Node::CrossesAbove(children) => children,
+            Node::Statistic(children, _) => children,
+            Node::Function(children, _, _, _) => children,
+            Node::Call(children, _) => children,
+            Node::Lookback(children, _) => children,
+            Node::Return(children, _) => children,
+            Node::Abort(children, _) => children,
+            Node::Require(children, _) => children,
+            Node::Assert(children, _) => children,
             Node::True => panic!("Cannot get children from true node"),
             Node::False => panic!("Cannot get children from false node"),
-            Node::Constant(_) => panic!("Cannot get children from constant node"),
+            Node::Money(..) => panic!("Cannot get children from money node"),
+            Node::Date(_) => panic!("Cannot get children from date node"),
+            Node::DateTime(_) => panic!("Cannot get children from date time node"),
+            Node::Duration(_) => panic!("Cannot get children from duration node"),
+            Node::String(_) => panic!("Cannot get children from string node"),
+            Node::Null => panic!("Cannot get children from null node"),
+            Node::Decimal(_) => panic!("Cannot get children from decimal node"),
+            Node::Number(_) => panic!("Cannot get children from number node"),
+            Node::Exit(_) => panic!("Cannot get children from exit node"),
+            Node::Break => panic!("Cannot get children from break node"),
+            Node::Continue => panic!("Cannot get children from continue node"),
         }
     }
 }
 
 impl Visitable for Box<Node> {
-    type Output = ();
-    fn accept(&mut self, visitor: &impl NodeVisitor) {
-        visitor.visit(self);
+    fn accept<V: NodeVisitor>(&self, visitor: &V) -> V::Output {
+        visitor.visit(self)
     }
 }
 
 impl ConstVisitable for Box<Node> {
-    type Output = ();
-    fn const_accept(&self, visitor: &impl NodeConstVisitor) {
-        visitor.const_visit(self.clone());
+    fn const_accept<V: NodeConstVisitor>(&self, visitor: &V) -> V::Output {
+        visitor.const_visit(self.clone())
     }
 }
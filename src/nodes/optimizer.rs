@@ -0,0 +1,661 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use super::{
+    expressionevaluator::{ExpressionEvaluator, Value},
+    node::{ExpressionTree, Node},
+    traits::{NodeConstVisitor, NodeVisitor},
+};
+use crate::utils::decimal::{Decimal, RoundingMode, DEFAULT_SCALE};
+
+/// Folds the constant expressions of a tree, e.g. `100 * 0.05` into `5`, and
+/// replaces `if true` and `if false` by the branch that runs. The optimized
+/// tree evaluates the same as the original one.
+///
+/// Arithmetic, comparisons, logic and the math builtins are folded when all
+/// their operands are constants, by evaluating them with the decimals the
+/// script runs with. Expressions that fail, such as `1 / 0`, are left for the
+/// run to report.
+///
+/// `x * 1`, `1 * x`, `x / 1` and `x - 0` become `x` when `x` is known to be a
+/// number or null: numeric literals, arithmetic on them, and variables only
+/// ever assigned such values. `x + 0` and `0 + x` also need `x` to be a
+/// decimal, as adding zero turns a floating point `-0` into `0`. Other
+/// operands are kept, e.g. `shares * 1` fails when `shares` is a string.
+///
+/// Runs on a parsed tree, before the indexer.
+pub struct Optimizer {
+    decimal_scale: u32,
+    rounding: RoundingMode,
+    // What the variables of the script are known to hold, by name
+    variables: Mutex<HashMap<String, Numeric>>,
+}
+
+// The values a numeric expression can take, besides null
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Numeric {
+    // decimals, with no more decimals than the scale
+    Exact,
+    // decimals or floating point numbers
+    Any,
+}
+
+impl Default for Optimizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NodeVisitor for Optimizer {
+    type Output = ExpressionTree;
+    fn visit(&self, node: &Node) -> ExpressionTree {
+        match node {
+            _ if node.is_leaf() => Box::new(node.clone()),
+
+            Node::Base(children) => {
+                *self.variables.lock().unwrap() = numeric_variables(node);
+                Box::new(Node::Base(self.visit_block(children)))
+            }
+
+            Node::If(children, first_else, line) => {
+                let Some(condition) = children.first() else {
+                    return Box::new(node.clone());
+                };
+                let condition = self.visit(condition);
+                let last_then = first_else
+                    .unwrap_or(children.len())
+                    .clamp(1, children.len());
+                let then = self.visit_block(&children[1..last_then]);
+                let otherwise = self.visit_block(&children[last_then..]);
+
+                // The branch that runs is kept as `if true`, which the
+                // enclosing block unwraps
+                let (branch, first_else) = match condition.as_ref() {
+                    Node::True => (then, None),
+                    Node::False => (otherwise, None),
+                    _ => {
+                        let first_else = first_else.map(|_| 1 + then.len());
+                        let mut branch = then;
+                        branch.extend(otherwise);
+                        return Box::new(Node::If(
+                            [vec![condition], branch].concat(),
+                            first_else,
+                            *line,
+                        ));
+                    }
+                };
+                Box::new(Node::If(
+                    [vec![Box::new(Node::True)], branch].concat(),
+                    first_else,
+                    *line,
+                ))
+            }
+
+            Node::Select(children) => {
+                let mut children: Vec<_> = children.iter().map(|child| self.visit(child)).collect();
+                match children.first().map(|condition| condition.as_ref()) {
+                    Some(Node::True) if children.len() == 3 => children.swap_remove(1),
+                    Some(Node::False) if children.len() == 3 => children.swap_remove(2),
+                    _ => Box::new(Node::Select(children)),
+                }
+            }
+
+            Node::For(children, variables) => {
+                let variables = (*variables).min(children.len());
+                let (head, body) = children.split_at((variables + 1).min(children.len()));
+                let head = head.iter().map(|child| self.visit(child)).collect();
+                Box::new(Node::For(
+                    [head, self.visit_block(body)].concat(),
+                    variables,
+                ))
+            }
+
+            Node::Match(children, first_else) => {
+                let last_case = first_else
+                    .unwrap_or(children.len())
+                    .clamp(1, children.len());
+                let cases = children[..last_case]
+                    .iter()
+                    .map(|child| self.visit(child))
+                    .collect();
+                let otherwise = self.visit_block(&children[last_case..]);
+                Box::new(Node::Match([cases, otherwise].concat(), *first_else))
+            }
+
+            Node::Case(children, values) => {
+                let values = (*values).min(children.len());
+                let matched = children[..values]
+                    .iter()
+                    .map(|child| self.visit(child))
+                    .collect();
+                let body = self.visit_block(&children[values..]);
+                Box::new(Node::Case([matched, body].concat(), values))
+            }
+
+            Node::Try(children, catch_index, binding) => {
+                let catch_index = (*catch_index).min(children.len());
+                let tried = self.visit_block(&children[..catch_index]);
+                let caught = self.visit_block(&children[catch_index..]);
+                Box::new(Node::Try(
+                    [tried.clone(), caught].concat(),
+                    tried.len(),
+                    binding.clone(),
+                ))
+            }
+
+            Node::Function(children, name, params, layout) => Box::new(Node::Function(
+                self.visit_block(children),
+                name.clone(),
+                params.clone(),
+                layout.clone(),
+            )),
+
+            _ => {
+                let mut node = node.clone();
+                let children = node.children_mut();
+                *children = children.iter().map(|child| self.visit(child)).collect();
+                self.simplify(self.fold(Box::new(node)))
+            }
+        }
+    }
+}
+
+impl Optimizer {
+    pub fn new() -> Self {
+        Optimizer {
+            decimal_scale: DEFAULT_SCALE,
+            rounding: RoundingMode::default(),
+            variables: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Folds decimals as an evaluator set up with the same
+    /// `ExpressionEvaluator::with_decimals` computes them
+    pub fn with_decimals(mut self, scale: u32, rounding: RoundingMode) -> Self {
+        self.decimal_scale = scale;
+        self.rounding = rounding;
+        self
+    }
+
+    // The statements of a block, with the branch of an `if` on a constant
+    // condition in place of the `if`. A branch declaring locals stays in its
+    // `if true`, which scopes them.
+    fn visit_block(&self, children: &[ExpressionTree]) -> Vec<ExpressionTree> {
+        let mut block = Vec::with_capacity(children.len());
+        for child in children {
+            let child = self.visit(child);
+            match child.as_ref() {
+                Node::If(branch, None, _)
+                    if matches!(branch.first().map(|c| c.as_ref()), Some(Node::True))
+                        && !branch.iter().any(|c| matches!(c.as_ref(), Node::Local(_))) =>
+                {
+                    block.extend(branch[1..].iter().cloned());
+                }
+                _ => block.push(child),
+            }
+        }
+        block
+    }
+
+    // Evaluates an operation on constants, unless it fails or its value has
+    // no literal
+    fn fold(&self, node: ExpressionTree) -> ExpressionTree {
+        let foldable = matches!(
+            node.as_ref(),
+            Node::Add(_)
+                | Node::Subtract(_)
+                | Node::Multiply(_)
+                | Node::Divide(_)
+                | Node::Modulo(_)
+                | Node::Pow(_)
+                | Node::UnaryPlus(_)
+                | Node::UnaryMinus(_)
+                | Node::Min(_)
+                | Node::Max(_)
+                | Node::Exp(_)
+                | Node::Ln(_)
+                | Node::Math(..)
+                | Node::Clamp(_)
+                | Node::Equal(_)
+                | Node::NotEqual(_)
+                | Node::Superior(_)
+                | Node::Inferior(_)
+                | Node::SuperiorOrEqual(_)
+                | Node::InferiorOrEqual(_)
+                | Node::And(_)
+                | Node::Or(_)
+                | Node::Not(_)
+                | Node::Coalesce(_)
+        );
+        if !foldable || !node.children().iter().all(|child| is_constant(child)) {
+            return node;
+        }
+
+        let evaluator = ExpressionEvaluator::new().with_decimals(self.decimal_scale, self.rounding);
+        if evaluator.const_visit(node.clone()).is_err() {
+            return node;
+        }
+        let literal = match evaluator.stack().pop() {
+            Some(Value::Bool(true)) => Node::True,
+            Some(Value::Bool(false)) => Node::False,
+            Some(Value::Null) => Node::Null,
+            Some(Value::Decimal(value)) => Node::Decimal(value),
+            Some(Value::Number(value)) => Node::Number(value),
+            Some(Value::Date(date)) => Node::Date(date),
+            Some(Value::DateTime(time)) => Node::DateTime(time),
+            Some(Value::Duration(duration)) => Node::Duration(duration),
+            Some(Value::String(value)) => Node::String(value),
+            Some(Value::Money(amount, currency)) => Node::Money(amount, currency),
+            _ => return node,
+        };
+        Box::new(literal)
+    }
+
+    // Replaces an arithmetic identity by its operand, when that operand is
+    // known to be a number
+    fn simplify(&self, node: ExpressionTree) -> ExpressionTree {
+        let variables = self.variables.lock().unwrap();
+        let kind = |operand: &Node| numeric(operand, &variables);
+        let is = |operand: &Node, value: i64| matches!(operand, Node::Decimal(decimal) if *decimal == Decimal::from(value));
+        let operand = match node.as_ref() {
+            Node::Multiply(children) => match children.as_slice() {
+                [x, one] | [one, x] if is(one, 1) && kind(x).is_some() => x,
+                _ => return node,
+            },
+            Node::Divide(children) => match children.as_slice() {
+                [x, one] if is(one, 1) && kind(x).is_some() => x,
+                _ => return node,
+            },
+            Node::Subtract(children) => match children.as_slice() {
+                [x, zero] if is(zero, 0) && kind(x).is_some() => x,
+                _ => return node,
+            },
+            Node::Add(children) => match children.as_slice() {
+                [x, zero] | [zero, x] if is(zero, 0) && kind(x) == Some(Numeric::Exact) => x,
+                _ => return node,
+            },
+            _ => return node,
+        };
+        operand.clone()
+    }
+}
+
+// What an expression is known to evaluate to, if it is a number or null
+fn numeric(node: &Node, variables: &HashMap<String, Numeric>) -> Option<Numeric> {
+    match node {
+        Node::Decimal(_) | Node::Null | Node::Len(_) => Some(Numeric::Exact),
+        Node::Number(_) | Node::Ln(_) | Node::Exp(_) | Node::Pow(_) => Some(Numeric::Any),
+        Node::Variable(_, name, _, _) => variables.get(name).copied(),
+        Node::Add(children)
+        | Node::Subtract(children)
+        | Node::Multiply(children)
+        | Node::Divide(children)
+        | Node::Modulo(children)
+        | Node::UnaryPlus(children)
+        | Node::UnaryMinus(children) => children.iter().try_fold(Numeric::Exact, |kind, child| {
+            match numeric(child, variables)? {
+                Numeric::Exact => Some(kind),
+                Numeric::Any => Some(Numeric::Any),
+            }
+        }),
+        _ => None,
+    }
+}
+
+// The variables that only ever hold numbers or null. Every variable assigned
+// in the script is first assumed to be exact, and loses that assumption, then
+// the one of being a number, while one of its assignments may not keep it.
+// Loop variables, caught errors and parameters can hold anything.
+fn numeric_variables(root: &Node) -> HashMap<String, Numeric> {
+    let mut assignments = Vec::new();
+    let mut bound = HashSet::new();
+    collect_assignments(root, &mut assignments, &mut bound);
+
+    let mut variables: HashMap<String, Numeric> = assignments
+        .iter()
+        .filter(|(name, _)| !bound.contains(*name))
+        .map(|(name, _)| (name.to_string(), Numeric::Exact))
+        .collect();
+    loop {
+        let mut changed = false;
+        for (name, value) in &assignments {
+            let Some(&current) = variables.get(*name) else {
+                continue;
+            };
+            let kind = match value {
+                Some(value) => numeric(value, &variables),
+                // A declaration without value holds null
+                None => Some(Numeric::Exact),
+            };
+            match kind {
+                None => {
+                    variables.remove(*name);
+                    changed = true;
+                }
+                Some(Numeric::Any) if current == Numeric::Exact => {
+                    variables.insert(name.to_string(), Numeric::Any);
+                    changed = true;
+                }
+                _ => (),
+            }
+        }
+        if !changed {
+            return variables;
+        }
+    }
+}
+
+// The values assigned to each variable, `None` for a `local` declaration
+// without value, and the names bound otherwise
+fn collect_assignments<'a>(
+    node: &'a Node,
+    assignments: &mut Vec<(&'a str, Option<&'a Node>)>,
+    bound: &mut HashSet<&'a str>,
+) {
+    let name = |target: &'a Node| match target {
+        Node::Variable(_, name, _, _) => Some(name.as_str()),
+        _ => None,
+    };
+    match node {
+        Node::Assign(children) | Node::Local(children) => {
+            if let Some(target) = children.first().and_then(|target| name(target)) {
+                assignments.push((target, children.get(1).map(|value| value.as_ref())));
+            }
+        }
+        Node::For(children, variables) => {
+            bound.extend(children.iter().take(*variables).filter_map(|c| name(c)));
+        }
+        Node::Try(_, _, Some(binding)) => bound.extend(name(binding)),
+        Node::Function(_, _, params, _) => bound.extend(params.iter().map(String::as_str)),
+        _ => (),
+    }
+    if !node.is_leaf() {
+        for child in node.children() {
+            collect_assignments(child, assignments, bound);
+        }
+    }
+}
+
+fn is_constant(node: &Node) -> bool {
+    matches!(
+        node,
+        Node::True
+            | Node::False
+            | Node::Money(..)
+            | Node::Date(_)
+            | Node::DateTime(_)
+            | Node::Duration(_)
+            | Node::String(_)
+            | Node::Null
+            | Node::Decimal(_)
+            | Node::Number(_)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{
+        nodes::expressionindexer::ExpressionIndexer,
+        parsers::{lexer::Lexer, parser::Parser},
+        utils::{decimal::Decimal, errors::Result},
+    };
+
+    fn parse(script: &str) -> Box<Node> {
+        let tokens = Lexer::new(script.to_string()).tokenize().unwrap();
+        Parser::new(tokens).parse().unwrap()
+    }
+
+    // The statements of the optimized script
+    fn optimize(script: &str) -> Vec<ExpressionTree> {
+        match *Optimizer::new().visit(&parse(script)) {
+            Node::Base(statements) => statements,
+            node => panic!("Expected a base node, found {:?}", node),
+        }
+    }
+
+    // The value assigned by the only statement of the optimized script
+    fn folded(script: &str) -> Node {
+        match optimize(script).as_slice() {
+            [assign] => match assign.as_ref() {
+                Node::Assign(children) => *children[1].clone(),
+                node => panic!("Expected an assignment, found {:?}", node),
+            },
+            statements => panic!("Expected one statement, found {:?}", statements),
+        }
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        assert!(matches!(folded("x = 100 * 0.05"), Node::Decimal(value) if value.to_f64() == 5.0));
+        assert!(
+            matches!(folded("x = -(2 + 3) % 4"), Node::Decimal(value) if value.to_f64() == 3.0)
+        );
+        assert!(
+            matches!(folded("x = max(1, min(5, 3))"), Node::Decimal(value) if value.to_f64() == 3.0)
+        );
+        assert_eq!(folded("x = ln(exp(2))"), Node::Number(2.0));
+        assert_eq!(folded("x = abs(-4) > 3 and not (1 == 2)"), Node::True);
+        assert_eq!(
+            folded("x = null ?? \"none\""),
+            Node::String("none".to_string())
+        );
+        assert_eq!(
+            folded("x = select(1 > 2, y, 5)"),
+            Node::Decimal(Decimal::from(5))
+        );
+    }
+
+    #[test]
+    fn test_keep_what_cannot_fold() {
+        // Variables, failures and calls are left as written
+        assert!(matches!(folded("x = shares * 2"), Node::Multiply(_)));
+        assert!(matches!(folded("x = 1 / 0"), Node::Divide(_)));
+        assert!(matches!(folded("x = today() + 1d"), Node::Add(_)));
+        assert_eq!(
+            folded("x = 2 * 5.50 USD"),
+            Node::Money("11".parse().unwrap(), "USD".to_string())
+        );
+        let Node::Multiply(children) = folded("x = shares * (2 * 3)") else {
+            panic!("Expected a product");
+        };
+        assert!(matches!(children[1].as_ref(), Node::Decimal(value) if value.to_f64() == 6.0));
+    }
+
+    #[test]
+    fn test_simplify_identities() {
+        // The value assigned by the last statement of the optimized script
+        let last = |script: &str| match optimize(script).last().map(|s| s.as_ref().clone()) {
+            Some(Node::Assign(children)) => *children[1].clone(),
+            node => panic!("Expected an assignment, found {:?}", node),
+        };
+        let variable = |node: Node, expected: &str| matches!(node, Node::Variable(_, name, _, _) if name == expected);
+
+        assert!(variable(
+            last(
+                "shares = 12
+x = shares * 1.0"
+            ),
+            "shares"
+        ));
+        assert!(variable(
+            last(
+                "shares = 12
+x = 1 * shares / 1"
+            ),
+            "shares"
+        ));
+        assert!(variable(
+            last(
+                "shares = 12
+x = 0 + shares - 0"
+            ),
+            "shares"
+        ));
+        // Updated with numbers only, and floating point numbers too
+        assert!(variable(
+            last(
+                "n = 0
+for i in [1, 2] do n += 1 end
+x = n + 0"
+            ),
+            "n"
+        ));
+        assert!(variable(
+            last(
+                "r = ln(2)
+r = r * 2
+x = r * 1"
+            ),
+            "r"
+        ));
+        assert!(matches!(
+            last(
+                "r = ln(2)
+x = r + 0"
+            ),
+            Node::Add(_)
+        ));
+        assert!(matches!(
+            last(
+                "r = -ln(1)
+x = 0 + r"
+            ),
+            Node::Add(_)
+        ));
+
+        // Strings, amounts of money, calls and loop variables are kept
+        assert!(matches!(last("s = \"a\"\nx = s * 1"), Node::Multiply(_)));
+        assert!(matches!(last("m = 5 USD\nx = m + 0"), Node::Add(_)));
+        assert!(matches!(
+            last("m = 5\nm = 5 USD\nx = m * 1"),
+            Node::Multiply(_)
+        ));
+        assert!(matches!(
+            last("x = sma(\"AAPL\", 5) * 1"),
+            Node::Multiply(_)
+        ));
+        assert!(matches!(
+            last("for i in [\"a\"] do x = i * 1 end\nx = i * 1"),
+            Node::Multiply(_)
+        ));
+        assert!(matches!(
+            last("function f(p) { return p * 1 }\np = 1\nx = p * 1"),
+            Node::Multiply(_)
+        ));
+    }
+
+    #[test]
+    fn test_remove_constant_branches() {
+        assert_eq!(
+            optimize("if 1 > 2 { x = 1 } else if 2 > 1 { x = 2 } else { x = 3 }"),
+            optimize("x = 2")
+        );
+        assert_eq!(optimize("if false then x = 1 end"), vec![]);
+        assert_eq!(
+            optimize("while_true = 1\nif true then y = 2 end\nz = 3"),
+            optimize("while_true = 1\ny = 2\nz = 3")
+        );
+        // The else block runs unless the condition is constant
+        let statements = optimize("if spot > 1 + 1 then x = 1 else x = 2 end");
+        assert!(
+            matches!(statements[0].as_ref(), Node::If(children, Some(2), _) if children.len() == 3)
+        );
+        // Locals stay scoped to their branch
+        let statements = optimize("if true then local y = 1 end");
+        assert!(
+            matches!(statements[0].as_ref(), Node::If(children, None, _) if children.len() == 2)
+        );
+    }
+
+    // Runs a script as parsed and as optimized, and checks that both end the
+    // same way with the same variables, down to the kind of their values
+    fn assert_equivalent(script: &str) {
+        let run = |optimize: bool| -> (Result<String>, HashMap<String, String>) {
+            let mut nodes = parse(script);
+            if optimize {
+                nodes = Optimizer::new().visit(&nodes);
+            }
+            let indexer = ExpressionIndexer::new();
+            indexer.visit(&nodes);
+            let evaluator = ExpressionEvaluator::new().with_variables(indexer.get_size());
+            let result = evaluator
+                .run(nodes)
+                .map(|result| format!("{:?}", result.status));
+            let variables = evaluator.variables();
+            let values = indexer
+                .variables
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(name, slot)| (name.clone(), format!("{:?}", variables[*slot])))
+                .collect();
+            (result, values)
+        };
+
+        let (expected, before) = run(false);
+        let (actual, after) = run(true);
+        match (expected, actual) {
+            (Ok(expected), Ok(actual)) => assert_eq!(actual, expected, "in {}", script),
+            (Err(expected), Err(actual)) => {
+                assert_eq!(actual.to_string(), expected.to_string(), "in {}", script)
+            }
+            (expected, actual) => panic!("{:?} is not {:?} in {}", actual, expected, script),
+        }
+        let null = format!("{:?}", Value::Null);
+        for (name, value) in &before {
+            let optimized = after.get(name).unwrap_or(&null);
+            assert_eq!(optimized, value, "{} in {}", name, script);
+        }
+    }
+
+    #[test]
+    fn test_differential() {
+        let scripts = [
+            "x = 100 * 0.05\ny = 1 / 3 * 3\nz = 0.1 + 0.2 == 0.3",
+            "x = exp(1) * 2\ny = ln(10) / ln(2)\nz = pow(2, 0.5) + 1",
+            "x = min(3, 1.5, 2)\ny = max(-1, 0) + abs(-2.5)\nz = sqrt(2) > 1.41",
+            "x = 7 % 3 + mod(-7, 3) * clamp(150, 0, 100)",
+            "x = 10 USD * 3\ny = 10 USD > 5 USD\nz = 2024-05-04 + 1m < 2024-06-05",
+            "x = \"a\" == \"a\"\ny = null ?? 2 * 2\nz = not (1 >= 2 or 3 <= 2)",
+            "shares = 12\nx = shares * 1.0\ny = shares * (100 * 0.05)",
+            "x = 1\nif 2 > 1 then x = x + 1 else x = 0 end\nif false then y = 1 end",
+            "if 1 == 2 { x = 1 } else if 1 + 1 == 2 { x = 2 } else { x = 3 }",
+            "total = 0\nfor i in [1, 2, 3] do\n if true then total += i * (2 - 1) end\nend",
+            "function fee(x)\n if 1 > 0 then return x * (0.5 / 100) end\n return 0\nend\ny = fee(200)",
+            "x = 1\nif true then local x = 2\n y = x end\nz = x",
+            "try\n x = 1 / 0\ncatch err\n kind = err.kind\n x = select(true, 1, 1 / 0)\nend",
+            "match 1 + 1 with case 2 then x = 2 * 2 else x = 0 end",
+            "x = 1 / 0",
+            "x = 1\nif x > 0 and true then exit end\nx = 2",
+            "if 1 + 1 == 2 then abort(\"always\") end",
+            "shares = 12\nx = shares * 1.0\ny = 1 * shares / 1\nz = 0 + shares - 0",
+            "n = 0.5\nfor i in [1, 2] do n = n * 3 / 7 end\nx = n * 1\ny = n + 0",
+            "r = exp(1)\nr = r / 3\nx = r * 1 - 0\nz = -ln(1)\ny = z - 0",
+            "n = null\nx = n * 1\ny = 0 + n\nz = len([1, 2]) + 0",
+            "m = 5 USD\nx = m * 1\ny = m - 0",
+            "s = \"a\"\ntry\n x = s * 1\ncatch err\n x = err.message\nend",
+            "x = 12\nlocal y\ny = y * 1\nz = x / 1 + 0",
+        ];
+        scripts.iter().for_each(|script| assert_equivalent(script));
+    }
+
+    #[test]
+    fn test_fold_with_decimals() {
+        let script = "x = 2 / 3";
+        let nodes = Optimizer::new()
+            .with_decimals(2, RoundingMode::HalfUp)
+            .visit(&parse(script));
+        let indexer = ExpressionIndexer::new();
+        indexer.visit(&nodes);
+        let evaluator = ExpressionEvaluator::new()
+            .with_variables(indexer.get_size())
+            .with_decimals(2, RoundingMode::HalfUp);
+        evaluator.run(nodes).unwrap();
+        assert_eq!(evaluator.variables()[0].to_string(), "0.67");
+    }
+}
@@ -2,7 +2,7 @@ use super::node::Node;
 
 pub trait NodeVisitor {
     type Output;
-    fn visit(&self, node: &Box<Node>) -> Self::Output;
+    fn visit(&self, node: &Node) -> Self::Output;
 }
 
 pub trait NodeConstVisitor {
@@ -10,12 +10,15 @@ pub trait NodeConstVisitor {
     fn const_visit(&self, node: Box<Node>) -> Self::Output;
 }
 
+/// A node that can be visited. The output is whatever the visitor produces,
+/// so errors raised by the visitor reach the caller.
 pub trait Visitable {
-    type Output;
-    fn accept(&mut self, visitor: &impl NodeVisitor) -> Self::Output;
+    fn accept<V: NodeVisitor>(&self, visitor: &V) -> V::Output;
 }
 
+/// A node that can be visited by a [`NodeConstVisitor`]. The output is
+/// whatever the visitor produces, so errors raised by the visitor reach the
+/// caller.
 pub trait ConstVisitable {
-    type Output;
-    fn const_accept(&self, visitor: &impl NodeConstVisitor) -> Self::Output;
+    fn const_accept<V: NodeConstVisitor>(&self, visitor: &V) -> V::Output;
 }
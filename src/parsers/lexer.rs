@@ -1,16 +1,38 @@
-use std::cell::RefCell;
+use std::{cell::RefCell, collections::VecDeque};
 
-use crate::utils::errors::{Result, ScriptingError};
+use crate::utils::{
+    errors::{Result, ScriptingError},
+    time::{Date, DateTime, Duration},
+};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
-    Value(Option<f64>, Option<bool>),
+    // A number is kept as its digits in plain decimal notation, `2.5e-3` and
+    // `25bp` being `0.0025`, so that the parser can build it exactly
+    Value(Option<String>, Option<bool>),
+    // an amount of money, `100 USD`: the digits then the currency code
+    Money(String, String),
+    // `2024-05-04`, `2024-05-04T09:30` and `30d`
+    Date(Date),
+    DateTime(DateTime),
+    Duration(Duration),
+    String(String),
+    // An interpolated string is lexed as `InterpolationStart`, its text parts
+    // as `String` and its `{expression}` parts as the expression tokens
+    // between curly braces, then `InterpolationEnd`
+    InterpolationStart,
+    InterpolationEnd,
     Identifier(String),
     Plus,
     Minus,
     Multiply,
     Divide,
+    Modulo,
     Assign,
+    PlusAssign,
+    MinusAssign,
+    MultiplyAssign,
+    DivideAssign,
     Equal,
     NotEqual,
     And,
@@ -20,10 +42,15 @@ pub enum Token {
     Inferior,
     SuperiorOrEqual,
     InferiorOrEqual,
+    Coalesce,
+    Null,
     OpenParen,
     CloseParen,
     OpenCurlyParen,
     CloseCurlyParen,
+    OpenBracket,
+    CloseBracket,
+    Colon,
     If,
     Then,
     Else,
@@ -31,14 +58,36 @@ pub enum Token {
     Comma,
     Power,
     For,
+    In,
+    Do,
+    Function,
+    Return,
+    Local,
+    Exit,
+    Abort,
+    Break,
+    Continue,
+    Try,
+    Catch,
+    Match,
+    With,
+    Case,
+    Require,
+    Assert,
+    Dot,
     Semicolon, // for end of an expression or statement
     Newline,   // for end of a line
+    // Comments are trivia: they are kept, delimiters included, so that tools
+    // can reproduce them, but they carry no meaning for the parser.
+    Comment(String),
     EOF,
 }
 
 pub struct Lexer {
     input: Vec<char>,
     position: RefCell<usize>,
+    // Tokens already read, e.g. the rest of an interpolated string
+    pending: RefCell<VecDeque<Token>>,
 }
 
 impl Lexer {
@@ -46,6 +95,7 @@ impl Lexer {
         Self {
             input: input.chars().collect(),
             position: RefCell::new(0),
+            pending: RefCell::new(VecDeque::new()),
         }
     }
 
@@ -68,26 +118,65 @@ impl Lexer {
     }
 
     pub fn next_token(&self) -> Result<Token> {
+        if let Some(token) = self.pending.borrow_mut().pop_front() {
+            return Ok(token);
+        }
         self.skip_whitespace();
         let ch = self.next_char();
         match ch {
-            '+' => Ok(Token::Plus),
-            '-' => Ok(Token::Minus),
+            '+' => {
+                if self.peek_char() == '=' {
+                    self.next_char();
+                    Ok(Token::PlusAssign)
+                } else {
+                    Ok(Token::Plus)
+                }
+            }
+            '-' => {
+                if self.peek_char() == '=' {
+                    self.next_char();
+                    Ok(Token::MinusAssign)
+                } else if self.peek_char() == '-' {
+                    self.next_char();
+                    if self.peek_char() == '[' && self.peek_char_at(1) == '[' {
+                        self.read_block_comment("--", "]]")
+                    } else {
+                        Ok(self.read_line_comment("--"))
+                    }
+                } else {
+                    Ok(Token::Minus)
+                }
+            }
             '*' => {
                 if self.peek_char() == '*' {
                     self.next_char();
                     Ok(Token::Power)
+                } else if self.peek_char() == '=' {
+                    self.next_char();
+                    Ok(Token::MultiplyAssign)
                 } else {
                     Ok(Token::Multiply)
                 }
             }
-            '#' => {
-                while self.peek_char() != '\n' && self.peek_char() != '\0' {
+            '#' => Ok(self.read_line_comment("#")),
+            '"' => {
+                let mut tokens = self.read_string()?.into_iter();
+                let first = tokens.next().unwrap_or(Token::EOF);
+                self.pending.borrow_mut().extend(tokens);
+                Ok(first)
+            }
+            '/' => {
+                if self.peek_char() == '*' {
                     self.next_char();
+                    self.read_block_comment("/*", "*/")
+                } else if self.peek_char() == '=' {
+                    self.next_char();
+                    Ok(Token::DivideAssign)
+                } else {
+                    Ok(Token::Divide)
                 }
-                self.next_token()
             }
-            '/' => Ok(Token::Divide),
+            '%' => Ok(Token::Modulo),
             '=' => {
                 if self.peek_char() == '=' {
                     self.next_char();
@@ -98,6 +187,7 @@ impl Lexer {
             }
             '\n' => Ok(Token::Newline),
             ',' => Ok(Token::Comma),
+            '.' => Ok(Token::Dot),
             '!' => {
                 if self.peek_char() == '=' {
                     self.next_char();
@@ -108,10 +198,23 @@ impl Lexer {
                     ))
                 }
             }
+            '?' => {
+                if self.peek_char() == '?' {
+                    self.next_char();
+                    Ok(Token::Coalesce)
+                } else {
+                    Err(ScriptingError::InvalidSyntax(
+                        "Invalid character: ?".to_string(),
+                    ))
+                }
+            }
             '(' => Ok(Token::OpenParen),
             ')' => Ok(Token::CloseParen),
             '{' => Ok(Token::OpenCurlyParen),
             '}' => Ok(Token::CloseCurlyParen),
+            '[' => Ok(Token::OpenBracket),
+            ']' => Ok(Token::CloseBracket),
+            ':' => Ok(Token::Colon),
             ';' => Ok(Token::Semicolon),
             '\0' => Ok(Token::EOF),
             '>' => {
@@ -130,7 +233,7 @@ impl Lexer {
                     Ok(Token::Inferior)
                 }
             }
-            _ if ch.is_digit(10) => self.read_number(ch),
+            _ if ch.is_ascii_digit() => self.read_number(ch),
             _ if ch.is_alphabetic() => self.read_identifier(ch),
             _ => Err(ScriptingError::InvalidSyntax(format!(
                 "Invalid character: {}",
@@ -139,15 +242,380 @@ impl Lexer {
         }
     }
 
-    // This function is used to read numerical literals, including floating point numbers.
-    // Should fail if the number is not valid or if it is not a number.
+    // Reads a comment up to the end of the line, `prefix` being the already
+    // consumed delimiter
+    fn read_line_comment(&self, prefix: &str) -> Token {
+        let mut comment = prefix.to_string();
+        while self.peek_char() != '\n' && self.peek_char() != '\0' {
+            comment.push(self.next_char());
+        }
+        Token::Comment(comment)
+    }
+
+    // Reads a comment up to and including the `terminator`, `prefix` being the
+    // already consumed delimiter. Block comments can span several lines.
+    fn read_block_comment(&self, prefix: &str, terminator: &str) -> Result<Token> {
+        let start = *self.position.borrow() - prefix.len();
+        let mut comment = prefix.to_string();
+        loop {
+            if comment[prefix.len()..].ends_with(terminator) {
+                return Ok(Token::Comment(comment));
+            }
+            match self.next_char() {
+                '\0' => {
+                    let (line, column) = self.location(start);
+                    return Err(ScriptingError::InvalidToken(format!(
+                        "Unterminated comment at line {}, column {}: expected `{}`",
+                        line, column, terminator
+                    )));
+                }
+                ch => comment.push(ch),
+            }
+        }
+    }
+
+    // Reads a double-quoted string literal, the opening quote being already
+    // consumed. `\"`, `\\`, `\n`, `\t`, `\{` and `\}` are the supported escapes;
+    // a string cannot span several lines. A string with `{expression}` parts
+    // gives the tokens of an interpolated string, a plain one a single token.
+    fn read_string(&self) -> Result<Vec<Token>> {
+        let start = *self.position.borrow() - 1;
+        let mut string = String::new();
+        let mut tokens = Vec::new();
+        loop {
+            match self.next_char() {
+                '"' if tokens.is_empty() => return Ok(vec![Token::String(string)]),
+                '"' => {
+                    if !string.is_empty() {
+                        tokens.push(Token::String(string));
+                    }
+                    tokens.push(Token::InterpolationEnd);
+                    return Ok(tokens);
+                }
+                '{' => {
+                    if tokens.is_empty() {
+                        tokens.push(Token::InterpolationStart);
+                    }
+                    if !string.is_empty() {
+                        tokens.push(Token::String(std::mem::take(&mut string)));
+                    }
+                    tokens.extend(self.read_interpolation(start)?);
+                }
+                '\\' => match self.next_char() {
+                    '"' => string.push('"'),
+                    '\\' => string.push('\\'),
+                    'n' => string.push('\n'),
+                    't' => string.push('\t'),
+                    '{' => string.push('{'),
+                    '}' => string.push('}'),
+                    ch => {
+                        let (line, column) = self.location(*self.position.borrow() - 2);
+                        return Err(ScriptingError::InvalidToken(format!(
+                            "Invalid escape `\\{}` at line {}, column {}",
+                            ch, line, column
+                        )));
+                    }
+                },
+                '\0' | '\n' => {
+                    let (line, column) = self.location(start);
+                    return Err(ScriptingError::InvalidToken(format!(
+                        "Unterminated string at line {}, column {}",
+                        line, column
+                    )));
+                }
+                ch => string.push(ch),
+            }
+        }
+    }
+
+    // Reads the expression of a `{expression}` part of the string starting at
+    // `start`, the opening brace being already consumed. The tokens include
+    // the braces.
+    fn read_interpolation(&self, start: usize) -> Result<Vec<Token>> {
+        let mut tokens = vec![Token::OpenCurlyParen];
+        let mut depth = 1;
+        loop {
+            let token = self.next_token()?;
+            match token {
+                Token::OpenCurlyParen => depth += 1,
+                Token::CloseCurlyParen => depth -= 1,
+                Token::Comment(_) => continue,
+                Token::Newline | Token::EOF => {
+                    let (line, column) = self.location(start);
+                    return Err(ScriptingError::InvalidToken(format!(
+                        "Unterminated string at line {}, column {}",
+                        line, column
+                    )));
+                }
+                _ => (),
+            }
+            tokens.push(token);
+            if depth == 0 {
+                break;
+            }
+        }
+        if tokens.len() == 2 {
+            let (line, column) = self.location(start);
+            return Err(ScriptingError::InvalidToken(format!(
+                "Empty interpolation in string at line {}, column {}",
+                line, column
+            )));
+        }
+        Ok(tokens)
+    }
+
+    // Line and column (1-based) of the character at `position`
+    fn location(&self, position: usize) -> (usize, usize) {
+        let consumed = &self.input[..position.min(self.input.len())];
+        let line = consumed.iter().filter(|&&c| c == '\n').count() + 1;
+        let column = consumed.iter().rev().take_while(|&&c| c != '\n').count() + 1;
+        (line, column)
+    }
+
+    fn number_error(&self, start: usize, reason: &str) -> ScriptingError {
+        // Consume the rest of the literal so that it shows up in the message
+        while self.peek_char().is_alphanumeric() || matches!(self.peek_char(), '.' | '_') {
+            self.next_char();
+        }
+        let (line, column) = self.location(start);
+        let end = (*self.position.borrow()).min(self.input.len());
+        let literal: String = self.input[start..end].iter().collect();
+        ScriptingError::InvalidToken(format!(
+            "Malformed number literal `{}` at line {}, column {}: {}",
+            literal, line, column, reason
+        ))
+    }
+
+    fn peek_char_at(&self, offset: usize) -> char {
+        self.input
+            .get(*self.position.borrow() + offset)
+            .copied()
+            .unwrap_or('\0')
+    }
+
+    // Reads a run of digits that may be grouped with `_` separators, as in
+    // `1_000_000`. A separator must sit between two digits.
+    fn read_digits(&self, digits: &mut String, start: usize) -> Result<()> {
+        loop {
+            match self.peek_char() {
+                c if c.is_ascii_digit() => digits.push(self.next_char()),
+                '_' => {
+                    self.next_char();
+                    if !digits.ends_with(|c: char| c.is_ascii_digit())
+                        || !self.peek_char().is_ascii_digit()
+                    {
+                        return Err(
+                            self.number_error(start, "`_` must be placed between two digits")
+                        );
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    // This function is used to read numerical literals: integers and decimals,
+    // with optional `_` digit separators, an exponent (`1e6`, `2.5E-3`) and a
+    // percentage (`5%` is 0.05, while `5%3` and `5 % 3` take a remainder) or
+    // basis point (`25bp` is 0.0025) suffix, a duration unit (`30d`) or a
+    // currency code (`100 USD`). Dates start like numbers too. Fails with the
+    // position of the literal if it is malformed.
     fn read_number(&self, first_char: char) -> Result<Token> {
+        let start = *self.position.borrow() - 1;
+        if self.at_date() {
+            return self.read_date(start);
+        }
         let mut number = first_char.to_string();
-        while self.peek_char().is_digit(10) || self.peek_char() == '.' {
+        self.read_digits(&mut number, start)?;
+
+        if self.peek_char() == '.' {
             number.push(self.next_char());
+            if !self.peek_char().is_ascii_digit() {
+                return Err(self.number_error(start, "expected a digit after the decimal point"));
+            }
+            self.read_digits(&mut number, start)?;
+        }
+
+        if matches!(self.peek_char(), 'e' | 'E') {
+            let next = self.peek_char_at(1);
+            let exponent_digit = match next {
+                '+' | '-' => self.peek_char_at(2),
+                _ => next,
+            };
+            if exponent_digit.is_ascii_digit() {
+                number.push(self.next_char());
+                if matches!(next, '+' | '-') {
+                    number.push(self.next_char());
+                }
+                self.read_digits(&mut number, start)?;
+            } else if matches!(next, '+' | '-') || !(next.is_alphanumeric() || next == '_') {
+                // `1e`, `1e+`: an exponent marker that is not the start of an identifier
+                self.next_char();
+                return Err(self.number_error(start, "missing exponent digits"));
+            }
+        }
+
+        if self.peek_char() == '.' {
+            self.next_char();
+            return Err(self.number_error(start, "unexpected `.`"));
         }
 
-        Ok(Token::Value(Some(number.parse::<f64>()?), None))
+        // Percentages and basis points take no unit or currency
+        let shift = if self.peek_char() == '%' && !self.operand_at(1) {
+            self.next_char();
+            -2
+        } else if self.peek_char() == 'b'
+            && self.peek_char_at(1) == 'p'
+            && !(self.peek_char_at(2).is_alphanumeric() || self.peek_char_at(2) == '_')
+        {
+            self.next_char();
+            self.next_char();
+            -4
+        } else {
+            0
+        };
+        let Some(value) = plain_decimal(&number, shift) else {
+            return Err(self.number_error(start, "exponent out of range"));
+        };
+
+        if shift == 0 {
+            if let Some(unit) = self.duration_unit() {
+                if value.contains('.') {
+                    return Err(self.number_error(start, "a duration must be a whole number"));
+                }
+                for _ in 0..unit.len() {
+                    self.next_char();
+                }
+                let duration = value
+                    .parse()
+                    .ok()
+                    .and_then(|value| Duration::from_unit(value, unit));
+                return match duration {
+                    Some(duration) => Ok(Token::Duration(duration)),
+                    None => Err(self.number_error(start, "duration out of range")),
+                };
+            }
+            if let Some(currency) = self.read_currency() {
+                return Ok(Token::Money(value, currency));
+            }
+        }
+
+        Ok(Token::Value(Some(value), None))
+    }
+
+    // Whether the digit just read starts a date, `YYYY-MM-DD`
+    fn at_date(&self) -> bool {
+        let digit = |offset| self.peek_char_at(offset).is_ascii_digit();
+        digit(0)
+            && digit(1)
+            && digit(2)
+            && self.peek_char_at(3) == '-'
+            && digit(4)
+            && digit(5)
+            && self.peek_char_at(6) == '-'
+            && digit(7)
+            && digit(8)
+    }
+
+    // Reads a date, followed by `THH:MM` or `THH:MM:SS` for a date and time
+    fn read_date(&self, start: usize) -> Result<Token> {
+        for _ in 0..9 {
+            self.next_char();
+        }
+        let has_time = self.peek_char() == 'T' && self.peek_char_at(1).is_ascii_digit();
+        if has_time {
+            while self.peek_char().is_ascii_digit() || matches!(self.peek_char(), 'T' | ':') {
+                self.next_char();
+            }
+        }
+        if self.peek_char().is_alphanumeric() || self.peek_char() == '_' {
+            return Err(self.date_error(start));
+        }
+
+        let end = *self.position.borrow();
+        let literal: String = self.input[start..end].iter().collect();
+        match has_time {
+            true => literal.parse().map(Token::DateTime),
+            false => literal.parse().map(Token::Date),
+        }
+        .map_err(|_| self.date_error(start))
+    }
+
+    fn date_error(&self, start: usize) -> ScriptingError {
+        while self.peek_char().is_alphanumeric() || matches!(self.peek_char(), ':' | '_') {
+            self.next_char();
+        }
+        let (line, column) = self.location(start);
+        let end = (*self.position.borrow()).min(self.input.len());
+        let literal: String = self.input[start..end].iter().collect();
+        ScriptingError::InvalidToken(format!(
+            "Malformed date literal `{}` at line {}, column {}",
+            literal, line, column
+        ))
+    }
+
+    // The duration unit following a number, if any: `y`, `m` (months), `w`,
+    // `d`, `h`, `min` or `s`
+    fn duration_unit(&self) -> Option<&'static str> {
+        let ends_at = |offset: usize| {
+            let next = self.peek_char_at(offset);
+            !(next.is_alphanumeric() || next == '_')
+        };
+        if self.peek_char() == 'm'
+            && self.peek_char_at(1) == 'i'
+            && self.peek_char_at(2) == 'n'
+            && ends_at(3)
+        {
+            return Some("min");
+        }
+        let unit = match self.peek_char() {
+            'y' => "y",
+            'm' => "m",
+            'w' => "w",
+            'd' => "d",
+            'h' => "h",
+            's' => "s",
+            _ => return None,
+        };
+        ends_at(1).then_some(unit)
+    }
+
+    // Reads the currency code of an amount of money, three capital letters
+    // following the number, as in `100 USD`
+    fn read_currency(&self) -> Option<String> {
+        let mut offset = 0;
+        while matches!(self.peek_char_at(offset), ' ' | '\t') {
+            offset += 1;
+        }
+        let code: String = (offset..offset + 3).map(|i| self.peek_char_at(i)).collect();
+        let next = self.peek_char_at(offset + 3);
+        if !code.chars().all(|c| c.is_ascii_uppercase()) || next.is_alphanumeric() || next == '_' {
+            return None;
+        }
+        for _ in 0..offset + 3 {
+            self.next_char();
+        }
+        Some(code)
+    }
+
+    // Whether an operand starts right after `offset` characters: a number, a
+    // string, a parenthesis or an identifier other than a keyword
+    fn operand_at(&self, mut offset: usize) -> bool {
+        match self.peek_char_at(offset) {
+            c if c.is_ascii_digit() => true,
+            '(' | '"' => true,
+            c if c.is_alphabetic() => {
+                let mut word = String::new();
+                while self.peek_char_at(offset).is_alphanumeric()
+                    || self.peek_char_at(offset) == '_'
+                {
+                    word.push(self.peek_char_at(offset));
+                    offset += 1;
+                }
+                matches!(keyword(&word), Token::Identifier(_))
+            }
+            _ => false,
+        }
     }
 
     // This function is used to read identifiers and special keywords
@@ -156,19 +624,7 @@ impl Lexer {
         while self.peek_char().is_alphanumeric() || self.peek_char() == '_' {
             identifier.push(self.next_char());
         }
-        match identifier.as_str() {
-            "if" => Ok(Token::If),
-            "then" => Ok(Token::Then),
-            "else" => Ok(Token::Else),
-            "end" => Ok(Token::End),
-            "and" => Ok(Token::And),
-            "or" => Ok(Token::Or),
-            "not" => Ok(Token::Not),
-            "for" => Ok(Token::For),
-            "true" => Ok(Token::Value(None, Some(true))),
-            "false" => Ok(Token::Value(None, Some(false))),
-            _ => Ok(Token::Identifier(identifier)),
-        }
+        Ok(keyword(&identifier))
     }
 
     fn skip_whitespace(&self) {
@@ -177,7 +633,18 @@ impl Lexer {
         }
     }
 
+    /// Tokenizes the input, leaving out comments.
     pub fn tokenize(&self) -> Result<Vec<Token>> {
+        Ok(self
+            .tokenize_with_trivia()?
+            .into_iter()
+            .filter(|token| !matches!(token, Token::Comment(_)))
+            .collect())
+    }
+
+    /// Tokenizes the input, keeping comments as [`Token::Comment`] so that
+    /// formatters and documentation tools can preserve them.
+    pub fn tokenize_with_trivia(&self) -> Result<Vec<Token>> {
         let mut tokens = Vec::new();
         loop {
             let token = self.next_token()?;
@@ -190,6 +657,78 @@ impl Lexer {
     }
 }
 
+// The keyword or literal spelled `identifier`, otherwise an identifier
+fn keyword(identifier: &str) -> Token {
+    match identifier {
+        "if" => Token::If,
+        "then" => Token::Then,
+        "else" => Token::Else,
+        "end" => Token::End,
+        "and" => Token::And,
+        "or" => Token::Or,
+        "not" => Token::Not,
+        "for" => Token::For,
+        "in" => Token::In,
+        "do" => Token::Do,
+        "function" => Token::Function,
+        "return" => Token::Return,
+        "local" => Token::Local,
+        "exit" => Token::Exit,
+        "abort" => Token::Abort,
+        "break" => Token::Break,
+        "continue" => Token::Continue,
+        "try" => Token::Try,
+        "catch" => Token::Catch,
+        "match" => Token::Match,
+        "with" => Token::With,
+        "case" => Token::Case,
+        "require" => Token::Require,
+        "assert" => Token::Assert,
+        "true" => Token::Value(None, Some(true)),
+        "false" => Token::Value(None, Some(false)),
+        "null" => Token::Null,
+        _ => Token::Identifier(identifier.to_string()),
+    }
+}
+
+// Writes the number `number`, which may have an exponent, times `10^shift`
+// in plain decimal notation without superfluous zeros, e.g. `1.50e3` as `1500`
+// and `5` shifted by -2 as `0.05`. Exponents are bounded, as numbers of more
+// than a few dozen digits cannot be decimals anyway.
+fn plain_decimal(number: &str, shift: i64) -> Option<String> {
+    let (mantissa, exponent) = match number.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, exponent.parse::<i64>().ok()?),
+        None => (number, 0),
+    };
+    let exponent = exponent + shift;
+    if exponent.abs() > 64 {
+        return None;
+    }
+    let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let digits = format!("{}{}", integer, fraction);
+    let point = integer.len() as i64 + exponent;
+    let (integer, fraction) = if point <= 0 {
+        ("0".to_string(), "0".repeat(-point as usize) + &digits)
+    } else if point as usize >= digits.len() {
+        (
+            digits.clone() + &"0".repeat(point as usize - digits.len()),
+            String::new(),
+        )
+    } else {
+        let (integer, fraction) = digits.split_at(point as usize);
+        (integer.to_string(), fraction.to_string())
+    };
+
+    let integer = match integer.trim_start_matches('0') {
+        "" => "0",
+        integer => integer,
+    };
+    match fraction.trim_end_matches('0') {
+        "" => Some(integer.to_string()),
+        fraction => Some(format!("{}.{}", integer, fraction)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -217,8 +756,8 @@ mod tests {
     fn test_numerical_literals() {
         let input = "123 4.56";
         let expected_tokens = vec![
-            Token::Value(Some(123.0), None),
-            Token::Value(Some(4.56), None),
+            Token::Value(Some("123".to_string()), None),
+            Token::Value(Some("4.56".to_string()), None),
         ];
 
         let lexer = Lexer::new(input.to_string());
@@ -227,6 +766,29 @@ mod tests {
         assert_eq!(tokens, expected_tokens);
     }
 
+    #[test]
+    fn test_exact_number_digits() {
+        // Digits past the precision of a float are kept as written
+        let input = "12345678901234567.1 9007199254740993 0.00000000001 001.50e3 \
+                     0.123456789012345678% 12345678901234567.89 USD";
+        let digits = |text: &str| Token::Value(Some(text.to_string()), None);
+        let expected_tokens = vec![
+            digits("12345678901234567.1"),
+            digits("9007199254740993"),
+            digits("0.00000000001"),
+            digits("1500"),
+            digits("0.00123456789012345678"),
+            Token::Money("12345678901234567.89".to_string(), "USD".to_string()),
+        ];
+        assert_eq!(
+            Lexer::new(input.to_string()).tokenize().unwrap(),
+            expected_tokens
+        );
+
+        let error = Lexer::new("1e65".to_string()).tokenize().unwrap_err();
+        assert!(error.to_string().contains("exponent out of range"));
+    }
+
     #[test]
     fn test_identifiers_and_keywords() {
         let input = "x if else and or not true false";
@@ -255,19 +817,19 @@ mod tests {
             Token::OpenParen,
             Token::Identifier("x".to_string()),
             Token::Superior,
-            Token::Value(Some(100.0), None),
+            Token::Value(Some("100".to_string()), None),
             Token::CloseParen,
             Token::OpenCurlyParen,
             Token::Identifier("x".to_string()),
             Token::Assign,
-            Token::Value(Some(100.0), None),
+            Token::Value(Some("100".to_string()), None),
             Token::Semicolon,
             Token::CloseCurlyParen,
             Token::Else,
             Token::OpenCurlyParen,
             Token::Identifier("x".to_string()),
             Token::Assign,
-            Token::Value(Some(0.0), None),
+            Token::Value(Some("0".to_string()), None),
             Token::Semicolon,
             Token::CloseCurlyParen,
         ];
@@ -291,9 +853,9 @@ mod tests {
     fn test_whitespace() {
         let input = "  1  + 2  ";
         let expected_tokens = vec![
-            Token::Value(Some(1.0), None),
+            Token::Value(Some("1".to_string()), None),
             Token::Plus,
-            Token::Value(Some(2.0), None),
+            Token::Value(Some("2".to_string()), None),
         ];
 
         let lexer = Lexer::new(input.to_string());
@@ -351,7 +913,7 @@ mod tests {
         let expected_tokens = vec![
             Token::Identifier("x".to_string()),
             Token::Assign,
-            Token::Value(Some(10.0), None),
+            Token::Value(Some("10".to_string()), None),
             Token::Semicolon,
         ];
 
@@ -367,7 +929,7 @@ mod tests {
         let expected_tokens = vec![
             Token::Identifier("long_variable_name".to_string()),
             Token::Assign,
-            Token::Value(Some(10.0), None),
+            Token::Value(Some("10".to_string()), None),
             Token::Semicolon,
         ];
 
@@ -398,9 +960,9 @@ mod tests {
         let lexer = Lexer::new(input.to_string());
         let tokens = lexer.tokenize();
         let expected_tokens = vec![
-            Token::Value(Some(1.0), None),
+            Token::Value(Some("1".to_string()), None),
             Token::Identifier("var_1".to_string()),
-            Token::Value(Some(2.0), None),
+            Token::Value(Some("2".to_string()), None),
             Token::Identifier("var_2".to_string()),
         ];
 
@@ -470,12 +1032,12 @@ mod tests {
         let expected_tokens = vec![
             Token::Identifier("x".to_string()),
             Token::Assign,
-            Token::Value(Some(10.0), None),
+            Token::Value(Some("10".to_string()), None),
             Token::Semicolon,
             Token::Newline,
             Token::Identifier("y".to_string()),
             Token::Assign,
-            Token::Value(Some(20.0), None),
+            Token::Value(Some("20".to_string()), None),
             Token::Semicolon,
         ];
 
@@ -527,7 +1089,7 @@ mod tests {
     #[test]
     fn test_skipt_coments() {
         let input = " 1 #+ 2 ";
-        let expected_tokens = vec![Token::Value(Some(1.0), None)];
+        let expected_tokens = vec![Token::Value(Some("1".to_string()), None)];
 
         let lexer = Lexer::new(input.to_string());
         let tokens = lexer.tokenize().unwrap();
@@ -535,7 +1097,7 @@ mod tests {
         assert_eq!(tokens, expected_tokens);
 
         let input = " 1 ###+ 2## ";
-        let expected_tokens = vec![Token::Value(Some(1.0), None)];
+        let expected_tokens = vec![Token::Value(Some("1".to_string()), None)];
 
         let lexer = Lexer::new(input.to_string());
         let tokens = lexer.tokenize().unwrap();
@@ -547,9 +1109,9 @@ mod tests {
     fn test_power_operator() {
         let input = "2 ** 3";
         let expected_tokens = vec![
-            Token::Value(Some(2.0), None),
+            Token::Value(Some("2".to_string()), None),
             Token::Power,
-            Token::Value(Some(3.0), None),
+            Token::Value(Some("3".to_string()), None),
         ];
         let lexer = Lexer::new(input.to_string());
         let tokens = lexer.tokenize().unwrap();
@@ -561,12 +1123,12 @@ mod tests {
         let input = "(2 + 3) ** 2";
         let expected_tokens = vec![
             Token::OpenParen,
-            Token::Value(Some(2.0), None),
+            Token::Value(Some("2".to_string()), None),
             Token::Plus,
-            Token::Value(Some(3.0), None),
+            Token::Value(Some("3".to_string()), None),
             Token::CloseParen,
             Token::Power,
-            Token::Value(Some(2.0), None),
+            Token::Value(Some("2".to_string()), None),
         ];
         let lexer = Lexer::new(input.to_string());
         let tokens = lexer.tokenize().unwrap();
@@ -579,13 +1141,437 @@ mod tests {
         let expected_tokens = vec![
             Token::Identifier("max".to_string()),
             Token::OpenParen,
-            Token::Value(Some(2.0), None),
+            Token::Value(Some("2".to_string()), None),
             Token::Comma,
-            Token::Value(Some(3.0), None),
+            Token::Value(Some("3".to_string()), None),
             Token::CloseParen,
         ];
         let lexer = Lexer::new(input.to_string());
         let tokens = lexer.tokenize().unwrap();
         assert_eq!(tokens, expected_tokens);
     }
+
+    #[test]
+    fn test_scientific_notation() {
+        let input = "1e6 2.5E-3 4e+2";
+        let expected_tokens = vec![
+            Token::Value(Some("1000000".to_string()), None),
+            Token::Value(Some("0.0025".to_string()), None),
+            Token::Value(Some("400".to_string()), None),
+        ];
+        let lexer = Lexer::new(input.to_string());
+        let tokens = lexer.tokenize().unwrap();
+        assert_eq!(tokens, expected_tokens);
+    }
+
+    #[test]
+    fn test_digit_separators() {
+        let input = "1_000_000 1_234.567_8";
+        let expected_tokens = vec![
+            Token::Value(Some("1000000".to_string()), None),
+            Token::Value(Some("1234.5678".to_string()), None),
+        ];
+        let lexer = Lexer::new(input.to_string());
+        let tokens = lexer.tokenize().unwrap();
+        assert_eq!(tokens, expected_tokens);
+    }
+
+    #[test]
+    fn test_percentage_and_basis_points() {
+        let input = "5% 12.5% 25bp 1_000bp";
+        let expected_tokens = vec![
+            Token::Value(Some("0.05".to_string()), None),
+            Token::Value(Some("0.125".to_string()), None),
+            Token::Value(Some("0.0025".to_string()), None),
+            Token::Value(Some("0.1".to_string()), None),
+        ];
+        let lexer = Lexer::new(input.to_string());
+        let tokens = lexer.tokenize().unwrap();
+        assert_eq!(tokens, expected_tokens);
+
+        // `bp` followed by more characters is an identifier
+        let input = "2bps";
+        let expected_tokens = vec![
+            Token::Value(Some("2".to_string()), None),
+            Token::Identifier("bps".to_string()),
+        ];
+        let lexer = Lexer::new(input.to_string());
+        assert_eq!(lexer.tokenize().unwrap(), expected_tokens);
+    }
+
+    #[test]
+    fn test_modulo() {
+        // A percent sign followed by an operand takes a remainder
+        let input = "7 % 3 7%3 x%2 5%(1) 5% and 5%";
+        let expected_tokens = vec![
+            Token::Value(Some("7".to_string()), None),
+            Token::Modulo,
+            Token::Value(Some("3".to_string()), None),
+            Token::Value(Some("7".to_string()), None),
+            Token::Modulo,
+            Token::Value(Some("3".to_string()), None),
+            Token::Identifier("x".to_string()),
+            Token::Modulo,
+            Token::Value(Some("2".to_string()), None),
+            Token::Value(Some("5".to_string()), None),
+            Token::Modulo,
+            Token::OpenParen,
+            Token::Value(Some("1".to_string()), None),
+            Token::CloseParen,
+            Token::Value(Some("0.05".to_string()), None),
+            Token::And,
+            Token::Value(Some("0.05".to_string()), None),
+        ];
+        let lexer = Lexer::new(input.to_string());
+        assert_eq!(lexer.tokenize().unwrap(), expected_tokens);
+    }
+
+    #[test]
+    fn test_money_literals() {
+        let input = "100 USD 1_500.25EUR 2e3\tCLP 5 Usd 7 USDT";
+        let expected_tokens = vec![
+            Token::Money("100".to_string(), "USD".to_string()),
+            Token::Money("1500.25".to_string(), "EUR".to_string()),
+            Token::Money("2000".to_string(), "CLP".to_string()),
+            Token::Value(Some("5".to_string()), None),
+            Token::Identifier("Usd".to_string()),
+            Token::Value(Some("7".to_string()), None),
+            Token::Identifier("USDT".to_string()),
+        ];
+        let lexer = Lexer::new(input.to_string());
+        assert_eq!(lexer.tokenize().unwrap(), expected_tokens);
+
+        // The code must be on the same line as the number
+        let tokens = Lexer::new("x = 100\nUSD = 1".to_string())
+            .tokenize()
+            .unwrap();
+        assert_eq!(tokens[2], Token::Value(Some("100".to_string()), None));
+    }
+
+    #[test]
+    fn test_dates_and_durations() {
+        let input = "2024-05-04 2024-05-04T09:30 30d 1m 2y 3w 4h 15min 10s";
+        let duration = |amount, unit| Token::Duration(Duration::from_unit(amount, unit).unwrap());
+        let expected_tokens = vec![
+            Token::Date("2024-05-04".parse().unwrap()),
+            Token::DateTime("2024-05-04T09:30:00".parse().unwrap()),
+            duration(30, "d"),
+            duration(1, "m"),
+            duration(2, "y"),
+            duration(3, "w"),
+            duration(4, "h"),
+            duration(15, "min"),
+            duration(10, "s"),
+        ];
+        let lexer = Lexer::new(input.to_string());
+        assert_eq!(lexer.tokenize().unwrap(), expected_tokens);
+
+        // Subtraction still needs its operands
+        let tokens = Lexer::new("2024 - 05".to_string()).tokenize().unwrap();
+        assert_eq!(tokens.len(), 3);
+        let tokens = Lexer::new("2 days".to_string()).tokenize().unwrap();
+        assert_eq!(tokens[1], Token::Identifier("days".to_string()));
+
+        let error = |input: &str| {
+            Lexer::new(input.to_string())
+                .tokenize()
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(error("x = 2023-02-29")
+            .contains("Malformed date literal `2023-02-29` at line 1, column 5"));
+        assert!(error("2024-05-04T25:00").contains("Malformed date literal"));
+        assert!(error("2024-05-04x").contains("Malformed date literal"));
+        assert!(error("1.5d").contains("a duration must be a whole number"));
+    }
+
+    #[test]
+    fn test_malformed_numbers() {
+        for input in ["1.2.3", "1.", "1__0", "1_", "1_.5", "1e", "1e+", "2.5E-x"] {
+            let lexer = Lexer::new(input.to_string());
+            assert!(
+                matches!(lexer.tokenize(), Err(ScriptingError::InvalidToken(_))),
+                "{} should not be a valid number",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_malformed_number_location() {
+        let lexer = Lexer::new("x = 1;\ny = 1.2.3;".to_string());
+        let err = lexer.tokenize().unwrap_err().to_string();
+        assert!(err.contains("`1.2.3`"));
+        assert!(err.contains("line 2, column 5"));
+    }
+
+    #[test]
+    fn test_lua_comments() {
+        let input = "x = 1 -- set x\ny = 2";
+        let expected_tokens = vec![
+            Token::Identifier("x".to_string()),
+            Token::Assign,
+            Token::Value(Some("1".to_string()), None),
+            Token::Newline,
+            Token::Identifier("y".to_string()),
+            Token::Assign,
+            Token::Value(Some("2".to_string()), None),
+        ];
+        let lexer = Lexer::new(input.to_string());
+        assert_eq!(lexer.tokenize().unwrap(), expected_tokens);
+
+        // A single minus is still an operator
+        let input = "1 - 2";
+        let expected_tokens = vec![
+            Token::Value(Some("1".to_string()), None),
+            Token::Minus,
+            Token::Value(Some("2".to_string()), None),
+        ];
+        let lexer = Lexer::new(input.to_string());
+        assert_eq!(lexer.tokenize().unwrap(), expected_tokens);
+    }
+
+    #[test]
+    fn test_block_comments() {
+        let input = "x /* inline */ = --[[ multi\nline ]] 1 /**/";
+        let expected_tokens = vec![
+            Token::Identifier("x".to_string()),
+            Token::Assign,
+            Token::Value(Some("1".to_string()), None),
+        ];
+        let lexer = Lexer::new(input.to_string());
+        assert_eq!(lexer.tokenize().unwrap(), expected_tokens);
+    }
+
+    #[test]
+    fn test_unterminated_block_comments() {
+        for input in ["x = 1 /* never closed", "x = 1 --[[ never closed ]", "/*/"] {
+            let lexer = Lexer::new(input.to_string());
+            assert!(lexer.tokenize().is_err(), "{} should not tokenize", input);
+        }
+    }
+
+    #[test]
+    fn test_comments_as_trivia() {
+        let input = "# header\nx = 1 -- note\n/* block */ --[[ lua\nblock ]]";
+        let expected_tokens = vec![
+            Token::Comment("# header".to_string()),
+            Token::Newline,
+            Token::Identifier("x".to_string()),
+            Token::Assign,
+            Token::Value(Some("1".to_string()), None),
+            Token::Comment("-- note".to_string()),
+            Token::Newline,
+            Token::Comment("/* block */".to_string()),
+            Token::Comment("--[[ lua\nblock ]]".to_string()),
+        ];
+        let lexer = Lexer::new(input.to_string());
+        assert_eq!(lexer.tokenize_with_trivia().unwrap(), expected_tokens);
+    }
+
+    #[test]
+    fn test_compound_assignment_operators() {
+        let input = "+= -= *= /= ** - =";
+        let expected_tokens = vec![
+            Token::PlusAssign,
+            Token::MinusAssign,
+            Token::MultiplyAssign,
+            Token::DivideAssign,
+            Token::Power,
+            Token::Minus,
+            Token::Assign,
+        ];
+        let lexer = Lexer::new(input.to_string());
+        assert_eq!(lexer.tokenize().unwrap(), expected_tokens);
+    }
+
+    #[test]
+    fn test_function_keywords() {
+        let input = "function f(a) return a end";
+        let expected_tokens = vec![
+            Token::Function,
+            Token::Identifier("f".to_string()),
+            Token::OpenParen,
+            Token::Identifier("a".to_string()),
+            Token::CloseParen,
+            Token::Return,
+            Token::Identifier("a".to_string()),
+            Token::End,
+        ];
+        let lexer = Lexer::new(input.to_string());
+        assert_eq!(lexer.tokenize().unwrap(), expected_tokens);
+    }
+
+    #[test]
+    fn test_local_keyword() {
+        let input = "local x = 1\nlocals";
+        let expected_tokens = vec![
+            Token::Local,
+            Token::Identifier("x".to_string()),
+            Token::Assign,
+            Token::Value(Some("1".to_string()), None),
+            Token::Newline,
+            Token::Identifier("locals".to_string()),
+        ];
+        let lexer = Lexer::new(input.to_string());
+        assert_eq!(lexer.tokenize().unwrap(), expected_tokens);
+    }
+
+    #[test]
+    fn test_strings() {
+        let input = r#"abort("Not \"enough\" cash\n") """#;
+        let expected_tokens = vec![
+            Token::Abort,
+            Token::OpenParen,
+            Token::String("Not \"enough\" cash\n".to_string()),
+            Token::CloseParen,
+            Token::String(String::new()),
+        ];
+        let lexer = Lexer::new(input.to_string());
+        assert_eq!(lexer.tokenize().unwrap(), expected_tokens);
+
+        let err = Lexer::new("x = \"open\ny = 1".to_string())
+            .tokenize()
+            .unwrap_err();
+        assert!(err.to_string().contains("line 1, column 5"));
+        assert!(Lexer::new(r#""\q""#.to_string()).tokenize().is_err());
+    }
+
+    #[test]
+    fn test_termination_keywords() {
+        let input = "exit abort break continue require assert exits";
+        let expected_tokens = vec![
+            Token::Exit,
+            Token::Abort,
+            Token::Break,
+            Token::Continue,
+            Token::Require,
+            Token::Assert,
+            Token::Identifier("exits".to_string()),
+        ];
+        let lexer = Lexer::new(input.to_string());
+        assert_eq!(lexer.tokenize().unwrap(), expected_tokens);
+    }
+
+    #[test]
+    fn test_try_catch_tokens() {
+        let input = "try x = 1 catch err Notify(err.message) end";
+        let expected_tokens = vec![
+            Token::Try,
+            Token::Identifier("x".to_string()),
+            Token::Assign,
+            Token::Value(Some("1".to_string()), None),
+            Token::Catch,
+            Token::Identifier("err".to_string()),
+            Token::Identifier("Notify".to_string()),
+            Token::OpenParen,
+            Token::Identifier("err".to_string()),
+            Token::Dot,
+            Token::Identifier("message".to_string()),
+            Token::CloseParen,
+            Token::End,
+        ];
+        let lexer = Lexer::new(input.to_string());
+        assert_eq!(lexer.tokenize().unwrap(), expected_tokens);
+        // A dot right after digits is a decimal point
+        assert_eq!(
+            Lexer::new("1.5".to_string()).tokenize().unwrap(),
+            vec![Token::Value(Some("1.5".to_string()), None)]
+        );
+    }
+
+    #[test]
+    fn test_list_tokens() {
+        let input = "for s in [\"AAPL\", xs[1:]] do end";
+        let expected_tokens = vec![
+            Token::For,
+            Token::Identifier("s".to_string()),
+            Token::In,
+            Token::OpenBracket,
+            Token::String("AAPL".to_string()),
+            Token::Comma,
+            Token::Identifier("xs".to_string()),
+            Token::OpenBracket,
+            Token::Value(Some("1".to_string()), None),
+            Token::Colon,
+            Token::CloseBracket,
+            Token::CloseBracket,
+            Token::Do,
+            Token::End,
+        ];
+        let lexer = Lexer::new(input.to_string());
+        assert_eq!(lexer.tokenize().unwrap(), expected_tokens);
+    }
+
+    #[test]
+    fn test_null_tokens() {
+        let input = "x = price ?? null";
+        let expected_tokens = vec![
+            Token::Identifier("x".to_string()),
+            Token::Assign,
+            Token::Identifier("price".to_string()),
+            Token::Coalesce,
+            Token::Null,
+        ];
+        let lexer = Lexer::new(input.to_string());
+        assert_eq!(lexer.tokenize().unwrap(), expected_tokens);
+        assert!(Lexer::new("x ? 1".to_string()).tokenize().is_err());
+    }
+
+    #[test]
+    fn test_interpolated_strings() {
+        let input = "\"Sold {units} {symbol}!\" \"{format(x, \"{y}\")}\"";
+        let expected_tokens = vec![
+            Token::InterpolationStart,
+            Token::String("Sold ".to_string()),
+            Token::OpenCurlyParen,
+            Token::Identifier("units".to_string()),
+            Token::CloseCurlyParen,
+            Token::String(" ".to_string()),
+            Token::OpenCurlyParen,
+            Token::Identifier("symbol".to_string()),
+            Token::CloseCurlyParen,
+            Token::String("!".to_string()),
+            Token::InterpolationEnd,
+            Token::InterpolationStart,
+            Token::OpenCurlyParen,
+            Token::Identifier("format".to_string()),
+            Token::OpenParen,
+            Token::Identifier("x".to_string()),
+            Token::Comma,
+            Token::InterpolationStart,
+            Token::OpenCurlyParen,
+            Token::Identifier("y".to_string()),
+            Token::CloseCurlyParen,
+            Token::InterpolationEnd,
+            Token::CloseParen,
+            Token::CloseCurlyParen,
+            Token::InterpolationEnd,
+        ];
+        let lexer = Lexer::new(input.to_string());
+        assert_eq!(lexer.tokenize().unwrap(), expected_tokens);
+
+        let lexer = Lexer::new("\"\\{not} {{a: 1}.a}\"".to_string());
+        assert_eq!(
+            lexer.tokenize().unwrap(),
+            vec![
+                Token::InterpolationStart,
+                Token::String("{not} ".to_string()),
+                Token::OpenCurlyParen,
+                Token::OpenCurlyParen,
+                Token::Identifier("a".to_string()),
+                Token::Colon,
+                Token::Value(Some("1".to_string()), None),
+                Token::CloseCurlyParen,
+                Token::Dot,
+                Token::Identifier("a".to_string()),
+                Token::CloseCurlyParen,
+                Token::InterpolationEnd,
+            ]
+        );
+
+        assert!(Lexer::new("\"{}\"".to_string()).tokenize().is_err());
+        assert!(Lexer::new("\"{x\"".to_string()).tokenize().is_err());
+        assert!(Lexer::new("\"{x\n}\"".to_string()).tokenize().is_err());
+    }
 }
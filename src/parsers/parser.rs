@@ -1,27 +1,199 @@
 use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::sync::OnceLock;
 
 use super::lexer::Token;
-use crate::nodes::node::{ExpressionTree, Node};
-use crate::utils::errors::{Result, ScriptingError};
+use crate::nodes::node::{ExpressionTree, Indicator, MathFunction, Node, Statistic};
+use crate::utils::{
+    decimal::Decimal,
+    errors::{Result, ScriptingError},
+};
+
+/// A function provided by the language: its name, the number of arguments it
+/// takes and the node evaluating a call
+pub struct Builtin {
+    pub name: &'static str,
+    pub min_args: usize,
+    pub max_args: usize,
+    node: fn(Vec<ExpressionTree>) -> Node,
+}
+
+impl Builtin {
+    const fn new(
+        name: &'static str,
+        min_args: usize,
+        max_args: usize,
+        node: fn(Vec<ExpressionTree>) -> Node,
+    ) -> Self {
+        Builtin {
+            name,
+            min_args,
+            max_args,
+            node,
+        }
+    }
+}
+
+/// The functions provided by the language, which scripts cannot redefine
+pub const BUILTINS: &[Builtin] = &[
+    // math
+    Builtin::new("ln", 1, 1, Node::Ln),
+    Builtin::new("exp", 1, 1, Node::Exp),
+    Builtin::new("pow", 2, 2, Node::Pow),
+    Builtin::new("min", 2, 100, Node::Min),
+    Builtin::new("max", 2, 100, Node::Max),
+    Builtin::new("mod", 2, 2, Node::Modulo),
+    Builtin::new("clamp", 3, 3, Node::Clamp),
+    Builtin::new("round", 1, 2, Node::RoundTo),
+    Builtin::new("abs", 1, 1, |args| Node::Math(args, MathFunction::Abs)),
+    Builtin::new("sign", 1, 1, |args| Node::Math(args, MathFunction::Sign)),
+    Builtin::new("floor", 1, 1, |args| Node::Math(args, MathFunction::Floor)),
+    Builtin::new("ceil", 1, 1, |args| Node::Math(args, MathFunction::Ceil)),
+    Builtin::new("sqrt", 1, 1, |args| Node::Math(args, MathFunction::Sqrt)),
+    Builtin::new("log10", 1, 1, |args| Node::Math(args, MathFunction::Log10)),
+    Builtin::new("sin", 1, 1, |args| Node::Math(args, MathFunction::Sin)),
+    Builtin::new("cos", 1, 1, |args| Node::Math(args, MathFunction::Cos)),
+    Builtin::new("tan", 1, 1, |args| Node::Math(args, MathFunction::Tan)),
+    Builtin::new("ncdf", 1, 1, |args| Node::Math(args, MathFunction::Ncdf)),
+    Builtin::new("npdf", 1, 1, |args| Node::Math(args, MathFunction::Npdf)),
+    Builtin::new("select", 3, 3, Node::Select),
+    // lists
+    Builtin::new("len", 1, 1, Node::Len),
+    Builtin::new("append", 2, 2, Node::Append),
+    // statistics
+    Builtin::new("sum", 1, 1, |args| Node::Statistic(args, Statistic::Sum)),
+    Builtin::new("mean", 1, 1, |args| Node::Statistic(args, Statistic::Mean)),
+    Builtin::new("median", 1, 1, |args| {
+        Node::Statistic(args, Statistic::Median)
+    }),
+    Builtin::new("variance", 1, 1, |args| {
+        Node::Statistic(args, Statistic::Variance)
+    }),
+    Builtin::new("stdev", 1, 1, |args| {
+        Node::Statistic(args, Statistic::Stdev)
+    }),
+    Builtin::new("percentile", 2, 2, |args| {
+        Node::Statistic(args, Statistic::Percentile)
+    }),
+    Builtin::new("correlation", 2, 2, |args| {
+        Node::Statistic(args, Statistic::Correlation)
+    }),
+    Builtin::new("cumsum", 1, 1, |args| {
+        Node::Statistic(args, Statistic::Cumsum)
+    }),
+    Builtin::new("max_drawdown", 1, 1, |args| {
+        Node::Statistic(args, Statistic::MaxDrawdown)
+    }),
+    // formatting
+    Builtin::new("format", 2, 2, Node::Format),
+    Builtin::new("format_currency", 2, 3, Node::FormatCurrency),
+    Builtin::new("round_to", 2, 3, Node::RoundTo),
+    // money
+    Builtin::new("convert", 2, 2, Node::Convert),
+    // time
+    Builtin::new("today", 0, 0, Node::Today),
+    Builtin::new("now", 0, 0, Node::Now),
+    Builtin::new("day_of_month", 1, 1, Node::DayOfMonth),
+    Builtin::new("is_weekday", 1, 1, Node::IsWeekday),
+    // calendars
+    Builtin::new("is_business_day", 1, 2, Node::IsBusinessDay),
+    Builtin::new("add_business_days", 2, 3, Node::AddBusinessDays),
+    Builtin::new("adjust", 2, 3, Node::Adjust),
+    Builtin::new("end_of_month", 1, 1, Node::EndOfMonth),
+    Builtin::new("is_market_open", 1, 2, Node::IsMarketOpen),
+    // price history
+    Builtin::new("sma", 2, 2, |args| Node::Indicator(args, Indicator::Sma)),
+    Builtin::new("ema", 2, 2, |args| Node::Indicator(args, Indicator::Ema)),
+    Builtin::new("rsi", 2, 2, |args| Node::Indicator(args, Indicator::Rsi)),
+    Builtin::new("bollinger", 2, 3, |args| {
+        Node::Indicator(args, Indicator::Bollinger)
+    }),
+    Builtin::new("high", 2, 2, |args| Node::Indicator(args, Indicator::High)),
+    Builtin::new("low", 2, 2, |args| Node::Indicator(args, Indicator::Low)),
+    Builtin::new("crosses_above", 2, 2, Node::CrossesAbove),
+];
+
+/// The builtin function called `name`, if there is one
+pub fn builtin(name: &str) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|builtin| builtin.name == name)
+}
+
+// A call to a user-defined function, checked once all definitions are known
+struct PendingCall {
+    name: String,
+    arity: usize,
+    // Whether the call has an `at` argument, which only host functions take
+    looks_back: bool,
+    line: usize,
+    column: usize,
+}
 
 pub struct Parser {
     tokens: RefCell<Vec<Token>>,
     position: RefCell<usize>,
     line: RefCell<usize>,
     column: RefCell<usize>,
+    // Depth of open parentheses; line breaks are not significant inside them
+    nesting: RefCell<usize>,
+    // Depth of statement blocks, the script itself being the first one
+    block_depth: RefCell<usize>,
+    in_function: RefCell<bool>,
+    // Depth of the loops enclosing the current statement
+    loop_depth: RefCell<usize>,
+    // Arity of the user-defined functions
+    functions: RefCell<HashMap<String, usize>>,
+    // Functions provided by the host application, which check their own
+    // arguments
+    host_functions: HashSet<String>,
+    // Host functions executing transactions, which pre-conditions must precede
+    transactional_functions: HashSet<String>,
+    calls: RefCell<Vec<PendingCall>>,
+    // Issues that do not prevent running the script, such as unreachable cases
+    warnings: RefCell<Vec<String>>,
 }
 
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Self {
+        // Comments carry no meaning for the grammar
+        let tokens = tokens
+            .into_iter()
+            .filter(|token| !matches!(token, Token::Comment(_)))
+            .collect();
         Self {
             tokens: RefCell::new(tokens),
             position: RefCell::new(0),
             line: RefCell::new(1),
             column: RefCell::new(1),
+            nesting: RefCell::new(0),
+            block_depth: RefCell::new(0),
+            in_function: RefCell::new(false),
+            loop_depth: RefCell::new(0),
+            functions: RefCell::new(HashMap::new()),
+            host_functions: HashSet::new(),
+            transactional_functions: HashSet::new(),
+            calls: RefCell::new(Vec::new()),
+            warnings: RefCell::new(Vec::new()),
         }
     }
 
+    /// Declares the functions the host application provides to the script,
+    /// such as `Sell` or `Notify`.
+    pub fn with_host_functions(mut self, names: &[&str]) -> Self {
+        self.host_functions
+            .extend(names.iter().map(|name| name.to_string()));
+        self
+    }
+
+    /// Declares the host functions executing transactions, such as `Buy` or
+    /// `TransferAmount`. In a block, every `require` must come before the
+    /// first call to one of them.
+    pub fn with_transactional_functions(mut self, names: &[&str]) -> Self {
+        let names = names.iter().map(|name| name.to_string());
+        self.host_functions.extend(names.clone());
+        self.transactional_functions.extend(names);
+        self
+    }
+
     pub fn current_token(&self) -> Token {
         self.tokens
             .borrow()
@@ -30,6 +202,15 @@ impl Parser {
             .unwrap_or(Token::EOF)
     }
 
+    // The token right after the current one, line breaks included
+    pub fn peek_token(&self) -> Token {
+        self.tokens
+            .borrow()
+            .get(*self.position.borrow() + 1)
+            .cloned()
+            .unwrap_or(Token::EOF)
+    }
+
     pub fn prev_token(&self) -> Token {
         self.tokens
             .borrow()
@@ -44,6 +225,11 @@ impl Parser {
         let mut column = self.column.borrow_mut();
         let tokens = self.tokens.borrow();
 
+        // Only the first token can be a newline, the others being skipped
+        if tokens.get(*pos) == Some(&Token::Newline) {
+            *line += 1;
+            *column = 1;
+        }
         loop {
             let current_token = tokens.get(*pos + 1).cloned().unwrap_or(Token::EOF);
             match current_token {
@@ -66,14 +252,28 @@ impl Parser {
 
     // Generate an error message containing the current line and column
     pub fn error_message(&self, msg: &str) -> ScriptingError {
-        let line = *self.line.borrow();
-        let column = *self.column.borrow();
+        self.error_at(*self.line.borrow(), *self.column.borrow(), msg)
+    }
+
+    fn error_at(&self, line: usize, column: usize, msg: &str) -> ScriptingError {
         ScriptingError::InvalidSyntax(format!(
             "Error at line {}, column {}: {}",
             line, column, msg
         ))
     }
 
+    fn warn_at(&self, line: usize, column: usize, msg: &str) {
+        self.warnings.borrow_mut().push(format!(
+            "Warning at line {}, column {}: {}",
+            line, column, msg
+        ));
+    }
+
+    /// Warnings found while parsing, with their line and column
+    pub fn warnings(&self) -> Vec<String> {
+        self.warnings.borrow().clone()
+    }
+
     /// Verifies that the current token matches the expected token and advances the parser.
     /// Returns an error if the token does not match.
     pub fn expect_token(&self, expected: Token) -> Result<()> {
@@ -87,31 +287,196 @@ impl Parser {
             )))
         }
     }
+
+    /// Whether a line break separates the current token from the previous one.
+    /// `advance` skips newline tokens, but they stay in the token list.
+    /// Inside parentheses line breaks are ignored.
+    pub fn newline_before(&self) -> bool {
+        let pos = *self.position.borrow();
+        *self.nesting.borrow() == 0
+            && pos > 0
+            && self.tokens.borrow().get(pos - 1) == Some(&Token::Newline)
+    }
+
+    // Runs `parse` as if inside parentheses
+    fn nested<T>(&self, parse: impl FnOnce() -> Result<T>) -> Result<T> {
+        *self.nesting.borrow_mut() += 1;
+        let result = parse();
+        *self.nesting.borrow_mut() -= 1;
+        result
+    }
+
+    // Whether the current token ends a statement, see `end_statement`
+    fn at_statement_end(&self) -> bool {
+        matches!(
+            self.current_token(),
+            Token::Semicolon
+                | Token::EOF
+                | Token::End
+                | Token::Else
+                | Token::Catch
+                | Token::Case
+                | Token::CloseCurlyParen
+        ) || self.newline_before()
+    }
+
+    /// Statements are terminated by a semicolon or a line break. The end of
+    /// the enclosing block (`end`, `else`, `catch`, `}`) or of the input also
+    /// closes the last statement, so `if x > 1 then y = 2 end` is valid on a
+    /// single line.
+    pub fn end_statement(&self) -> Result<()> {
+        match self.current_token() {
+            Token::Semicolon => {
+                self.advance();
+                Ok(())
+            }
+            Token::EOF
+            | Token::End
+            | Token::Else
+            | Token::Catch
+            | Token::Case
+            | Token::CloseCurlyParen => Ok(()),
+            _ if self.newline_before() => Ok(()),
+            token => Err(self.error_message(&format!(
+                "Expected `;` or a newline after statement, found {:?}",
+                token
+            ))),
+        }
+    }
+
+    // Skips empty statements between two statements
+    fn skip_separators(&self) {
+        while matches!(self.current_token(), Token::Semicolon | Token::Newline) {
+            self.advance();
+        }
+    }
 }
 
 impl Parser {
     pub fn parse(&self) -> Result<ExpressionTree> {
+        let expressions = self.parse_block(&[])?;
+        if self.current_token() != Token::EOF {
+            return Err(self.error_message(&format!("Unexpected token {:?}", self.current_token())));
+        }
+        self.check_calls()?;
+        Ok(Box::new(Node::Base(expressions)))
+    }
+
+    // Functions can be called before their definition, so arity is checked
+    // once the whole script has been parsed
+    fn check_calls(&self) -> Result<()> {
+        let functions = self.functions.borrow();
+        self.calls
+            .borrow()
+            .iter()
+            .try_for_each(|call| match functions.get(&call.name) {
+                None if self.host_functions.contains(&call.name) => Ok(()),
+                Some(_) if call.looks_back => Err(self.error_at(
+                    call.line,
+                    call.column,
+                    &format!(
+                        "Cannot look back at function {}, only at host functions",
+                        call.name
+                    ),
+                )),
+                Some(&arity) if arity == call.arity => Ok(()),
+                Some(&arity) => Err(self.error_at(
+                    call.line,
+                    call.column,
+                    &format!(
+                        "Function {} expects {} arguments, found {}",
+                        call.name, arity, call.arity
+                    ),
+                )),
+                None => Err(self.error_at(
+                    call.line,
+                    call.column,
+                    &format!("Undefined function {}", call.name),
+                )),
+            })
+    }
+
+    /// Parses statements until one of the `terminators` (or the end of the
+    /// input) is found. The terminator itself is not consumed.
+    pub fn parse_block(&self, terminators: &[Token]) -> Result<Vec<ExpressionTree>> {
+        *self.block_depth.borrow_mut() += 1;
         let mut expressions = Vec::new();
-        while self.current_token() != Token::EOF {
-            if self.current_token() == Token::Newline {
-                self.advance();
-                continue;
+        // First transactional call of the block
+        let mut transaction = None;
+        let result = loop {
+            self.skip_separators();
+            let token = self.current_token();
+            if token == Token::EOF || terminators.contains(&token) {
+                break Ok(expressions);
+            }
+            let (line, column) = (*self.line.borrow(), *self.column.borrow());
+            let expr = match self.parse_expression() {
+                Ok(expr) => expr,
+                Err(err) => break Err(err),
+            };
+            if let (Node::Require(..), Some(name)) = (expr.as_ref(), &transaction) {
+                break Err(self.error_at(
+                    line,
+                    column,
+                    &format!(
+                        "`require` must come before the call to {} in its block",
+                        name
+                    ),
+                ));
+            }
+            if transaction.is_none() {
+                transaction = self.transactional_call(&expr);
             }
-            let expr = self.parse_expression()?;
             expressions.push(expr);
+        };
+        *self.block_depth.borrow_mut() -= 1;
+        result
+    }
+
+    // Name of a transactional function called by the statement, function
+    // definitions aside as their body does not run there
+    fn transactional_call(&self, node: &Node) -> Option<String> {
+        match node {
+            Node::Call(_, name) if self.transactional_functions.contains(name) => {
+                Some(name.clone())
+            }
+            Node::Function(..) => None,
+            _ if node.is_leaf() => None,
+            _ => node
+                .children()
+                .iter()
+                .find_map(|child| self.transactional_call(child)),
         }
-        Ok(Box::new(Node::Base(expressions)))
     }
 
     pub fn parse_expression(&self) -> Result<ExpressionTree> {
         match self.current_token() {
             Token::If => self.parse_if(),
+            Token::For => self.parse_for(),
+            Token::Try => self.parse_try(),
+            Token::Match => self.parse_match(),
+            Token::Function => self.parse_function(),
+            Token::Return => self.parse_return(),
+            Token::Local => self.parse_local(),
+            Token::Exit => self.parse_exit(),
+            Token::Abort => self.parse_abort(),
+            Token::Break | Token::Continue => self.parse_loop_control(),
+            Token::Require | Token::Assert => self.parse_precondition(),
             Token::EOF => Err(self.error_message("Unexpected end of expression")),
+            Token::Identifier(_) if self.peek_token() == Token::OpenParen => {
+                // A function called for its side effects
+                let call = self.parse_var_const_func()?;
+                self.end_statement()?;
+                Ok(call)
+            }
             _ => {
-                //let lhs = self.parse_variable()?;
                 let lhs = self.parse_variable()?;
                 match self.current_token() {
                     Token::Assign => self.parse_assign(lhs),
+                    Token::PlusAssign
+                    | Token::MinusAssign
+                    | Token::MultiplyAssign
+                    | Token::DivideAssign => self.parse_compound_assign(lhs),
                     Token::EOF => Err(self.error_message("Unexpected end of expression")),
                     Token::Newline => Err(self.error_message("Unexpected newline")),
                     _ => Err(ScriptingError::UnexpectedToken(format!(
@@ -123,60 +488,446 @@ impl Parser {
         }
     }
 
+    /// Parses an `if` statement in either of its two forms:
+    ///
+    /// ```text
+    /// if x > 1 then ... else ... end
+    /// if x > 1 { ... } else { ... }
+    /// ```
+    ///
+    /// In the brace form, `else if` chains without extra braces.
     pub fn parse_if(&self) -> Result<ExpressionTree> {
         self.expect_token(Token::If)?;
+        let line = *self.line.borrow();
         self.advance();
         let mut conditions = self.parse_conditions()?;
 
-        self.expect_token(Token::Then)?;
+        let (expressions, else_statements) = match self.current_token() {
+            Token::Then => {
+                self.advance();
+                self.parse_then_end_blocks()?
+            }
+            Token::OpenCurlyParen => self.parse_brace_blocks()?,
+            token => {
+                return Err(self.error_message(&format!(
+                    "Expected `then` or `{{` after condition, found {:?}",
+                    token
+                )))
+            }
+        };
+
+        // The else block starts right after the condition and the then block
+        let else_index = else_statements
+            .as_ref()
+            .map(|_| conditions.len() + expressions.len());
+        conditions.extend(expressions);
+        conditions.extend(else_statements.unwrap_or_default());
+        Ok(Box::new(Node::If(conditions, else_index, line)))
+    }
+
+    fn parse_then_end_blocks(&self) -> Result<(Vec<ExpressionTree>, Option<Vec<ExpressionTree>>)> {
+        let expressions = self.parse_block(&[Token::Else, Token::End])?;
+
+        let mut else_statements = None;
+        if self.current_token() == Token::Else {
+            self.advance();
+            else_statements = Some(self.parse_block(&[Token::End])?);
+            if self.current_token() != Token::End {
+                return Err(self.error_message("Expected `end` after `else` block"));
+            }
+        } else if self.current_token() != Token::End {
+            return Err(self.error_message("Expected `else` or `end` after `then` block"));
+        }
+        self.advance();
+        Ok((expressions, else_statements))
+    }
+
+    fn parse_brace_blocks(&self) -> Result<(Vec<ExpressionTree>, Option<Vec<ExpressionTree>>)> {
+        let expressions = self.parse_braces()?;
+
+        if self.current_token() != Token::Else {
+            return Ok((expressions, None));
+        }
+        self.advance();
+        let else_statements = match self.current_token() {
+            Token::If => vec![self.parse_if()?],
+            _ => self.parse_braces()?,
+        };
+        Ok((expressions, Some(else_statements)))
+    }
+
+    /// Parses a `match` statement, which runs the block of the first case
+    /// with a value equal to the matched one, or else the else block:
+    ///
+    /// ```text
+    /// match symbol with
+    /// case "AAPL", "MSFT" then ...
+    /// case "GOOG" then ...
+    /// else ...
+    /// end
+    /// ```
+    ///
+    /// A case repeating the literal value of a previous case is reported as
+    /// a warning, as it can never match.
+    pub fn parse_match(&self) -> Result<ExpressionTree> {
+        self.expect_token(Token::Match)?;
         self.advance();
+        let mut children = vec![self.parse_expr()?];
+        self.expect_token(Token::With)?;
+        self.advance();
+        self.skip_separators();
 
-        let mut expressions = Vec::new();
-        while self.current_token() != Token::EOF
-            && self.current_token() != Token::Else
-            && self.current_token() != Token::End
-        {
-            let expr = self.parse_expression()?;
-            expressions.push(expr);
+        // Line of the case each literal value was first found in
+        let mut seen = HashMap::new();
+        while self.current_token() == Token::Case {
+            let (line, column) = (*self.line.borrow(), *self.column.borrow());
+            self.advance();
+            let mut case = vec![self.parse_expr()?];
+            while self.current_token() == Token::Comma {
+                self.advance();
+                case.push(self.parse_expr()?);
+            }
+            self.expect_token(Token::Then)?;
+            self.advance();
+            self.check_case(&case, &mut seen, line, column);
+
+            let values = case.len();
+            case.extend(self.parse_block(&[Token::Case, Token::Else, Token::End])?);
+            children.push(Box::new(Node::Case(case, values)));
+        }
+        if children.len() == 1 {
+            return Err(self.error_message("Expected `case` after `with`"));
         }
 
         let mut else_index = None;
         if self.current_token() == Token::Else {
             self.advance();
-            else_index = Some(expressions.len());
-
-            let mut else_statements = Vec::new();
-            while self.current_token() != Token::EOF && self.current_token() != Token::End {
-                // Parse either a regular expression or another nested if
-                let statement = match self.current_token() {
-                    Token::If => self.parse_if()?, // Handle nested `if` here
-                    _ => self.parse_expression()?,
-                };
-                else_statements.push(statement);
+            else_index = Some(children.len());
+            children.extend(self.parse_block(&[Token::End])?);
+        }
+        if self.current_token() != Token::End {
+            return Err(self.error_message("Expected `case`, `else` or `end` in match"));
+        }
+        self.advance();
+        Ok(Box::new(Node::Match(children, else_index)))
+    }
+
+    // Warns about the literal values of a case already found in a previous
+    // case, or twice in this one
+    fn check_case(
+        &self,
+        values: &[ExpressionTree],
+        seen: &mut HashMap<String, usize>,
+        line: usize,
+        column: usize,
+    ) {
+        let mut repeated = Vec::new();
+        for value in values {
+            let Some(literal) = literal_value(value) else {
+                continue;
+            };
+            match seen.get(&literal) {
+                Some(&first) => repeated.push((literal, first)),
+                None => {
+                    seen.insert(literal, line);
+                }
             }
+        }
+        if !repeated.is_empty() && repeated.len() == values.len() {
+            let literals = repeated
+                .iter()
+                .map(|(literal, _)| literal.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            self.warn_at(
+                line,
+                column,
+                &format!("Unreachable case: {} already matched", literals),
+            );
+            return;
+        }
+        for (literal, first) in repeated {
+            self.warn_at(
+                line,
+                column,
+                &format!(
+                    "Duplicate case {}, already matched at line {}",
+                    literal, first
+                ),
+            );
+        }
+    }
+
+    /// Parses a `try` statement in either of its two forms:
+    ///
+    /// ```text
+    /// try ... catch err ... end
+    /// try { ... } catch err { ... }
+    /// ```
+    ///
+    /// The name after `catch` is optional; when given, it is a local of the
+    /// catch block holding the error.
+    pub fn parse_try(&self) -> Result<ExpressionTree> {
+        self.expect_token(Token::Try)?;
+        self.advance();
+
+        let braces = self.current_token() == Token::OpenCurlyParen;
+        let mut children = match braces {
+            true => self.parse_braces()?,
+            false => self.parse_block(&[Token::Catch])?,
+        };
+        self.expect_token(Token::Catch)?;
+        self.advance();
+
+        // A name directly followed by the end of the line or by the block is
+        // the error variable, anything else starts the catch block
+        let binding = match self.current_token() {
+            Token::Identifier(_)
+                if matches!(
+                    self.peek_token(),
+                    Token::Newline | Token::Semicolon | Token::OpenCurlyParen | Token::End
+                ) =>
+            {
+                Some(self.parse_variable()?)
+            }
+            _ => None,
+        };
 
-            if self.current_token() == Token::End {
+        let catch_index = children.len();
+        match braces {
+            true => children.extend(self.parse_braces()?),
+            false => {
+                children.extend(self.parse_block(&[Token::End])?);
+                self.expect_token(Token::End)?;
                 self.advance();
-                conditions.extend(expressions);
-                conditions.extend(else_statements);
-                return Ok(Box::new(Node::If(conditions, else_index)));
-            } else {
-                return Err(self.error_message("Expected `end` after `else` block"));
             }
-        } else if self.current_token() == Token::End {
+        }
+        Ok(Box::new(Node::Try(children, catch_index, binding)))
+    }
+
+    /// Parses a `{ ... }` block of statements.
+    pub fn parse_braces(&self) -> Result<Vec<ExpressionTree>> {
+        self.expect_token(Token::OpenCurlyParen)?;
+        self.advance();
+        let expressions = self.parse_block(&[Token::CloseCurlyParen])?;
+        self.expect_token(Token::CloseCurlyParen)?;
+        self.advance();
+        Ok(expressions)
+    }
+
+    /// Parses a function definition, in either of the two block forms:
+    ///
+    /// ```text
+    /// function stop_loss(price, limit) return price < limit end
+    /// function stop_loss(price, limit) { return price < limit }
+    /// ```
+    ///
+    /// Functions are defined at the top level of the script and can be called
+    /// from anywhere, including before their definition and from themselves.
+    pub fn parse_function(&self) -> Result<ExpressionTree> {
+        self.expect_token(Token::Function)?;
+        if *self.block_depth.borrow() > 1 || *self.in_function.borrow() {
+            return Err(
+                self.error_message("Functions must be defined at the top level of the script")
+            );
+        }
+        self.advance();
+
+        let name = match self.current_token() {
+            Token::Identifier(name) => name,
+            token => {
+                return Err(
+                    self.error_message(&format!("Expected function name, found {:?}", token))
+                )
+            }
+        };
+        if builtin(&name).is_some() {
+            return Err(self.error_message(&format!("Cannot redefine builtin function {}", name)));
+        }
+        if self.host_functions.contains(&name) {
+            return Err(self.error_message(&format!("Cannot redefine host function {}", name)));
+        }
+        if self.functions.borrow().contains_key(&name) {
+            return Err(self.error_message(&format!("Function {} is already defined", name)));
+        }
+        self.advance();
+
+        let params = self.parse_parameters()?;
+        // Registered before the body so that the function can call itself
+        self.functions
+            .borrow_mut()
+            .insert(name.clone(), params.len());
+
+        *self.in_function.borrow_mut() = true;
+        // `break` and `continue` cannot leave the function
+        let loop_depth = self.loop_depth.replace(0);
+        let body = match self.current_token() {
+            Token::OpenCurlyParen => self.parse_braces(),
+            _ => self.parse_block(&[Token::End]).and_then(|body| {
+                self.expect_token(Token::End)?;
+                self.advance();
+                Ok(body)
+            }),
+        };
+        *self.in_function.borrow_mut() = false;
+        *self.loop_depth.borrow_mut() = loop_depth;
+
+        Ok(Box::new(Node::Function(
+            body?,
+            name,
+            params,
+            OnceLock::new(),
+        )))
+    }
+
+    fn parse_parameters(&self) -> Result<Vec<String>> {
+        self.expect_token(Token::OpenParen)?;
+        self.advance();
+
+        let mut params: Vec<String> = Vec::new();
+        while self.current_token() != Token::CloseParen {
+            match self.current_token() {
+                Token::Identifier(param) if params.contains(&param) => {
+                    return Err(self.error_message(&format!("Duplicate parameter {}", param)))
+                }
+                Token::Identifier(param) => params.push(param),
+                token => {
+                    return Err(
+                        self.error_message(&format!("Expected parameter name, found {:?}", token))
+                    )
+                }
+            }
             self.advance();
-            conditions.extend(expressions);
-            return Ok(Box::new(Node::If(conditions, else_index)));
-        } else {
-            return Err(self.error_message("Expected `else` or `end` after `then` block"));
+            match self.current_token() {
+                Token::Comma => self.advance(),
+                Token::CloseParen => (),
+                _ => return Err(self.error_message("Expected comma or closing parenthesis")),
+            }
+        }
+        self.advance();
+        Ok(params)
+    }
+
+    /// Parses `return` and its optional value. Inside a function it ends the
+    /// call, at the top level it ends the script successfully.
+    pub fn parse_return(&self) -> Result<ExpressionTree> {
+        self.expect_token(Token::Return)?;
+        let line = *self.line.borrow();
+        self.advance();
+
+        let mut node = Node::new_return(line);
+        if !self.at_statement_end() {
+            node.add_child(self.parse_expr()?);
+        }
+        self.end_statement()?;
+        Ok(Box::new(node))
+    }
+
+    /// Parses `exit`, which ends the script successfully from anywhere,
+    /// functions included.
+    pub fn parse_exit(&self) -> Result<ExpressionTree> {
+        self.expect_token(Token::Exit)?;
+        let line = *self.line.borrow();
+        self.advance();
+        self.end_statement()?;
+        Ok(Box::new(Node::Exit(line)))
+    }
+
+    /// Parses `abort(reason)`, which ends the script with a failure. The
+    /// reason is an expression evaluating to a string.
+    pub fn parse_abort(&self) -> Result<ExpressionTree> {
+        self.expect_token(Token::Abort)?;
+        let line = *self.line.borrow();
+        self.advance();
+
+        let mut node = Node::new_abort(line);
+        self.expect_token(Token::OpenParen)?;
+        self.advance();
+        node.add_child(self.nested(|| self.parse_expr())?);
+        self.expect_token(Token::CloseParen)?;
+        self.advance();
+
+        self.end_statement()?;
+        Ok(Box::new(node))
+    }
+
+    /// Parses `require(condition, message)` and `assert(condition, message)`.
+    /// An optional third argument, when true, sends the message as a
+    /// notification if the condition does not hold.
+    pub fn parse_precondition(&self) -> Result<ExpressionTree> {
+        let line = *self.line.borrow();
+        let (mut node, keyword) = match self.current_token() {
+            Token::Require => (Node::new_require(line), "require"),
+            Token::Assert => (Node::new_assert(line), "assert"),
+            token => return Err(ScriptingError::UnexpectedToken(format!("{:?}", token))),
+        };
+        self.advance();
+
+        let args = self.parse_function_args()?;
+        self.expect_token(Token::CloseParen)?;
+        self.advance();
+        if !(2..=3).contains(&args.len()) {
+            return Err(self.error_message(&format!(
+                "`{}` expects a condition, a message and an optional notification flag",
+                keyword
+            )));
+        }
+        args.into_iter().for_each(|arg| node.add_child(arg));
+
+        self.end_statement()?;
+        Ok(Box::new(node))
+    }
+
+    /// Parses `break` and `continue`, only valid inside a loop.
+    pub fn parse_loop_control(&self) -> Result<ExpressionTree> {
+        let (node, keyword) = match self.current_token() {
+            Token::Break => (Node::Break, "break"),
+            Token::Continue => (Node::Continue, "continue"),
+            token => return Err(ScriptingError::UnexpectedToken(format!("{:?}", token))),
+        };
+        if *self.loop_depth.borrow() == 0 {
+            return Err(self.error_message(&format!("`{}` outside of a loop", keyword)));
+        }
+        self.advance();
+        self.end_statement()?;
+        Ok(Box::new(node))
+    }
+
+    /// Parses `local x` or `local x = e`, declaring `x` in the enclosing
+    /// block. Without a value the variable starts uninitialized.
+    pub fn parse_local(&self) -> Result<ExpressionTree> {
+        self.expect_token(Token::Local)?;
+        self.advance();
+
+        let mut node = Node::new_local();
+        match self.current_token() {
+            Token::Identifier(_) => node.add_child(self.parse_variable()?),
+            token => {
+                return Err(self.error_message(&format!(
+                    "Expected a variable name after `local`, found {:?}",
+                    token
+                )))
+            }
+        }
+        if self.current_token() == Token::Assign {
+            self.advance();
+            node.add_child(self.parse_expr()?);
         }
+        self.end_statement()?;
+        Ok(Box::new(node))
     }
 
     pub fn parse_variable(&self) -> Result<ExpressionTree> {
         match self.current_token() {
             Token::Identifier(name) => {
+                let line = *self.line.borrow();
                 self.advance();
-                Ok(Box::new(Node::Variable(Vec::new(), name, OnceLock::new())))
+                Ok(Box::new(Node::Variable(
+                    Vec::new(),
+                    name,
+                    OnceLock::new(),
+                    line,
+                )))
             }
             _ => Err(ScriptingError::UnexpectedToken(format!(
                 "{:?}",
@@ -191,22 +942,86 @@ impl Parser {
 
         let rhs = self.parse_expr()?; // Parse the right-hand side of the assignment
 
-        // Check for semicolon after the assignment expression
-        self.expect_token(Token::Semicolon)?;
-        self.advance(); // Advance past the ';' token
+        // A semicolon, a newline or the end of the enclosing block ends the assignment
+        self.end_statement()?;
 
         // Create and return the assignment node
         Ok(Box::new(Node::Assign(vec![lhs, rhs])))
     }
 
+    /// Parses `x += e`, `x -= e`, `x *= e` and `x /= e`, desugared to
+    /// `x = x + e` and so on. The target is read before being written, so it
+    /// must have been initialized.
+    pub fn parse_compound_assign(&self, lhs: ExpressionTree) -> Result<ExpressionTree> {
+        let mut operation = match self.current_token() {
+            Token::PlusAssign => Node::new_add(),
+            Token::MinusAssign => Node::new_subtract(),
+            Token::MultiplyAssign => Node::new_multiply(),
+            Token::DivideAssign => Node::new_divide(),
+            token => return Err(ScriptingError::UnexpectedToken(format!("{:?}", token))),
+        };
+        self.advance(); // Advance past the operator
+
+        let rhs = self.parse_expr()?;
+        self.end_statement()?;
+
+        operation.add_child(lhs.clone());
+        operation.add_child(rhs);
+        Ok(Box::new(Node::Assign(vec![lhs, Box::new(operation)])))
+    }
+
+    // The exact value of the digits of a number literal, which must fit a
+    // decimal rather than be rounded
+    fn decimal_literal(&self, digits: &str) -> Result<Decimal> {
+        let decimals = digits
+            .split_once('.')
+            .map_or(0, |(_, fraction)| fraction.len());
+        if decimals > Decimal::MAX_SCALE as usize {
+            return Err(self.error_message(&format!(
+                "Number {} has more than {} decimals",
+                digits,
+                Decimal::MAX_SCALE
+            )));
+        }
+        digits
+            .parse()
+            .map_err(|_| self.error_message(&format!("Number {} is too large", digits)))
+    }
+
     pub fn parse_constant(&self) -> Result<ExpressionTree> {
+        if let Token::String(value) = self.current_token() {
+            self.advance();
+            return Ok(Box::new(Node::String(value)));
+        }
+        if self.current_token() == Token::Null {
+            self.advance();
+            return Ok(Box::new(Node::Null));
+        }
+        if let Token::Money(amount, currency) = self.current_token() {
+            let amount = self.decimal_literal(&amount)?;
+            self.advance();
+            return Ok(Box::new(Node::Money(amount, currency)));
+        }
+        let time = match self.current_token() {
+            Token::Date(date) => Some(Node::Date(date)),
+            Token::DateTime(time) => Some(Node::DateTime(time)),
+            Token::Duration(duration) => Some(Node::Duration(duration)),
+            _ => None,
+        };
+        if let Some(time) = time {
+            self.advance();
+            return Ok(Box::new(time));
+        }
         if let Token::Value(value, boolean) = self.current_token() {
+            let value = value
+                .map(|digits| self.decimal_literal(&digits))
+                .transpose()?;
             self.advance(); // Advance immediately after checking the token
             match boolean {
                 Some(true) => Ok(Box::new(Node::True)),
                 Some(false) => Ok(Box::new(Node::False)),
                 None => match value {
-                    Some(v) => Ok(Box::new(Node::Constant(v))),
+                    Some(v) => Ok(Box::new(Node::Decimal(v))),
                     None => Err(ScriptingError::UnexpectedToken(format!(
                         "{:?}",
                         self.current_token()
@@ -221,80 +1036,235 @@ impl Parser {
         }
     }
 
+    /// Parses a loop over the elements of a list or the entries of a map, in
+    /// either of the two block forms:
+    ///
+    /// ```text
+    /// for s in symbols do ... end
+    /// for key, value in positions { ... }
+    /// ```
+    ///
+    /// With one variable, a loop over a map goes through its keys. With two,
+    /// they receive the key and the value, or the index and the element of a
+    /// list. The loop variables are locals of the body.
     pub fn parse_for(&self) -> Result<ExpressionTree> {
-        todo!("Implement for loop parsing")
-    }
-
-    pub fn parse_conditions(&self) -> Result<Vec<ExpressionTree>> {
-        let mut conditions = Vec::new();
-        let mut condition = self.parse_condition_element()?;
-
-        // Loop to handle all logical operators within a condition
-        while matches!(self.current_token(), Token::And | Token::Or) {
-            let operator = self.current_token();
-            self.advance(); // Move past the logical operator
-
-            let rhs = self.parse_condition_element()?; // Parse the right-hand side condition
-            condition = match operator {
-                Token::And => Box::new(Node::And(vec![condition, rhs])),
-                Token::Or => Box::new(Node::Or(vec![condition, rhs])),
-                _ => return Err(ScriptingError::UnexpectedToken(format!("{:?}", operator))),
-            };
-        }
-
-        conditions.push(condition);
-        Ok(conditions)
-    }
-
-    pub fn parse_condition_element(&self) -> Result<ExpressionTree> {
-        // Parse the left-hand side expression
-        let lhs = self.parse_expr_l2()?; // Or another appropriate parsing level
+        self.expect_token(Token::For)?;
+        self.advance();
 
-        // Match a comparison operator and advance to the next token
-        let comparator = self.current_token();
-        match comparator {
-            Token::Equal
-            | Token::NotEqual
-            | Token::Superior
-            | Token::Inferior
-            | Token::SuperiorOrEqual
-            | Token::InferiorOrEqual => {
-                self.advance(); // Move to the right-hand side expression
+        let mut variables = Vec::new();
+        loop {
+            match self.current_token() {
+                Token::Identifier(_) => variables.push(self.parse_variable()?),
+                token => {
+                    return Err(self.error_message(&format!(
+                        "Expected a loop variable after `for`, found {:?}",
+                        token
+                    )))
+                }
             }
-            _ => {
-                return Err(ScriptingError::UnexpectedToken(format!("{:?}", comparator)));
+            if self.current_token() != Token::Comma || variables.len() == 2 {
+                break;
             }
+            self.advance();
         }
+        let mut node = Node::new_for(variables.len());
+        variables
+            .into_iter()
+            .for_each(|variable| node.add_child(variable));
+        self.expect_token(Token::In)?;
+        self.advance();
+        node.add_child(self.parse_expr()?);
 
-        // Parse the right-hand side expression
-        let rhs = self.parse_expr_l2()?; // Or another appropriate parsing level
-
-        // Create the appropriate comparison node
-        let comparison_node = match comparator {
-            Token::Equal => Box::new(Node::Equal(vec![lhs, rhs])),
-            Token::NotEqual => Box::new(Node::NotEqual(vec![lhs, rhs])),
-            Token::Superior => Box::new(Node::Superior(vec![lhs, rhs])),
-            Token::Inferior => Box::new(Node::Inferior(vec![lhs, rhs])),
-            Token::SuperiorOrEqual => Box::new(Node::SuperiorOrEqual(vec![lhs, rhs])),
-            Token::InferiorOrEqual => Box::new(Node::InferiorOrEqual(vec![lhs, rhs])),
-            _ => return Err(ScriptingError::UnexpectedToken(format!("{:?}", comparator))),
+        *self.loop_depth.borrow_mut() += 1;
+        let body = match self.current_token() {
+            Token::Do => {
+                self.advance();
+                self.parse_block(&[Token::End]).and_then(|body| {
+                    self.expect_token(Token::End)?;
+                    self.advance();
+                    Ok(body)
+                })
+            }
+            Token::OpenCurlyParen => self.parse_braces(),
+            token => Err(self.error_message(&format!(
+                "Expected `do` or `{{` after the list, found {:?}",
+                token
+            ))),
         };
+        *self.loop_depth.borrow_mut() -= 1;
 
-        Ok(comparison_node)
+        body?.into_iter().for_each(|child| node.add_child(child));
+        Ok(Box::new(node))
     }
 
-    pub fn find_matching_parentheses(&self) -> Result<usize> {
-        let mut open_parens = 1;
-        let mut index = *self.position.borrow() + 1;
-        while open_parens > 0 {
-            match self.tokens.borrow().get(index) {
-                Some(Token::OpenParen) => open_parens += 1,
-                Some(Token::CloseParen) => open_parens -= 1,
-                Some(Token::EOF) => return Err(self.error_message("Expected closing parenthesis")),
-                _ => (),
-            }
-            index += 1;
-        }
+    /// Parses a map literal, `{units: 10, "avg cost": 95.5}`. Keys are names or
+    /// strings, and a trailing comma is allowed.
+    pub fn parse_map(&self) -> Result<ExpressionTree> {
+        self.expect_token(Token::OpenCurlyParen)?;
+        self.advance();
+
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        self.nested(|| {
+            while self.current_token() != Token::CloseCurlyParen {
+                let key = match self.current_token() {
+                    Token::Identifier(key) | Token::String(key) => key,
+                    token => {
+                        return Err(
+                            self.error_message(&format!("Expected a map key, found {:?}", token))
+                        )
+                    }
+                };
+                if keys.contains(&key) {
+                    return Err(self.error_message(&format!("Duplicate map key {}", key)));
+                }
+                self.advance();
+                self.expect_token(Token::Colon)?;
+                self.advance();
+                keys.push(key);
+                values.push(self.parse_expr()?);
+
+                match self.current_token() {
+                    Token::Comma => self.advance(),
+                    Token::CloseCurlyParen => (),
+                    _ => return Err(self.error_message("Expected comma or closing brace")),
+                }
+            }
+            Ok(())
+        })?;
+        self.advance();
+
+        let mut map = Node::new_map(keys);
+        values.into_iter().for_each(|value| map.add_child(value));
+        Ok(Box::new(map))
+    }
+
+    /// Parses the parts of an interpolated string, `"Sold {units} {symbol}"`
+    pub fn parse_interpolation(&self) -> Result<ExpressionTree> {
+        self.expect_token(Token::InterpolationStart)?;
+        self.advance();
+        let mut parts = Vec::new();
+        loop {
+            match self.current_token() {
+                Token::String(text) => {
+                    self.advance();
+                    parts.push(Box::new(Node::String(text)));
+                }
+                Token::OpenCurlyParen => {
+                    self.advance();
+                    parts.push(self.nested(|| self.parse_expr())?);
+                    self.expect_token(Token::CloseCurlyParen)?;
+                    self.advance();
+                }
+                Token::InterpolationEnd => {
+                    self.advance();
+                    return Ok(Box::new(Node::Interpolation(parts)));
+                }
+                token => {
+                    return Err(self
+                        .error_message(&format!("Unexpected {:?} in interpolated string", token)))
+                }
+            }
+        }
+    }
+
+    /// Parses a list literal, `[1, 2, 3]`. A trailing comma is allowed.
+    pub fn parse_list(&self) -> Result<ExpressionTree> {
+        self.expect_token(Token::OpenBracket)?;
+        self.advance();
+
+        let mut list = Node::new_list();
+        self.nested(|| {
+            while self.current_token() != Token::CloseBracket {
+                list.add_child(self.parse_expr()?);
+                match self.current_token() {
+                    Token::Comma => self.advance(),
+                    Token::CloseBracket => (),
+                    _ => return Err(self.error_message("Expected comma or closing bracket")),
+                }
+            }
+            Ok(())
+        })?;
+        self.advance();
+        Ok(Box::new(list))
+    }
+
+    // Parses `[i]` or `[start:end]` after `list`, either bound of a slice being
+    // optional. Maps are indexed by their keys, `m["units"]`.
+    fn parse_subscript(&self, list: ExpressionTree) -> Result<ExpressionTree> {
+        self.expect_token(Token::OpenBracket)?;
+        self.advance();
+
+        let node = self.nested(|| {
+            let start = match self.current_token() {
+                Token::Colon => None,
+                _ => Some(self.parse_expr()?),
+            };
+            if self.current_token() != Token::Colon {
+                let mut index = Node::new_index();
+                index.add_child(list);
+                index.add_child(start.ok_or_else(|| self.error_message("Expected an index"))?);
+                return Ok(index);
+            }
+            self.advance();
+            let end = match self.current_token() {
+                Token::CloseBracket => None,
+                _ => Some(self.parse_expr()?),
+            };
+
+            let mut slice = Node::new_slice(start.is_some(), end.is_some());
+            slice.add_child(list);
+            start
+                .into_iter()
+                .chain(end)
+                .for_each(|bound| slice.add_child(bound));
+            Ok(slice)
+        })?;
+        self.expect_token(Token::CloseBracket)?;
+        self.advance();
+        Ok(Box::new(node))
+    }
+
+    pub fn parse_conditions(&self) -> Result<Vec<ExpressionTree>> {
+        Ok(vec![self.parse_expr()?])
+    }
+
+    // Comparisons bind tighter than `not`, `and` and `or` but looser than `??`
+    pub fn parse_condition_element(&self) -> Result<ExpressionTree> {
+        let lhs = self.parse_coalesce()?;
+
+        let comparator = self.current_token();
+        let node = match comparator {
+            Token::Equal => Node::Equal(Vec::new()),
+            Token::NotEqual => Node::NotEqual(Vec::new()),
+            Token::Superior => Node::Superior(Vec::new()),
+            Token::Inferior => Node::Inferior(Vec::new()),
+            Token::SuperiorOrEqual => Node::SuperiorOrEqual(Vec::new()),
+            Token::InferiorOrEqual => Node::InferiorOrEqual(Vec::new()),
+            _ => return Ok(lhs),
+        };
+        self.advance(); // Move to the right-hand side expression
+
+        let rhs = self.parse_coalesce()?;
+
+        let mut comparison_node = Box::new(node);
+        comparison_node.add_child(lhs);
+        comparison_node.add_child(rhs);
+        Ok(comparison_node)
+    }
+
+    pub fn find_matching_parentheses(&self) -> Result<usize> {
+        let mut open_parens = 1;
+        let mut index = *self.position.borrow() + 1;
+        while open_parens > 0 {
+            match self.tokens.borrow().get(index) {
+                Some(Token::OpenParen) => open_parens += 1,
+                Some(Token::CloseParen) => open_parens -= 1,
+                Some(Token::EOF) => return Err(self.error_message("Expected closing parenthesis")),
+                _ => (),
+            }
+            index += 1;
+        }
         Ok(index)
     }
 
@@ -321,80 +1291,121 @@ impl Parser {
     pub fn parse_function_args(&self) -> Result<Vec<ExpressionTree>> {
         self.expect_token(Token::OpenParen)?;
         self.advance();
-        let mut args = Vec::new();
-        while self.current_token() != Token::CloseParen {
-            let arg = self.parse_expr()?;
-            args.push(arg);
-            match self.current_token() {
-                Token::Comma => self.advance(),
-                Token::CloseParen => (),
-                _ => return Err(self.error_message("Expected comma or closing parenthesis")),
-            };
-        }
-        Ok(args)
+        self.nested(|| {
+            let mut args = Vec::new();
+            while self.current_token() != Token::CloseParen {
+                let arg = self.parse_expr()?;
+                args.push(arg);
+                match self.current_token() {
+                    Token::Comma => self.advance(),
+                    Token::CloseParen => (),
+                    _ => return Err(self.error_message("Expected comma or closing parenthesis")),
+                };
+            }
+            Ok(args)
+        })
     }
 
     pub fn parse_var_const_func(&self) -> Result<ExpressionTree> {
-        let try_const = self.parse_constant();
-        if try_const.is_ok() {
-            return try_const;
-        }
-
-        let mut min_args = 0;
-        let mut max_args = 0;
-        let mut expr = None;
         match self.current_token() {
-            Token::Identifier(name) => match name.as_str() {
-                "ln" => {
-                    min_args = 1;
-                    max_args = 1;
-                    expr = Some(Node::Ln(Vec::new()));
-                }
-                "exp" => {
-                    min_args = 1;
-                    max_args = 1;
-                    expr = Some(Node::Exp(Vec::new()));
-                }
-                "pow" => {
-                    min_args = 2;
-                    max_args = 2;
-                    expr = Some(Node::Pow(Vec::new()));
-                }
-                "min" => {
-                    min_args = 2;
-                    max_args = 100;
-                    expr = Some(Node::Min(Vec::new()));
-                }
-                "max" => {
-                    min_args = 2;
-                    max_args = 100;
-                    expr = Some(Node::Max(Vec::new()));
-                }
-                _ => (),
-            },
-            _ => {
-                return Err(ScriptingError::UnexpectedToken(format!(
-                    "{:?}",
-                    self.current_token()
-                )))
-            }
+            Token::OpenBracket => return self.parse_list(),
+            Token::OpenCurlyParen => return self.parse_map(),
+            Token::InterpolationStart => return self.parse_interpolation(),
+            _ => (),
         }
-        if expr.is_some() {
+        match self.parse_constant() {
+            Ok(constant) => return Ok(constant),
+            // A number that does not fit a decimal, rather than no constant
+            Err(error @ ScriptingError::InvalidSyntax(_)) => return Err(error),
+            Err(_) => (),
+        }
+
+        let name = match self.current_token() {
+            Token::Identifier(name) => name,
+            token => return Err(ScriptingError::UnexpectedToken(format!("{:?}", token))),
+        };
+        let builtin = builtin(&name).filter(|_| self.peek_token() == Token::OpenParen);
+        if let Some(builtin) = builtin {
             self.advance();
             let args = self.parse_function_args()?;
             self.expect_token(Token::CloseParen)?;
             self.advance();
-            if args.len() < min_args || args.len() > max_args {
+            if args.len() < builtin.min_args || args.len() > builtin.max_args {
                 return Err(self.error_message("Invalid number of arguments"));
             }
-            args.iter()
-                .for_each(|arg| expr.as_mut().unwrap().add_child(arg.clone()));
-            return Ok(Box::new(expr.unwrap()));
+            let node = (builtin.node)(args);
+            if let Node::CrossesAbove(args) = &node {
+                if !args.iter().all(|arg| follows_history(arg)) {
+                    return Err(self.error_message(
+                        "Expected indicators or constants as the arguments of crosses_above",
+                    ));
+                }
+            }
+            return Ok(Box::new(node));
+        }
+        if self.peek_token() == Token::OpenParen {
+            return self.parse_call(name);
         }
 
         self.parse_variable()
     }
 
+    /// Parses a call to a user-defined function. Whether the function exists
+    /// and takes that many arguments is checked at the end of the parsing.
+    /// A last `at = when` argument looks back at a past result of a host
+    /// function instead of calling it.
+    pub fn parse_call(&self, name: String) -> Result<ExpressionTree> {
+        let (line, column) = (*self.line.borrow(), *self.column.borrow());
+        self.advance();
+        let (mut args, at) = self.parse_call_args()?;
+        self.expect_token(Token::CloseParen)?;
+        self.advance();
+
+        self.calls.borrow_mut().push(PendingCall {
+            name: name.clone(),
+            arity: args.len(),
+            looks_back: at.is_some(),
+            line,
+            column,
+        });
+        match at {
+            Some(at) => {
+                args.push(at);
+                Ok(Box::new(Node::Lookback(args, name)))
+            }
+            None => Ok(Box::new(Node::Call(args, name))),
+        }
+    }
+
+    fn parse_call_args(&self) -> Result<(Vec<ExpressionTree>, Option<ExpressionTree>)> {
+        self.expect_token(Token::OpenParen)?;
+        self.advance();
+        self.nested(|| {
+            let mut args = Vec::new();
+            let mut at = None;
+            while self.current_token() != Token::CloseParen {
+                if at.is_some() {
+                    return Err(self.error_message("Expected `at` to be the last argument"));
+                }
+                if self.current_token() == Token::Identifier("at".to_string())
+                    && self.peek_token() == Token::Assign
+                {
+                    self.advance();
+                    self.advance();
+                    at = Some(self.parse_expr()?);
+                } else {
+                    args.push(self.parse_expr()?);
+                }
+                match self.current_token() {
+                    Token::Comma => self.advance(),
+                    Token::CloseParen => (),
+                    _ => return Err(self.error_message("Expected comma or closing parenthesis")),
+                };
+            }
+            Ok((args, at))
+        })
+    }
+
     pub fn parse_parentheses<T, U>(
         &self,
         fun_on_match: T,
@@ -407,7 +1418,7 @@ impl Parser {
         match self.current_token() {
             Token::OpenParen => {
                 self.advance();
-                let expr = fun_on_match(self)?;
+                let expr = self.nested(|| fun_on_match(self))?;
                 match self.current_token() {
                     Token::CloseParen => {
                         self.advance();
@@ -420,14 +1431,64 @@ impl Parser {
         }
     }
 
+    // `or` has the lowest precedence of all operators
     pub fn parse_expr(&self) -> Result<ExpressionTree> {
+        let mut lhs = self.parse_and()?;
+
+        while self.current_token() == Token::Or {
+            self.advance();
+            let rhs = self.parse_and()?;
+            lhs = Box::new(Node::Or(vec![lhs, rhs]));
+        }
+        Ok(lhs)
+    }
+
+    pub fn parse_and(&self) -> Result<ExpressionTree> {
+        let mut lhs = self.parse_not()?;
+
+        while self.current_token() == Token::And {
+            self.advance();
+            let rhs = self.parse_not()?;
+            lhs = Box::new(Node::And(vec![lhs, rhs]));
+        }
+        Ok(lhs)
+    }
+
+    pub fn parse_not(&self) -> Result<ExpressionTree> {
+        match self.current_token() {
+            Token::Not => {
+                self.advance();
+                let expr = self.parse_not()?;
+                Ok(Box::new(Node::Not(vec![expr])))
+            }
+            _ => self.parse_condition_element(),
+        }
+    }
+
+    // `??` binds looser than arithmetic, so `price ?? 0 + 1` defaults to 1,
+    // and tighter than comparisons, so `price ?? 0 > 100` compares the result
+    pub fn parse_coalesce(&self) -> Result<ExpressionTree> {
+        let lhs = self.parse_additive()?;
+        if self.current_token() != Token::Coalesce || self.newline_before() {
+            return Ok(lhs);
+        }
+        self.advance();
+        match self.current_token() {
+            Token::EOF => Err(self.error_message("Unexpected end of expression")),
+            // Right associative: `a ?? b ?? c` tries each value in turn
+            _ => {
+                let rhs = self.parse_coalesce()?;
+                Ok(Box::new(Node::Coalesce(vec![lhs, rhs])))
+            }
+        }
+    }
+
+    pub fn parse_additive(&self) -> Result<ExpressionTree> {
         let mut lhs = self.parse_expr_l2()?;
 
-        while self.current_token() == Token::Plus
-            || self.current_token() == Token::Minus
-            || self.current_token() == Token::And
-            || self.current_token() == Token::Or && self.current_token() != Token::EOF
-        {
+        // An operator starting a new line begins a new statement, so only a
+        // trailing operator continues the expression on the next line
+        while matches!(self.current_token(), Token::Plus | Token::Minus) && !self.newline_before() {
             let token = self.current_token();
             self.advance();
             match self.current_token() {
@@ -436,15 +1497,7 @@ impl Parser {
                     let rhs = self.parse_expr_l2()?;
                     lhs = match token {
                         Token::Plus => Box::new(Node::Add(vec![lhs, rhs])),
-                        Token::Minus => Box::new(Node::Subtract(vec![lhs, rhs])),
-                        Token::And => Box::new(Node::And(vec![lhs, rhs])),
-                        Token::Or => Box::new(Node::Or(vec![lhs, rhs])),
-                        _ => {
-                            return Err(ScriptingError::UnexpectedToken(format!(
-                                "{:?}",
-                                self.current_token()
-                            )))
-                        }
+                        _ => Box::new(Node::Subtract(vec![lhs, rhs])),
                     };
                 }
             }
@@ -455,8 +1508,10 @@ impl Parser {
     pub fn parse_expr_l2(&self) -> Result<ExpressionTree> {
         let mut lhs = self.parse_expr_l3()?;
 
-        while self.current_token() == Token::Multiply
-            || self.current_token() == Token::Divide && self.current_token() != Token::EOF
+        while matches!(
+            self.current_token(),
+            Token::Multiply | Token::Divide | Token::Modulo
+        ) && !self.newline_before()
         {
             let token = self.current_token();
             self.advance();
@@ -466,13 +1521,8 @@ impl Parser {
                     let rhs = self.parse_expr_l3()?;
                     lhs = match token {
                         Token::Multiply => Box::new(Node::Multiply(vec![lhs, rhs])),
-                        Token::Divide => Box::new(Node::Divide(vec![lhs, rhs])),
-                        _ => {
-                            return Err(ScriptingError::UnexpectedToken(format!(
-                                "{:?}",
-                                self.current_token()
-                            )))
-                        }
+                        Token::Modulo => Box::new(Node::Modulo(vec![lhs, rhs])),
+                        _ => Box::new(Node::Divide(vec![lhs, rhs])),
                     };
                 }
             }
@@ -480,37 +1530,121 @@ impl Parser {
         Ok(lhs)
     }
 
+    // unary plus and minus, and the right associative power operator
     pub fn parse_expr_l3(&self) -> Result<ExpressionTree> {
-        let mut lhs = self.parse_var_const_func()?;
-
-        while self.current_token() == Token::Power && self.current_token() != Token::EOF {
-            self.advance();
-            match self.current_token() {
-                Token::EOF => return Err(self.error_message("Unexpected end of expression")),
-                _ => {
-                    let rhs = self.parse_var_const_func()?;
-                    lhs = Box::new(Node::Pow(vec![lhs, rhs]));
-                }
-            }
-        }
-        Ok(lhs)
-    }
-
-    // unary plus and minus
-    pub fn parse_expr_l4(&self) -> Result<ExpressionTree> {
         match self.current_token() {
             Token::Plus => {
                 self.advance();
-                self.parse_expr_l4()
+                self.parse_expr_l3()
             }
             Token::Minus => {
                 self.advance();
-                let expr = self.parse_expr_l4()?;
+                let expr = self.parse_expr_l3()?;
                 Ok(Box::new(Node::UnaryMinus(vec![expr])))
             }
-            _ => self.parse_parentheses(Parser::parse_expr, Parser::parse_var_const_func),
+            _ => {
+                let lhs = self.parse_expr_l4()?;
+                if self.current_token() != Token::Power {
+                    return Ok(lhs);
+                }
+                self.advance();
+                match self.current_token() {
+                    Token::EOF => Err(self.error_message("Unexpected end of expression")),
+                    _ => {
+                        let rhs = self.parse_expr_l3()?;
+                        Ok(Box::new(Node::Pow(vec![lhs, rhs])))
+                    }
+                }
+            }
+        }
+    }
+
+    // Operands, followed by any number of `.name` field accesses and `[i]`
+    // subscripts
+    pub fn parse_expr_l4(&self) -> Result<ExpressionTree> {
+        let mut expr = self.parse_parentheses(Parser::parse_expr, Parser::parse_var_const_func)?;
+        loop {
+            expr = match self.current_token() {
+                Token::OpenBracket if !self.newline_before() => self.parse_subscript(expr)?,
+                Token::Dot => self.parse_field(expr)?,
+                _ => return Ok(expr),
+            }
         }
     }
+
+    // Parses `.name` after `object`
+    fn parse_field(&self, object: ExpressionTree) -> Result<ExpressionTree> {
+        self.expect_token(Token::Dot)?;
+        self.advance();
+        let name = match self.current_token() {
+            Token::Identifier(name) => name,
+            token => {
+                return Err(self.error_message(&format!("Expected a field name, found {:?}", token)))
+            }
+        };
+        self.advance();
+        let mut field = Node::new_field(name);
+        field.add_child(object);
+        Ok(Box::new(field))
+    }
+}
+
+// Whether an argument of `crosses_above` has a value one price earlier, as it
+// is evaluated twice: indicators, literals and operations on them. Variables
+// only hold their current value, and calls would run twice.
+fn follows_history(node: &Node) -> bool {
+    match node {
+        Node::Indicator(args, _) => args.iter().all(|arg| !calls_function(arg)),
+        Node::Add(children)
+        | Node::Subtract(children)
+        | Node::Multiply(children)
+        | Node::Divide(children)
+        | Node::Modulo(children)
+        | Node::Pow(children)
+        | Node::UnaryPlus(children)
+        | Node::UnaryMinus(children)
+        | Node::Min(children)
+        | Node::Max(children)
+        | Node::Exp(children)
+        | Node::Ln(children)
+        | Node::Clamp(children)
+        | Node::Math(children, _)
+        | Node::Field(children, _) => children.iter().all(|child| follows_history(child)),
+        Node::Variable(..) => false,
+        node => node.is_leaf(),
+    }
+}
+
+fn calls_function(node: &Node) -> bool {
+    match node {
+        Node::Call(..) | Node::Lookback(..) => true,
+        node if node.is_leaf() => false,
+        node => node.children().iter().any(|child| calls_function(child)),
+    }
+}
+
+// Source form of a literal, used to compare the values of match cases
+fn literal_value(node: &Node) -> Option<String> {
+    match node {
+        Node::Decimal(value) => Some(value.to_string()),
+        Node::Money(amount, currency) => Some(format!("{} {}", amount, currency)),
+        Node::Date(date) => Some(date.to_string()),
+        Node::DateTime(time) => Some(time.to_string()),
+        Node::Duration(duration) => Some(duration.to_string()),
+        Node::UnaryMinus(children) => match children.as_slice() {
+            [child] => match child.as_ref() {
+                Node::Decimal(value) => Some((-*value).to_string()),
+                Node::Money(amount, currency) => Some(format!("{} {}", -*amount, currency)),
+                _ => None,
+            },
+            _ => None,
+        },
+        Node::String(value) => Some(format!("{:?}", value)),
+        Node::True => Some("true".to_string()),
+        Node::False => Some("false".to_string()),
+        Node::Null => Some("null".to_string()),
+        _ => None,
+    }
 }
 
 #[cfg(test)]
@@ -526,7 +1660,10 @@ mod tests_advance {
         parser.advance();
         assert_eq!(parser.current_token(), Token::Assign);
         parser.advance();
-        assert_eq!(parser.current_token(), Token::Value(Some(1.0), None));
+        assert_eq!(
+            parser.current_token(),
+            Token::Value(Some("1".to_string()), None)
+        );
         parser.advance();
         assert_eq!(parser.current_token(), Token::Semicolon);
         parser.advance();
@@ -542,7 +1679,10 @@ mod tests_advance {
         parser.advance();
         assert_eq!(parser.current_token(), Token::Assign);
         parser.advance();
-        assert_eq!(parser.current_token(), Token::Value(Some(1.0), None));
+        assert_eq!(
+            parser.current_token(),
+            Token::Value(Some("1".to_string()), None)
+        );
         parser.advance();
         assert_eq!(parser.current_token(), Token::Semicolon);
         parser.advance();
@@ -557,6 +1697,7 @@ mod tests_expect_token {
     use crate::{
         nodes::node::Node,
         parsers::{lexer::Lexer, parser::Parser},
+        utils::decimal::Decimal,
     };
 
     #[test]
@@ -582,8 +1723,13 @@ mod tests_expect_token {
         let result = parser.parse().unwrap();
 
         let expected = Box::new(Node::Base(vec![Box::new(Node::Assign(vec![
-            Box::new(Node::Variable(Vec::new(), "a".to_string(), OnceLock::new())),
-            Box::new(Node::Constant(1.0)),
+            Box::new(Node::Variable(
+                Vec::new(),
+                "a".to_string(),
+                OnceLock::new(),
+                1,
+            )),
+            Box::new(Node::Decimal(Decimal::from(1))),
         ]))]));
 
         assert_eq!(result, expected);
@@ -603,8 +1749,13 @@ mod tests_expect_token {
         let result = parser.parse().unwrap();
 
         let expected = Box::new(Node::Base(vec![Box::new(Node::Assign(vec![
-            Box::new(Node::Variable(Vec::new(), "a".to_string(), OnceLock::new())),
-            Box::new(Node::Constant(1.0)),
+            Box::new(Node::Variable(
+                Vec::new(),
+                "a".to_string(),
+                OnceLock::new(),
+                1,
+            )),
+            Box::new(Node::Decimal(Decimal::from(1))),
         ]))]));
 
         assert_eq!(result, expected);
@@ -630,15 +1781,26 @@ mod tests_expect_token {
         let expected = Box::new(Node::Base(vec![Box::new(Node::If(
             vec![
                 Box::new(Node::Equal(vec![
-                    Box::new(Node::Variable(Vec::new(), "a".to_string(), OnceLock::new())),
-                    Box::new(Node::Constant(1.0)),
+                    Box::new(Node::Variable(
+                        Vec::new(),
+                        "a".to_string(),
+                        OnceLock::new(),
+                        3,
+                    )),
+                    Box::new(Node::Decimal(Decimal::from(1))),
                 ])),
                 Box::new(Node::Assign(vec![
-                    Box::new(Node::Variable(Vec::new(), "b".to_string(), OnceLock::new())),
-                    Box::new(Node::Constant(2.0)),
+                    Box::new(Node::Variable(
+                        Vec::new(),
+                        "b".to_string(),
+                        OnceLock::new(),
+                        4,
+                    )),
+                    Box::new(Node::Decimal(Decimal::from(2))),
                 ])),
             ],
             None,
+            3,
         ))]));
         assert_eq!(result, expected);
     }
@@ -663,19 +1825,35 @@ mod tests_expect_token {
         let expected = Box::new(Node::Base(vec![Box::new(Node::If(
             vec![
                 Box::new(Node::Equal(vec![
-                    Box::new(Node::Variable(Vec::new(), "a".to_string(), OnceLock::new())),
-                    Box::new(Node::Constant(1.0)),
+                    Box::new(Node::Variable(
+                        Vec::new(),
+                        "a".to_string(),
+                        OnceLock::new(),
+                        2,
+                    )),
+                    Box::new(Node::Decimal(Decimal::from(1))),
                 ])),
                 Box::new(Node::Assign(vec![
-                    Box::new(Node::Variable(Vec::new(), "b".to_string(), OnceLock::new())),
-                    Box::new(Node::Constant(2.0)),
+                    Box::new(Node::Variable(
+                        Vec::new(),
+                        "b".to_string(),
+                        OnceLock::new(),
+                        3,
+                    )),
+                    Box::new(Node::Decimal(Decimal::from(2))),
                 ])),
                 Box::new(Node::Assign(vec![
-                    Box::new(Node::Variable(Vec::new(), "b".to_string(), OnceLock::new())),
-                    Box::new(Node::Constant(3.0)),
+                    Box::new(Node::Variable(
+                        Vec::new(),
+                        "b".to_string(),
+                        OnceLock::new(),
+                        5,
+                    )),
+                    Box::new(Node::Decimal(Decimal::from(3))),
                 ])),
             ],
-            Some(1),
+            Some(2),
+            2,
         ))]));
 
         assert_eq!(result, expected);
@@ -704,32 +1882,59 @@ mod tests_expect_token {
         let expected = Box::new(Node::Base(vec![Box::new(Node::If(
             vec![
                 Box::new(Node::Equal(vec![
-                    Box::new(Node::Variable(Vec::new(), "a".to_string(), OnceLock::new())),
-                    Box::new(Node::Constant(1.0)),
+                    Box::new(Node::Variable(
+                        Vec::new(),
+                        "a".to_string(),
+                        OnceLock::new(),
+                        2,
+                    )),
+                    Box::new(Node::Decimal(Decimal::from(1))),
                 ])),
                 Box::new(Node::If(
                     vec![
                         Box::new(Node::Equal(vec![
-                            Box::new(Node::Variable(Vec::new(), "b".to_string(), OnceLock::new())),
-                            Box::new(Node::Constant(2.0)),
+                            Box::new(Node::Variable(
+                                Vec::new(),
+                                "b".to_string(),
+                                OnceLock::new(),
+                                3,
+                            )),
+                            Box::new(Node::Decimal(Decimal::from(2))),
                         ])),
                         Box::new(Node::Assign(vec![
-                            Box::new(Node::Variable(Vec::new(), "c".to_string(), OnceLock::new())),
-                            Box::new(Node::Constant(3.0)),
+                            Box::new(Node::Variable(
+                                Vec::new(),
+                                "c".to_string(),
+                                OnceLock::new(),
+                                4,
+                            )),
+                            Box::new(Node::Decimal(Decimal::from(3))),
                         ])),
                         Box::new(Node::Assign(vec![
-                            Box::new(Node::Variable(Vec::new(), "c".to_string(), OnceLock::new())),
-                            Box::new(Node::Constant(4.0)),
+                            Box::new(Node::Variable(
+                                Vec::new(),
+                                "c".to_string(),
+                                OnceLock::new(),
+                                6,
+                            )),
+                            Box::new(Node::Decimal(Decimal::from(4))),
                         ])),
                     ],
-                    Some(1),
+                    Some(2),
+                    3,
                 )),
                 Box::new(Node::Assign(vec![
-                    Box::new(Node::Variable(Vec::new(), "c".to_string(), OnceLock::new())),
-                    Box::new(Node::Constant(5.0)),
+                    Box::new(Node::Variable(
+                        Vec::new(),
+                        "c".to_string(),
+                        OnceLock::new(),
+                        9,
+                    )),
+                    Box::new(Node::Decimal(Decimal::from(5))),
                 ])),
             ],
-            Some(1),
+            Some(2),
+            2,
         ))]));
 
         assert_eq!(result, expected);
@@ -761,44 +1966,86 @@ mod tests_expect_token {
         let expected = Box::new(Node::Base(vec![Box::new(Node::If(
             vec![
                 Box::new(Node::Equal(vec![
-                    Box::new(Node::Variable(Vec::new(), "a".to_string(), OnceLock::new())),
-                    Box::new(Node::Constant(1.0)),
+                    Box::new(Node::Variable(
+                        Vec::new(),
+                        "a".to_string(),
+                        OnceLock::new(),
+                        2,
+                    )),
+                    Box::new(Node::Decimal(Decimal::from(1))),
                 ])),
                 Box::new(Node::If(
                     vec![
                         Box::new(Node::Equal(vec![
-                            Box::new(Node::Variable(Vec::new(), "b".to_string(), OnceLock::new())),
-                            Box::new(Node::Constant(2.0)),
+                            Box::new(Node::Variable(
+                                Vec::new(),
+                                "b".to_string(),
+                                OnceLock::new(),
+                                3,
+                            )),
+                            Box::new(Node::Decimal(Decimal::from(2))),
                         ])),
                         Box::new(Node::Assign(vec![
-                            Box::new(Node::Variable(Vec::new(), "c".to_string(), OnceLock::new())),
-                            Box::new(Node::Constant(3.0)),
+                            Box::new(Node::Variable(
+                                Vec::new(),
+                                "c".to_string(),
+                                OnceLock::new(),
+                                4,
+                            )),
+                            Box::new(Node::Decimal(Decimal::from(3))),
                         ])),
                         Box::new(Node::Assign(vec![
-                            Box::new(Node::Variable(Vec::new(), "d".to_string(), OnceLock::new())),
-                            Box::new(Node::Constant(4.0)),
+                            Box::new(Node::Variable(
+                                Vec::new(),
+                                "d".to_string(),
+                                OnceLock::new(),
+                                5,
+                            )),
+                            Box::new(Node::Decimal(Decimal::from(4))),
                         ])),
                         Box::new(Node::Assign(vec![
-                            Box::new(Node::Variable(Vec::new(), "c".to_string(), OnceLock::new())),
-                            Box::new(Node::Constant(5.0)),
+                            Box::new(Node::Variable(
+                                Vec::new(),
+                                "c".to_string(),
+                                OnceLock::new(),
+                                7,
+                            )),
+                            Box::new(Node::Decimal(Decimal::from(5))),
                         ])),
                         Box::new(Node::Assign(vec![
-                            Box::new(Node::Variable(Vec::new(), "d".to_string(), OnceLock::new())),
-                            Box::new(Node::Constant(6.0)),
+                            Box::new(Node::Variable(
+                                Vec::new(),
+                                "d".to_string(),
+                                OnceLock::new(),
+                                8,
+                            )),
+                            Box::new(Node::Decimal(Decimal::from(6))),
                         ])),
                     ],
-                    Some(2),
+                    Some(3),
+                    3,
                 )),
                 Box::new(Node::Assign(vec![
-                    Box::new(Node::Variable(Vec::new(), "c".to_string(), OnceLock::new())),
-                    Box::new(Node::Constant(7.0)),
+                    Box::new(Node::Variable(
+                        Vec::new(),
+                        "c".to_string(),
+                        OnceLock::new(),
+                        11,
+                    )),
+                    Box::new(Node::Decimal(Decimal::from(7))),
                 ])),
                 Box::new(Node::Assign(vec![
-                    Box::new(Node::Variable(Vec::new(), "d".to_string(), OnceLock::new())),
-                    Box::new(Node::Constant(8.0)),
+                    Box::new(Node::Variable(
+                        Vec::new(),
+                        "d".to_string(),
+                        OnceLock::new(),
+                        12,
+                    )),
+                    Box::new(Node::Decimal(Decimal::from(8))),
                 ])),
             ],
-            Some(1),
+            Some(2),
+            2,
         ))]));
 
         assert_eq!(result, expected);
@@ -823,20 +2070,36 @@ mod tests_expect_token {
             vec![
                 Box::new(Node::And(vec![
                     Box::new(Node::Equal(vec![
-                        Box::new(Node::Variable(Vec::new(), "a".to_string(), OnceLock::new())),
-                        Box::new(Node::Constant(1.0)),
+                        Box::new(Node::Variable(
+                            Vec::new(),
+                            "a".to_string(),
+                            OnceLock::new(),
+                            2,
+                        )),
+                        Box::new(Node::Decimal(Decimal::from(1))),
                     ])),
                     Box::new(Node::Equal(vec![
-                        Box::new(Node::Variable(Vec::new(), "b".to_string(), OnceLock::new())),
-                        Box::new(Node::Constant(2.0)),
+                        Box::new(Node::Variable(
+                            Vec::new(),
+                            "b".to_string(),
+                            OnceLock::new(),
+                            2,
+                        )),
+                        Box::new(Node::Decimal(Decimal::from(2))),
                     ])),
                 ])),
                 Box::new(Node::Assign(vec![
-                    Box::new(Node::Variable(Vec::new(), "c".to_string(), OnceLock::new())),
-                    Box::new(Node::Constant(3.0)),
+                    Box::new(Node::Variable(
+                        Vec::new(),
+                        "c".to_string(),
+                        OnceLock::new(),
+                        3,
+                    )),
+                    Box::new(Node::Decimal(Decimal::from(3))),
                 ])),
             ],
             None,
+            2,
         ))]));
 
         assert_eq!(result, expected);
@@ -857,20 +2120,36 @@ mod tests_expect_token {
             vec![
                 Box::new(Node::Or(vec![
                     Box::new(Node::Equal(vec![
-                        Box::new(Node::Variable(Vec::new(), "a".to_string(), OnceLock::new())),
-                        Box::new(Node::Constant(1.0)),
+                        Box::new(Node::Variable(
+                            Vec::new(),
+                            "a".to_string(),
+                            OnceLock::new(),
+                            2,
+                        )),
+                        Box::new(Node::Decimal(Decimal::from(1))),
                     ])),
                     Box::new(Node::Equal(vec![
-                        Box::new(Node::Variable(Vec::new(), "b".to_string(), OnceLock::new())),
-                        Box::new(Node::Constant(2.0)),
+                        Box::new(Node::Variable(
+                            Vec::new(),
+                            "b".to_string(),
+                            OnceLock::new(),
+                            2,
+                        )),
+                        Box::new(Node::Decimal(Decimal::from(2))),
                     ])),
                 ])),
                 Box::new(Node::Assign(vec![
-                    Box::new(Node::Variable(Vec::new(), "c".to_string(), OnceLock::new())),
-                    Box::new(Node::Constant(3.0)),
+                    Box::new(Node::Variable(
+                        Vec::new(),
+                        "c".to_string(),
+                        OnceLock::new(),
+                        3,
+                    )),
+                    Box::new(Node::Decimal(Decimal::from(3))),
                 ])),
             ],
             None,
+            2,
         ))]));
 
         assert_eq!(result, expected);
@@ -895,25 +2174,46 @@ mod tests_expect_token {
 
         let expected = Box::new(Node::Base(vec![
             Box::new(Node::Assign(vec![
-                Box::new(Node::Variable(Vec::new(), "x".to_string(), OnceLock::new())),
-                Box::new(Node::Constant(2.0)),
+                Box::new(Node::Variable(
+                    Vec::new(),
+                    "x".to_string(),
+                    OnceLock::new(),
+                    2,
+                )),
+                Box::new(Node::Decimal(Decimal::from(2))),
             ])),
             Box::new(Node::If(
                 vec![
                     Box::new(Node::Equal(vec![
-                        Box::new(Node::Variable(Vec::new(), "x".to_string(), OnceLock::new())),
-                        Box::new(Node::Constant(1.0)),
+                        Box::new(Node::Variable(
+                            Vec::new(),
+                            "x".to_string(),
+                            OnceLock::new(),
+                            3,
+                        )),
+                        Box::new(Node::Decimal(Decimal::from(1))),
                     ])),
                     Box::new(Node::Assign(vec![
-                        Box::new(Node::Variable(Vec::new(), "z".to_string(), OnceLock::new())),
-                        Box::new(Node::Constant(3.0)),
+                        Box::new(Node::Variable(
+                            Vec::new(),
+                            "z".to_string(),
+                            OnceLock::new(),
+                            4,
+                        )),
+                        Box::new(Node::Decimal(Decimal::from(3))),
                     ])),
                     Box::new(Node::Assign(vec![
-                        Box::new(Node::Variable(Vec::new(), "w".to_string(), OnceLock::new())),
-                        Box::new(Node::Constant(4.0)),
+                        Box::new(Node::Variable(
+                            Vec::new(),
+                            "w".to_string(),
+                            OnceLock::new(),
+                            5,
+                        )),
+                        Box::new(Node::Decimal(Decimal::from(4))),
                     ])),
                 ],
                 None,
+                3,
             )),
         ]));
 
@@ -939,25 +2239,46 @@ mod tests_expect_token {
 
         let expected = Box::new(Node::Base(vec![
             Box::new(Node::Assign(vec![
-                Box::new(Node::Variable(Vec::new(), "x".to_string(), OnceLock::new())),
+                Box::new(Node::Variable(
+                    Vec::new(),
+                    "x".to_string(),
+                    OnceLock::new(),
+                    2,
+                )),
                 Box::new(Node::True),
             ])),
             Box::new(Node::Assign(vec![
-                Box::new(Node::Variable(Vec::new(), "y".to_string(), OnceLock::new())),
+                Box::new(Node::Variable(
+                    Vec::new(),
+                    "y".to_string(),
+                    OnceLock::new(),
+                    3,
+                )),
                 Box::new(Node::False),
             ])),
             Box::new(Node::If(
                 vec![
                     Box::new(Node::Equal(vec![
-                        Box::new(Node::Variable(Vec::new(), "x".to_string(), OnceLock::new())),
+                        Box::new(Node::Variable(
+                            Vec::new(),
+                            "x".to_string(),
+                            OnceLock::new(),
+                            4,
+                        )),
                         Box::new(Node::True),
                     ])),
                     Box::new(Node::Assign(vec![
-                        Box::new(Node::Variable(Vec::new(), "z".to_string(), OnceLock::new())),
-                        Box::new(Node::Constant(3.0)),
+                        Box::new(Node::Variable(
+                            Vec::new(),
+                            "z".to_string(),
+                            OnceLock::new(),
+                            5,
+                        )),
+                        Box::new(Node::Decimal(Decimal::from(3))),
                     ])),
                 ],
                 None,
+                4,
             )),
         ]));
 
@@ -979,25 +2300,65 @@ mod tests_expect_token {
 
         let expected = Box::new(Node::Base(vec![
             Box::new(Node::Assign(vec![
-                Box::new(Node::Variable(Vec::new(), "x".to_string(), OnceLock::new())),
+                Box::new(Node::Variable(
+                    Vec::new(),
+                    "x".to_string(),
+                    OnceLock::new(),
+                    2,
+                )),
                 Box::new(Node::True),
             ])),
             Box::new(Node::Assign(vec![
-                Box::new(Node::Variable(Vec::new(), "y".to_string(), OnceLock::new())),
+                Box::new(Node::Variable(
+                    Vec::new(),
+                    "y".to_string(),
+                    OnceLock::new(),
+                    3,
+                )),
                 Box::new(Node::False),
             ])),
             Box::new(Node::Assign(vec![
-                Box::new(Node::Variable(Vec::new(), "z".to_string(), OnceLock::new())),
+                Box::new(Node::Variable(
+                    Vec::new(),
+                    "z".to_string(),
+                    OnceLock::new(),
+                    4,
+                )),
                 Box::new(Node::And(vec![
-                    Box::new(Node::Variable(Vec::new(), "x".to_string(), OnceLock::new())),
-                    Box::new(Node::Variable(Vec::new(), "y".to_string(), OnceLock::new())),
+                    Box::new(Node::Variable(
+                        Vec::new(),
+                        "x".to_string(),
+                        OnceLock::new(),
+                        4,
+                    )),
+                    Box::new(Node::Variable(
+                        Vec::new(),
+                        "y".to_string(),
+                        OnceLock::new(),
+                        4,
+                    )),
                 ])),
             ])),
             Box::new(Node::Assign(vec![
-                Box::new(Node::Variable(Vec::new(), "w".to_string(), OnceLock::new())),
+                Box::new(Node::Variable(
+                    Vec::new(),
+                    "w".to_string(),
+                    OnceLock::new(),
+                    5,
+                )),
                 Box::new(Node::Or(vec![
-                    Box::new(Node::Variable(Vec::new(), "x".to_string(), OnceLock::new())),
-                    Box::new(Node::Variable(Vec::new(), "y".to_string(), OnceLock::new())),
+                    Box::new(Node::Variable(
+                        Vec::new(),
+                        "x".to_string(),
+                        OnceLock::new(),
+                        5,
+                    )),
+                    Box::new(Node::Variable(
+                        Vec::new(),
+                        "y".to_string(),
+                        OnceLock::new(),
+                        5,
+                    )),
                 ])),
             ])),
         ]));
@@ -1018,13 +2379,1174 @@ mod tests_expect_token {
         let nodes = Parser::new(tokens).parse().unwrap();
 
         let expected = Box::new(Node::Base(vec![Box::new(Node::Assign(vec![
-            Box::new(Node::Variable(Vec::new(), "z".to_string(), OnceLock::new())),
+            Box::new(Node::Variable(
+                Vec::new(),
+                "z".to_string(),
+                OnceLock::new(),
+                2,
+            )),
             Box::new(Node::Max(vec![
-                Box::new(Node::Constant(1.0)),
-                Box::new(Node::Constant(2.0)),
+                Box::new(Node::Decimal(Decimal::from(1))),
+                Box::new(Node::Decimal(Decimal::from(2))),
             ])),
         ]))]));
 
         assert_eq!(nodes, expected);
     }
 }
+
+// Parsing helpers and expected nodes shared by the statement tests below
+#[cfg(test)]
+mod test_support {
+    use std::sync::OnceLock;
+
+    use crate::{
+        nodes::node::Node,
+        parsers::{lexer::Lexer, parser::Parser},
+        utils::{decimal::Decimal, errors::Result},
+    };
+
+    // Variables are compared without their lines, which `test_lines` checks
+    pub(super) fn parse(script: &str) -> Result<Box<Node>> {
+        let tokens = Lexer::new(script.to_string()).tokenize()?;
+        Parser::new(tokens)
+            .parse()
+            .map(|node| Box::new(node.without_lines()))
+    }
+
+    pub(super) fn assign(name: &str, value: f64) -> Box<Node> {
+        Box::new(Node::Assign(vec![
+            Box::new(Node::Variable(
+                Vec::new(),
+                name.to_string(),
+                OnceLock::new(),
+                0,
+            )),
+            Box::new(Node::Decimal(Decimal::from_f64(value).unwrap())),
+        ]))
+    }
+
+    pub(super) fn greater(name: &str, value: f64) -> Box<Node> {
+        Box::new(Node::Superior(vec![
+            Box::new(Node::Variable(
+                Vec::new(),
+                name.to_string(),
+                OnceLock::new(),
+                0,
+            )),
+            Box::new(Node::Decimal(Decimal::from_f64(value).unwrap())),
+        ]))
+    }
+
+    pub(super) fn parse_with_host(script: &str) -> Result<Box<Node>> {
+        let tokens = Lexer::new(script.to_string()).tokenize()?;
+        Parser::new(tokens)
+            .with_host_functions(&["Sell", "Notify"])
+            .parse()
+            .map(|node| Box::new(node.without_lines()))
+    }
+
+    pub(super) fn parse_with_transactions(script: &str) -> Result<Box<Node>> {
+        let tokens = Lexer::new(script.to_string()).tokenize()?;
+        Parser::new(tokens)
+            .with_host_functions(&["Spot"])
+            .with_transactional_functions(&["Buy", "Sell"])
+            .parse()
+            .map(|node| Box::new(node.without_lines()))
+    }
+}
+
+// Separators, blocks, comments and assignments
+#[cfg(test)]
+mod tests_blocks {
+    use std::sync::OnceLock;
+
+    use crate::{
+        nodes::node::Node,
+        parsers::{lexer::Lexer, parser::Parser},
+        utils::decimal::Decimal,
+    };
+
+    use super::test_support::{assign, greater, parse};
+
+    #[test]
+    fn test_newline_separators() {
+        let result = parse(
+            "
+            a = 1
+            b = 2
+            ",
+        )
+        .unwrap();
+
+        let expected = Box::new(Node::Base(vec![assign("a", 1.0), assign("b", 2.0)]));
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_semicolon_separators_on_single_line() {
+        let result = parse("a = 1; b = 2;; c = 3").unwrap();
+
+        let expected = Box::new(Node::Base(vec![
+            assign("a", 1.0),
+            assign("b", 2.0),
+            assign("c", 3.0),
+        ]));
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_lines() {
+        let tokens = Lexer::new("a = 1\n\nif a > 0 then\n  b = a\nend".to_string())
+            .tokenize()
+            .unwrap();
+        let result = Parser::new(tokens).parse().unwrap();
+        let variable = |name: &str, line| {
+            Box::new(Node::Variable(
+                Vec::new(),
+                name.to_string(),
+                OnceLock::new(),
+                line,
+            ))
+        };
+        let expected = Box::new(Node::Base(vec![
+            Box::new(Node::Assign(vec![
+                variable("a", 1),
+                Box::new(Node::Decimal(Decimal::from(1))),
+            ])),
+            Box::new(Node::If(
+                vec![
+                    Box::new(Node::Superior(vec![
+                        variable("a", 3),
+                        Box::new(Node::Decimal(Decimal::from(0))),
+                    ])),
+                    Box::new(Node::Assign(vec![variable("b", 4), variable("a", 4)])),
+                ],
+                None,
+                3,
+            )),
+        ]));
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_missing_separator() {
+        assert!(parse("a = 1 b = 2").is_err());
+    }
+
+    #[test]
+    fn test_single_line_if() {
+        let expected = |line| {
+            Box::new(Node::Base(vec![
+                assign("a", 101.0),
+                Box::new(Node::If(
+                    vec![greater("a", 100.0), assign("b", 1.0)],
+                    None,
+                    line,
+                )),
+            ]))
+        };
+
+        assert_eq!(
+            parse("a = 101; if a > 100 then b = 1; end").unwrap(),
+            expected(1)
+        );
+        assert_eq!(
+            parse("a = 101; if a > 100 then b = 1 end").unwrap(),
+            expected(1)
+        );
+        assert_eq!(
+            parse("a = 101\nif a > 100 then\nb = 1\nend").unwrap(),
+            expected(2)
+        );
+    }
+
+    #[test]
+    fn test_single_line_if_else() {
+        let result = parse("if a > 100 then b = 1 else b = 2 end").unwrap();
+
+        let expected = Box::new(Node::Base(vec![Box::new(Node::If(
+            vec![greater("a", 100.0), assign("b", 1.0), assign("b", 2.0)],
+            Some(2),
+            1,
+        ))]));
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_brace_blocks() {
+        let expected = |line| {
+            Box::new(Node::Base(vec![Box::new(Node::If(
+                vec![greater("a", 100.0), assign("b", 1.0), assign("b", 2.0)],
+                Some(2),
+                line,
+            ))]))
+        };
+
+        assert_eq!(
+            parse("if (a > 100) { b = 1; } else { b = 2; }").unwrap(),
+            expected(1)
+        );
+        assert_eq!(
+            parse(
+                "
+                if a > 100 {
+                    b = 1
+                }
+                else {
+                    b = 2
+                }
+                "
+            )
+            .unwrap(),
+            expected(2)
+        );
+    }
+
+    #[test]
+    fn test_brace_else_if() {
+        let result = parse("if a > 100 { b = 1 } else if a > 50 { b = 2 } else { b = 3 }").unwrap();
+
+        let expected = Box::new(Node::Base(vec![Box::new(Node::If(
+            vec![
+                greater("a", 100.0),
+                assign("b", 1.0),
+                Box::new(Node::If(
+                    vec![greater("a", 50.0), assign("b", 2.0), assign("b", 3.0)],
+                    Some(2),
+                    1,
+                )),
+            ],
+            Some(2),
+            1,
+        ))]));
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_unclosed_braces() {
+        assert!(parse("if a > 100 { b = 1").is_err());
+        assert!(parse("if a > 100 { b = 1 } }").is_err());
+    }
+
+    #[test]
+    fn test_expression_continues_after_trailing_operator() {
+        let result = parse("a = 1 +\n 2\nb = (1\n + 2)").unwrap();
+
+        let sum = Box::new(Node::Add(vec![
+            Box::new(Node::Decimal(Decimal::from(1))),
+            Box::new(Node::Decimal(Decimal::from(2))),
+        ]));
+        let expected = Box::new(Node::Base(vec![
+            Box::new(Node::Assign(vec![
+                Box::new(Node::Variable(
+                    Vec::new(),
+                    "a".to_string(),
+                    OnceLock::new(),
+                    0,
+                )),
+                sum.clone(),
+            ])),
+            Box::new(Node::Assign(vec![
+                Box::new(Node::Variable(
+                    Vec::new(),
+                    "b".to_string(),
+                    OnceLock::new(),
+                    0,
+                )),
+                sum,
+            ])),
+        ]));
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_comments_are_ignored() {
+        let script = "
+            -- first value
+            a = 1 # trailing
+            /* second
+               value */
+            b = 2 --[[ done ]]
+        ";
+        let tokens = Lexer::new(script.to_string())
+            .tokenize_with_trivia()
+            .unwrap();
+        let result = Parser::new(tokens).parse().unwrap().without_lines();
+
+        let expected = Node::Base(vec![assign("a", 1.0), assign("b", 2.0)]);
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_compound_assignment() {
+        let result = parse("a += 1\na -= 2; a *= 3; a /= 4").unwrap();
+
+        let variable = || {
+            Box::new(Node::Variable(
+                Vec::new(),
+                "a".to_string(),
+                OnceLock::new(),
+                0,
+            ))
+        };
+        let compound = |operation: fn(Vec<Box<Node>>) -> Node, value: f64| {
+            Box::new(Node::Assign(vec![
+                variable(),
+                Box::new(operation(vec![
+                    variable(),
+                    Box::new(Node::Decimal(Decimal::from_f64(value).unwrap())),
+                ])),
+            ]))
+        };
+        let expected = Box::new(Node::Base(vec![
+            compound(Node::Add, 1.0),
+            compound(Node::Subtract, 2.0),
+            compound(Node::Multiply, 3.0),
+            compound(Node::Divide, 4.0),
+        ]));
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_compound_assignment_requires_variable() {
+        assert!(parse("1 += 2").is_err());
+        assert!(parse("a += ").is_err());
+    }
+
+    #[test]
+    fn test_local_declaration() {
+        let result = parse("local x = 1; local y").unwrap();
+        let expected = Box::new(Node::Base(vec![
+            Box::new(Node::Local(vec![
+                Box::new(Node::new_variable("x".to_string())),
+                Box::new(Node::Decimal(Decimal::from(1))),
+            ])),
+            Box::new(Node::Local(vec![Box::new(Node::new_variable(
+                "y".to_string(),
+            ))])),
+        ]));
+        assert_eq!(result, expected);
+
+        assert!(parse("local = 1").is_err());
+        assert!(parse("local 1").is_err());
+        assert!(parse("local x y").is_err());
+    }
+}
+
+// Operators and literals
+#[cfg(test)]
+mod tests_expressions {
+    use std::sync::OnceLock;
+
+    use crate::{
+        nodes::node::Node,
+        utils::{decimal::Decimal, time::Duration},
+    };
+
+    use super::test_support::parse;
+
+    #[test]
+    fn test_operator_precedence() {
+        let result = parse("a = 1 + 2 * 3 > 4 and not b").unwrap();
+
+        let expected = Box::new(Node::Base(vec![Box::new(Node::Assign(vec![
+            Box::new(Node::Variable(
+                Vec::new(),
+                "a".to_string(),
+                OnceLock::new(),
+                0,
+            )),
+            Box::new(Node::And(vec![
+                Box::new(Node::Superior(vec![
+                    Box::new(Node::Add(vec![
+                        Box::new(Node::Decimal(Decimal::from(1))),
+                        Box::new(Node::Multiply(vec![
+                            Box::new(Node::Decimal(Decimal::from(2))),
+                            Box::new(Node::Decimal(Decimal::from(3))),
+                        ])),
+                    ])),
+                    Box::new(Node::Decimal(Decimal::from(4))),
+                ])),
+                Box::new(Node::Not(vec![Box::new(Node::Variable(
+                    Vec::new(),
+                    "b".to_string(),
+                    OnceLock::new(),
+                    0,
+                ))])),
+            ])),
+        ]))]));
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_null_and_default() {
+        let variable = |name: &str| Box::new(Node::new_variable(name.to_string()));
+        let result = parse("x = a ?? b ?? 0 > 1 + 2").unwrap();
+        let expected = Box::new(Node::Base(vec![Box::new(Node::Assign(vec![
+            variable("x"),
+            Box::new(Node::Superior(vec![
+                Box::new(Node::Coalesce(vec![
+                    variable("a"),
+                    Box::new(Node::Coalesce(vec![
+                        variable("b"),
+                        Box::new(Node::Decimal(Decimal::from(0))),
+                    ])),
+                ])),
+                Box::new(Node::Add(vec![
+                    Box::new(Node::Decimal(Decimal::from(1))),
+                    Box::new(Node::Decimal(Decimal::from(2))),
+                ])),
+            ])),
+        ]))]));
+        assert_eq!(result, expected);
+
+        let result = parse("y = x == null").unwrap();
+        let expected = Box::new(Node::Base(vec![Box::new(Node::Assign(vec![
+            variable("y"),
+            Box::new(Node::Equal(vec![variable("x"), Box::new(Node::Null)])),
+        ]))]));
+        assert_eq!(result, expected);
+
+        assert!(parse("x = a ??").is_err());
+        assert!(parse("null = 1").is_err());
+    }
+
+    #[test]
+    fn test_interpolated_strings() {
+        let result = parse("m = \"Sold {units * 2} {symbol}\"").unwrap();
+        let expected = Box::new(Node::Base(vec![Box::new(Node::Assign(vec![
+            Box::new(Node::new_variable("m".to_string())),
+            Box::new(Node::Interpolation(vec![
+                Box::new(Node::String("Sold ".to_string())),
+                Box::new(Node::Multiply(vec![
+                    Box::new(Node::new_variable("units".to_string())),
+                    Box::new(Node::Decimal(Decimal::from(2))),
+                ])),
+                Box::new(Node::String(" ".to_string())),
+                Box::new(Node::new_variable("symbol".to_string())),
+            ])),
+        ]))]));
+        assert_eq!(result, expected);
+
+        assert!(parse("m = \"{format(x, \".2\")} at {round_to(y, 1)}\"").is_ok());
+        assert!(parse("m = \"{x y}\"").is_err());
+        assert!(parse("m = \"{x = 1}\"").is_err());
+        assert!(parse("m = format(1)").is_err());
+        assert!(parse("function round_to(x) { return x }").is_err());
+    }
+
+    #[test]
+    fn test_money_literals() {
+        let result = parse("fee = -2.5 USD + convert(fee, \"EUR\")").unwrap();
+        let expected = Box::new(Node::Base(vec![Box::new(Node::Assign(vec![
+            Box::new(Node::new_variable("fee".to_string())),
+            Box::new(Node::Add(vec![
+                Box::new(Node::UnaryMinus(vec![Box::new(Node::Money(
+                    "2.5".parse().unwrap(),
+                    "USD".to_string(),
+                ))])),
+                Box::new(Node::Convert(vec![
+                    Box::new(Node::new_variable("fee".to_string())),
+                    Box::new(Node::String("EUR".to_string())),
+                ])),
+            ])),
+        ]))]));
+        assert_eq!(result, expected);
+
+        assert!(parse("x = convert(1 USD)").is_err());
+        assert!(parse("function convert(x, c) { return x }").is_err());
+    }
+
+    #[test]
+    fn test_time_literals() {
+        let result = parse("due = today() + 1m > 2024-05-04").unwrap();
+        let expected = Box::new(Node::Base(vec![Box::new(Node::Assign(vec![
+            Box::new(Node::new_variable("due".to_string())),
+            Box::new(Node::Superior(vec![
+                Box::new(Node::Add(vec![
+                    Box::new(Node::Today(Vec::new())),
+                    Box::new(Node::Duration(Duration::new(1, 0, 0))),
+                ])),
+                Box::new(Node::Date("2024-05-04".parse().unwrap())),
+            ])),
+        ]))]));
+        assert_eq!(result, expected);
+
+        assert!(parse("x = today(1)").is_err());
+        assert!(parse("x = is_weekday()").is_err());
+        assert!(parse("function now() { return 1 }").is_err());
+    }
+}
+
+// Function definitions, host functions and builtins
+#[cfg(test)]
+mod tests_functions {
+    use std::sync::OnceLock;
+
+    use crate::{
+        nodes::node::{Indicator, MathFunction, Node, Statistic},
+        parsers::{
+            lexer::Lexer,
+            parser::{Parser, BUILTINS},
+        },
+        utils::decimal::Decimal,
+    };
+
+    use super::test_support::{parse, parse_with_host};
+
+    #[test]
+    fn test_function_definition() {
+        let result = parse("function add(a, b) return a + b end\nc = add(1, 2)").unwrap();
+
+        let variable = |name: &str| {
+            Box::new(Node::Variable(
+                Vec::new(),
+                name.to_string(),
+                OnceLock::new(),
+                0,
+            ))
+        };
+        let expected = Box::new(Node::Base(vec![
+            Box::new(Node::Function(
+                vec![Box::new(Node::Return(
+                    vec![Box::new(Node::Add(vec![variable("a"), variable("b")]))],
+                    1,
+                ))],
+                "add".to_string(),
+                vec!["a".to_string(), "b".to_string()],
+                OnceLock::new(),
+            )),
+            Box::new(Node::Assign(vec![
+                variable("c"),
+                Box::new(Node::Call(
+                    vec![
+                        Box::new(Node::Decimal(Decimal::from(1))),
+                        Box::new(Node::Decimal(Decimal::from(2))),
+                    ],
+                    "add".to_string(),
+                )),
+            ])),
+        ]));
+        assert_eq!(result, expected);
+
+        // Brace form, call before definition and call as a statement
+        assert!(parse("log(1)\nfunction log(x) { y = x }").is_ok());
+    }
+
+    #[test]
+    fn test_function_arity() {
+        assert!(parse("function f(a, b) return a end; c = f(1)").is_err());
+        assert!(parse("c = f(1, 2, 3); function f(a, b) return a end").is_err());
+        assert!(parse("function f() return 1 end; c = f()").is_ok());
+    }
+
+    #[test]
+    fn test_invalid_functions() {
+        // undefined function
+        assert!(parse("c = g(1)").is_err());
+        // duplicate definition
+        assert!(parse("function f() return 1 end\nfunction f() return 2 end").is_err());
+        // duplicate parameter
+        assert!(parse("function f(a, a) return a end").is_err());
+        // builtin redefinition
+        assert!(parse("function max(a, b) return a end").is_err());
+        // nested definition
+        assert!(parse("function f() function g() return 1 end end").is_err());
+        assert!(parse("if a > 1 then function g() return 1 end end").is_err());
+        // functions are not expressions
+        assert!(parse("x = function f() return 1 end").is_err());
+    }
+
+    #[test]
+    fn test_host_functions() {
+        assert!(parse("Sell(100)").is_err());
+        assert!(parse_with_host("Sell(100)").is_ok());
+        // Host functions check their own arguments
+        assert!(parse_with_host("x = Sell(\"AAPL\", 100)").is_ok());
+        assert!(parse_with_host("function Sell(x) return x end").is_err());
+    }
+
+    #[test]
+    fn test_lookback() {
+        let result = parse_with_host("x = Sell(10, at=when)").unwrap();
+        let expected = Box::new(Node::Base(vec![Box::new(Node::Assign(vec![
+            Box::new(Node::new_variable("x".to_string())),
+            Box::new(Node::Lookback(
+                vec![
+                    Box::new(Node::Decimal(Decimal::from(10))),
+                    Box::new(Node::new_variable("when".to_string())),
+                ],
+                "Sell".to_string(),
+            )),
+        ]))]));
+        assert_eq!(result, expected);
+
+        // `at` is a variable anywhere else
+        assert!(parse_with_host("at = 1\nx = Sell(at)").is_ok());
+        assert!(parse_with_host("x = Sell(at=-1, 10)").is_err());
+        assert!(parse("function f(x) return x end\ny = f(1, at=-1)").is_err());
+        assert!(parse("y = g(1, at=-1)").is_err());
+    }
+
+    #[test]
+    fn test_math_builtins() {
+        let result = parse("x = abs(y % 2) * 5%").unwrap();
+        let expected = Box::new(Node::Base(vec![Box::new(Node::Assign(vec![
+            Box::new(Node::new_variable("x".to_string())),
+            Box::new(Node::Multiply(vec![
+                Box::new(Node::Math(
+                    vec![Box::new(Node::Modulo(vec![
+                        Box::new(Node::new_variable("y".to_string())),
+                        Box::new(Node::Decimal(Decimal::from(2))),
+                    ]))],
+                    MathFunction::Abs,
+                )),
+                Box::new(Node::Decimal("0.05".parse().unwrap())),
+            ])),
+        ]))]));
+        assert_eq!(result, expected);
+
+        assert!(parse("x = round(1, 2, \"floor\")").is_err());
+        assert!(parse("x = select(true, 1)").is_err());
+        let error = parse("function abs(x) { return x }").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Cannot redefine builtin function abs"));
+        assert!(BUILTINS
+            .iter()
+            .all(|builtin| builtin.min_args <= builtin.max_args));
+    }
+
+    #[test]
+    fn test_variables_named_after_builtins() {
+        // Only a name followed by `(` calls a builtin
+        let result = parse("high = 1\nsum = high + 1\nx = sum(sum)").unwrap();
+        let expected = Box::new(Node::Base(vec![
+            Box::new(Node::Assign(vec![
+                Box::new(Node::new_variable("high".to_string())),
+                Box::new(Node::Decimal(Decimal::from(1))),
+            ])),
+            Box::new(Node::Assign(vec![
+                Box::new(Node::new_variable("sum".to_string())),
+                Box::new(Node::Add(vec![
+                    Box::new(Node::new_variable("high".to_string())),
+                    Box::new(Node::Decimal(Decimal::from(1))),
+                ])),
+            ])),
+            Box::new(Node::Assign(vec![
+                Box::new(Node::new_variable("x".to_string())),
+                Box::new(Node::Statistic(
+                    vec![Box::new(Node::new_variable("sum".to_string()))],
+                    Statistic::Sum,
+                )),
+            ])),
+        ]));
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_indicators() {
+        let result = parse("buy = crosses_above(sma(\"AAPL\", 50), 100)").unwrap();
+        let expected = Box::new(Node::Base(vec![Box::new(Node::Assign(vec![
+            Box::new(Node::new_variable("buy".to_string())),
+            Box::new(Node::CrossesAbove(vec![
+                Box::new(Node::Indicator(
+                    vec![
+                        Box::new(Node::String("AAPL".to_string())),
+                        Box::new(Node::Decimal(Decimal::from(50))),
+                    ],
+                    Indicator::Sma,
+                )),
+                Box::new(Node::Decimal(Decimal::from(100))),
+            ])),
+        ]))]));
+        assert_eq!(result, expected);
+
+        assert!(parse("x = bollinger(\"AAPL\", 20, 2, 1)").is_err());
+        assert!(parse("x = rsi(\"AAPL\")").is_err());
+
+        // Both arguments are evaluated one price earlier too
+        let crosses = |script: &str| {
+            let tokens = Lexer::new(script.to_string()).tokenize()?;
+            Parser::new(tokens).with_host_functions(&["Spot"]).parse()
+        };
+        assert!(
+            crosses("n = 50; x = crosses_above(sma(\"AAPL\", 1) * 1.02, -sma(\"AAPL\", n))")
+                .is_ok()
+        );
+        assert!(crosses("x = crosses_above(bollinger(\"AAPL\", 20).upper, 70)").is_ok());
+        for script in [
+            "x = crosses_above(Spot(\"AAPL\"), sma(\"AAPL\", 50))",
+            "price = 1; x = crosses_above(price, sma(\"AAPL\", 50))",
+            "x = crosses_above(sma(\"AAPL\", Spot(\"AAPL\")), 100)",
+        ] {
+            let error = crosses(script).unwrap_err();
+            assert!(error
+                .to_string()
+                .contains("Expected indicators or constants as the arguments of crosses_above"));
+        }
+    }
+
+    #[test]
+    fn test_statistics() {
+        let result = parse("x = percentile(xs, 95)").unwrap();
+        let expected = Box::new(Node::Base(vec![Box::new(Node::Assign(vec![
+            Box::new(Node::new_variable("x".to_string())),
+            Box::new(Node::Statistic(
+                vec![
+                    Box::new(Node::new_variable("xs".to_string())),
+                    Box::new(Node::Decimal(Decimal::from(95))),
+                ],
+                Statistic::Percentile,
+            )),
+        ]))]));
+        assert_eq!(result, expected);
+
+        assert!(parse("x = correlation(xs)").is_err());
+        assert!(parse("x = sum(xs, ys)").is_err());
+    }
+}
+
+// Loops, termination statements and preconditions
+#[cfg(test)]
+mod tests_control_flow {
+    use crate::{nodes::node::Node, utils::decimal::Decimal};
+
+    use super::test_support::{parse, parse_with_transactions};
+
+    #[test]
+    fn test_termination_statements() {
+        let result = parse("x = 1\nif x > 0 { exit }\nabort(\"no trade\")\nreturn").unwrap();
+        let expected = Box::new(Node::Base(vec![
+            Box::new(Node::Assign(vec![
+                Box::new(Node::new_variable("x".to_string())),
+                Box::new(Node::Decimal(Decimal::from(1))),
+            ])),
+            Box::new(Node::If(
+                vec![
+                    Box::new(Node::Superior(vec![
+                        Box::new(Node::new_variable("x".to_string())),
+                        Box::new(Node::Decimal(Decimal::from(0))),
+                    ])),
+                    Box::new(Node::Exit(2)),
+                ],
+                None,
+                2,
+            )),
+            Box::new(Node::Abort(
+                vec![Box::new(Node::String("no trade".to_string()))],
+                3,
+            )),
+            Box::new(Node::Return(Vec::new(), 4)),
+        ]));
+        assert_eq!(result, expected);
+
+        assert!(parse("return x > 1").is_ok());
+        assert!(parse("abort \"reason\"").is_err());
+        assert!(parse("abort()").is_err());
+        assert!(parse("exit 1").is_err());
+    }
+
+    #[test]
+    fn test_loop_control_outside_loop() {
+        let err = parse("x = 1\nbreak").unwrap_err();
+        assert!(err.to_string().contains("`break` outside of a loop"));
+        assert!(parse("function f() { continue }").is_err());
+    }
+
+    #[test]
+    fn test_for_loop() {
+        let result = parse("for s in xs do\n  if s > 1 { break }\n  n += 1\nend").unwrap();
+        let s = || Box::new(Node::new_variable("s".to_string()));
+        let n = || Box::new(Node::new_variable("n".to_string()));
+        let expected = |line| {
+            Box::new(Node::Base(vec![Box::new(Node::For(
+                vec![
+                    s(),
+                    Box::new(Node::new_variable("xs".to_string())),
+                    Box::new(Node::If(
+                        vec![
+                            Box::new(Node::Superior(vec![
+                                s(),
+                                Box::new(Node::Decimal(Decimal::from(1))),
+                            ])),
+                            Box::new(Node::Break),
+                        ],
+                        None,
+                        line,
+                    )),
+                    Box::new(Node::Assign(vec![
+                        n(),
+                        Box::new(Node::Add(vec![
+                            n(),
+                            Box::new(Node::Decimal(Decimal::from(1))),
+                        ])),
+                    ])),
+                ],
+                1,
+            ))]))
+        };
+        assert_eq!(result, expected(2));
+
+        assert_eq!(
+            parse("for s in xs { if s > 1 { break }; n += 1 }").unwrap(),
+            expected(1)
+        );
+        assert!(parse("for s in [1, 2] do continue end").is_ok());
+        assert!(parse("for s in xs end").is_err());
+        assert!(parse("for s xs do end").is_err());
+        assert!(parse("for 1 in xs do end").is_err());
+        // `break` only applies to the loop it is in
+        assert!(parse("for s in xs do end; break").is_err());
+    }
+
+    #[test]
+    fn test_for_key_value() {
+        let result = parse("for k, v in m do end").unwrap();
+        let expected = Box::new(Node::Base(vec![Box::new(Node::For(
+            vec![
+                Box::new(Node::new_variable("k".to_string())),
+                Box::new(Node::new_variable("v".to_string())),
+                Box::new(Node::new_variable("m".to_string())),
+            ],
+            2,
+        ))]));
+        assert_eq!(result, expected);
+        assert!(parse("for a, b, c in m do end").is_err());
+        assert!(parse("for a, in m do end").is_err());
+    }
+
+    #[test]
+    fn test_preconditions() {
+        let result =
+            parse("require(x > 1, \"too low\")\nassert(x < 5, \"too high\", true)").unwrap();
+        let x = || Box::new(Node::new_variable("x".to_string()));
+        let expected = Box::new(Node::Base(vec![
+            Box::new(Node::Require(
+                vec![
+                    Box::new(Node::Superior(vec![
+                        x(),
+                        Box::new(Node::Decimal(Decimal::from(1))),
+                    ])),
+                    Box::new(Node::String("too low".to_string())),
+                ],
+                1,
+            )),
+            Box::new(Node::Assert(
+                vec![
+                    Box::new(Node::Inferior(vec![
+                        x(),
+                        Box::new(Node::Decimal(Decimal::from(5))),
+                    ])),
+                    Box::new(Node::String("too high".to_string())),
+                    Box::new(Node::True),
+                ],
+                2,
+            )),
+        ]));
+        assert_eq!(result, expected);
+
+        assert!(parse("require(x > 1)").is_err());
+        assert!(parse("require(x > 1, \"a\", true, 1)").is_err());
+        assert!(parse("require x > 1").is_err());
+    }
+
+    #[test]
+    fn test_require_before_transactions() {
+        assert!(parse_with_transactions(
+            "spot = Spot(\"AAPL\")
+            require(spot < 100, \"Too expensive\")
+            Buy(spot)"
+        )
+        .is_ok());
+
+        let err = parse_with_transactions(
+            "spot = Spot(\"AAPL\")
+            Buy(spot)
+            require(spot < 100, \"Too expensive\")",
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("line 3"));
+        assert!(err.to_string().contains("call to Buy"));
+
+        // Calls nested in a statement count, `assert` can come anywhere
+        assert!(parse_with_transactions(
+            "if Sell(1) { x = 1 }
+            require(true, \"never\")"
+        )
+        .is_err());
+        assert!(parse_with_transactions("Sell(1); assert(true, \"ok\")").is_ok());
+
+        // Each block is checked on its own
+        assert!(parse_with_transactions(
+            "Buy(1)
+            if true {
+                require(true, \"inner\")
+                Sell(1)
+            }"
+        )
+        .is_ok());
+        assert!(parse_with_transactions(
+            "function f() { Buy(1) }
+            require(true, \"top\")"
+        )
+        .is_ok());
+    }
+}
+
+// Lists and maps
+#[cfg(test)]
+mod tests_collections {
+    use crate::{nodes::node::Node, utils::decimal::Decimal};
+
+    use super::test_support::parse;
+
+    #[test]
+    fn test_list_literals() {
+        let result = parse("xs = [1, [true], \"a\",]\nys = []").unwrap();
+        let expected = Box::new(Node::Base(vec![
+            Box::new(Node::Assign(vec![
+                Box::new(Node::new_variable("xs".to_string())),
+                Box::new(Node::List(vec![
+                    Box::new(Node::Decimal(Decimal::from(1))),
+                    Box::new(Node::List(vec![Box::new(Node::True)])),
+                    Box::new(Node::String("a".to_string())),
+                ])),
+            ])),
+            Box::new(Node::Assign(vec![
+                Box::new(Node::new_variable("ys".to_string())),
+                Box::new(Node::List(Vec::new())),
+            ])),
+        ]));
+        assert_eq!(result, expected);
+
+        // Line breaks are allowed inside the brackets
+        assert!(parse("xs = [\n  1,\n  2\n]").is_ok());
+        assert!(parse("xs = [1 2]").is_err());
+        assert!(parse("xs = [1, 2").is_err());
+    }
+
+    #[test]
+    fn test_subscripts() {
+        let xs = || Box::new(Node::new_variable("xs".to_string()));
+        let value = |expr: &str| match *parse(&format!("y = {}", expr)).unwrap() {
+            Node::Base(mut statements) => statements.remove(0).children()[1].clone(),
+            _ => unreachable!(),
+        };
+
+        assert_eq!(
+            value("xs[0]"),
+            Box::new(Node::Index(vec![
+                xs(),
+                Box::new(Node::Decimal(Decimal::from(0)))
+            ]))
+        );
+        assert_eq!(
+            value("xs[1:-1]"),
+            Box::new(Node::Slice(
+                vec![
+                    xs(),
+                    Box::new(Node::Decimal(Decimal::from(1))),
+                    Box::new(Node::UnaryMinus(vec![Box::new(Node::Decimal(
+                        Decimal::from(1)
+                    ))])),
+                ],
+                true,
+                true
+            ))
+        );
+        assert_eq!(
+            value("xs[:2]"),
+            Box::new(Node::Slice(
+                vec![xs(), Box::new(Node::Decimal(Decimal::from(2)))],
+                false,
+                true
+            ))
+        );
+        assert_eq!(
+            value("xs[1:]"),
+            Box::new(Node::Slice(
+                vec![xs(), Box::new(Node::Decimal(Decimal::from(1)))],
+                true,
+                false
+            ))
+        );
+        assert_eq!(
+            value("xs[:]"),
+            Box::new(Node::Slice(vec![xs()], false, false))
+        );
+        // Subscripts chain and bind tighter than operators
+        assert_eq!(
+            value("-xs[0][1]"),
+            Box::new(Node::UnaryMinus(vec![Box::new(Node::Index(vec![
+                Box::new(Node::Index(vec![
+                    xs(),
+                    Box::new(Node::Decimal(Decimal::from(0)))
+                ])),
+                Box::new(Node::Decimal(Decimal::from(1))),
+            ]))]))
+        );
+        assert!(parse("y = xs[]").is_err());
+    }
+
+    #[test]
+    fn test_map_literals() {
+        let result = parse("pos = {units: 10, \"avg cost\": 9.5, tags: {},}").unwrap();
+        let expected = Box::new(Node::Base(vec![Box::new(Node::Assign(vec![
+            Box::new(Node::new_variable("pos".to_string())),
+            Box::new(Node::Map(
+                vec![
+                    Box::new(Node::Decimal(Decimal::from(10))),
+                    Box::new(Node::Decimal("9.5".parse().unwrap())),
+                    Box::new(Node::Map(Vec::new(), Vec::new())),
+                ],
+                vec![
+                    "units".to_string(),
+                    "avg cost".to_string(),
+                    "tags".to_string(),
+                ],
+            )),
+        ]))]));
+        assert_eq!(result, expected);
+
+        assert!(parse("pos = {\n  units: 10,\n  cost: 2\n}").is_ok());
+        assert!(parse("pos = {units: 1, units: 2}").is_err());
+        assert!(parse("pos = {units 1}").is_err());
+        assert!(parse("pos = {1: 1}").is_err());
+        // A brace after a condition still opens the block
+        assert!(parse("if pos.units > 0 { x = {a: 1}.a }").is_ok());
+    }
+
+    #[test]
+    fn test_field_access() {
+        let result = parse("x = (e).kind.size").unwrap();
+        let expected = Box::new(Node::Base(vec![Box::new(Node::Assign(vec![
+            Box::new(Node::new_variable("x".to_string())),
+            Box::new(Node::Field(
+                vec![Box::new(Node::Field(
+                    vec![Box::new(Node::new_variable("e".to_string()))],
+                    "kind".to_string(),
+                ))],
+                "size".to_string(),
+            )),
+        ]))]));
+        assert_eq!(result, expected);
+        assert!(parse("x = e.").is_err());
+    }
+}
+
+// `match` statements
+#[cfg(test)]
+mod tests_match {
+    use crate::{
+        nodes::node::Node,
+        parsers::{lexer::Lexer, parser::Parser},
+    };
+
+    use super::test_support::{assign, parse};
+
+    #[test]
+    fn test_match() {
+        let result = parse(
+            "match symbol with
+            case \"AAPL\", \"MSFT\" then x = 1
+            case \"GOOG\" then
+                x = 2
+            else
+                x = 3
+            end",
+        )
+        .unwrap();
+        let string = |value: &str| Box::new(Node::String(value.to_string()));
+        let expected = Box::new(Node::Base(vec![Box::new(Node::Match(
+            vec![
+                Box::new(Node::new_variable("symbol".to_string())),
+                Box::new(Node::Case(
+                    vec![string("AAPL"), string("MSFT"), assign("x", 1.0)],
+                    2,
+                )),
+                Box::new(Node::Case(vec![string("GOOG"), assign("x", 2.0)], 1)),
+                assign("x", 3.0),
+            ],
+            Some(3),
+        ))]));
+        assert_eq!(result, expected);
+
+        assert!(parse("match x with case 1 then y = 1 end").is_ok());
+        assert!(parse("match x with end").is_err());
+        assert!(parse("match x case 1 then y = 1 end").is_err());
+        assert!(parse("match x with case 1 y = 1 end").is_err());
+        assert!(parse("match x with else y = 1 end").is_err());
+        assert!(parse("match x with case 1 then y = 1").is_err());
+    }
+
+    #[test]
+    fn test_match_warnings() {
+        let warnings = |script: &str| {
+            let tokens = Lexer::new(script.to_string()).tokenize().unwrap();
+            let parser = Parser::new(tokens);
+            parser.parse().unwrap();
+            parser.warnings()
+        };
+
+        assert!(
+            warnings("match x with case 1, -1, \"1\" then y = 1 case y then y = 2 end").is_empty()
+        );
+        let found =
+            warnings("match x with\ncase \"AAPL\" then y = 1\ncase \"AAPL\" then y = 2\nend");
+        assert_eq!(found.len(), 1);
+        assert!(found[0].starts_with("Warning at line 3"));
+        assert!(found[0].ends_with("Unreachable case: \"AAPL\" already matched"));
+        let found = warnings("match x with\ncase -1, 2 then y = 1\ncase 2, 3, 3 then y = 2\nend");
+        assert_eq!(found.len(), 2);
+        assert!(found[0].contains("Duplicate case 2, already matched at line 2"));
+        assert!(found[1].contains("Duplicate case 3, already matched at line 3"));
+    }
+}
+
+// `try`/`catch` blocks
+#[cfg(test)]
+mod tests_try {
+    use crate::{nodes::node::Node, utils::decimal::Decimal};
+
+    use super::test_support::{assign, parse, parse_with_host};
+
+    #[test]
+    fn test_try_catch() {
+        let result =
+            parse_with_host("try\n  Sell(100)\ncatch err\n  Notify(err.message)\nend").unwrap();
+        let expected = Box::new(Node::Base(vec![Box::new(Node::Try(
+            vec![
+                Box::new(Node::Call(
+                    vec![Box::new(Node::Decimal(Decimal::from(100)))],
+                    "Sell".to_string(),
+                )),
+                Box::new(Node::Call(
+                    vec![Box::new(Node::Field(
+                        vec![Box::new(Node::new_variable("err".to_string()))],
+                        "message".to_string(),
+                    ))],
+                    "Notify".to_string(),
+                )),
+            ],
+            1,
+            Some(Box::new(Node::new_variable("err".to_string()))),
+        ))]));
+        assert_eq!(result, expected);
+
+        // Without error variable, on a single line and with braces
+        let result = parse("try x = 1 catch x = 2 end").unwrap();
+        let expected = Box::new(Node::Base(vec![Box::new(Node::Try(
+            vec![assign("x", 1.0), assign("x", 2.0)],
+            1,
+            None,
+        ))]));
+        assert_eq!(result, expected);
+        assert_eq!(parse("try { x = 1 } catch { x = 2 }").unwrap(), expected);
+        assert!(parse("try { x = 1 } catch e { x = 2 }").is_ok());
+
+        assert!(parse("try x = 1 end").is_err());
+        assert!(parse("try x = 1 catch e x = 2").is_err());
+    }
+}
@@ -1,5 +1,9 @@
 pub use crate::{
-    nodes::{expressionevaluator::*, expressionindexer::*, node::*, traits::*},
+    nodes::{
+        expressionevaluator::*, expressionindexer::*, initializationchecker::*, linter::*, node::*,
+        optimizer::*, traits::*,
+    },
     parsers::{lexer::*, parser::*},
-    utils::errors::*,
+    providers::{calendar::*, clock::*, fx::*, history::*, observations::*},
+    utils::{decimal::*, errors::*, format::*, indicators::*, math::*, statistics::*, time::*},
 };
@@ -0,0 +1,319 @@
+use std::{collections::BTreeSet, fs, path::Path, str::FromStr};
+
+use crate::utils::{
+    errors::{Result, ScriptingError},
+    time::{Date, DateTime},
+};
+
+/// How a date that is not a business day is moved to one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Adjustment {
+    /// To the next business day
+    Following,
+    /// To the next business day, unless it is in the next month, in which
+    /// case to the previous one
+    ModifiedFollowing,
+    /// To the previous business day
+    Preceding,
+}
+
+impl FromStr for Adjustment {
+    type Err = ScriptingError;
+
+    /// Parses the names used by scripts, e.g. `modified_following`
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "following" => Ok(Adjustment::Following),
+            "modified_following" => Ok(Adjustment::ModifiedFollowing),
+            "preceding" => Ok(Adjustment::Preceding),
+            _ => Err(ScriptingError::EvaluationError(format!(
+                "Unknown business day convention {}",
+                name
+            ))),
+        }
+    }
+}
+
+/// The business days of a market or a country: the weekdays that are not
+/// holidays.
+///
+/// Holidays are read from files with one date per line, optionally followed
+/// by its name; `#` starts a comment:
+///
+/// ```text
+/// # NYSE
+/// 2024-01-01 New Year's Day
+/// 2024-12-25 Christmas Day
+/// ```
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct BusinessCalendar {
+    name: String,
+    holidays: BTreeSet<Date>,
+}
+
+impl BusinessCalendar {
+    /// A calendar without holidays, where only weekends are closed
+    pub fn new(name: &str) -> Self {
+        BusinessCalendar {
+            name: name.to_string(),
+            holidays: BTreeSet::new(),
+        }
+    }
+
+    pub fn with_holiday(mut self, date: Date) -> Self {
+        self.holidays.insert(date);
+        self
+    }
+
+    /// Reads the holidays of a calendar from the text of a holiday file
+    pub fn parse(name: &str, text: &str) -> Result<Self> {
+        let mut calendar = BusinessCalendar::new(name);
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let Some(date) = line.split_whitespace().next() else {
+                continue;
+            };
+            let date = date.parse::<Date>().map_err(|_| {
+                ScriptingError::InvalidSyntax(format!(
+                    "Invalid holiday `{}` in calendar {} at line {}",
+                    date,
+                    name,
+                    number + 1
+                ))
+            })?;
+            calendar.holidays.insert(date);
+        }
+        Ok(calendar)
+    }
+
+    /// Loads a holiday file, the calendar being named after the file, e.g.
+    /// `NYSE` for `holidays/NYSE.txt`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        BusinessCalendar::parse(&name, &fs::read_to_string(path)?)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_holiday(&self, date: Date) -> bool {
+        self.holidays.contains(&date)
+    }
+
+    pub fn is_business_day(&self, date: Date) -> bool {
+        date.is_weekday() && !self.is_holiday(date)
+    }
+
+    /// The date itself if it is a business day, or the business day chosen by
+    /// the convention. `None` past the supported dates.
+    pub fn adjust(&self, date: Date, adjustment: Adjustment) -> Option<Date> {
+        match adjustment {
+            Adjustment::Following => self.step_to_business_day(date, 1),
+            Adjustment::Preceding => self.step_to_business_day(date, -1),
+            Adjustment::ModifiedFollowing => {
+                let following = self.step_to_business_day(date, 1);
+                match following.map(|following| following.ymd().1) == Some(date.ymd().1) {
+                    true => following,
+                    false => self.step_to_business_day(date, -1),
+                }
+            }
+        }
+    }
+
+    /// The date `days` business days later, or earlier for a negative number.
+    /// Counting starts from the date even if it is not a business day, so one
+    /// business day after a Saturday is the Monday.
+    pub fn add_business_days(&self, date: Date, days: i64) -> Option<Date> {
+        let step = if days < 0 { -1 } else { 1 };
+        let mut date = date;
+        for _ in 0..days.unsigned_abs() {
+            date = self.step_to_business_day(date.checked_add_days(step)?, step)?;
+        }
+        Some(date)
+    }
+
+    /// The last business day of the month of the date
+    pub fn last_business_day_of_month(&self, date: Date) -> Option<Date> {
+        self.adjust(date.end_of_month(), Adjustment::Preceding)
+    }
+
+    // The first business day from the date on, moving by `step` days
+    fn step_to_business_day(&self, mut date: Date, step: i64) -> Option<Date> {
+        while !self.is_business_day(date) {
+            date = date.checked_add_days(step)?;
+        }
+        Some(date)
+    }
+}
+
+/// The trading hours of a market on its business days, e.g. 09:30 to 16:00
+/// for the NYSE. The hours are in the time of the clock given to the
+/// evaluator, so a market is best checked with a clock in its time zone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MarketSession {
+    calendar: BusinessCalendar,
+    // Seconds since midnight, the session being open from `open` to before
+    // `close`
+    open: u32,
+    close: u32,
+}
+
+impl MarketSession {
+    /// A session from `open` to `close`, given as `(hour, minute)`. Fails
+    /// unless the market opens before it closes on the same day.
+    pub fn new(calendar: BusinessCalendar, open: (u32, u32), close: (u32, u32)) -> Result<Self> {
+        let seconds = |(hour, minute): (u32, u32)| match hour < 24 && minute < 60 {
+            true => Ok(hour * 3600 + minute * 60),
+            false => Err(ScriptingError::EvaluationError(format!(
+                "Invalid session time {:02}:{:02}",
+                hour, minute
+            ))),
+        };
+        let (open, close) = (seconds(open)?, seconds(close)?);
+        if open >= close {
+            return Err(ScriptingError::EvaluationError(
+                "A market session must open before it closes".to_string(),
+            ));
+        }
+        Ok(MarketSession {
+            calendar,
+            open,
+            close,
+        })
+    }
+
+    pub fn calendar(&self) -> &BusinessCalendar {
+        &self.calendar
+    }
+
+    pub fn is_open(&self, time: DateTime) -> bool {
+        let (hour, minute, second) = time.hms();
+        let seconds = hour * 3600 + minute * 60 + second;
+        self.calendar.is_business_day(time.date()) && (self.open..self.close).contains(&seconds)
+    }
+
+    /// The next opening of the market at or after the given time
+    pub fn next_open(&self, time: DateTime) -> Option<DateTime> {
+        let opening = |date: Date| DateTime::new(date, self.open / 3600, self.open / 60 % 60, 0);
+        let today = time.date();
+        if self.calendar.is_business_day(today) && opening(today)? >= time {
+            return opening(today);
+        }
+        opening(self.calendar.add_business_days(today, 1)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(text: &str) -> Date {
+        text.parse().unwrap()
+    }
+
+    fn calendar() -> BusinessCalendar {
+        BusinessCalendar::parse(
+            "NYSE",
+            "# NYSE holidays\n2024-05-27 Memorial Day\n\n2024-06-19 # Juneteenth\n2024-05-31",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_business_days() {
+        let calendar = calendar();
+        assert_eq!(calendar.name(), "NYSE");
+        assert!(calendar.is_business_day(date("2024-05-24")));
+        assert!(!calendar.is_business_day(date("2024-05-25")));
+        assert!(!calendar.is_business_day(date("2024-05-27")));
+        assert!(calendar.is_holiday(date("2024-06-19")));
+
+        assert_eq!(
+            calendar.add_business_days(date("2024-05-24"), 1),
+            Some(date("2024-05-28"))
+        );
+        assert_eq!(
+            calendar.add_business_days(date("2024-05-25"), 1),
+            Some(date("2024-05-28"))
+        );
+        assert_eq!(
+            calendar.add_business_days(date("2024-05-28"), -2),
+            Some(date("2024-05-23"))
+        );
+        assert_eq!(
+            calendar.add_business_days(date("2024-05-26"), 0),
+            Some(date("2024-05-26"))
+        );
+        assert_eq!(
+            calendar.last_business_day_of_month(date("2024-05-10")),
+            Some(date("2024-05-30"))
+        );
+
+        assert!(BusinessCalendar::parse("X", "2024-13-01").is_err());
+        assert!(BusinessCalendar::load("/nonexistent/NYSE.txt").is_err());
+    }
+
+    #[test]
+    fn test_adjustments() {
+        let calendar = calendar();
+        let adjust = |text, adjustment| calendar.adjust(date(text), adjustment).unwrap();
+
+        assert_eq!(
+            adjust("2024-05-24", Adjustment::Following),
+            date("2024-05-24")
+        );
+        assert_eq!(
+            adjust("2024-05-25", Adjustment::Following),
+            date("2024-05-28")
+        );
+        assert_eq!(
+            adjust("2024-05-25", Adjustment::Preceding),
+            date("2024-05-24")
+        );
+        // June 1st is a Saturday: following would leave May
+        assert_eq!(
+            adjust("2024-06-01", Adjustment::ModifiedFollowing),
+            date("2024-06-03")
+        );
+        assert_eq!(
+            adjust("2024-06-30", Adjustment::Following),
+            date("2024-07-01")
+        );
+        assert_eq!(
+            adjust("2024-06-30", Adjustment::ModifiedFollowing),
+            date("2024-06-28")
+        );
+        assert_eq!(
+            "modified_following".parse::<Adjustment>().unwrap(),
+            Adjustment::ModifiedFollowing
+        );
+        assert!("nearest".parse::<Adjustment>().is_err());
+    }
+
+    #[test]
+    fn test_market_sessions() {
+        let session = MarketSession::new(calendar(), (9, 30), (16, 0)).unwrap();
+        let time = |text: &str| text.parse::<DateTime>().unwrap();
+
+        assert!(session.is_open(time("2024-05-24T09:30")));
+        assert!(session.is_open(time("2024-05-24T15:59:59")));
+        assert!(!session.is_open(time("2024-05-24T16:00")));
+        assert!(!session.is_open(time("2024-05-27T12:00")));
+        assert_eq!(
+            session.next_open(time("2024-05-24T08:00")),
+            Some(time("2024-05-24T09:30"))
+        );
+        assert_eq!(
+            session.next_open(time("2024-05-24T10:00")),
+            Some(time("2024-05-28T09:30"))
+        );
+
+        assert!(MarketSession::new(calendar(), (16, 0), (9, 30)).is_err());
+        assert!(MarketSession::new(calendar(), (24, 0), (9, 30)).is_err());
+    }
+}
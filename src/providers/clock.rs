@@ -0,0 +1,33 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::utils::time::DateTime;
+
+/// Source of the current time for `now()` and `today()`. Hosts running
+/// scripts for a given time zone, or tests that need a fixed date, provide
+/// their own.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime;
+}
+
+/// The system clock, in UTC
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime {
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as i64);
+        DateTime::from_seconds_since_epoch(seconds)
+    }
+}
+
+/// A clock stopped at a given time
+#[derive(Debug, Clone, Copy)]
+pub struct FixedClock(pub DateTime);
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime {
+        self.0
+    }
+}
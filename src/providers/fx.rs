@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+
+use crate::utils::{
+    decimal::{Decimal, RoundingMode},
+    errors::{Result, ScriptingError},
+};
+
+/// Source of the exchange rates used by `convert(amount, currency)`.
+/// Implementations report missing rates or failures to fetch them with
+/// [`ScriptingError::HostError`], which scripts can catch.
+pub trait FxRateProvider: Send + Sync {
+    /// Units of `to` for one unit of `from`, e.g. about `0.92` for USD to EUR
+    fn rate(&self, from: &str, to: &str) -> Result<Decimal>;
+}
+
+/// Exchange rates held in memory, e.g. for tests. A rate given for one
+/// direction is used, inverted, for the other.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryFxRates {
+    rates: HashMap<(String, String), Decimal>,
+}
+
+impl InMemoryFxRates {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the units of `to` for one unit of `from`
+    pub fn with_rate(mut self, from: &str, to: &str, rate: Decimal) -> Self {
+        self.rates.insert((from.to_string(), to.to_string()), rate);
+        self
+    }
+}
+
+impl FxRateProvider for InMemoryFxRates {
+    fn rate(&self, from: &str, to: &str) -> Result<Decimal> {
+        if from == to {
+            return Ok(Decimal::from(1));
+        }
+        if let Some(rate) = self.rates.get(&(from.to_string(), to.to_string())) {
+            return Ok(*rate);
+        }
+        self.rates
+            .get(&(to.to_string(), from.to_string()))
+            .and_then(|rate| {
+                Decimal::from(1).checked_div(rate, Decimal::MAX_SCALE, RoundingMode::HalfEven)
+            })
+            .ok_or_else(|| {
+                ScriptingError::HostError(
+                    "unknown_rate".to_string(),
+                    format!("No exchange rate from {} to {}", from, to),
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decimal(text: &str) -> Decimal {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_in_memory_rates() {
+        let rates = InMemoryFxRates::new()
+            .with_rate("USD", "EUR", decimal("0.8"))
+            .with_rate("USD", "CLP", decimal("900"));
+
+        assert_eq!(rates.rate("USD", "EUR").unwrap(), decimal("0.8"));
+        assert_eq!(rates.rate("EUR", "USD").unwrap(), decimal("1.25"));
+        assert_eq!(rates.rate("CLP", "CLP").unwrap(), decimal("1"));
+        assert!(matches!(
+            rates.rate("EUR", "CLP"),
+            Err(ScriptingError::HostError(kind, _)) if kind == "unknown_rate"
+        ));
+    }
+}
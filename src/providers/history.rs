@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+
+use crate::utils::errors::{Result, ScriptingError};
+
+/// Source of the past prices used by the indicator builtins such as
+/// `sma(symbol, window)`. Implementations report unknown symbols or failures
+/// to fetch prices with [`ScriptingError::HostError`], which scripts can catch.
+pub trait PriceHistoryProvider: Send + Sync {
+    /// The prices of `symbol` at regular intervals, e.g. daily closes, oldest
+    /// first and ending with the latest price
+    fn prices(&self, symbol: &str) -> Result<Vec<f64>>;
+}
+
+/// Price series held in memory, e.g. for tests
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryPriceHistory {
+    series: HashMap<String, Vec<f64>>,
+}
+
+impl InMemoryPriceHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the prices of `symbol`, oldest first
+    pub fn with_prices(mut self, symbol: &str, prices: Vec<f64>) -> Self {
+        self.series.insert(symbol.to_string(), prices);
+        self
+    }
+}
+
+impl PriceHistoryProvider for InMemoryPriceHistory {
+    fn prices(&self, symbol: &str) -> Result<Vec<f64>> {
+        self.series.get(symbol).cloned().ok_or_else(|| {
+            ScriptingError::HostError(
+                "unknown_symbol".to_string(),
+                format!("No price history for {}", symbol),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_memory_history() {
+        let history = InMemoryPriceHistory::new().with_prices("AAPL", vec![101.5, 102.0]);
+
+        assert_eq!(history.prices("AAPL").unwrap(), vec![101.5, 102.0]);
+        assert!(matches!(
+            history.prices("MSFT"),
+            Err(ScriptingError::HostError(kind, _)) if kind == "unknown_symbol"
+        ));
+    }
+}
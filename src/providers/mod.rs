@@ -0,0 +1,5 @@
+pub mod calendar;
+pub mod clock;
+pub mod fx;
+pub mod history;
+pub mod observations;
@@ -0,0 +1,110 @@
+use std::{collections::HashMap, sync::Arc, sync::Mutex};
+
+use crate::nodes::expressionevaluator::Value;
+use crate::utils::{errors::Result, time::DateTime};
+
+/// A value returned by a host function, and when it was returned
+#[derive(Debug, Clone, PartialEq)]
+pub struct Observation {
+    pub time: DateTime,
+    pub value: Value,
+}
+
+/// Keeps the results of host function calls across runs, so that scripts can
+/// look back at them with `Spot("AAPL", at=-1)`. Observations are keyed by the
+/// call, e.g. `Spot("AAPL")`.
+pub trait ObservationStore: Send + Sync {
+    /// The observations of `key` recorded so far, oldest first
+    fn observations(&self, key: &str) -> Result<Vec<Observation>>;
+
+    /// Records the observation of `key` made by a run that completed
+    fn record(&self, key: &str, observation: Observation) -> Result<()>;
+}
+
+/// Lets a host keep the store it gives to the evaluators of successive runs
+impl<T: ObservationStore + ?Sized> ObservationStore for Arc<T> {
+    fn observations(&self, key: &str) -> Result<Vec<Observation>> {
+        self.as_ref().observations(key)
+    }
+
+    fn record(&self, key: &str, observation: Observation) -> Result<()> {
+        self.as_ref().record(key, observation)
+    }
+}
+
+/// Observations held in memory, for as long as the process runs
+#[derive(Debug, Default)]
+pub struct InMemoryObservations {
+    observations: Mutex<HashMap<String, Vec<Observation>>>,
+}
+
+impl InMemoryObservations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a past observation of `key`, after those already there
+    pub fn with_observation(self, key: &str, time: DateTime, value: Value) -> Self {
+        self.observations
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .push(Observation { time, value });
+        self
+    }
+}
+
+impl ObservationStore for InMemoryObservations {
+    fn observations(&self, key: &str) -> Result<Vec<Observation>> {
+        Ok(self
+            .observations
+            .lock()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn record(&self, key: &str, observation: Observation) -> Result<()> {
+        self.observations
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .push(observation);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::time::Date;
+
+    #[test]
+    fn test_in_memory_observations() {
+        let monday = DateTime::new(Date::from_ymd(2024, 5, 6).unwrap(), 9, 30, 0).unwrap();
+        let tuesday = DateTime::new(Date::from_ymd(2024, 5, 7).unwrap(), 9, 30, 0).unwrap();
+        let store = Arc::new(InMemoryObservations::new().with_observation(
+            "Spot(\"AAPL\")",
+            monday,
+            Value::Number(101.5),
+        ));
+        store
+            .record(
+                "Spot(\"AAPL\")",
+                Observation {
+                    time: tuesday,
+                    value: Value::Number(99.0),
+                },
+            )
+            .unwrap();
+
+        let observations = store.observations("Spot(\"AAPL\")").unwrap();
+        assert_eq!(observations.len(), 2);
+        assert_eq!(observations[1].time, tuesday);
+        assert_eq!(observations[1].value, Value::Number(99.0));
+        assert!(store.observations("Spot(\"MSFT\")").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,407 @@
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+use crate::utils::errors::ScriptingError;
+
+/// Decimals kept by default when a product or a quotient must be rounded
+pub const DEFAULT_SCALE: u32 = 10;
+
+/// How to round a number to fewer decimals.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RoundingMode {
+    /// To the nearest, ties to the even neighbour: banker's rounding
+    #[default]
+    HalfEven,
+    /// To the nearest, ties away from zero
+    HalfUp,
+    /// To the nearest, ties toward zero
+    HalfDown,
+    /// Toward zero, i.e. truncation
+    Down,
+    /// Away from zero
+    Up,
+    /// Toward negative infinity
+    Floor,
+    /// Toward positive infinity
+    Ceiling,
+}
+
+impl FromStr for RoundingMode {
+    type Err = ScriptingError;
+
+    /// Parses the names used by scripts, e.g. `half_even` or `floor`
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "half_even" | "bankers" => Ok(RoundingMode::HalfEven),
+            "half_up" => Ok(RoundingMode::HalfUp),
+            "half_down" => Ok(RoundingMode::HalfDown),
+            "down" => Ok(RoundingMode::Down),
+            "up" => Ok(RoundingMode::Up),
+            "floor" => Ok(RoundingMode::Floor),
+            "ceiling" => Ok(RoundingMode::Ceiling),
+            _ => Err(ScriptingError::EvaluationError(format!(
+                "Unknown rounding mode {}",
+                name
+            ))),
+        }
+    }
+}
+
+/// A fixed-point decimal number: `mantissa * 10^-scale`, so that `0.1 + 0.2`
+/// is exactly `0.3`. Numbers are kept without trailing zeros, which makes
+/// equal numbers identical.
+///
+/// Operations that could overflow return `None`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Decimal {
+    mantissa: i128,
+    scale: u32,
+}
+
+impl Decimal {
+    /// Largest number of decimals, so that `10^scale` fits the mantissa
+    pub const MAX_SCALE: u32 = 28;
+
+    /// `mantissa * 10^-scale`, rounded half to even to at most
+    /// [`Decimal::MAX_SCALE`] decimals
+    pub fn new(mantissa: i128, scale: u32) -> Decimal {
+        let mut decimal = match scale.checked_sub(Decimal::MAX_SCALE) {
+            Some(extra) if extra > 0 => Decimal {
+                mantissa: pow10(extra).map_or(0, |divisor| {
+                    divide(mantissa, divisor, RoundingMode::HalfEven)
+                }),
+                scale: Decimal::MAX_SCALE,
+            },
+            _ => Decimal { mantissa, scale },
+        };
+        while decimal.scale > 0 && decimal.mantissa % 10 == 0 {
+            decimal.mantissa /= 10;
+            decimal.scale -= 1;
+        }
+        decimal
+    }
+
+    /// The decimal written the same as the shortest representation of
+    /// `value`, e.g. `0.1` rather than the binary value closest to it
+    pub fn from_f64(value: f64) -> Option<Decimal> {
+        if !value.is_finite() {
+            return None;
+        }
+        value.to_string().parse().ok()
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        self.to_string().parse().unwrap_or(f64::NAN)
+    }
+
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.mantissa == 0
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.mantissa < 0
+    }
+
+    pub fn abs(&self) -> Decimal {
+        Decimal::new(self.mantissa.abs(), self.scale)
+    }
+
+    pub fn checked_add(&self, other: &Decimal) -> Option<Decimal> {
+        let (left, right, scale) = align(self, other)?;
+        Some(Decimal::new(left.checked_add(right)?, scale))
+    }
+
+    pub fn checked_sub(&self, other: &Decimal) -> Option<Decimal> {
+        let (left, right, scale) = align(self, other)?;
+        Some(Decimal::new(left.checked_sub(right)?, scale))
+    }
+
+    /// The exact product, unless it has more than `scale` decimals in which
+    /// case it is rounded
+    pub fn checked_mul(&self, other: &Decimal, scale: u32, mode: RoundingMode) -> Option<Decimal> {
+        let mantissa = self.mantissa.checked_mul(other.mantissa)?;
+        let exact = self.scale + other.scale;
+        if exact <= Decimal::MAX_SCALE {
+            return Some(Decimal::new(mantissa, exact).round(scale as i32, mode));
+        }
+        let divisor = pow10(exact - scale.min(Decimal::MAX_SCALE))?;
+        Some(Decimal::new(
+            divide(mantissa, divisor, mode),
+            scale.min(Decimal::MAX_SCALE),
+        ))
+    }
+
+    /// The quotient rounded to `scale` decimals, `None` when dividing by zero
+    pub fn checked_div(&self, other: &Decimal, scale: u32, mode: RoundingMode) -> Option<Decimal> {
+        if other.is_zero() {
+            return None;
+        }
+        let scale = scale.min(Decimal::MAX_SCALE);
+        // self / other = (m1 * 10^(s2 + scale)) / (m2 * 10^s1) * 10^-scale
+        let numerator = self.mantissa.checked_mul(pow10(other.scale + scale)?)?;
+        let denominator = other.mantissa.checked_mul(pow10(self.scale)?)?;
+        let (numerator, denominator) = match denominator < 0 {
+            true => (numerator.checked_neg()?, denominator.checked_neg()?),
+            false => (numerator, denominator),
+        };
+        Some(Decimal::new(divide(numerator, denominator, mode), scale))
+    }
+
+    /// The remainder of the division rounded towards negative infinity, which
+    /// has the sign of `other`: `-7 mod 3` is `2`. `None` when dividing by zero
+    pub fn checked_rem(&self, other: &Decimal) -> Option<Decimal> {
+        if other.is_zero() {
+            return None;
+        }
+        let (left, right, scale) = align(self, other)?;
+        let remainder = left % right;
+        match remainder != 0 && (remainder < 0) != (right < 0) {
+            true => Some(Decimal::new(remainder + right, scale)),
+            false => Some(Decimal::new(remainder, scale)),
+        }
+    }
+
+    /// Rounds to `digits` decimals, which can be negative to round to tens,
+    /// hundreds, etc.
+    pub fn round(&self, digits: i32, mode: RoundingMode) -> Decimal {
+        if digits >= self.scale as i32 {
+            return *self;
+        }
+        let dropped = (self.scale as i64 - digits as i64).min(38) as u32;
+        let Some(divisor) = pow10(dropped) else {
+            return *self;
+        };
+        let rounded = divide(self.mantissa, divisor, mode);
+        match digits {
+            0.. => Decimal::new(rounded, digits as u32),
+            _ => pow10(digits.unsigned_abs())
+                .and_then(|factor| rounded.checked_mul(factor))
+                .map_or(*self, |mantissa| Decimal::new(mantissa, 0)),
+        }
+    }
+}
+
+// The mantissas of both numbers at the same scale
+fn align(left: &Decimal, right: &Decimal) -> Option<(i128, i128, u32)> {
+    let scale = left.scale.max(right.scale);
+    Some((
+        left.mantissa.checked_mul(pow10(scale - left.scale)?)?,
+        right.mantissa.checked_mul(pow10(scale - right.scale)?)?,
+        scale,
+    ))
+}
+
+fn pow10(exponent: u32) -> Option<i128> {
+    10i128.checked_pow(exponent)
+}
+
+// `numerator / divisor` rounded to an integer, the divisor being positive
+fn divide(numerator: i128, divisor: i128, mode: RoundingMode) -> i128 {
+    let quotient = numerator / divisor;
+    let remainder = (numerator % divisor).abs();
+    if remainder == 0 {
+        return quotient;
+    }
+    let negative = numerator < 0;
+    // Compares the remainder with half of the divisor without overflowing
+    let half = remainder.cmp(&(divisor - remainder));
+    let away_from_zero = match mode {
+        RoundingMode::Down => false,
+        RoundingMode::Up => true,
+        RoundingMode::Floor => negative,
+        RoundingMode::Ceiling => !negative,
+        RoundingMode::HalfUp => half != Ordering::Less,
+        RoundingMode::HalfDown => half == Ordering::Greater,
+        RoundingMode::HalfEven => {
+            half == Ordering::Greater || (half == Ordering::Equal && quotient % 2 != 0)
+        }
+    };
+    match (away_from_zero, negative) {
+        (false, _) => quotient,
+        (true, false) => quotient + 1,
+        (true, true) => quotient - 1,
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match align(self, other) {
+            Some((left, right, _)) => left.cmp(&right),
+            // Aligning only overflows when the scales are far apart
+            None => self.to_f64().total_cmp(&other.to_f64()),
+        }
+    }
+}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl std::ops::Neg for Decimal {
+    type Output = Decimal;
+
+    fn neg(self) -> Decimal {
+        Decimal::new(-self.mantissa, self.scale)
+    }
+}
+
+impl From<i64> for Decimal {
+    fn from(value: i64) -> Decimal {
+        Decimal::new(value as i128, 0)
+    }
+}
+
+impl FromStr for Decimal {
+    type Err = ScriptingError;
+
+    /// Parses plain decimal notation, such as `-152.345`, failing when the
+    /// digits do not fit the mantissa
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || ScriptingError::EvaluationError(format!("Invalid decimal {}", text));
+
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, text),
+        };
+        let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        if integer.is_empty() && fraction.is_empty() {
+            return Err(invalid());
+        }
+        // Trailing zeros do not count toward the scale, and decimals past
+        // the largest scale are rounded by `Decimal::new`
+        let fraction = fraction.trim_end_matches('0');
+
+        let mut mantissa: i128 = 0;
+        for digit in integer.chars().chain(fraction.chars()) {
+            let digit = digit.to_digit(10).ok_or_else(invalid)?;
+            mantissa = mantissa
+                .checked_mul(10)
+                .and_then(|mantissa| mantissa.checked_add(digit as i128))
+                .ok_or_else(invalid)?;
+        }
+        if negative {
+            mantissa = -mantissa;
+        }
+        Ok(Decimal::new(mantissa, fraction.len() as u32))
+    }
+}
+
+// Plain decimal notation, without exponent
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let digits = self.mantissa.unsigned_abs().to_string();
+        let scale = self.scale as usize;
+        let sign = if self.is_negative() { "-" } else { "" };
+        if scale == 0 {
+            return write!(f, "{}{}", sign, digits);
+        }
+        let digits = format!("{:0>width$}", digits, width = scale + 1);
+        let (integer, fraction) = digits.split_at(digits.len() - scale);
+        write!(f, "{}{}.{}", sign, integer, fraction)
+    }
+}
+
+impl fmt::Debug for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decimal(text: &str) -> Decimal {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_and_display() {
+        assert_eq!(decimal("152.340").to_string(), "152.34");
+        assert_eq!(decimal("-0.05").to_string(), "-0.05");
+        assert_eq!(decimal("100").to_string(), "100");
+        assert_eq!(decimal("100"), Decimal::new(10000, 2));
+        assert_eq!(
+            Decimal::new(15, 29),
+            decimal("0.0000000000000000000000000002")
+        );
+        assert_eq!(Decimal::from_f64(0.1), Some(decimal("0.1")));
+        assert_eq!(Decimal::from_f64(f64::NAN), None);
+        assert!("1.2.3".parse::<Decimal>().is_err());
+        assert!("".parse::<Decimal>().is_err());
+        assert!("1e5".parse::<Decimal>().is_err());
+    }
+
+    #[test]
+    fn test_exact_arithmetic() {
+        let sum = decimal("0.1").checked_add(&decimal("0.2")).unwrap();
+        assert_eq!(sum, decimal("0.3"));
+        let difference = decimal("1").checked_sub(&decimal("0.9")).unwrap();
+        assert_eq!(difference, decimal("0.1"));
+
+        let mode = RoundingMode::HalfEven;
+        let product = decimal("1.05")
+            .checked_mul(&decimal("3"), 10, mode)
+            .unwrap();
+        assert_eq!(product, decimal("3.15"));
+        // The product is rounded to the scale
+        let product = decimal("0.125")
+            .checked_mul(&decimal("0.1"), 3, mode)
+            .unwrap();
+        assert_eq!(product, decimal("0.012"));
+
+        let quotient = decimal("1").checked_div(&decimal("3"), 4, mode).unwrap();
+        assert_eq!(quotient, decimal("0.3333"));
+        let quotient = decimal("-2").checked_div(&decimal("0.3"), 2, mode).unwrap();
+        assert_eq!(quotient, decimal("-6.67"));
+        assert_eq!(decimal("1").checked_div(&Decimal::default(), 4, mode), None);
+
+        let remainder = decimal("10.5").checked_rem(&decimal("3")).unwrap();
+        assert_eq!(remainder, decimal("1.5"));
+        let remainder = decimal("-7").checked_rem(&decimal("3")).unwrap();
+        assert_eq!(remainder, decimal("2"));
+        let remainder = decimal("7").checked_rem(&decimal("-0.3")).unwrap();
+        assert_eq!(remainder, decimal("-0.2"));
+        assert_eq!(decimal("1").checked_rem(&Decimal::default()), None);
+        assert_eq!(decimal("-2.5").abs(), decimal("2.5"));
+    }
+
+    #[test]
+    fn test_rounding_modes() {
+        let round = |text: &str, digits: i32, mode: RoundingMode| {
+            decimal(text).round(digits, mode).to_string()
+        };
+
+        assert_eq!(round("2.345", 2, RoundingMode::HalfEven), "2.34");
+        assert_eq!(round("2.355", 2, RoundingMode::HalfEven), "2.36");
+        assert_eq!(round("-2.5", 0, RoundingMode::HalfEven), "-2");
+        assert_eq!(round("2.345", 2, RoundingMode::HalfUp), "2.35");
+        assert_eq!(round("-2.5", 0, RoundingMode::HalfUp), "-3");
+        assert_eq!(round("2.345", 2, RoundingMode::HalfDown), "2.34");
+        assert_eq!(round("2.3451", 2, RoundingMode::HalfDown), "2.35");
+        assert_eq!(round("-2.99", 0, RoundingMode::Down), "-2");
+        assert_eq!(round("2.01", 0, RoundingMode::Up), "3");
+        assert_eq!(round("-2.01", 0, RoundingMode::Floor), "-3");
+        assert_eq!(round("-2.99", 0, RoundingMode::Ceiling), "-2");
+        assert_eq!(round("1250", -2, RoundingMode::HalfEven), "1200");
+        assert_eq!(round("1.5", 3, RoundingMode::HalfEven), "1.5");
+
+        assert_eq!(
+            "bankers".parse::<RoundingMode>().unwrap(),
+            RoundingMode::HalfEven
+        );
+        assert!("nearest".parse::<RoundingMode>().is_err());
+    }
+
+    #[test]
+    fn test_ordering() {
+        assert!(decimal("0.30") < decimal("0.31"));
+        assert!(decimal("-1") < decimal("0.001"));
+        assert_eq!(decimal("2.50").cmp(&decimal("2.5")), Ordering::Equal);
+    }
+}
@@ -12,6 +12,14 @@ pub enum ScriptingError {
     UnexpectedToken(String),
     #[error("Error while evaluating: {0}")]
     EvaluationError(String),
+    #[error("Uninitialized variable: {0}")]
+    UninitializedVariable(String),
+    /// Failure reported by a host function, with its kind (e.g.
+    /// `insufficient_funds`) and message
+    #[error("Host error ({0}): {1}")]
+    HostError(String, String),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, ScriptingError>;
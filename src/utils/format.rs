@@ -0,0 +1,207 @@
+use crate::utils::{
+    decimal::{Decimal, RoundingMode},
+    errors::{Result, ScriptingError},
+};
+
+// Decimals kept by a format without precision, trailing zeros are dropped
+const DEFAULT_DECIMALS: usize = 6;
+
+/// Formats a number following `spec`, written `[+][,][.decimals][%]`:
+///
+/// - `+` shows the sign of positive numbers too,
+/// - `,` separates thousands,
+/// - `.decimals` gives the number of decimals, rounding half away from zero,
+/// - `%` formats the number as a percentage.
+///
+/// Without decimals, the number keeps up to six of them, e.g.
+/// `format_number(0.032, "+.1%")` is `+3.2%` and `format_number(1234.5, ",")`
+/// is `1,234.5`. The number is rounded as it is written, so that 152.345
+/// gives 152.35 even though the closest double is slightly below it.
+pub fn format_number(value: f64, spec: &str) -> Result<String> {
+    let spec = Spec::parse(spec)?;
+    Ok(match Decimal::from_f64(value) {
+        Some(value) => spec.format(&value),
+        None => value.to_string(),
+    })
+}
+
+/// Formats a decimal the same as [`format_number`]
+pub fn format_decimal(value: &Decimal, spec: &str) -> Result<String> {
+    Ok(Spec::parse(spec)?.format(value))
+}
+
+/// Formats an amount of money with thousands separators, e.g. `$1,234.50` or
+/// `1,234.50 CHF`. Currencies without a known symbol are written after the
+/// amount. `decimals` defaults to the usual number for the currency.
+pub fn format_currency(value: f64, currency: &str, decimals: Option<usize>) -> String {
+    match Decimal::from_f64(value) {
+        Some(value) => format_decimal_currency(&value, currency, decimals),
+        None => format!("{} {}", value, currency),
+    }
+}
+
+/// Formats a decimal amount of money the same as [`format_currency`]
+pub fn format_decimal_currency(value: &Decimal, currency: &str, decimals: Option<usize>) -> String {
+    let (symbol, usual_decimals) = match currency {
+        "USD" => (Some("$"), 2),
+        "EUR" => (Some("€"), 2),
+        "GBP" => (Some("£"), 2),
+        "JPY" => (Some("¥"), 0),
+        _ => (None, 2),
+    };
+    let digits = format_digits(value, Some(decimals.unwrap_or(usual_decimals)), true);
+    match (symbol, digits.strip_prefix('-')) {
+        (Some(symbol), Some(digits)) => format!("-{}{}", symbol, digits),
+        (Some(symbol), None) => format!("{}{}", symbol, digits),
+        (None, _) => format!("{} {}", digits, currency),
+    }
+}
+
+struct Spec {
+    sign: bool,
+    thousands: bool,
+    percent: bool,
+    decimals: Option<usize>,
+}
+
+impl Spec {
+    fn parse(spec: &str) -> Result<Spec> {
+        let invalid = || ScriptingError::EvaluationError(format!("Invalid format `{}`", spec));
+
+        let (sign, rest) = match spec.strip_prefix('+') {
+            Some(rest) => (true, rest),
+            None => (false, spec),
+        };
+        let (thousands, rest) = match rest.strip_prefix(',') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        let (percent, rest) = match rest.strip_suffix('%') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        let decimals = match rest {
+            "" => None,
+            _ => match rest.strip_prefix('.').map(str::parse::<usize>) {
+                Some(Ok(decimals)) if decimals <= 20 => Some(decimals),
+                _ => return Err(invalid()),
+            },
+        };
+        Ok(Spec {
+            sign,
+            thousands,
+            percent,
+            decimals,
+        })
+    }
+
+    fn format(&self, value: &Decimal) -> String {
+        let hundred = Decimal::from(100);
+        let value = match self.percent {
+            true => match value.checked_mul(&hundred, Decimal::MAX_SCALE, RoundingMode::HalfUp) {
+                Some(value) => value,
+                None => return format!("{}%", value.to_f64() * 100.0),
+            },
+            false => *value,
+        };
+        let mut formatted = format_digits(&value, self.decimals, self.thousands);
+        if self.sign && !formatted.starts_with('-') {
+            formatted.insert(0, '+');
+        }
+        if self.percent {
+            formatted.push('%');
+        }
+        formatted
+    }
+}
+
+// The number rounded half away from zero to the given decimals, or to at
+// most six of them
+fn format_digits(value: &Decimal, decimals: Option<usize>, thousands: bool) -> String {
+    let rounded = value.round(
+        decimals.unwrap_or(DEFAULT_DECIMALS) as i32,
+        RoundingMode::HalfUp,
+    );
+    // A small negative number rounded to zero has no sign
+    let digits = rounded.to_string();
+    let (sign, digits) = match digits.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", digits.as_str()),
+    };
+    let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+
+    let mut formatted = sign.to_string();
+    for (i, digit) in integer.chars().enumerate() {
+        if thousands && i > 0 && (integer.len() - i) % 3 == 0 {
+            formatted.push(',');
+        }
+        formatted.push(digit);
+    }
+    // Without a precision, trailing zeros are dropped
+    let width = decimals.unwrap_or(fraction.len());
+    if width > 0 {
+        formatted.push('.');
+        formatted.push_str(&format!("{:0<width$}", fraction, width = width));
+    }
+    formatted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_number() {
+        assert_eq!(format_number(152.345, ".2").unwrap(), "152.35");
+        assert_eq!(format_number(152.0, ".2").unwrap(), "152.00");
+        assert_eq!(format_number(152.5, ".0").unwrap(), "153");
+        assert_eq!(format_number(1234567.891, ",.2").unwrap(), "1,234,567.89");
+        assert_eq!(format_number(-1234.5, ",").unwrap(), "-1,234.5");
+        assert_eq!(format_number(123.0, ",").unwrap(), "123");
+        assert_eq!(format_number(0.1 + 0.2, "").unwrap(), "0.3");
+        assert_eq!(format_number(3.0, "+").unwrap(), "+3");
+        assert_eq!(format_number(-0.001, ".2").unwrap(), "0.00");
+        assert_eq!(format_number(999.996, ",.2").unwrap(), "1,000.00");
+        assert_eq!(format_number(0.5, ".0").unwrap(), "1");
+    }
+
+    #[test]
+    fn test_format_percent() {
+        assert_eq!(format_number(0.032, "+.1%").unwrap(), "+3.2%");
+        assert_eq!(format_number(-0.032, "+.1%").unwrap(), "-3.2%");
+        assert_eq!(format_number(0.032, "%").unwrap(), "3.2%");
+        assert_eq!(format_number(12.5, ",.0%").unwrap(), "1,250%");
+    }
+
+    #[test]
+    fn test_invalid_formats() {
+        assert!(format_number(1.0, "2").is_err());
+        assert!(format_number(1.0, ".x").is_err());
+        assert!(format_number(1.0, ",+").is_err());
+        assert!(format_number(1.0, ".99").is_err());
+    }
+
+    #[test]
+    fn test_format_currency() {
+        assert_eq!(format_currency(152.34, "USD", None), "$152.34");
+        assert_eq!(format_currency(-1234.5, "EUR", None), "-€1,234.50");
+        assert_eq!(format_currency(1234.5, "JPY", None), "¥1,235");
+        assert_eq!(format_currency(1234.5, "CHF", None), "1,234.50 CHF");
+        assert_eq!(format_currency(1234.5, "USD", Some(0)), "$1,235");
+    }
+
+    #[test]
+    fn test_format_decimal() {
+        let decimal = |text: &str| text.parse::<Decimal>().unwrap();
+        assert_eq!(format_decimal(&decimal("2.345"), ".2").unwrap(), "2.35");
+        assert_eq!(format_decimal(&decimal("0.1"), ",.3%").unwrap(), "10.000%");
+        assert_eq!(
+            format_decimal(&decimal("12345678901234567890.12"), ",").unwrap(),
+            "12,345,678,901,234,567,890.12"
+        );
+        assert_eq!(
+            format_decimal_currency(&decimal("-0.004"), "USD", None),
+            "$0.00"
+        );
+    }
+}
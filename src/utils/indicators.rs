@@ -0,0 +1,147 @@
+// Technical indicators over a price series, oldest price first. They are
+// `None` when the series is shorter than the window they need.
+
+/// The simple moving average of the last `window` prices
+pub fn sma(prices: &[f64], window: usize) -> Option<f64> {
+    let last = last(prices, window)?;
+    Some(last.iter().sum::<f64>() / window as f64)
+}
+
+/// The exponential moving average with a smoothing of `2 / (window + 1)`,
+/// starting from the simple average of the first `window` prices
+pub fn ema(prices: &[f64], window: usize) -> Option<f64> {
+    let seed = sma(prices.get(..window)?, window)?;
+    let smoothing = 2.0 / (window as f64 + 1.0);
+    Some(prices[window..].iter().fold(seed, |average, price| {
+        average + smoothing * (price - average)
+    }))
+}
+
+/// The relative strength index, from 0 to 100, with Wilder's smoothing of
+/// the average gains and losses. Needs `window + 1` prices; a flat series is
+/// at 50.
+pub fn rsi(prices: &[f64], window: usize) -> Option<f64> {
+    if window == 0 || prices.len() <= window {
+        return None;
+    }
+    let changes: Vec<f64> = prices.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    let periods = window as f64;
+    let (mut gain, mut loss) = changes[..window]
+        .iter()
+        .fold((0.0, 0.0), |(gain, loss), change| {
+            (gain + change.max(0.0), loss + (-change).max(0.0))
+        });
+    gain /= periods;
+    loss /= periods;
+    for change in &changes[window..] {
+        gain = (gain * (periods - 1.0) + change.max(0.0)) / periods;
+        loss = (loss * (periods - 1.0) + (-change).max(0.0)) / periods;
+    }
+    Some(match (gain, loss) {
+        (0.0, 0.0) => 50.0,
+        (_, 0.0) => 100.0,
+        _ => 100.0 - 100.0 / (1.0 + gain / loss),
+    })
+}
+
+/// Bollinger bands: the simple moving average of the last `window` prices,
+/// and `width` population standard deviations below and above it, as
+/// `(lower, middle, upper)`
+pub fn bollinger(prices: &[f64], window: usize, width: f64) -> Option<(f64, f64, f64)> {
+    let middle = sma(prices, window)?;
+    let variance = last(prices, window)?
+        .iter()
+        .map(|price| (price - middle).powi(2))
+        .sum::<f64>()
+        / window as f64;
+    let band = width * variance.sqrt();
+    Some((middle - band, middle, middle + band))
+}
+
+/// The highest of the last `window` prices
+pub fn high(prices: &[f64], window: usize) -> Option<f64> {
+    last(prices, window)?.iter().copied().reduce(f64::max)
+}
+
+/// The lowest of the last `window` prices
+pub fn low(prices: &[f64], window: usize) -> Option<f64> {
+    last(prices, window)?.iter().copied().reduce(f64::min)
+}
+
+fn last(prices: &[f64], window: usize) -> Option<&[f64]> {
+    match window {
+        0 => None,
+        _ => prices.get(prices.len().checked_sub(window)?..),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Closing prices of the moving average example of StockCharts
+    const CLOSES: [f64; 30] = [
+        22.27, 22.19, 22.08, 22.17, 22.18, 22.13, 22.23, 22.43, 22.24, 22.29, 22.15, 22.39, 22.38,
+        22.61, 23.36, 24.05, 23.75, 23.83, 23.95, 23.63, 23.82, 23.87, 23.65, 23.19, 23.10, 23.33,
+        22.68, 23.10, 22.40, 22.17,
+    ];
+
+    // Closing prices of the relative strength index example of StockCharts
+    const RSI_CLOSES: [f64; 33] = [
+        44.34, 44.09, 44.15, 43.61, 44.33, 44.83, 45.10, 45.42, 45.84, 46.08, 45.89, 46.03, 45.61,
+        46.28, 46.28, 46.00, 46.03, 46.41, 46.22, 45.64, 46.21, 46.25, 45.71, 46.45, 45.78, 45.35,
+        44.03, 44.18, 44.22, 44.57, 43.42, 42.66, 43.13,
+    ];
+
+    fn assert_close(actual: Option<f64>, expected: f64) {
+        let actual = actual.unwrap();
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_moving_averages() {
+        assert_close(sma(&CLOSES[..10], 10), 22.221);
+        assert_close(sma(&CLOSES, 10), 23.131);
+        // The published table, rounded to cents
+        let ema_at = |count: usize| (ema(&CLOSES[..count], 10).unwrap() * 100.0).round() / 100.0;
+        assert_eq!(ema_at(10), 22.22);
+        assert_eq!(ema_at(11), 22.21);
+        assert_eq!(ema_at(20), 23.34);
+        assert_eq!(ema_at(30), 22.92);
+        assert_close(ema(&CLOSES, 10), 22.915_004_434_033_058);
+
+        assert_eq!(sma(&CLOSES[..9], 10), None);
+        assert_eq!(ema(&CLOSES[..9], 10), None);
+        assert_eq!(sma(&CLOSES, 0), None);
+    }
+
+    #[test]
+    fn test_rsi() {
+        assert_close(rsi(&RSI_CLOSES[..15], 14), 70.464_135_021_097_05);
+        assert_close(rsi(&RSI_CLOSES, 14), 37.788_771_982_057_824);
+        assert_eq!(rsi(&RSI_CLOSES[..14], 14), None);
+        assert_eq!(rsi(&[1.0, 2.0, 3.0], 2), Some(100.0));
+        assert_eq!(rsi(&[2.0, 2.0, 2.0], 2), Some(50.0));
+    }
+
+    #[test]
+    fn test_bollinger_bands() {
+        let (lower, middle, upper) = bollinger(&CLOSES, 20, 2.0).unwrap();
+        assert_close(Some(lower), 21.905_533_992_551_575);
+        assert_close(Some(middle), 23.1705);
+        assert_close(Some(upper), 24.435_466_007_448_42);
+        assert_eq!(bollinger(&[1.0, 1.0], 2, 2.0), Some((1.0, 1.0, 1.0)));
+    }
+
+    #[test]
+    fn test_high_and_low() {
+        assert_eq!(high(&CLOSES, 20), Some(24.05));
+        assert_eq!(low(&CLOSES, 5), Some(22.17));
+        assert_eq!(high(&CLOSES, 31), None);
+    }
+}
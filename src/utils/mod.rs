@@ -1 +1,7 @@
+pub mod decimal;
 pub mod errors;
+pub mod format;
+pub mod indicators;
+pub mod math;
+pub mod statistics;
+pub mod time;
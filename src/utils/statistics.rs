@@ -0,0 +1,147 @@
+// Statistics over a series of values, e.g. prices or returns. They are `None`
+// when the series is too short for them.
+
+/// The arithmetic mean
+pub fn mean(values: &[f64]) -> Option<f64> {
+    match values.len() {
+        0 => None,
+        count => Some(values.iter().sum::<f64>() / count as f64),
+    }
+}
+
+/// The middle value, or the mean of the two middle values
+pub fn median(values: &[f64]) -> Option<f64> {
+    percentile(values, 50.0)
+}
+
+/// The sample variance, which divides by one less than the number of values.
+/// Needs two values.
+pub fn variance(values: &[f64]) -> Option<f64> {
+    if values.len() < 2 {
+        return None;
+    }
+    let mean = mean(values)?;
+    let squares = values
+        .iter()
+        .map(|value| (value - mean).powi(2))
+        .sum::<f64>();
+    Some(squares / (values.len() - 1) as f64)
+}
+
+/// The sample standard deviation
+pub fn stdev(values: &[f64]) -> Option<f64> {
+    variance(values).map(f64::sqrt)
+}
+
+/// The value below which `percent` of the values fall, from 0 to 100,
+/// interpolated linearly between the closest ranks
+pub fn percentile(values: &[f64], percent: f64) -> Option<f64> {
+    if values.is_empty() || !(0.0..=100.0).contains(&percent) {
+        return None;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let rank = percent / 100.0 * (sorted.len() - 1) as f64;
+    let (below, above) = (sorted[rank.floor() as usize], sorted[rank.ceil() as usize]);
+    Some(below + (above - below) * rank.fract())
+}
+
+/// Pearson's correlation coefficient of two series of the same length, from
+/// -1 to 1. Needs two pairs of values, and neither series to be constant.
+pub fn correlation(xs: &[f64], ys: &[f64]) -> Option<f64> {
+    if xs.len() != ys.len() || xs.len() < 2 {
+        return None;
+    }
+    let (mean_x, mean_y) = (mean(xs)?, mean(ys)?);
+    let (mut covariance, mut squares_x, mut squares_y) = (0.0, 0.0, 0.0);
+    for (x, y) in xs.iter().zip(ys) {
+        covariance += (x - mean_x) * (y - mean_y);
+        squares_x += (x - mean_x).powi(2);
+        squares_y += (y - mean_y).powi(2);
+    }
+    match squares_x * squares_y {
+        0.0 => None,
+        product => Some(covariance / product.sqrt()),
+    }
+}
+
+/// The largest fall from a peak to a later trough, as a fraction of the peak,
+/// e.g. `0.25` for a fall from 100 to 75. Zero when the values never fall.
+pub fn max_drawdown(values: &[f64]) -> Option<f64> {
+    let mut peak = *values.first()?;
+    let mut drawdown: f64 = 0.0;
+    for &value in values {
+        peak = peak.max(value);
+        drawdown = drawdown.max((peak - value) / peak);
+    }
+    Some(drawdown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: Option<f64>, expected: f64) {
+        let actual = actual.unwrap();
+        assert!(
+            (actual - expected).abs() < 1e-12,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_mean_and_median() {
+        assert_eq!(mean(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]), Some(5.0));
+        assert_eq!(median(&[3.0, 1.0, 2.0]), Some(2.0));
+        assert_eq!(median(&[4.0, 1.0, 3.0, 2.0]), Some(2.5));
+        assert_eq!(mean(&[]), None);
+        assert_eq!(median(&[]), None);
+    }
+
+    #[test]
+    fn test_variance_and_stdev() {
+        let values = [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0];
+        // The population variance is 4, the sample one 32 / 7
+        assert_close(variance(&values), 32.0 / 7.0);
+        assert_close(stdev(&values), (32.0f64 / 7.0).sqrt());
+        assert_eq!(variance(&[1.0, 1.0]), Some(0.0));
+        assert_eq!(variance(&[1.0]), None);
+    }
+
+    #[test]
+    fn test_percentile() {
+        let values = [15.0, 20.0, 35.0, 40.0, 50.0];
+        assert_eq!(percentile(&values, 0.0), Some(15.0));
+        assert_eq!(percentile(&values, 100.0), Some(50.0));
+        assert_eq!(percentile(&values, 50.0), Some(35.0));
+        assert_close(percentile(&values, 40.0), 29.0);
+        assert_close(percentile(&values, 95.0), 48.0);
+        assert_eq!(percentile(&[7.0], 30.0), Some(7.0));
+        assert_eq!(percentile(&values, 101.0), None);
+        assert_eq!(percentile(&values, -1.0), None);
+    }
+
+    #[test]
+    fn test_correlation() {
+        let xs = [1.0, 2.0, 3.0, 4.0, 5.0];
+        assert_close(correlation(&xs, &[2.0, 4.0, 6.0, 8.0, 10.0]), 1.0);
+        assert_close(correlation(&xs, &[5.0, 4.0, 3.0, 2.0, 1.0]), -1.0);
+        assert_close(
+            correlation(&xs, &[2.0, 4.0, 5.0, 4.0, 5.0]),
+            0.774_596_669_241_483_4,
+        );
+        assert_eq!(correlation(&xs, &[1.0, 1.0, 1.0, 1.0, 1.0]), None);
+        assert_eq!(correlation(&xs, &[1.0, 2.0]), None);
+        assert_eq!(correlation(&[1.0], &[2.0]), None);
+    }
+
+    #[test]
+    fn test_max_drawdown() {
+        assert_close(max_drawdown(&[100.0, 120.0, 90.0, 110.0, 60.0, 130.0]), 0.5);
+        assert_close(max_drawdown(&[100.0, 75.0, 80.0]), 0.25);
+        assert_eq!(max_drawdown(&[1.0, 2.0, 3.0]), Some(0.0));
+        assert_eq!(max_drawdown(&[]), None);
+    }
+}
@@ -0,0 +1,494 @@
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+use crate::utils::errors::ScriptingError;
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/// A calendar day of the proleptic Gregorian calendar, e.g. `2024-05-04`.
+/// Dates have no time zone: the host decides which day `today()` is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Date {
+    // Days since 1970-01-01
+    days: i64,
+}
+
+impl Date {
+    /// The date, `None` when the month or the day does not exist or the year
+    /// is not between 1 and 9999
+    pub fn from_ymd(year: i32, month: u32, day: u32) -> Option<Date> {
+        if !(1..=9999).contains(&year) || !(1..=12).contains(&month) {
+            return None;
+        }
+        if day == 0 || day > days_in_month(year, month) {
+            return None;
+        }
+        Some(Date {
+            days: days_from_civil(year, month, day),
+        })
+    }
+
+    pub fn from_days_since_epoch(days: i64) -> Date {
+        Date { days }
+    }
+
+    pub fn days_since_epoch(&self) -> i64 {
+        self.days
+    }
+
+    /// Year, month and day of the month
+    pub fn ymd(&self) -> (i32, u32, u32) {
+        civil_from_days(self.days)
+    }
+
+    pub fn day_of_month(&self) -> u32 {
+        self.ymd().2
+    }
+
+    /// Day of the week, from 1 for Monday to 7 for Sunday
+    pub fn weekday(&self) -> u32 {
+        // 1970-01-01 was a Thursday
+        ((self.days + 3).rem_euclid(7) + 1) as u32
+    }
+
+    /// Whether the date falls from Monday to Friday
+    pub fn is_weekday(&self) -> bool {
+        self.weekday() <= 5
+    }
+
+    /// The date `days` later, `None` past the year 9999
+    pub fn checked_add_days(&self, days: i64) -> Option<Date> {
+        let days = self.days.checked_add(days)?;
+        let first = days_from_civil(1, 1, 1);
+        let last = days_from_civil(9999, 12, 31);
+        (first..=last).contains(&days).then_some(Date { days })
+    }
+
+    /// The same day `months` later, or the last day of that month if it is
+    /// shorter: a month after January 31st is the end of February
+    pub fn checked_add_months(&self, months: i64) -> Option<Date> {
+        let (year, month, day) = self.ymd();
+        let index = (year as i64 * 12 + month as i64 - 1).checked_add(months)?;
+        let year = i32::try_from(index.div_euclid(12)).ok()?;
+        let month = index.rem_euclid(12) as u32 + 1;
+        Date::from_ymd(year, month, day.min(days_in_month(year, month)))
+    }
+
+    /// The last day of the month of the date
+    pub fn end_of_month(&self) -> Date {
+        let (year, month, _) = self.ymd();
+        Date {
+            days: days_from_civil(year, month, days_in_month(year, month)),
+        }
+    }
+
+    /// The date moved by a duration, which must be whole days
+    pub fn checked_add(&self, duration: &Duration) -> Option<Date> {
+        if duration.seconds != 0 {
+            return None;
+        }
+        self.checked_add_months(duration.months as i64)?
+            .checked_add_days(duration.days)
+    }
+
+    /// Midnight at the start of the day
+    pub fn at_midnight(&self) -> DateTime {
+        DateTime {
+            seconds: self.days * SECONDS_PER_DAY,
+        }
+    }
+}
+
+impl FromStr for Date {
+    type Err = ScriptingError;
+
+    /// Parses `YYYY-MM-DD`
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || ScriptingError::InvalidToken(format!("Invalid date {}", text));
+        let parts: Vec<&str> = text.split('-').collect();
+        match parts.as_slice() {
+            [year, month, day] if year.len() == 4 && month.len() == 2 && day.len() == 2 => {
+                let number = |part: &str| match part.chars().all(|c| c.is_ascii_digit()) {
+                    true => part.parse::<u32>().map_err(|_| invalid()),
+                    false => Err(invalid()),
+                };
+                Date::from_ymd(number(year)? as i32, number(month)?, number(day)?)
+                    .ok_or_else(invalid)
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (year, month, day) = self.ymd();
+        write!(f, "{:04}-{:02}-{:02}", year, month, day)
+    }
+}
+
+/// An instant to the second, without time zone, e.g. `2024-05-04T09:30:00`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    // Seconds since 1970-01-01T00:00:00
+    seconds: i64,
+}
+
+impl DateTime {
+    /// The date at `hour:minute:second`, `None` for an invalid time of day
+    pub fn new(date: Date, hour: u32, minute: u32, second: u32) -> Option<DateTime> {
+        if hour > 23 || minute > 59 || second > 59 {
+            return None;
+        }
+        Some(DateTime {
+            seconds: date.days * SECONDS_PER_DAY + (hour * 3600 + minute * 60 + second) as i64,
+        })
+    }
+
+    pub fn from_seconds_since_epoch(seconds: i64) -> DateTime {
+        DateTime { seconds }
+    }
+
+    pub fn seconds_since_epoch(&self) -> i64 {
+        self.seconds
+    }
+
+    pub fn date(&self) -> Date {
+        Date {
+            days: self.seconds.div_euclid(SECONDS_PER_DAY),
+        }
+    }
+
+    /// Hour, minute and second of the day
+    pub fn hms(&self) -> (u32, u32, u32) {
+        let seconds = self.seconds.rem_euclid(SECONDS_PER_DAY) as u32;
+        (seconds / 3600, seconds / 60 % 60, seconds % 60)
+    }
+
+    /// The instant moved by a duration, months first as for dates
+    pub fn checked_add(&self, duration: &Duration) -> Option<DateTime> {
+        let date = self
+            .date()
+            .checked_add_months(duration.months as i64)?
+            .checked_add_days(duration.days)?;
+        let seconds = date
+            .at_midnight()
+            .seconds
+            .checked_add(self.seconds.rem_euclid(SECONDS_PER_DAY))?
+            .checked_add(duration.seconds)?;
+        // The seconds may carry over to a day out of range
+        let time = DateTime { seconds };
+        time.date().checked_add_days(0)?;
+        Some(time)
+    }
+}
+
+impl FromStr for DateTime {
+    type Err = ScriptingError;
+
+    /// Parses `YYYY-MM-DDTHH:MM` with optional seconds
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || ScriptingError::InvalidToken(format!("Invalid date and time {}", text));
+        let (date, time) = text.split_once('T').ok_or_else(invalid)?;
+        let date = date.parse::<Date>().map_err(|_| invalid())?;
+        let parts = time
+            .split(':')
+            .map(
+                |part| match part.len() == 2 && part.chars().all(|c| c.is_ascii_digit()) {
+                    true => part.parse::<u32>().map_err(|_| invalid()),
+                    false => Err(invalid()),
+                },
+            )
+            .collect::<Result<Vec<_>, _>>()?;
+        match parts.as_slice() {
+            [hour, minute] => DateTime::new(date, *hour, *minute, 0),
+            [hour, minute, second] => DateTime::new(date, *hour, *minute, *second),
+            _ => None,
+        }
+        .ok_or_else(invalid)
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (hour, minute, second) = self.hms();
+        write!(f, "{}T{:02}:{:02}:{:02}", self.date(), hour, minute, second)
+    }
+}
+
+/// A span of time, e.g. `30d` or `1m`. Months and days are calendar units:
+/// a month after January 31st is the end of February, so months are only
+/// comparable with months, and days and seconds with days and seconds.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Duration {
+    months: i32,
+    days: i64,
+    seconds: i64,
+}
+
+impl Duration {
+    pub fn new(months: i32, days: i64, seconds: i64) -> Duration {
+        Duration {
+            months,
+            days,
+            seconds,
+        }
+    }
+
+    pub fn months(&self) -> i32 {
+        self.months
+    }
+
+    pub fn days(&self) -> i64 {
+        self.days
+    }
+
+    pub fn seconds(&self) -> i64 {
+        self.seconds
+    }
+
+    /// Whether the duration is made of whole days and months, so that adding
+    /// it to a date gives a date
+    pub fn is_whole_days(&self) -> bool {
+        self.seconds == 0
+    }
+
+    /// The duration of `amount` units: `y`, `m` (months), `w`, `d`, `h`,
+    /// `min` or `s`
+    pub fn from_unit(amount: i64, unit: &str) -> Option<Duration> {
+        let duration = match unit {
+            "y" => Duration::new(i32::try_from(amount.checked_mul(12)?).ok()?, 0, 0),
+            "m" => Duration::new(i32::try_from(amount).ok()?, 0, 0),
+            "w" => Duration::new(0, amount.checked_mul(7)?, 0),
+            "d" => Duration::new(0, amount, 0),
+            "h" => Duration::new(0, 0, amount.checked_mul(3600)?),
+            "min" => Duration::new(0, 0, amount.checked_mul(60)?),
+            "s" => Duration::new(0, 0, amount),
+            _ => return None,
+        };
+        Some(duration)
+    }
+
+    pub fn checked_add(&self, other: &Duration) -> Option<Duration> {
+        Some(Duration {
+            months: self.months.checked_add(other.months)?,
+            days: self.days.checked_add(other.days)?,
+            seconds: self.seconds.checked_add(other.seconds)?,
+        })
+    }
+
+    pub fn checked_mul(&self, factor: i64) -> Option<Duration> {
+        Some(Duration {
+            months: self.months.checked_mul(i32::try_from(factor).ok()?)?,
+            days: self.days.checked_mul(factor)?,
+            seconds: self.seconds.checked_mul(factor)?,
+        })
+    }
+
+    // Length in seconds, for durations without months
+    fn fixed_seconds(&self) -> Option<i64> {
+        match self.months {
+            0 => Some(self.days * SECONDS_PER_DAY + self.seconds),
+            _ => None,
+        }
+    }
+}
+
+impl std::ops::Neg for Duration {
+    type Output = Duration;
+
+    fn neg(self) -> Duration {
+        Duration {
+            months: -self.months,
+            days: -self.days,
+            seconds: -self.seconds,
+        }
+    }
+}
+
+// `1d` equals `24h`, but never `1m`
+impl PartialEq for Duration {
+    fn eq(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+impl Eq for Duration {}
+
+// Months compare with months, days and seconds with days and seconds
+impl PartialOrd for Duration {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        if let (Some(left), Some(right)) = (self.fixed_seconds(), other.fixed_seconds()) {
+            return Some(left.cmp(&right));
+        }
+        match self.days == 0 && self.seconds == 0 && other.days == 0 && other.seconds == 0 {
+            true => Some(self.months.cmp(&other.months)),
+            false => None,
+        }
+    }
+}
+
+// Written the way it is typed, e.g. `1y 2m 3d` or `-90min`
+impl fmt::Display for Duration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self.seconds;
+        let parts = [
+            (self.months as i64 / 12, "y"),
+            (self.months as i64 % 12, "m"),
+            (self.days, "d"),
+            (seconds / 3600, "h"),
+            (seconds / 60 % 60, "min"),
+            (seconds % 60, "s"),
+        ];
+        let parts: Vec<String> = parts
+            .iter()
+            .filter(|(amount, _)| *amount != 0)
+            .map(|(amount, unit)| format!("{}{}", amount, unit))
+            .collect();
+        match parts.is_empty() {
+            true => write!(f, "0d"),
+            false => write!(f, "{}", parts.join(" ")),
+        }
+    }
+}
+
+fn is_leap_year(year: i32) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i32, month: u32) -> u32 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Days since 1970-01-01 of a civil date, counting in 400 year eras which
+// start on March 1st so that leap days end the year
+fn days_from_civil(year: i32, month: u32, day: u32) -> i64 {
+    let year = year as i64 - (month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (month as i64 + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// Inverse of `days_from_civil`
+fn civil_from_days(days: i64) -> (i32, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u32;
+    let month = ((month_from_march + 2) % 12 + 1) as u32;
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year as i32, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(text: &str) -> Date {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_dates() {
+        assert_eq!(date("1970-01-01").days_since_epoch(), 0);
+        assert_eq!(date("2024-05-04").days_since_epoch(), 19_847);
+        assert_eq!(date("2024-02-29").ymd(), (2024, 2, 29));
+        assert_eq!(date("1999-12-31").to_string(), "1999-12-31");
+        assert_eq!(date("2024-05-04").weekday(), 6);
+        assert!(!date("2024-05-04").is_weekday());
+        assert!(date("2024-05-06").is_weekday());
+        assert_eq!(date("2024-02-10").end_of_month(), date("2024-02-29"));
+        assert_eq!(date("2023-12-31").end_of_month(), date("2023-12-31"));
+
+        assert!("2023-02-29".parse::<Date>().is_err());
+        assert!("2024-13-01".parse::<Date>().is_err());
+        assert!("2024-5-4".parse::<Date>().is_err());
+        assert!("0000-01-01".parse::<Date>().is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for days in (-800_000..3_000_000).step_by(997) {
+            let date = Date::from_days_since_epoch(days);
+            let (year, month, day) = date.ymd();
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn test_add_months() {
+        assert_eq!(
+            date("2024-01-31").checked_add_months(1),
+            Some(date("2024-02-29"))
+        );
+        assert_eq!(
+            date("2023-01-31").checked_add_months(1),
+            Some(date("2023-02-28"))
+        );
+        assert_eq!(
+            date("2024-03-15").checked_add_months(-3),
+            Some(date("2023-12-15"))
+        );
+        assert_eq!(
+            date("2024-05-04").checked_add(&Duration::new(1, 30, 0)),
+            Some(date("2024-07-04"))
+        );
+        assert_eq!(
+            date("2024-05-04").checked_add(&Duration::new(0, 0, 60)),
+            None
+        );
+        assert_eq!(date("9999-12-01").checked_add_months(1), None);
+        assert_eq!(date("9999-12-31").checked_add_days(1), None);
+        assert_eq!(date("0001-01-01").checked_add_days(-1), None);
+    }
+
+    #[test]
+    fn test_date_times() {
+        let time: DateTime = "2024-05-04T09:30".parse().unwrap();
+        assert_eq!(time.to_string(), "2024-05-04T09:30:00");
+        assert_eq!(time.date(), date("2024-05-04"));
+        assert_eq!(time.hms(), (9, 30, 0));
+        assert_eq!(
+            time.checked_add(&Duration::new(1, 0, 15 * 3600))
+                .unwrap()
+                .to_string(),
+            "2024-06-05T00:30:00"
+        );
+        assert!("2024-05-04T24:00".parse::<DateTime>().is_err());
+        assert!("2024-05-04 09:30".parse::<DateTime>().is_err());
+    }
+
+    #[test]
+    fn test_durations() {
+        let duration = |amount, unit| Duration::from_unit(amount, unit).unwrap();
+        assert_eq!(duration(2, "w"), duration(14, "d"));
+        assert_eq!(duration(1, "d"), duration(24, "h"));
+        assert_ne!(duration(1, "m"), duration(30, "d"));
+        assert_eq!(duration(1, "y").to_string(), "1y");
+        assert_eq!(
+            duration(14, "m")
+                .checked_add(&duration(3, "d"))
+                .unwrap()
+                .checked_add(&duration(-90, "min"))
+                .unwrap()
+                .to_string(),
+            "1y 2m 3d -1h -30min"
+        );
+        assert_eq!(Duration::default().to_string(), "0d");
+        assert!(duration(1, "d") > duration(23, "h"));
+        assert!(duration(1, "y") > duration(11, "m"));
+        assert_eq!(duration(1, "m").partial_cmp(&duration(30, "d")), None);
+        assert!(Duration::from_unit(1, "q").is_none());
+    }
+}
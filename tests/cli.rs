@@ -0,0 +1,42 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+// Runs the `lefi` command on a script written to a temporary file
+fn run(name: &str, script: &str) -> Output {
+    let path: PathBuf =
+        std::env::temp_dir().join(format!("lefi-cli-{}-{}.lefi", name, std::process::id()));
+    std::fs::write(&path, script).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_lefi"))
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    output
+}
+
+#[test]
+fn test_transaction_after_require() {
+    let output = run(
+        "transaction",
+        "cash = 10\nrequire(cash > 0, \"need cash\")\nBuy(\"AAPL\", 1)\n",
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("Buy(AAPL, 1)"), "{}", stdout);
+}
+
+#[test]
+fn test_require_notifies() {
+    let output = run(
+        "notify",
+        "cash = 10\nrequire(cash > 100, \"need more cash\", true)\nBuy(\"AAPL\", 1)\n",
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stdout.contains("Notify(need more cash)"), "{}", stdout);
+    assert!(!stdout.contains("Buy("), "{}", stdout);
+}